@@ -159,6 +159,22 @@ pub mod write {
         Ok(())
     }
 
+    /// Writes the last chunk of a body sent with `transfer-encoding: chunked`,
+    /// followed by the `trailers`.
+    ///
+    /// # Errors
+    ///
+    /// Passes any errors from writing to `writer`.
+    pub async fn last_chunk(
+        trailers: &HeaderMap,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> io::Result<()> {
+        write_bytes!(writer, b"0\r\n");
+        headers(trailers, &mut writer).await?;
+        write_bytes!(writer, b"\r\n");
+        Ok(())
+    }
+
     /// `writer` should be buffered.
     ///
     /// # Errors
@@ -194,6 +210,7 @@ pub mod read {
     // use super::{parse::Error, AsyncRead, AsyncReadExt, Bytes, BytesMut, CR, LF};
     use crate::prelude::*;
     use parse::{Error, RequestParseStage};
+    use std::time::{Duration, Instant};
 
     pub(crate) fn contains_two_newlines(bytes: &[u8]) -> bool {
         let mut in_row = 0_u8;
@@ -213,6 +230,7 @@ pub mod read {
         mut reader: impl AsyncRead + Unpin,
         read: &mut usize,
         max_len: usize,
+        timeout: Duration,
     ) -> Result<usize, Error> {
         assert!(buffer.len() == *read);
         if buffer.len() == max_len {
@@ -228,33 +246,46 @@ pub mod read {
         }
 
         unsafe { buffer.set_len(buffer.capacity()) };
-        let read_now = tokio::time::timeout(
-            std::time::Duration::from_secs(1),
-            reader.read(&mut buffer[*read..]),
-        )
-        .await
-        .ok()
-        .ok_or(Error::Done)?
-        .ok()
-        .ok_or(Error::Done)?;
+        let read_now = tokio::time::timeout(timeout, reader.read(&mut buffer[*read..]))
+            .await
+            .ok()
+            .ok_or(Error::Timeout)?
+            .ok()
+            .ok_or(Error::Done)?;
         *read += read_now;
         unsafe { buffer.set_len(*read) };
 
         Ok(read_now)
     }
 
+    /// Waits `idle_timeout` for the first bytes, then gives the rest of the head
+    /// `header_timeout` to arrive.
+    ///
+    /// If nothing was received, [`Error::Done`] is returned when `idle_timeout` runs out.
     pub(crate) async fn read_headers(
         mut reader: impl AsyncRead + Unpin,
         max_len: usize,
+        idle_timeout: Duration,
+        header_timeout: Duration,
     ) -> Result<Bytes, Error> {
         let mut buffer = BytesMut::with_capacity(1024);
         let mut read = 0;
         let read = &mut read;
+        let mut deadline = None;
 
         loop {
-            if read_more(&mut buffer, &mut reader, read, max_len).await? == 0 {
-                break;
-            };
+            let timeout = deadline.map_or(idle_timeout, |deadline: Instant| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            match read_more(&mut buffer, &mut reader, read, max_len, timeout).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(Error::Timeout) if deadline.is_none() => return Err(Error::Done),
+                Err(err) => return Err(err),
+            }
+            if deadline.is_none() {
+                deadline = Some(Instant::now() + header_timeout);
+            }
             if !(valid_method(&buffer) || valid_version(&buffer)) {
                 return Err(Error::Syntax);
             }
@@ -277,13 +308,21 @@ pub mod read {
     /// # Limitations
     ///
     /// Request will be cut off at `max_len`.
+    ///
+    /// # Timeouts
+    ///
+    /// If no bytes are received within `idle_timeout`, [`Error::Done`] is returned.
+    /// After the first bytes, the whole head must be received within `header_timeout`,
+    /// else [`Error::Timeout`] is returned.
     pub async fn request<R: AsyncRead + Unpin>(
         mut stream: impl std::ops::DerefMut<Target = R>,
         max_len: usize,
         default_host: &[u8],
         scheme: &str,
+        idle_timeout: Duration,
+        header_timeout: Duration,
     ) -> Result<(Request<()>, Bytes), Error> {
-        let buffer = read_headers(&mut *stream, max_len).await?;
+        let buffer = read_headers(&mut *stream, max_len, idle_timeout, header_timeout).await?;
 
         let mut parse_stage = RequestParseStage::Method;
         // Method is max 7 bytes long
@@ -408,9 +447,17 @@ pub mod read {
     /// Passes errors from [`http::response::Builder::body`] and internal checks.
     /// See [`Error`] for variants.
     /// Will also return errors similar to [`request`].
+    ///
+    /// # Timeouts
+    ///
+    /// If no bytes are received within `idle_timeout`, [`Error::Done`] is returned.
+    /// After the first bytes, the whole head must be received within `header_timeout`,
+    /// else [`Error::Timeout`] is returned.
     pub async fn response(
         mut reader: impl AsyncRead + Unpin,
         max_len: usize,
+        idle_timeout: Duration,
+        header_timeout: Duration,
     ) -> Result<Response<Bytes>, Error> {
         enum ParseStage {
             Version,
@@ -418,7 +465,7 @@ pub mod read {
             CanonicalReason,
         }
 
-        let bytes = read_headers(&mut reader, max_len).await?;
+        let bytes = read_headers(&mut reader, max_len, idle_timeout, header_timeout).await?;
 
         // Version is at most 8 bytes long
         let mut version_bytes = [0; 8];
@@ -0,0 +1,113 @@
+//! Compares cache hits on a single lock to hits on a [`ShardedCache`]
+//! on the multi-threaded runtime.
+//!
+//! Run using `cargo bench --bench cache --features mt`.
+use kvarn::comprash::{Cache, FileCache, ShardedCache};
+use kvarn::prelude::*;
+use std::time::Instant;
+
+/// The number of files in the cache.
+const FILES: usize = 256;
+/// The number of lookups each task does.
+const LOOKUPS: usize = 100_000;
+
+fn paths() -> Vec<PathBuf> {
+    (0..FILES)
+        .map(|n| PathBuf::from(format!("public/{}.html", n)))
+        .collect()
+}
+/// Gets the `n`th path of the `task`, spread over all the paths.
+fn path(paths: &[PathBuf], task: usize, n: usize) -> &Path {
+    &paths[(task * 7919 + n * 31) % paths.len()]
+}
+
+async fn tokio_mutex(paths: Arc<Vec<PathBuf>>, tasks: usize) {
+    let mut cache: Cache<PathBuf, Bytes> = Cache::default();
+    for path in paths.iter() {
+        cache.cache(path.clone(), Bytes::from_static(&[0; 1024]));
+    }
+    let cache = Arc::new(Mutex::new(cache));
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let cache = Arc::clone(&cache);
+            let paths = Arc::clone(&paths);
+            tokio::spawn(async move {
+                for n in 0..LOOKUPS {
+                    let mut lock = cache.lock().await;
+                    let file = lock.get(path(&paths, task, n)).into_option().cloned();
+                    drop(lock);
+                    assert!(file.is_some());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+async fn sharded(paths: Arc<Vec<PathBuf>>, tasks: usize, shards: usize) {
+    let cache: FileCache = ShardedCache::default().with_shards(shards);
+    for path in paths.iter() {
+        cache.cache(path.clone(), Bytes::from_static(&[0; 1024]));
+    }
+    let cache = Arc::new(cache);
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let cache = Arc::clone(&cache);
+            let paths = Arc::clone(&paths);
+            tokio::spawn(async move {
+                for n in 0..LOOKUPS {
+                    let file = cache.get(path(&paths, task, n)).into_option();
+                    assert!(file.is_some());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+fn measure<F: std::future::Future<Output = ()>>(
+    runtime: &tokio::runtime::Runtime,
+    name: &str,
+    tasks: usize,
+    future: F,
+) {
+    let start = Instant::now();
+    runtime.block_on(future);
+    let elapsed = start.elapsed();
+    let lookups = (tasks * LOOKUPS) as f64;
+    println!(
+        "{:<28}{:>10.1} ms{:>14.0} lookups/s",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        lookups / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let threads = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    let tasks = threads * 4;
+    let paths = Arc::new(paths());
+
+    println!(
+        "{} threads, {} tasks each looking up {} of {} files\n",
+        threads, tasks, LOOKUPS, FILES
+    );
+    measure(
+        &runtime,
+        "tokio Mutex<Cache>",
+        tasks,
+        tokio_mutex(Arc::clone(&paths), tasks),
+    );
+    for shards in [1, 4, ShardedCache::<PathBuf, Bytes>::DEFAULT_SHARDS, 64] {
+        measure(
+            &runtime,
+            &format!("ShardedCache, {} shards", shards),
+            tasks,
+            sharded(Arc::clone(&paths), tasks, shards),
+        );
+    }
+}
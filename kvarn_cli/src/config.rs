@@ -0,0 +1,1388 @@
+//! The configuration file of the `kvarn` binary.
+//!
+//! The file is either TOML (`.toml`) or RON (`.ron`), chosen by the file extension.
+//! It's deserialized to [`Config`], which is then checked and turned into a [`RunConfig`]
+//! by [`load`]. All problems found are reported at once, with the name of the
+//! host or the port they belong to.
+//!
+//! Relative paths are resolved from the directory of the configuration file.
+//!
+//! # Examples
+//!
+//! ```toml
+//! default_host = "example.org"
+//! unknown_host = "misdirected"
+//!
+//! [[host]]
+//! name = "example.org"
+//! aliases = ["www.example.org", "*.example.net"]
+//! path = "example.org"
+//! certificate = "certs/example.org.pem"
+//! private_key = "certs/example.org-key.pem"
+//! http_redirect = true
+//!
+//! [host.options]
+//! folder_default = "index.html"
+//! max_body_size = 1_048_576
+//! brotli_level = 11
+//!
+//! [host.options.path_max_body_sizes]
+//! "/upload/" = 1_073_741_824
+//!
+//! [host.limiter]
+//! max_requests = 20
+//!
+//! [host.cache.response]
+//! size_limit = 16_777_216
+//! max_size = 536_870_912
+//! eviction = "lfu"
+//! shards = 32
+//!
+//! [host.access_log]
+//! path = "logs/example.org.log"
+//! format = "json"
+//!
+//! [host.extensions]
+//! autoindex = ["/downloads/"]
+//!
+//! [[host.extensions.force_cache]]
+//! rule = ".woff2"
+//! preference = "full"
+//!
+//! [[host.extensions.reverse_proxy]]
+//! path = "/api/"
+//! tcp = "127.0.0.1:8080"
+//!
+//! [[port]]
+//! port = 80
+//!
+//! [[port]]
+//! port = 443
+//! https = true
+//! http3 = true
+//! header_timeout = 5
+//!
+//! [metrics]
+//! port = 9100
+//! addresses = ["127.0.0.1:9100"]
+//! ```
+
+use kvarn::{
+    access_log::{AccessLog, Format as LogFormat},
+    host::DataHandle,
+    limiting::Manager as LimitManager,
+    metrics::Metrics,
+    prelude::*,
+};
+use kvarn_extensions::{fastcgi, AutoIndex, ReverseProxy, ReverseProxyConnection};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+/// The root of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The virtual hosts. All hosts are available on all ports.
+    #[serde(rename = "host", default)]
+    pub hosts: Vec<HostConfig>,
+    /// The ports to listen on.
+    #[serde(rename = "port", default)]
+    pub ports: Vec<PortConfig>,
+    /// The name of the host used when no other matches.
+    ///
+    /// If no value is passed, the first host is used.
+    pub default_host: Option<String>,
+    /// What to do with requests to hosts which aren't configured;
+    /// `default`, `misdirected` or `drop`. See [`host::UnknownHost`].
+    ///
+    /// Defaults to `default`, which serves them from the default host.
+    pub unknown_host: Option<String>,
+    /// Disables [handover](https://kvarn.org/shutdown-handover.).
+    #[serde(default)]
+    pub disable_handover: bool,
+    /// The path of the handover socket.
+    pub handover_socket_path: Option<String>,
+    /// Where to serve the metrics of all hosts. See [`kvarn::metrics`].
+    pub metrics: Option<MetricsConfig>,
+}
+
+/// A virtual host. See [`Host`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// The name of the host, see [`Host::name`].
+    pub name: String,
+    /// Other names of the host, see [`Host::aliases`].
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// The base path of the host, see [`Host::path`].
+    pub path: PathBuf,
+    /// The PEM encoded certificate chain.
+    pub certificate: Option<PathBuf>,
+    /// The PEM encoded private key of the certificate.
+    pub private_key: Option<PathBuf>,
+    /// Redirects HTTP requests to HTTPS. See [`Host::set_http_redirect_to_https`].
+    #[serde(default)]
+    pub http_redirect: bool,
+    /// Enables HSTS. See [`Host::enable_hsts`].
+    #[serde(default)]
+    pub hsts: bool,
+    /// See [`host::Options`].
+    #[serde(default)]
+    pub options: OptionsConfig,
+    /// See [`LimitManager`].
+    #[serde(default)]
+    pub limiter: LimiterConfig,
+    /// The sizes of the server caches.
+    #[serde(default)]
+    pub cache: CachesConfig,
+    /// Which extensions to mount.
+    #[serde(default)]
+    pub extensions: ExtensionsConfig,
+    /// Where to log the requests. See [`Host::enable_access_log`].
+    pub access_log: Option<AccessLogConfig>,
+}
+
+/// The settings of [`host::Options`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptionsConfig {
+    /// See [`host::Options::folder_default`].
+    pub folder_default: Option<String>,
+    /// See [`host::Options::extension_default`].
+    pub extension_default: Option<String>,
+    /// See [`host::Options::public_data_dir`].
+    pub public_data_dir: Option<PathBuf>,
+    /// See [`host::Options::disable_client_cache`].
+    #[serde(default)]
+    pub disable_client_cache: bool,
+    /// See [`host::Options::disable_if_modified_since`].
+    #[serde(default)]
+    pub disable_if_modified_since: bool,
+    /// See [`host::Options::disable_fs`].
+    #[serde(default)]
+    pub disable_fs: bool,
+    /// See [`host::Options::streaming_threshold`].
+    pub streaming_threshold: Option<u64>,
+    /// In seconds. See [`host::Options::body_timeout`].
+    pub body_timeout: Option<u64>,
+    /// In seconds. See [`host::Options::response_timeout`].
+    pub response_timeout: Option<u64>,
+    /// In bytes. See [`host::Options::max_body_size`].
+    pub max_body_size: Option<u64>,
+    /// The maximum body sizes (in bytes) of paths starting with the keys.
+    /// See [`host::Options::set_path_max_body_size`].
+    #[serde(default)]
+    pub path_max_body_sizes: BTreeMap<String, u64>,
+    /// See [`host::Options::identity_while_compressing`].
+    #[serde(default)]
+    pub identity_while_compressing: bool,
+    /// See [`comprash::CompressionLevels::gzip`].
+    pub gzip_level: Option<u32>,
+    /// See [`comprash::CompressionLevels::br`].
+    pub brotli_level: Option<u32>,
+    /// See [`comprash::CompressionLevels::zstd`].
+    pub zstd_level: Option<i32>,
+}
+
+/// The settings of the [`LimitManager`] of a host.
+///
+/// Values which aren't set use the defaults of [`LimitManager::default`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimiterConfig {
+    /// Disables limiting. See [`LimitManager::disable`].
+    #[serde(default)]
+    pub disable: bool,
+    /// See [`LimitManager::set_max_requests`].
+    pub max_requests: Option<usize>,
+    /// See [`LimitManager::set_check_every`].
+    pub check_every: Option<usize>,
+    /// See [`LimitManager::set_reset_seconds`].
+    pub reset_seconds: Option<u64>,
+}
+
+/// The settings of both server caches of a host.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachesConfig {
+    /// See [`Host::file_cache`].
+    #[serde(default)]
+    pub file: CacheConfig,
+    /// See [`Host::response_cache`].
+    #[serde(default)]
+    pub response: CacheConfig,
+}
+/// The settings of a [`comprash::ShardedCache`].
+///
+/// Values which aren't set use the defaults of [`comprash::ShardedCache::default`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Disables the cache.
+    #[serde(default)]
+    pub disable: bool,
+    /// The maximum number of items in the cache.
+    pub max_items: Option<usize>,
+    /// The maximum size of one item, in bytes.
+    pub size_limit: Option<usize>,
+    /// The maximum size of all items, in bytes.
+    pub max_size: Option<usize>,
+    /// A [`comprash::EvictionPolicy`]; `lru` or `lfu`.
+    ///
+    /// Defaults to `lru`.
+    pub eviction: Option<String>,
+    /// The number of shards the cache is split into, which can be used concurrently.
+    pub shards: Option<usize>,
+}
+impl CacheConfig {
+    fn build<K, V>(&self) -> Result<Option<comprash::ShardedCache<K, V>>, String> {
+        if self.disable {
+            return Ok(None);
+        }
+        let eviction = match self.eviction.as_deref() {
+            None | Some("lru") => comprash::EvictionPolicy::Lru,
+            Some("lfu") => comprash::EvictionPolicy::Lfu,
+            Some(policy) => {
+                return Err(format!(
+                    "the cache eviction policy {:?} isn't `lru` or `lfu`",
+                    policy
+                ))
+            }
+        };
+        let cache = comprash::ShardedCache::new(
+            self.max_items
+                .unwrap_or(comprash::Cache::<K, V>::DEFAULT_MAX_ITEMS),
+            self.size_limit
+                .unwrap_or(comprash::Cache::<K, V>::DEFAULT_SIZE_LIMIT),
+        )
+        .with_shards(
+            self.shards
+                .unwrap_or(comprash::ShardedCache::<K, V>::DEFAULT_SHARDS),
+        )
+        .with_max_size(
+            self.max_size
+                .unwrap_or(comprash::Cache::<K, V>::DEFAULT_MAX_SIZE),
+        )
+        .with_eviction_policy(eviction);
+        Ok(Some(cache))
+    }
+}
+
+/// The settings of the [`AccessLog`] of a host.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// The file to write to. Every host needs its own file.
+    pub path: PathBuf,
+    /// A [`LogFormat`]; `common`, `combined` or `json`.
+    ///
+    /// Defaults to `combined`.
+    pub format: Option<String>,
+    /// See [`AccessLog::with_max_size`].
+    pub max_size: Option<u64>,
+    /// See [`AccessLog::with_kept_files`].
+    pub kept_files: Option<usize>,
+    /// See [`AccessLog::without_rotation`].
+    #[serde(default)]
+    pub disable_rotation: bool,
+}
+impl AccessLogConfig {
+    /// Creates the [`AccessLog`], or reuses the one in `retained` with the same path.
+    fn build(self, base: &Path, retained: &Retained) -> Result<Arc<AccessLog>, String> {
+        let format = match self.format.as_deref() {
+            Some("common") => LogFormat::Common,
+            None | Some("combined") => LogFormat::Combined,
+            Some("json") => LogFormat::Json,
+            Some(format) => {
+                return Err(format!(
+                    "the access log format {:?} isn't `common`, `combined` or `json`",
+                    format
+                ))
+            }
+        };
+        let mut log = AccessLog::new(base.join(self.path), format);
+        if let Some(max_size) = self.max_size {
+            log = log.with_max_size(max_size);
+        }
+        if let Some(kept_files) = self.kept_files {
+            log = log.with_kept_files(kept_files);
+        }
+        if self.disable_rotation {
+            log = log.without_rotation();
+        }
+        match retained.access_logs.get(log.path()) {
+            Some(running)
+                if running.format() == log.format()
+                    && running.max_size() == log.max_size()
+                    && running.kept_files() == log.kept_files() =>
+            {
+                Ok(Arc::clone(running))
+            }
+            Some(_) => Err(format!(
+                "the settings of the access log {} can only be changed by restarting",
+                log.path().display()
+            )),
+            None => Ok(log.build()),
+        }
+    }
+}
+
+/// Which extensions to mount on a host.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtensionsConfig {
+    /// Starts from [`Extensions::new`] instead of [`Extensions::empty`].
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "enabled")]
+    pub defaults: bool,
+    /// Mounts [`kvarn_extensions::mount_all`].
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "enabled")]
+    pub kvarn_extensions: bool,
+    /// Rules for [`kvarn_extensions::force_cache`].
+    #[serde(default)]
+    pub force_cache: Vec<ForceCacheConfig>,
+    /// CORS rules. See [`Cors`].
+    #[serde(default)]
+    pub cors: Vec<CorsConfig>,
+    /// Reverse proxies. See [`ReverseProxy`].
+    #[serde(default)]
+    pub reverse_proxy: Vec<ReverseProxyConfig>,
+    /// Paths to list directories without an index file in. See [`AutoIndex`].
+    #[serde(default)]
+    pub autoindex: Vec<String>,
+    /// Where to reach the FastCGI server running PHP.
+    ///
+    /// If this isn't set, the default of [`kvarn_extensions::php`] is used
+    /// when [`Self::kvarn_extensions`] is `true`.
+    pub php: Option<PhpConfig>,
+}
+impl Default for ExtensionsConfig {
+    fn default() -> Self {
+        Self {
+            defaults: true,
+            kvarn_extensions: true,
+            force_cache: Vec::new(),
+            cors: Vec::new(),
+            reverse_proxy: Vec::new(),
+            autoindex: Vec::new(),
+            php: None,
+        }
+    }
+}
+fn enabled() -> bool {
+    true
+}
+/// A rule of [`kvarn_extensions::force_cache`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForceCacheConfig {
+    /// `.<file extension>`, `/<path prefix>` or `*<part of path>*`.
+    pub rule: String,
+    /// A [`ClientCachePreference`]; `none`, `changing` or `full`.
+    pub preference: String,
+}
+/// A rule of [`Cors`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// The path to allow. See [`Cors::allow`].
+    pub path: String,
+    /// The allowed origins, e.g. `https://kvarn.org`. `*` allows all.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Additional allowed methods.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// The allowed headers.
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+/// A [`ReverseProxy`] from [`Self::path`] to exactly one of `tcp`, `udp` or `unix`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseProxyConfig {
+    /// The path prefix to forward. See [`ReverseProxy::base`].
+    pub path: String,
+    /// Connect using TCP to this address.
+    pub tcp: Option<SocketAddr>,
+    /// Connect using UDP to this address.
+    pub udp: Option<SocketAddr>,
+    /// Connect to this Unix socket.
+    pub unix: Option<PathBuf>,
+}
+/// The connection to the FastCGI server. See [`fastcgi::Connection`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PhpConfig {
+    /// The Unix socket. Only available on Unix.
+    pub socket: Option<PathBuf>,
+    /// The TCP port on localhost. Only available on Windows.
+    pub port: Option<u16>,
+}
+
+/// A port to listen on. See [`PortDescriptor`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    /// The port number.
+    pub port: u16,
+    /// Accepts encrypted connections. At least one host needs a certificate.
+    #[serde(default)]
+    pub https: bool,
+    /// Also accepts HTTP/3 connections. Requires [`Self::https`].
+    #[serde(default)]
+    pub http3: bool,
+    /// The addresses to bind to. See [`PortDescriptor::add_address`].
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    /// See [`PortDescriptor::ipv4_only`].
+    #[serde(default)]
+    pub ipv4_only: bool,
+    /// See [`PortDescriptor::ipv6_only`].
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// In seconds. See [`PortDescriptor::handshake_timeout`].
+    pub handshake_timeout: Option<u64>,
+    /// In seconds. See [`PortDescriptor::idle_timeout`].
+    pub idle_timeout: Option<u64>,
+    /// In seconds. See [`PortDescriptor::header_timeout`].
+    pub header_timeout: Option<u64>,
+}
+
+/// Where to serve the [`Metrics`].
+///
+/// At least one of `path` and `port` has to be set.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serves the metrics at this path on all hosts. See [`Extensions::add_metrics`].
+    pub path: Option<String>,
+    /// Serves the metrics at `/metrics` on this port. See [`Metrics::port_descriptor`].
+    pub port: Option<u16>,
+    /// The addresses to bind `port` to. See [`PortDescriptor::add_address`].
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// An error from [`load`].
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read.
+    Read(PathBuf, io::Error),
+    /// The file extension is neither `toml` nor `ron`.
+    UnknownFormat(PathBuf),
+    /// The TOML file is malformed or doesn't match [`Config`].
+    Toml(toml::de::Error),
+    /// The RON file is malformed or doesn't match [`Config`].
+    Ron(ron::error::SpannedError),
+    /// The configuration is well-formed, but has problems.
+    Invalid(Vec<String>),
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            Self::UnknownFormat(path) => write!(
+                f,
+                "unknown format of {}; use the file extension `toml` or `ron`",
+                path.display()
+            ),
+            Self::Toml(err) => write!(f, "invalid TOML: {}", err),
+            Self::Ron(err) => write!(f, "invalid RON: {}", err),
+            Self::Invalid(problems) => {
+                write!(
+                    f,
+                    "found {} problem(s) in the configuration:",
+                    problems.len()
+                )?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Reads the configuration at `path`.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or parsed.
+/// The contents aren't checked; see [`load`].
+pub fn read(path: &Path) -> Result<Config, Error> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| Error::Read(path.to_path_buf(), err))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(Error::Toml),
+        Some("ron") => ron::from_str(&contents).map_err(Error::Ron),
+        _ => Err(Error::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+/// Reads the configuration at `path`, checks it, and creates the [`RunConfig`]
+/// to pass to [`run`].
+///
+/// This reads the certificates of the hosts, but doesn't bind to any ports.
+///
+/// The [`RunConfig`] [reloads](RunConfig::set_reload_handler) the hosts from `path`
+/// using [`load_data`]. Changes to the ports, and enabling or disabling `metrics`,
+/// require a restart.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or parsed,
+/// or a list of all the problems found in it.
+pub fn load(path: &Path) -> Result<RunConfig, Error> {
+    let config = read(path)?;
+    config.build(path).map_err(Error::Invalid)
+}
+/// Reads the configuration at `path` and creates the [`Data`] of the hosts.
+///
+/// The hosts are measured by `metrics` if the file configures metrics.
+/// The ports aren't checked.
+/// What's `retained` from the running configuration is reused, and updated if this succeeds.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or parsed,
+/// or a list of all the problems found in the hosts.
+pub fn load_data(
+    path: &Path,
+    metrics: Option<&Arc<Metrics>>,
+    retained: &mut Retained,
+) -> Result<Arc<Data>, Error> {
+    let config = read(path)?;
+    let mut problems = Vec::new();
+    let metrics_path = config.metrics.as_ref().and_then(|m| m.path.as_deref());
+    let data = build_data(
+        config.hosts,
+        config.default_host.as_deref(),
+        config.unknown_host.as_deref(),
+        &base_path(path),
+        metrics.map(|metrics| (metrics, metrics_path)),
+        retained,
+        &mut problems,
+    );
+    match data {
+        Some(data) if problems.is_empty() => Ok(data),
+        _ => Err(Error::Invalid(problems)),
+    }
+}
+fn base_path(path: &Path) -> PathBuf {
+    path.parent()
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+/// Leaks `s`, as Kvarn wants some configuration to live for the rest of the program.
+///
+/// The values leaked when building the hosts are kept in [`Retained`],
+/// so a reload only leaks the values which changed.
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+/// What's kept from the running configuration when it's reloaded.
+///
+/// The [`AccessLog`]s are reused, so only one writer appends to (and rotates) each file.
+/// The leaked values are reused, so reloading the same configuration doesn't leak memory.
+#[derive(Debug, Default)]
+pub struct Retained {
+    /// The access logs of the running hosts, by path.
+    access_logs: HashMap<PathBuf, Arc<AccessLog>>,
+    /// The leaked rules of [`kvarn_extensions::force_cache`], by the configured rules.
+    force_cache: HashMap<
+        Vec<(String, ClientCachePreference)>,
+        &'static [(&'static str, ClientCachePreference)],
+    >,
+    /// The leaked paths of Unix sockets.
+    #[cfg(unix)]
+    paths: std::collections::HashSet<&'static Path>,
+}
+impl Retained {
+    /// Leaks `rules`, unless the same rules have been leaked before.
+    fn force_cache(
+        &mut self,
+        rules: Vec<(String, ClientCachePreference)>,
+    ) -> &'static [(&'static str, ClientCachePreference)] {
+        self.force_cache.entry(rules).or_insert_with_key(|rules| {
+            let rules: Vec<_> = rules
+                .iter()
+                .map(|(rule, preference)| (leak(rule.clone()), *preference))
+                .collect();
+            Box::leak(rules.into_boxed_slice())
+        })
+    }
+    /// Leaks `path`, unless it has been leaked before.
+    #[cfg(unix)]
+    fn path(&mut self, path: PathBuf) -> &'static Path {
+        if let Some(path) = self.paths.get(path.as_path()) {
+            return path;
+        }
+        let path: &'static Path = Box::leak(path.into_boxed_path());
+        self.paths.insert(path);
+        path
+    }
+}
+
+/// Checks the `hosts` and creates the [`Data`].
+///
+/// If `metrics` is set, all hosts are measured and they serve the metrics at the path, if any.
+/// If the hosts are valid, `retained` is updated with what they use.
+///
+/// Pushes all problems found to `problems`. Returns [`None`] if any of the hosts are invalid.
+fn build_data(
+    hosts: Vec<HostConfig>,
+    default_host: Option<&str>,
+    unknown_host: Option<&str>,
+    base: &Path,
+    metrics: Option<(&Arc<Metrics>, Option<&str>)>,
+    retained: &mut Retained,
+    problems: &mut Vec<String>,
+) -> Option<Arc<Data>> {
+    if hosts.is_empty() {
+        problems.push("no hosts are configured; add a `host`".to_owned());
+        return None;
+    }
+    for (index, host) in hosts.iter().enumerate() {
+        if hosts[..index].iter().any(|h| h.name == host.name) {
+            problems.push(format!("host {:?} is configured more than once", host.name));
+        }
+        if let Some(log) = &host.access_log {
+            let shared = hosts[..index]
+                .iter()
+                .filter_map(|h| h.access_log.as_ref())
+                .any(|other| base.join(&other.path) == base.join(&log.path));
+            if shared {
+                problems.push(format!(
+                    "host {:?}: the access log {} is used by another host",
+                    host.name,
+                    log.path.display()
+                ));
+            }
+        }
+    }
+    let default_index = match default_host {
+        Some(name) => match hosts.iter().position(|host| host.name == name) {
+            Some(index) => index,
+            None => {
+                problems.push(format!("the default host {:?} isn't configured", name));
+                return None;
+            }
+        },
+        None => 0,
+    };
+    let unknown_host = match unknown_host {
+        None | Some("default") => host::UnknownHost::Default,
+        Some("misdirected") => host::UnknownHost::Misdirected,
+        Some("drop") => host::UnknownHost::Drop,
+        Some(action) => {
+            problems.push(format!(
+                "`unknown_host` {:?} isn't `default`, `misdirected` or `drop`",
+                action
+            ));
+            return None;
+        }
+    };
+
+    let host_count = hosts.len();
+    let mut hosts: Vec<Host> = hosts
+        .into_iter()
+        .filter_map(|host| {
+            let name = host.name.clone();
+            host.build(base, retained)
+                .map_err(|errors| {
+                    problems.extend(
+                        errors
+                            .into_iter()
+                            .map(|err| format!("host {:?}: {}", name, err)),
+                    );
+                })
+                .ok()
+        })
+        .collect();
+    if hosts.len() != host_count {
+        return None;
+    }
+    // The logs no longer used are closed when the running hosts are dropped.
+    retained.access_logs = hosts
+        .iter()
+        .filter_map(|host| host.access_log.as_ref())
+        .map(|log| (log.path().to_path_buf(), Arc::clone(log)))
+        .collect();
+    if let Some((metrics, path)) = metrics {
+        for host in &mut hosts {
+            host.enable_metrics(metrics);
+            if let Some(path) = path {
+                host.extensions
+                    .add_metrics(path.to_owned(), Arc::clone(metrics));
+            }
+        }
+    }
+
+    let default = hosts.remove(default_index);
+    let mut data = Data::builder(default).set_unknown_host(unknown_host);
+    for host in hosts {
+        data = data.add_host(host);
+    }
+    Some(data.build())
+}
+
+impl Config {
+    /// Checks the configuration read from `path` and creates the [`RunConfig`].
+    ///
+    /// Relative paths are resolved from the directory of `path`,
+    /// and the hosts are reloaded from `path`. See [`load`].
+    ///
+    /// # Errors
+    ///
+    /// Returns all the problems found.
+    pub fn build(self, path: &Path) -> Result<RunConfig, Vec<String>> {
+        let mut problems = Vec::new();
+
+        if let Some(metrics) = &self.metrics {
+            if metrics.path.is_none() && metrics.port.is_none() {
+                problems.push("`metrics` requires a `path` or a `port`".to_owned());
+            }
+            if metrics
+                .path
+                .as_ref()
+                .map_or(false, |path| !path.starts_with('/'))
+            {
+                problems.push("the `path` of `metrics` has to start with `/`".to_owned());
+            }
+            if metrics.port.is_some() && self.ports.iter().any(|p| Some(p.port) == metrics.port) {
+                problems.push("the `port` of `metrics` is also configured as a `port`".to_owned());
+            }
+        }
+        let metrics = self.metrics.as_ref().map(|_| Metrics::new());
+        let metrics_path = self.metrics.as_ref().and_then(|m| m.path.as_deref());
+
+        let mut retained = Retained::default();
+        let data = build_data(
+            self.hosts,
+            self.default_host.as_deref(),
+            self.unknown_host.as_deref(),
+            &base_path(path),
+            metrics.as_ref().map(|metrics| (metrics, metrics_path)),
+            &mut retained,
+            &mut problems,
+        );
+
+        if self.ports.is_empty() {
+            problems.push("no ports are configured; add a `port`".to_owned());
+        }
+        let has_secure = data.as_ref().map_or(false, |data| data.has_secure());
+        for (index, port) in self.ports.iter().enumerate() {
+            if self.ports[..index].iter().any(|p| p.port == port.port) {
+                problems.push(format!("port {} is configured more than once", port.port));
+            }
+            if port.https && data.is_some() && !has_secure {
+                problems.push(format!(
+                    "port {}: `https` requires a host with a certificate",
+                    port.port
+                ));
+            }
+            if port.http3 && !port.https {
+                problems.push(format!("port {}: `http3` requires `https`", port.port));
+            }
+            if port.ipv4_only && port.ipv6_only {
+                problems.push(format!(
+                    "port {}: `ipv4_only` and `ipv6_only` are mutually exclusive",
+                    port.port
+                ));
+            }
+        }
+
+        let data = match data {
+            Some(data) if problems.is_empty() => data,
+            _ => return Err(problems),
+        };
+        let handle = DataHandle::new(data);
+
+        let mut run_config = RunConfig::new();
+        for port in self.ports {
+            let mut descriptor = if port.https {
+                PortDescriptor::new(port.port, handle.clone())
+            } else {
+                PortDescriptor::non_secure(port.port, handle.clone())
+            };
+            if port.http3 {
+                descriptor = descriptor.enable_http3();
+            }
+            for address in port.addresses {
+                descriptor = descriptor.add_address(address);
+            }
+            if port.ipv4_only {
+                descriptor = descriptor.ipv4_only();
+            }
+            if port.ipv6_only {
+                descriptor = descriptor.ipv6_only();
+            }
+            if let Some(seconds) = port.handshake_timeout {
+                descriptor = descriptor.handshake_timeout(Duration::from_secs(seconds));
+            }
+            if let Some(seconds) = port.idle_timeout {
+                descriptor = descriptor.idle_timeout(Duration::from_secs(seconds));
+            }
+            if let Some(seconds) = port.header_timeout {
+                descriptor = descriptor.header_timeout(Duration::from_secs(seconds));
+            }
+            run_config = run_config.add(descriptor);
+        }
+        if let (Some(metrics), Some(config)) = (&metrics, self.metrics) {
+            if let Some(port) = config.port {
+                let mut descriptor = metrics.port_descriptor(port);
+                for address in config.addresses {
+                    descriptor = descriptor.add_address(address);
+                }
+                run_config = run_config.add(descriptor);
+            }
+        }
+        if self.disable_handover {
+            run_config = run_config.disable_handover();
+        }
+        if let Some(path) = self.handover_socket_path {
+            run_config = run_config.set_handover_socket_path(leak(path));
+        }
+        let path = path.to_path_buf();
+        let retained = std::sync::Mutex::new(retained);
+        run_config = run_config.set_reload_handler(Box::new(move || {
+            let mut retained = retained
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let data =
+                load_data(&path, metrics.as_ref(), &mut retained).map_err(|err| err.to_string())?;
+            handle.replace(data);
+            Ok(())
+        }));
+        Ok(run_config)
+    }
+}
+
+impl HostConfig {
+    fn build(self, base: &Path, retained: &mut Retained) -> Result<Host, Vec<String>> {
+        let mut problems = Vec::new();
+
+        let path = base.join(&self.path);
+        if !path.is_dir() {
+            problems.push(format!("the path {} isn't a directory", path.display()));
+        }
+        if self.limiter.check_every == Some(0) {
+            problems.push("the limiter's `check_every` has to be greater than 0".to_owned());
+        }
+
+        let extensions = match self.extensions.build(retained) {
+            Ok(extensions) => Some(extensions),
+            Err(errors) => {
+                problems.extend(errors);
+                None
+            }
+        };
+
+        let access_log = match self
+            .access_log
+            .map(|log| log.build(base, retained))
+            .transpose()
+        {
+            Ok(access_log) => access_log,
+            Err(err) => {
+                problems.push(err);
+                None
+            }
+        };
+
+        let file_cache = self.cache.file.build().unwrap_or_else(|err| {
+            problems.push(format!("file cache: {}", err));
+            None
+        });
+        let response_cache = self.cache.response.build().unwrap_or_else(|err| {
+            problems.push(format!("response cache: {}", err));
+            None
+        });
+
+        let options = self.options.build();
+        let name = self.name;
+        let certificate = match (self.certificate, self.private_key) {
+            (Some(cert), Some(key)) => {
+                let (cert, key) = (base.join(cert), base.join(key));
+                match host::get_certified_key(&cert, &key) {
+                    Ok(certified_key) => Some(certified_key),
+                    Err(err) => {
+                        problems.push(format!(
+                            "failed to read the certificate {} or private key {}: {:?}",
+                            cert.display(),
+                            key.display(),
+                            err
+                        ));
+                        None
+                    }
+                }
+            }
+            (None, None) => None,
+            _ => {
+                problems.push("both `certificate` and `private_key` have to be set".to_owned());
+                None
+            }
+        };
+        if certificate.is_none() && (self.http_redirect || self.hsts) {
+            problems.push("`http_redirect` and `hsts` require a certificate".to_owned());
+        }
+
+        let extensions = match extensions {
+            Some(extensions) if problems.is_empty() => extensions,
+            _ => return Err(problems),
+        };
+
+        let mut host = match certificate {
+            Some((cert, pk)) => Host::from_cert_and_pk(name, cert, pk, path, extensions, options),
+            None => Host::non_secure(name, path, extensions, options),
+        };
+        if self.http_redirect {
+            host.set_http_redirect_to_https();
+        }
+        if self.hsts {
+            host.enable_hsts();
+        }
+        for alias in self.aliases {
+            host.add_alias(alias);
+        }
+        host.file_cache = file_cache;
+        host.response_cache = response_cache;
+        self.limiter.apply(&mut host.limiter);
+        if let Some(access_log) = access_log {
+            host.enable_access_log(access_log);
+        }
+        Ok(host)
+    }
+}
+
+impl OptionsConfig {
+    fn build(self) -> host::Options {
+        let mut options = host::Options::new();
+        options.folder_default = self.folder_default;
+        options.extension_default = self.extension_default;
+        options.public_data_dir = self.public_data_dir;
+        options.disable_client_cache = self.disable_client_cache;
+        options.disable_if_modified_since = self.disable_if_modified_since;
+        options.disable_fs = self.disable_fs;
+        options.streaming_threshold = self.streaming_threshold;
+        options.body_timeout = self.body_timeout.map(Duration::from_secs);
+        options.response_timeout = self.response_timeout.map(Duration::from_secs);
+        options.max_body_size = self.max_body_size;
+        options.identity_while_compressing = self.identity_while_compressing;
+        for (path, bytes) in self.path_max_body_sizes {
+            options.set_path_max_body_size(path, bytes);
+        }
+        let levels = &mut options.compression_levels;
+        if let Some(level) = self.gzip_level {
+            levels.gzip = level;
+        }
+        if let Some(level) = self.brotli_level {
+            levels.br = level;
+        }
+        if let Some(level) = self.zstd_level {
+            levels.zstd = level;
+        }
+        options
+    }
+}
+
+impl LimiterConfig {
+    fn apply(&self, limiter: &mut LimitManager) {
+        if let Some(max_requests) = self.max_requests {
+            limiter.set_max_requests(max_requests);
+        }
+        if let Some(check_every) = self.check_every {
+            limiter.set_check_every(check_every);
+        }
+        if let Some(reset_seconds) = self.reset_seconds {
+            limiter.set_reset_seconds(reset_seconds);
+        }
+        if self.disable {
+            limiter.disable();
+        }
+    }
+}
+
+impl ExtensionsConfig {
+    fn build(self, retained: &mut Retained) -> Result<Extensions, Vec<String>> {
+        let mut problems = Vec::new();
+        let mut extensions = if self.defaults {
+            Extensions::new()
+        } else {
+            Extensions::empty()
+        };
+        if self.kvarn_extensions {
+            kvarn_extensions::mount_all(&mut extensions);
+        }
+
+        let mut rules = Vec::with_capacity(self.force_cache.len());
+        for rule in self.force_cache {
+            let valid_shape = rule.rule.starts_with('.')
+                || rule.rule.starts_with('/')
+                || (rule.rule.len() > 1 && rule.rule.starts_with('*') && rule.rule.ends_with('*'));
+            if !valid_shape {
+                problems.push(format!(
+                    "the force cache rule {:?} has to start with `.` or `/`, or be surrounded by `*`",
+                    rule.rule
+                ));
+            }
+            match rule.preference.parse::<ClientCachePreference>() {
+                Ok(preference) => rules.push((rule.rule, preference)),
+                Err(_) => problems.push(format!(
+                    "the cache preference {:?} of force cache rule {:?} isn't `none`, `changing` or `full`",
+                    rule.preference, rule.rule
+                )),
+            }
+        }
+        if !rules.is_empty() {
+            kvarn_extensions::force_cache(&mut extensions, retained.force_cache(rules));
+        }
+
+        if !self.cors.is_empty() {
+            let mut cors = Cors::new();
+            for rule in self.cors {
+                match rule.build() {
+                    Ok(allow_list) => cors = cors.allow(&rule.path, allow_list),
+                    Err(errors) => problems.extend(
+                        errors
+                            .into_iter()
+                            .map(|err| format!("CORS rule {:?}: {}", rule.path, err)),
+                    ),
+                }
+            }
+            extensions.add_cors(cors.build());
+        }
+
+        for proxy in self.reverse_proxy {
+            if !proxy.path.starts_with('/') {
+                problems.push(format!(
+                    "the path {:?} of the reverse proxy has to start with `/`",
+                    proxy.path
+                ));
+                continue;
+            }
+            let connection = match (proxy.tcp, proxy.udp, proxy.unix) {
+                (Some(address), None, None) => ReverseProxyConnection::Tcp(address),
+                (None, Some(address), None) => ReverseProxyConnection::Udp(address),
+                #[cfg(unix)]
+                (None, None, Some(path)) => ReverseProxyConnection::UnixSocket(retained.path(path)),
+                #[cfg(not(unix))]
+                (None, None, Some(_)) => {
+                    problems.push(format!(
+                        "reverse proxy {:?}: Unix sockets are only available on Unix",
+                        proxy.path
+                    ));
+                    continue;
+                }
+                _ => {
+                    problems.push(format!(
+                        "reverse proxy {:?}: set exactly one of `tcp`, `udp` and `unix`",
+                        proxy.path
+                    ));
+                    continue;
+                }
+            };
+            ReverseProxy::base(&proxy.path, kvarn_extensions::static_connection(connection))
+                .mount(&mut extensions);
+        }
+
+        if !self.autoindex.is_empty() {
+            let mut autoindex = AutoIndex::new();
+            for path in self.autoindex {
+                if path.starts_with('/') {
+                    autoindex = autoindex.add(path);
+                } else {
+                    problems.push(format!(
+                        "the autoindex path {:?} has to start with `/`",
+                        path
+                    ));
+                }
+            }
+            autoindex.mount(&mut extensions);
+        }
+
+        if let Some(php) = self.php {
+            match php.build() {
+                Ok(connection) => {
+                    kvarn_extensions::php::mount_php_with_connection(&mut extensions, connection)
+                }
+                Err(err) => problems.push(err),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(extensions)
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+impl CorsConfig {
+    fn build(&self) -> Result<CorsAllowList, Vec<String>> {
+        let mut problems = Vec::new();
+        let mut allow_list = CorsAllowList::new();
+        for origin in &self.origins {
+            if origin == "*" {
+                allow_list = allow_list.allow_all_origins();
+                continue;
+            }
+            match origin.parse::<Uri>() {
+                Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => {
+                    allow_list = allow_list.add_origin_uri(uri);
+                }
+                _ => problems.push(format!(
+                    "the origin {:?} has to be `*` or have a scheme and host, e.g. `https://kvarn.org`",
+                    origin
+                )),
+            }
+        }
+        for method in &self.methods {
+            match Method::from_bytes(method.as_bytes()) {
+                Ok(method) => allow_list = allow_list.add_method(method),
+                Err(_) => problems.push(format!("{:?} isn't a valid method", method)),
+            }
+        }
+        for header in &self.headers {
+            match HeaderName::from_bytes(header.as_bytes()) {
+                Ok(header) => allow_list = allow_list.add_header(header),
+                Err(_) => problems.push(format!("{:?} isn't a valid header name", header)),
+            }
+        }
+        if problems.is_empty() {
+            Ok(allow_list)
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+impl PhpConfig {
+    fn build(self) -> Result<fastcgi::Connection, String> {
+        match (self.socket, self.port) {
+            #[cfg(unix)]
+            (Some(socket), None) => Ok(fastcgi::Connection::UnixSocket(socket)),
+            #[cfg(windows)]
+            (None, Some(port)) => Ok(fastcgi::Connection::Tcp(port)),
+            (None, None) => Ok(fastcgi::Connection::default()),
+            (Some(_), Some(_)) => Err("PHP: set either `socket` or `port`".to_owned()),
+            #[cfg(unix)]
+            (None, Some(_)) => Err("PHP: `port` is only available on Windows".to_owned()),
+            #[cfg(windows)]
+            (Some(_), None) => Err("PHP: `socket` is only available on Unix".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> &'static Path {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+    }
+    fn config_problems(toml: &str) -> Vec<String> {
+        let config: Config = toml::from_str(toml).unwrap();
+        config
+            .build(&base().join("kvarn.toml"))
+            .err()
+            .unwrap_or_default()
+    }
+    fn host_problems(ron: &str, retained: &mut Retained) -> Vec<String> {
+        let host: HostConfig = ron::from_str(ron).unwrap();
+        host.build(base(), retained).err().unwrap_or_default()
+    }
+    fn extensions_problems(toml: &str, retained: &mut Retained) -> Vec<String> {
+        let extensions: ExtensionsConfig = toml::from_str(toml).unwrap();
+        extensions.build(retained).err().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn config() {
+        let valid = r#"
+            [[host]]
+            name = "localhost"
+            path = "src"
+
+            [[port]]
+            port = 8080
+        "#;
+        assert_eq!(config_problems(valid), Vec::<String>::new());
+
+        let invalid = r#"
+            default_host = "missing"
+
+            [[host]]
+            name = "localhost"
+            path = "src"
+
+            [[port]]
+            port = 8080
+            http3 = true
+
+            [[port]]
+            port = 8080
+            ipv4_only = true
+            ipv6_only = true
+
+            [metrics]
+            path = "metrics"
+        "#;
+        assert_eq!(
+            config_problems(invalid),
+            [
+                "the `path` of `metrics` has to start with `/`",
+                "the default host \"missing\" isn't configured",
+                "port 8080: `http3` requires `https`",
+                "port 8080 is configured more than once",
+                "port 8080: `ipv4_only` and `ipv6_only` are mutually exclusive",
+            ]
+        );
+
+        let problems = config_problems("[[port]]\nport = 80\n\n[metrics]\n");
+        assert_eq!(
+            problems,
+            [
+                "`metrics` requires a `path` or a `port`",
+                "no hosts are configured; add a `host`",
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn host() {
+        let mut retained = Retained::default();
+        let invalid = r#"(
+            name: "localhost",
+            path: "missing",
+            certificate: Some("cert.pem"),
+            http_redirect: true,
+            limiter: (check_every: Some(0)),
+            cache: (response: (eviction: Some("fifo"))),
+            access_log: Some((path: "access.log", format: Some("xml"))),
+            extensions: (autoindex: ["downloads/"]),
+        )"#;
+        assert_eq!(
+            host_problems(invalid, &mut retained),
+            [
+                format!(
+                    "the path {} isn't a directory",
+                    base().join("missing").display()
+                ),
+                "the limiter's `check_every` has to be greater than 0".to_owned(),
+                "the autoindex path \"downloads/\" has to start with `/`".to_owned(),
+                "the access log format \"xml\" isn't `common`, `combined` or `json`".to_owned(),
+                "response cache: the cache eviction policy \"fifo\" isn't `lru` or `lfu`"
+                    .to_owned(),
+                "both `certificate` and `private_key` have to be set".to_owned(),
+                "`http_redirect` and `hsts` require a certificate".to_owned(),
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn access_log_reused() {
+        let host = |format: &str| {
+            let ron = format!(
+                r#"(name: "localhost", path: "src", access_log: Some((path: "access.log", format: Some({:?}))))"#,
+                format
+            );
+            ron::from_str::<HostConfig>(&ron).unwrap()
+        };
+        let mut retained = Retained::default();
+        let log = host("json")
+            .build(base(), &mut retained)
+            .unwrap()
+            .access_log
+            .unwrap();
+        retained
+            .access_logs
+            .insert(log.path().to_path_buf(), Arc::clone(&log));
+
+        let reloaded = host("json")
+            .build(base(), &mut retained)
+            .unwrap()
+            .access_log
+            .unwrap();
+        assert!(Arc::ptr_eq(&log, &reloaded));
+        assert_eq!(
+            host("common").build(base(), &mut retained).unwrap_err(),
+            [format!(
+                "the settings of the access log {} can only be changed by restarting",
+                base().join("access.log").display()
+            )]
+        );
+    }
+    #[tokio::test]
+    async fn extensions() {
+        let mut retained = Retained::default();
+        let invalid = r#"
+            autoindex = ["/downloads/", "files/"]
+
+            [[force_cache]]
+            rule = "woff2"
+            preference = "full"
+
+            [[force_cache]]
+            rule = ".css"
+            preference = "forever"
+
+            [[cors]]
+            path = "/api/"
+            origins = ["*", "example.org"]
+            methods = ["G T"]
+            headers = ["x-valid"]
+
+            [[reverse_proxy]]
+            path = "api"
+            tcp = "127.0.0.1:8080"
+
+            [[reverse_proxy]]
+            path = "/both/"
+            tcp = "127.0.0.1:8080"
+            udp = "127.0.0.1:8080"
+        "#;
+        assert_eq!(
+            extensions_problems(invalid, &mut retained),
+            [
+                "the force cache rule \"woff2\" has to start with `.` or `/`, or be surrounded by `*`",
+                "the cache preference \"forever\" of force cache rule \".css\" isn't `none`, `changing` or `full`",
+                "CORS rule \"/api/\": the origin \"example.org\" has to be `*` or have a scheme and host, e.g. `https://kvarn.org`",
+                "CORS rule \"/api/\": \"G T\" isn't a valid method",
+                "the path \"api\" of the reverse proxy has to start with `/`",
+                "reverse proxy \"/both/\": set exactly one of `tcp`, `udp` and `unix`",
+                "the autoindex path \"files/\" has to start with `/`",
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn leaked_once() {
+        let mut retained = Retained::default();
+        let valid = r#"
+            [[force_cache]]
+            rule = ".woff2"
+            preference = "full"
+
+            [[reverse_proxy]]
+            path = "/api/"
+            unix = "/run/api.sock"
+        "#;
+        for _ in 0..3 {
+            assert_eq!(
+                extensions_problems(valid, &mut retained),
+                Vec::<String>::new()
+            );
+        }
+        assert_eq!(retained.force_cache.len(), 1);
+        #[cfg(unix)]
+        assert_eq!(retained.paths.len(), 1);
+    }
+}
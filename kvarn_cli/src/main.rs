@@ -0,0 +1,63 @@
+//! # Kvarn
+//!
+//! The Kvarn web server, configured using a TOML or RON file.
+//! See [`config`] for the format.
+//!
+//! Pass the path of the configuration file as the first argument;
+//! `kvarn.toml` is used if none is given.
+//! Use `--check` to only check the configuration.
+//!
+//! The hosts are reloaded from the file when the process receives `SIGHUP`
+//! or `reload` is sent to the handover socket.
+//! If the new configuration is invalid, the old one is kept.
+//!
+//! Set the `RUST_LOG` environment variable to change the log level.
+
+use std::{env, path::PathBuf, process};
+
+pub mod config;
+
+const USAGE: &str = "Usage: kvarn [--check] [<config file>]
+
+Starts the Kvarn web server using the TOML or RON <config file>, by default `kvarn.toml`.
+
+Options:
+    -c, --check    Only check the configuration and exit.
+    -h, --help     Print this message.";
+
+fn exit_with_message(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut check = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-c" | "--check" => check = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(PathBuf::from(arg)),
+            _ => exit_with_message(format_args!("Unexpected argument {:?}.\n\n{}", arg, USAGE)),
+        }
+    }
+    let path = path.unwrap_or_else(|| PathBuf::from("kvarn.toml"));
+
+    let run_config = match config::load(&path) {
+        Ok(run_config) => run_config,
+        Err(err) => exit_with_message(format_args!("{}: {}", path.display(), err)),
+    };
+    if check {
+        println!("{} is valid.", path.display());
+        return;
+    }
+
+    let shutdown_manager = kvarn::run(run_config).await;
+    shutdown_manager.wait().await;
+}
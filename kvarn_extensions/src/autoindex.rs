@@ -0,0 +1,508 @@
+//! Listings of directories without an index file, also known as *autoindex*.
+//!
+//! Mount an [`AutoIndex`] with the paths to list.
+//! The listing is HTML, or JSON if the client sends `accept: application/json`.
+//! The query parameters `sort` (`name`, `size`, or `modified`) and `order` (`asc` or `desc`)
+//! control the sorting. Directories are always listed first.
+//!
+//! Files with the `private` extension or which start with a `!> hide` declaration aren't listed,
+//! nor are names starting with `.`.
+use crate::*;
+use std::fs;
+use std::time::SystemTime;
+
+/// The column to sort entries of a listing by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The file name.
+    Name,
+    /// The size of the file.
+    Size,
+    /// The time of last modification.
+    Modified,
+}
+impl SortBy {
+    fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "name" => Some(Self::Name),
+            "size" => Some(Self::Size),
+            "modified" => Some(Self::Modified),
+            _ => None,
+        }
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// An entry in a directory listing.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The name of the file or directory.
+    pub name: String,
+    /// If the entry is a directory.
+    pub is_dir: bool,
+    /// The size in bytes. Always `0` for directories.
+    pub size: u64,
+    /// The time of last modification, if known.
+    pub modified: Option<SystemTime>,
+}
+
+/// Lists the directories at the mounted paths which have no
+/// [`host::Options::folder_default`] file.
+///
+/// # Examples
+///
+/// ```
+/// # use kvarn::prelude::*;
+/// let mut extensions = Extensions::new();
+/// kvarn_extensions::AutoIndex::new()
+///     .add("/downloads/")
+///     .mount(&mut extensions);
+/// ```
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct AutoIndex {
+    paths: Vec<String>,
+}
+impl AutoIndex {
+    /// Creates a new [`AutoIndex`] without any paths.
+    pub fn new() -> Self {
+        Self { paths: Vec::new() }
+    }
+    /// Lists the directories at `path` and all it's subdirectories.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` doesn't start with `/`.
+    pub fn add(mut self, path: impl Into<String>) -> Self {
+        let mut path = path.into();
+        assert_eq!(path.chars().next(), Some('/'));
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        self.paths.push(path);
+        self
+    }
+    /// Mounts a [`Prepare`] extension (priority `-64`) on `extensions`
+    /// which responds with the listings.
+    /// Directories with an index file get that file, as usual.
+    pub fn mount(self, extensions: &mut Extensions) {
+        let paths = self.paths;
+        extensions.add_prepare_fn(
+            Box::new(move |request: &FatRequest, host: &Host| {
+                directory_path(request.uri().path(), host)
+                    .map_or(false, |dir| paths.iter().any(|path| in_path(dir, path)))
+            }),
+            prepare!(req, host, _path, _addr {
+                // The predicate only matches these.
+                let dir = directory_path(req.uri().path(), host).unwrap().to_owned();
+                let public = public_dir(host);
+                let path = file_path(host, &dir);
+                let index = path.join(folder_default(host));
+
+                let listing = threading::spawn_blocking(move || {
+                    if !path.is_dir() {
+                        Listing::NotFound
+                    } else if index.is_file() {
+                        Listing::Index(index)
+                    } else {
+                        list(&public, &path).map_or(Listing::NotFound, Listing::Entries)
+                    }
+                })
+                .await
+                .unwrap_or(Listing::NotFound);
+                let mut entries = match listing {
+                    Listing::Entries(entries) => entries,
+                    // Serve the index file, as Kvarn would without this extension.
+                    Listing::Index(index) => {
+                        return match read_file(&index, host.file_cache.as_ref()).await {
+                            Some(file) => FatResponse::cache(Response::new(file)),
+                            None => default_error_response(StatusCode::NOT_FOUND, host, None).await,
+                        }
+                    }
+                    Listing::NotFound => {
+                        return default_error_response(StatusCode::NOT_FOUND, host, None).await
+                    }
+                };
+
+                let mut sort = SortBy::Name;
+                let mut descending = false;
+                for (key, value) in req
+                    .uri()
+                    .query()
+                    .unwrap_or("")
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                {
+                    match key {
+                        "sort" => sort = SortBy::from_query(value).unwrap_or(sort),
+                        "order" => descending = value == "desc",
+                        _ => {}
+                    }
+                }
+                sort_entries(&mut entries, sort, descending);
+
+                let json = req
+                    .headers()
+                    .get("accept")
+                    .and_then(|accept| accept.to_str().ok())
+                    .map_or(false, |accept| {
+                        utils::list_header(accept)
+                            .iter()
+                            .any(|value| value.value == "application/json" && value.quality > 0.0)
+                    });
+                let (body, content_type) = if json {
+                    (json_listing(&dir, &entries), "application/json")
+                } else {
+                    (
+                        html_listing(&dir, &entries, sort, descending),
+                        "text/html; charset=utf-8",
+                    )
+                };
+                let mut response = Response::new(Bytes::from(body));
+                let headers = response.headers_mut();
+                headers.insert("content-type", HeaderValue::from_static(content_type));
+                headers.insert("vary", HeaderValue::from_static("accept"));
+                FatResponse::no_cache(response)
+                    .with_client_cache(ClientCachePreference::Changing)
+            }),
+            extensions::Id::new(-64, "Directory listing (autoindex)"),
+        );
+    }
+}
+impl Default for AutoIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What's at the requested directory.
+enum Listing {
+    /// It isn't a directory or can't be listed.
+    NotFound,
+    /// The directory has an index file, which is served instead.
+    Index(PathBuf),
+    /// The entries of the directory.
+    Entries(Vec<Entry>),
+}
+
+/// Checks if `dir` is the mounted `path` or inside it.
+///
+/// Compares whole segments, so `/downloads-old/` isn't in `/downloads/`.
+fn in_path(dir: &str, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    dir.strip_prefix(path)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+/// Gets the directory part of `path` if it's a request for a directory;
+/// ends with `/` or `/` followed by [`host::Options::folder_default`].
+fn directory_path<'a>(path: &'a str, host: &Host) -> Option<&'a str> {
+    if path.ends_with('/') {
+        return Some(path);
+    }
+    path.strip_suffix(folder_default(host))
+        .filter(|dir| dir.ends_with('/'))
+}
+fn folder_default(host: &Host) -> &str {
+    host.options
+        .folder_default
+        .as_deref()
+        .unwrap_or("index.html")
+}
+fn public_dir(host: &Host) -> PathBuf {
+    host.path.join(
+        host.options
+            .public_data_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new("public")),
+    )
+}
+fn file_path(host: &Host, uri_path: &str) -> PathBuf {
+    // The path is sanitized by Kvarn; `parse::uri` only fails on paths not starting with `/`.
+    let relative = utils::parse::uri(uri_path).unwrap_or_else(|| Path::new(""));
+    public_dir(host).join(relative)
+}
+
+/// Lists the visible entries in `dir`.
+///
+/// Returns [`None`] if `dir` can't be read or is outside `public`, after resolving symlinks.
+/// Entries resolving to files outside `public` are skipped.
+fn list(public: &Path, dir: &Path) -> Option<Vec<Entry>> {
+    let public = public.canonicalize().ok()?;
+    let dir = dir.canonicalize().ok()?;
+    if !dir.starts_with(&public) {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&dir).ok()?.filter_map(Result::ok) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        let inside = path
+            .canonicalize()
+            .map_or(false, |path| path.starts_with(&public));
+        if name.starts_with('.')
+            || !inside
+            || path
+                .extension()
+                .map_or(false, |extension| extension == "private")
+        {
+            continue;
+        }
+        // Follows symlinks, which are inside `public`.
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_file() && is_hidden(&path) {
+            continue;
+        }
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Some(entries)
+}
+/// Checks if the file at `path` declares the `hide` present extension.
+fn is_hidden(path: &Path) -> bool {
+    let mut start = [0; 512];
+    let read = match fs::File::open(path).and_then(|mut file| file.read(&mut start)) {
+        Ok(read) => read,
+        Err(_) => return false,
+    };
+    utils::PresentExtensions::new(Bytes::copy_from_slice(&start[..read])).map_or(
+        false,
+        |extensions| {
+            extensions
+                .iter()
+                .any(|extension| extension.name() == "hide")
+        },
+    )
+}
+fn sort_entries(entries: &mut [Entry], sort: SortBy, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match sort {
+            SortBy::Name => a.name.cmp(&b.name),
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+        };
+        let order = if descending { order.reverse() } else { order };
+        // Directories first, then by name if the column is equal.
+        b.is_dir
+            .cmp(&a.is_dir)
+            .then(order)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+fn format_date(time: SystemTime) -> String {
+    time::DateTime::<time::Utc>::from(time)
+        .format(utils::parse::HTTP_DATE)
+        .to_string()
+}
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    // Precision isn't important for a human-readable size.
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+fn escape_html(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+/// Percent encodes all bytes of `string` except unreserved characters.
+fn escape_uri(string: &str) -> String {
+    use std::fmt::Write;
+    let mut escaped = String::with_capacity(string.len());
+    for byte in string.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            escaped.push(byte as char);
+        } else {
+            // Writing to a string can't fail.
+            write!(escaped, "%{:02X}", byte).unwrap();
+        }
+    }
+    escaped
+}
+fn escape_json(string: &str) -> String {
+    use std::fmt::Write;
+    let mut escaped = String::with_capacity(string.len() + 2);
+    escaped.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            // Writing to a string can't fail.
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_listing(dir: &str, entries: &[Entry]) -> String {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .map_or_else(|| "null".to_owned(), |time| escape_json(&format_date(time)));
+            format!(
+                r#"{{"name":{},"type":"{}","size":{},"modified":{}}}"#,
+                escape_json(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                entry.size,
+                modified
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(r#"{{"path":{},"entries":[{}]}}"#, escape_json(dir), entries)
+}
+fn html_listing(dir: &str, entries: &[Entry], sort: SortBy, descending: bool) -> String {
+    use std::fmt::Write;
+    let title = escape_html(dir);
+    let header = |column: SortBy, name: &str| {
+        // Clicking the current column toggles the order.
+        let order = if column == sort && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!(
+            r#"<th><a href="?sort={}&amp;order={}">{}</a></th>"#,
+            column.as_str(),
+            order,
+            name
+        )
+    };
+    let mut body = format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" \
+        content=\"width=device-width, initial-scale=1\">\n<title>Index of {0}</title>\n</head>\n\
+        <body>\n<h1>Index of {0}</h1>\n<table>\n<thead>\n<tr>{1}{2}{3}</tr>\n</thead>\n<tbody>\n",
+        title,
+        header(SortBy::Name, "Name"),
+        header(SortBy::Size, "Size"),
+        header(SortBy::Modified, "Modified"),
+    );
+    if dir != "/" {
+        body.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        // Writing to a string can't fail.
+        writeln!(
+            body,
+            r#"<tr><td><a href="{}{}">{}{}</a></td><td>{}</td><td>{}</td></tr>"#,
+            escape_uri(&entry.name),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            if entry.is_dir {
+                "-".to_owned()
+            } else {
+                format_size(entry.size)
+            },
+            entry.modified.map(format_date).unwrap_or_default(),
+        )
+        .unwrap();
+    }
+    body.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvarn_testing::ServerBuilder;
+
+    #[test]
+    fn segments() {
+        assert!(in_path("/downloads/", "/downloads/"));
+        assert!(in_path("/downloads/files/", "/downloads/"));
+        assert!(!in_path("/downloads-old/", "/downloads/"));
+        assert!(!in_path("/", "/downloads/"));
+        assert!(in_path("/any/", "/"));
+    }
+
+    #[tokio::test]
+    async fn listing() {
+        let path = std::env::temp_dir().join("kvarn-autoindex");
+        let _ = std::fs::remove_dir_all(&path);
+        let public = path.join("public");
+        for dir in &["downloads/sub", "downloads/indexed", "downloads-old"] {
+            std::fs::create_dir_all(public.join(dir)).unwrap();
+        }
+        std::fs::write(public.join("downloads/file.txt"), "file").unwrap();
+        std::fs::write(public.join("downloads/.hidden"), "hidden").unwrap();
+        std::fs::write(public.join("downloads/indexed/index.html"), "index").unwrap();
+        std::fs::write(public.join("downloads-old/old.txt"), "old").unwrap();
+
+        let server = ServerBuilder::default()
+            .path(&path)
+            .with_extensions(|extensions| AutoIndex::new().add("/downloads").mount(extensions))
+            .run()
+            .await;
+
+        let response = server.get("downloads/").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("file.txt"));
+        assert!(body.contains("sub/"));
+        assert!(!body.contains(".hidden"));
+
+        let response = server
+            .get("downloads/sub/")
+            .header("accept", "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers()["content-type"].to_str().unwrap();
+        assert!(content_type.starts_with("application/json"));
+
+        let response = server.get("downloads/indexed/").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "index");
+
+        let response = server.get("downloads/missing/").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // Not a segment of the mounted path.
+        let response = server.get("downloads-old/").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
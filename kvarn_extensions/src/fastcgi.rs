@@ -7,8 +7,41 @@ pub enum FastcgiError {
     FailedToDoRequest(fastcgi_client::ClientError),
     NoStdout,
 }
+/// Where the FastCGI server listens.
+///
+/// On Unix, a Unix socket is used. On Windows, a TCP port on localhost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Connection {
+    #[cfg(windows)]
+    Tcp(u16),
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+}
+impl Default for Connection {
+    /// Port `6633` on Windows and `/run/php-fpm/php-fpm.sock` on Unix.
+    fn default() -> Self {
+        #[cfg(windows)]
+        {
+            Self::Tcp(6633)
+        }
+        #[cfg(unix)]
+        {
+            Self::UnixSocket(PathBuf::from("/run/php-fpm/php-fpm.sock"))
+        }
+    }
+}
+impl Display for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            Self::Tcp(port) => write!(f, "port {}", port),
+            #[cfg(unix)]
+            Self::UnixSocket(path) => write!(f, "'{}'", path.display()),
+        }
+    }
+}
 pub async fn connect(
-    _port: u16,
+    connection: &Connection,
     method: &str,
     file_name: &str,
     file_path: &str,
@@ -18,12 +51,16 @@ pub async fn connect(
 ) -> Result<Vec<u8>, FastcgiError> {
     // Create connection to FastCGI server
     #[cfg(windows)]
-    let stream = match networking::TcpStream::connect((net::Ipv4Addr::LOCALHOST, _port)).await {
+    let Connection::Tcp(port) = connection;
+    #[cfg(windows)]
+    let stream = match networking::TcpStream::connect((net::Ipv4Addr::LOCALHOST, *port)).await {
         Ok(stream) => stream,
         Err(err) => return Err(FastcgiError::FailedToConnect(err)),
     };
     #[cfg(unix)]
-    let stream = match tokio::net::UnixStream::connect("/run/php-fpm/php-fpm.sock").await {
+    let Connection::UnixSocket(path) = connection;
+    #[cfg(unix)]
+    let stream = match tokio::net::UnixStream::connect(path).await {
         Ok(stream) => stream,
         Err(err) => return Err(FastcgiError::FailedToConnect(err)),
     };
@@ -65,7 +102,7 @@ pub async fn from_prepare<T>(
     body: &[u8],
     path: &Path,
     address: SocketAddr,
-    fcgi_server_port: u16,
+    connection: &Connection,
 ) -> Result<Vec<u8>, Cow<'static, str>> {
     let file_name = match parse::format_file_name(&path) {
         Some(name) => name,
@@ -88,7 +125,7 @@ pub async fn from_prepare<T>(
 
     // Fetch fastcgi server response.
     match connect(
-        fcgi_server_port,
+        connection,
         request.method().as_str(),
         file_name,
         file_path,
@@ -100,21 +137,10 @@ pub async fn from_prepare<T>(
     {
         Ok(vec) => Ok(vec),
         Err(err) => match err {
-            FastcgiError::FailedToConnect(_err) => {
-                #[cfg(windows)]
-                {
-                    Err(Cow::Owned(format!(
-                        "Failed to connect to FastCGI server on port {}. IO Err: {}",
-                        fcgi_server_port, _err
-                    )))
-                }
-                #[cfg(unix)]
-                {
-                    Err(Cow::Borrowed(
-                        "Failed to connect to FastCGI on '/run/php-fmp/php-fmp.sock'",
-                    ))
-                }
-            }
+            FastcgiError::FailedToConnect(err) => Err(Cow::Owned(format!(
+                "Failed to connect to FastCGI server on {}. IO Err: {}",
+                connection, err
+            ))),
             FastcgiError::FailedToDoRequest(err) => Err(Cow::Owned(format!(
                 "Failed to request from FastCGI server! Err: {}",
                 err
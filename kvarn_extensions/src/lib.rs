@@ -38,6 +38,11 @@ pub mod templates;
 #[cfg(feature = "templates")]
 pub use templates::templates;
 
+#[cfg(feature = "autoindex")]
+pub mod autoindex;
+#[cfg(feature = "autoindex")]
+pub use autoindex::AutoIndex;
+
 /// Creates a new `Extensions` and adds all enabled `kvarn_extensions`.
 ///
 /// See [`mount_all()`] for more information.
@@ -86,7 +91,7 @@ pub mod parse {
 /// Makes the client download the file.
 pub fn download(mut data: PresentDataWrapper) -> RetFut<()> {
     let data = unsafe { data.get_inner() };
-    let headers = data.response_mut().headers_mut();
+    let headers = data.headers_mut();
     utils::replace_header_static(headers, "content-type", "application/octet-stream");
     ready(())
 }
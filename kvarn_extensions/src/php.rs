@@ -1,9 +1,17 @@
 use crate::*;
 
 pub fn mount_php(extensions: &mut Extensions) {
+    mount_php_with_connection(extensions, fastcgi::Connection::default());
+}
+/// Same as [`mount_php`], but connects to the FastCGI server at `connection`
+/// instead of the [default](fastcgi::Connection::default).
+pub fn mount_php_with_connection(extensions: &mut Extensions, connection: fastcgi::Connection) {
+    let connection = Arc::new(connection);
     extensions.add_prepare_fn(
         Box::new(|req, host| !host.options.disable_fs && req.uri().path().ends_with(".php")),
-        Box::new(php),
+        Box::new(move |req, host, path, address| {
+            php(req, host, path, address, Arc::clone(&connection))
+        }),
         extensions::Id::new(-8, "PHP"),
     );
 }
@@ -12,6 +20,7 @@ fn php(
     host: HostWrapper,
     path: PathOptionWrapper,
     address: SocketAddr,
+    connection: Arc<fastcgi::Connection>,
 ) -> RetFut<FatResponse> {
     box_fut!({
         let req = unsafe { req.get_inner() };
@@ -39,7 +48,7 @@ fn php(
                     )
                 }
             };
-            let output = match fastcgi::from_prepare(req, &body, path, address, 6633).await {
+            let output = match fastcgi::from_prepare(req, &body, path, address, &connection).await {
                 Ok(vec) => vec,
                 Err(err) => {
                     error!("FastCGI failed. {}", err);
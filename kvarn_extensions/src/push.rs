@@ -41,7 +41,7 @@ pub fn push(
                 urls.retain(|url| {
                     let correct_host = {
                         // only push https://; it's eight bytes long
-                        url.get(8..).map_or(false, |url| url.starts_with(host.name))
+                        url.get(8..).map_or(false, |url| url.starts_with(host.name.as_str()))
                     };
                     url.starts_with('/') || correct_host
                 });
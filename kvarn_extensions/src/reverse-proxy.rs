@@ -277,8 +277,15 @@ impl EstablishedConnection {
 
         debug!("Sent reverse-proxy request.");
 
-        let response = match timeout(std::time::Duration::from_millis(1000), async {
-            kvarn::prelude::async_bits::read::response(&mut *self, 16 * 1024).await
+        let head_timeout = std::time::Duration::from_millis(1000);
+        let response = match timeout(head_timeout, async {
+            kvarn::prelude::async_bits::read::response(
+                &mut *self,
+                16 * 1024,
+                head_timeout,
+                head_timeout,
+            )
+            .await
         })
         .await
         {
@@ -0,0 +1,576 @@
+//! Access logs, recording every request to a [`Host`].
+//!
+//! An [`AccessLog`] writes a line for each response sent, in the [`Format`] of your choice.
+//! The lines are written to the file in the background, so a slow disk doesn't slow
+//! down the requests. If the file grows too large, it's rotated;
+//! `access.log` is renamed to `access.log.1`, which is renamed to `access.log.2`, and so on.
+//!
+//! Use [`Host::enable_access_log`] to log the requests to a [`Host`].
+//! Several hosts can share one [`AccessLog`].
+//!
+//! # Examples
+//!
+//! ```
+//! # use kvarn::prelude::*;
+//! use kvarn::access_log::{AccessLog, Format};
+//!
+//! let log = AccessLog::new("logs/access.log", Format::Combined)
+//!     .with_max_size(16 * 1024 * 1024)
+//!     .with_kept_files(4)
+//!     .build();
+//!
+//! let mut host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), host::Options::default());
+//! host.enable_access_log(log);
+//! ```
+
+use crate::prelude::*;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::sync::{Mutex as StdMutex, Once, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// The default [`AccessLog::with_max_size`], 64 MiB.
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// The default [`AccessLog::with_kept_files`].
+pub const DEFAULT_KEPT_FILES: usize = 8;
+/// The number of lines which can wait to be written.
+/// If the disk can't keep up, new lines are dropped.
+const QUEUE_SIZE: usize = 4096;
+/// Write at most this many bytes at once.
+const MAX_BATCH_SIZE: usize = 64 * 1024;
+
+/// The format of the lines of an [`AccessLog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The [Common Log Format](https://en.wikipedia.org/wiki/Common_Log_Format),
+    /// `address - - [time] "request line" status bytes`.
+    Common,
+    /// The Combined Log Format; the [`Format::Common`] with the
+    /// `referer` and `user-agent` headers appended.
+    Combined,
+    /// A JSON object per line, with all the recorded fields:
+    /// `time`, `host`, `address`, `method`, `path`, `version`, `status`, `bytes`,
+    /// `compression`, `cache`, `duration_ms`, `referer`, and `user_agent`.
+    Json,
+}
+
+/// Whether the response was from the response cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cache {
+    Hit,
+    Miss,
+}
+impl Cache {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+/// The time a request started getting handled, and whether it was in the cache.
+///
+/// Made into an [`Entry`] when the response is sent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Start {
+    time: time::DateTime<time::Utc>,
+    instant: Instant,
+    pub(crate) cache: Cache,
+}
+impl Start {
+    pub(crate) fn now() -> Self {
+        Self {
+            time: time::Utc::now(),
+            instant: Instant::now(),
+            cache: Cache::Miss,
+        }
+    }
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.instant.elapsed()
+    }
+}
+
+/// A response which has been sent.
+#[derive(Debug)]
+pub(crate) struct Entry<'a> {
+    pub(crate) start: Start,
+    pub(crate) request: &'a FatRequest,
+    pub(crate) host: &'a Host,
+    pub(crate) address: SocketAddr,
+    pub(crate) status: StatusCode,
+    pub(crate) bytes: u64,
+    pub(crate) compression: Option<&'a HeaderValue>,
+}
+impl Entry<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+    fn path(&self) -> &str {
+        self.request
+            .uri()
+            .path_and_query()
+            .map_or("/", uri::PathAndQuery::as_str)
+    }
+    fn format(&self, format: Format, duration: Duration) -> String {
+        let mut line = String::with_capacity(256);
+        // Writing to a `String` can't fail.
+        match format {
+            Format::Common | Format::Combined => {
+                write!(
+                    line,
+                    "{} - - [{}] \"{} {} {:?}\" {} ",
+                    self.address.ip(),
+                    self.start.time.format("%d/%b/%Y:%H:%M:%S +0000"),
+                    self.request.method(),
+                    clf_escape(self.path()),
+                    self.request.version(),
+                    self.status.as_u16(),
+                )
+                .unwrap();
+                if self.bytes == 0 {
+                    line.push('-');
+                } else {
+                    write!(line, "{}", self.bytes).unwrap();
+                }
+                if format == Format::Combined {
+                    write!(
+                        line,
+                        " \"{}\" \"{}\"",
+                        clf_escape(self.header("referer").unwrap_or("-")),
+                        clf_escape(self.header("user-agent").unwrap_or("-")),
+                    )
+                    .unwrap();
+                }
+            }
+            Format::Json => {
+                let optional =
+                    |value: Option<&str>| value.map_or_else(|| "null".to_owned(), json_string);
+                write!(
+                    line,
+                    "{{\"time\":\"{}\",\"host\":{},\"address\":\"{}\",\"method\":{},\"path\":{},\
+                    \"version\":\"{:?}\",\"status\":{},\"bytes\":{},\"compression\":{},\"cache\":\"{}\",\
+                    \"duration_ms\":{:.3},\"referer\":{},\"user_agent\":{}}}",
+                    self.start.time.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                    json_string(&self.host.name),
+                    self.address,
+                    json_string(self.request.method().as_str()),
+                    json_string(self.path()),
+                    self.request.version(),
+                    self.status.as_u16(),
+                    self.bytes,
+                    json_string(
+                        self.compression
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or("identity")
+                    ),
+                    self.start.cache.as_str(),
+                    duration.as_secs_f64() * 1000.0,
+                    optional(self.header("referer")),
+                    optional(self.header("user-agent")),
+                )
+                .unwrap();
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Escapes `"`, `\`, and non-printable characters, as Apache does in the Common Log Format.
+fn clf_escape(s: &str) -> Cow<'_, str> {
+    if !s
+        .bytes()
+        .any(|byte| byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte))
+    {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 8);
+    for byte in s.bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => write!(escaped, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    Cow::Owned(escaped)
+}
+/// Encodes `s` as a JSON string, including the quotes.
+fn json_string(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len() + 2);
+    encoded.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            c if c < ' ' => write!(encoded, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => encoded.push(c),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+/// The settings of the background task writing to the file.
+#[derive(Debug, Clone)]
+struct Output {
+    path: PathBuf,
+    max_size: Option<u64>,
+    kept_files: usize,
+}
+impl Output {
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+    async fn open(&self) -> io::Result<(fs::File, u64)> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        let len = file.metadata().await?.len();
+        Ok((file, len))
+    }
+    /// Renames the log files; the current file becomes `<path>.1`.
+    async fn rotate(&self) -> io::Result<()> {
+        if self.kept_files == 0 {
+            return tokio::fs::remove_file(&self.path).await;
+        }
+        for n in (1..self.kept_files).rev() {
+            match tokio::fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        tokio::fs::rename(&self.path, self.rotated_path(1)).await
+    }
+    async fn write(
+        self,
+        mut lines: mpsc::Receiver<String>,
+        dropped: Arc<threading::atomic::AtomicU64>,
+    ) {
+        let (mut file, mut len) = match self.open().await {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Failed to open access log {}: {}", self.path.display(), err);
+                return;
+            }
+        };
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        while let Some(line) = lines.recv().await {
+            batch.clear();
+            batch.extend_from_slice(line.as_bytes());
+            while batch.len() < MAX_BATCH_SIZE {
+                match lines.try_recv() {
+                    Ok(line) => batch.extend_from_slice(line.as_bytes()),
+                    Err(_) => break,
+                }
+            }
+
+            let dropped = dropped.swap(0, threading::Ordering::Relaxed);
+            if dropped > 0 {
+                warn!(
+                    "Dropped {} lines of access log {}; the disk can't keep up.",
+                    dropped,
+                    self.path.display()
+                );
+            }
+
+            if self
+                .max_size
+                .map_or(false, |max| len > 0 && len + batch.len() as u64 > max)
+            {
+                let rotated = match self.rotate().await {
+                    Ok(()) => self.open().await,
+                    Err(err) => Err(err),
+                };
+                match rotated {
+                    Ok((new_file, new_len)) => {
+                        file = new_file;
+                        len = new_len;
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to rotate access log {}: {}",
+                            self.path.display(),
+                            err
+                        );
+                    }
+                }
+            }
+
+            let written = match file.write_all(&batch).await {
+                Ok(()) => file.flush().await,
+                Err(err) => Err(err),
+            };
+            match written {
+                Ok(()) => len += batch.len() as u64,
+                Err(err) => error!(
+                    "Failed to write to access log {}: {}",
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// A log of the requests to one or more [`Host`]s.
+///
+/// See the [module level documentation](self) for an example and more info.
+#[must_use]
+pub struct AccessLog {
+    format: Format,
+    output: Output,
+    sender: mpsc::Sender<String>,
+    receiver: StdMutex<Option<mpsc::Receiver<String>>>,
+    start: Once,
+    dropped: Arc<threading::atomic::AtomicU64>,
+}
+impl AccessLog {
+    /// Creates a new [`AccessLog`] writing lines on `format` to the file at `path`.
+    ///
+    /// The file (and its parent directories) are created when the first line is written.
+    /// By default, it's rotated when it grows beyond [`DEFAULT_MAX_SIZE`]
+    /// and [`DEFAULT_KEPT_FILES`] old files are kept.
+    pub fn new(path: impl AsRef<Path>, format: Format) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            format,
+            output: Output {
+                path: path.as_ref().to_path_buf(),
+                max_size: Some(DEFAULT_MAX_SIZE),
+                kept_files: DEFAULT_KEPT_FILES,
+            },
+            sender,
+            receiver: StdMutex::new(Some(receiver)),
+            start: Once::new(),
+            dropped: Arc::new(threading::atomic::AtomicU64::new(0)),
+        }
+    }
+    /// Rotates the file when it would grow beyond `bytes`.
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.output.max_size = Some(bytes);
+        self
+    }
+    /// Never rotates the file. Use this if you rotate it using another program,
+    /// such as `logrotate` with the `copytruncate` option.
+    pub fn without_rotation(mut self) -> Self {
+        self.output.max_size = None;
+        self
+    }
+    /// Keeps `files` rotated files. The oldest is removed when the file is rotated.
+    ///
+    /// If this is `0`, the file is emptied when it grows too large.
+    pub fn with_kept_files(mut self, files: usize) -> Self {
+        self.output.kept_files = files;
+        self
+    }
+    /// Puts `self` in a [`Arc`].
+    /// Useful for passing it to [`Host::enable_access_log`].
+    #[must_use]
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Gets the format of the lines.
+    #[inline]
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+    /// Gets the path of the file.
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.output.path
+    }
+    /// Gets the size the file is rotated at, or [`None`] if it's never rotated.
+    #[inline]
+    #[must_use]
+    pub fn max_size(&self) -> Option<u64> {
+        self.output.max_size
+    }
+    /// Gets the number of rotated files kept.
+    #[inline]
+    #[must_use]
+    pub fn kept_files(&self) -> usize {
+        self.output.kept_files
+    }
+
+    /// Queues `entry` to be written.
+    ///
+    /// The first call starts writing in the background, on the current Tokio runtime.
+    pub(crate) fn log(&self, entry: &Entry<'_>) {
+        self.start.call_once(|| {
+            let receiver = self
+                .receiver
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            if let Some(receiver) = receiver {
+                let output = self.output.clone();
+                tokio::spawn(output.write(receiver, Arc::clone(&self.dropped)));
+            }
+        });
+
+        let line = entry.format(self.format, entry.start.elapsed());
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, threading::Ordering::Relaxed);
+        }
+    }
+}
+impl Debug for AccessLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .field("path", &self.output.path)
+            .field("max_size", &self.output.max_size)
+            .field("kept_files", &self.output.kept_files)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(format: Format, request: http::request::Builder, bytes: u64) -> String {
+        let request = request.body(application::Body::Empty).unwrap();
+        let host = Host::non_secure(
+            "example.org",
+            PathBuf::from("."),
+            Extensions::empty(),
+            host::Options::default(),
+        );
+        let time = time::NaiveDate::from_ymd_opt(2000, 10, 10)
+            .unwrap()
+            .and_hms_opt(13, 55, 36)
+            .unwrap()
+            .and_utc();
+        let compression = HeaderValue::from_static("br");
+        let entry = Entry {
+            start: Start {
+                time,
+                instant: Instant::now(),
+                cache: Cache::Hit,
+            },
+            request: &request,
+            host: &host,
+            address: "192.0.2.1:1234".parse().unwrap(),
+            status: StatusCode::OK,
+            bytes,
+            compression: Some(&compression),
+        };
+        entry.format(format, Duration::from_micros(1500))
+    }
+    fn request() -> http::request::Builder {
+        Request::get("/a%20b?q=1")
+            .header("referer", "https://example.org/")
+            .header("user-agent", "curl \"7\"")
+    }
+
+    #[test]
+    fn common_format() {
+        assert_eq!(
+            line(Format::Common, request(), 2326),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%20b?q=1 HTTP/1.1\" 200 2326\n"
+        );
+        assert_eq!(
+            line(Format::Common, Request::head("/"), 0),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"HEAD / HTTP/1.1\" 200 -\n"
+        );
+    }
+    #[test]
+    fn combined_format() {
+        assert_eq!(
+            line(Format::Combined, request(), 2326),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%20b?q=1 HTTP/1.1\" 200 2326 \
+            \"https://example.org/\" \"curl \\\"7\\\"\"\n"
+        );
+        assert!(line(Format::Combined, Request::get("/"), 0).ends_with(" - \"-\" \"-\"\n"));
+    }
+    #[test]
+    fn json_format() {
+        assert_eq!(
+            line(Format::Json, request().version(Version::HTTP_2), 12),
+            "{\"time\":\"2000-10-10T13:55:36.000Z\",\"host\":\"example.org\",\"address\":\"192.0.2.1:1234\",\
+            \"method\":\"GET\",\"path\":\"/a%20b?q=1\",\"version\":\"HTTP/2.0\",\"status\":200,\"bytes\":12,\
+            \"compression\":\"br\",\"cache\":\"hit\",\"duration_ms\":1.500,\
+            \"referer\":\"https://example.org/\",\"user_agent\":\"curl \\\"7\\\"\"}\n"
+        );
+        assert!(line(Format::Json, Request::get("/"), 0)
+            .ends_with(",\"referer\":null,\"user_agent\":null}\n"));
+    }
+    #[test]
+    fn escaping() {
+        assert!(matches!(
+            clf_escape("/plain?a=b"),
+            Cow::Borrowed("/plain?a=b")
+        ));
+        assert_eq!(clf_escape("a\"b\\c\u{1}é"), "a\\\"b\\\\c\\x01\\xc3\\xa9");
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a\"b\\c\n\u{1f}é"),
+            "\"a\\\"b\\\\c\\u000a\\u001fé\""
+        );
+    }
+    #[tokio::test]
+    async fn rotation() {
+        async fn write(output: &Output, dir: &Path) {
+            tokio::fs::create_dir_all(dir).await.unwrap();
+            for (name, contents) in [("log", "current\n"), ("log.1", "old 1"), ("log.2", "old 2")] {
+                tokio::fs::write(dir.join(name), contents).await.unwrap();
+            }
+            let (sender, receiver) = mpsc::channel(4);
+            sender.send("new\n".to_owned()).await.unwrap();
+            drop(sender);
+            let dropped = Arc::new(threading::atomic::AtomicU64::new(0));
+            output.clone().write(receiver, dropped).await;
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).ok();
+
+        let dir = std::env::temp_dir().join("kvarn-access-log-rotation");
+        let _ = std::fs::remove_dir_all(&dir);
+        let output = Output {
+            path: dir.join("log"),
+            max_size: Some(10),
+            kept_files: 2,
+        };
+        write(&output, &dir).await;
+        assert_eq!(read(dir.join("log")).unwrap(), "new\n");
+        assert_eq!(read(dir.join("log.1")).unwrap(), "current\n");
+        assert_eq!(read(dir.join("log.2")).unwrap(), "old 1");
+        assert!(read(dir.join("log.3")).is_none());
+
+        // The file is emptied if no rotated files are kept.
+        let _ = std::fs::remove_dir_all(&dir);
+        let output = Output {
+            kept_files: 0,
+            ..output
+        };
+        write(&output, &dir).await;
+        assert_eq!(read(dir.join("log")).unwrap(), "new\n");
+        assert_eq!(read(dir.join("log.1")).unwrap(), "old 1");
+
+        // Small enough; not rotated.
+        let _ = std::fs::remove_dir_all(&dir);
+        let output = Output {
+            max_size: Some(100),
+            ..output
+        };
+        write(&output, &dir).await;
+        assert_eq!(read(dir.join("log")).unwrap(), "current\nnew\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1294 @@
+//! Certificates from [ACME](https://datatracker.ietf.org/doc/html/rfc8555) certificate authorities,
+//! such as [Let's Encrypt](https://letsencrypt.org/).
+//!
+//! An [`Acme`] orders a certificate for its domains, answers the challenges of the
+//! certificate authority, and renews the certificate before it expires.
+//! The new certificate is used for new connections right away; no restart is needed.
+//!
+//! Use [`Host::enable_acme`] to get the certificate of a [`Host`] using ACME.
+//! [`run`] starts ordering and renewing the certificates.
+//! The account key, certificate, and private key are stored in `<host path>/acme/`
+//! (`account.der`, `certificate.pem`, and `private-key.pem`), so they are reused after restarts.
+//!
+//! # Challenges
+//!
+//! The certificate authority checks that you control the domains using a [`Challenge`].
+//!
+//! - [`Challenge::Http01`], the default, requests a file under [`CHALLENGE_PATH`] on port 80.
+//!   The [`Host`] has to be reachable over HTTP on that port.
+//!   Those requests aren't redirected by [`Host::set_http_redirect_to_https`].
+//! - [`Challenge::TlsAlpn01`] makes a TLS handshake using the [`ALPN_PROTOCOL`] on port 443.
+//!   The [`Host`] has to be reachable over HTTPS on that port.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use kvarn::prelude::*;
+//! use kvarn::acme::Acme;
+//!
+//! # async {
+//! let acme = Acme::new(["example.org", "www.example.org"])
+//!     .with_contact("admin@example.org")
+//!     .build();
+//!
+//! let mut host = Host::non_secure("example.org", PathBuf::from("example.org"), Extensions::default(), host::Options::default());
+//! host.enable_acme(acme).set_http_redirect_to_https();
+//! let data = Data::builder(host).build();
+//!
+//! let config = RunConfig::new()
+//!     .add(PortDescriptor::non_secure(80, Arc::clone(&data)))
+//!     .add(PortDescriptor::new(443, data));
+//! run(config).await.wait().await;
+//! # };
+//! ```
+//!
+//! # Testing
+//!
+//! Use a local test server such as [Pebble](https://github.com/letsencrypt/pebble)
+//! instead of a public certificate authority.
+//! Pebble serves its API using a certificate from its own root,
+//! which has to be trusted using [`Acme::with_root_certificate`].
+//! By default, it validates HTTP-01 challenges on port 5002 and TLS-ALPN-01 on port 5001,
+//! so bind the [`PortDescriptor`]s to those.
+//! [`Acme::renew`] orders a certificate immediately.
+//!
+//! The ignored `pebble` tests of this module run both challenges against a local Pebble;
+//! see their documentation for how to run them.
+//!
+//! ```no_run
+//! # use kvarn::prelude::*;
+//! use kvarn::acme::{Acme, Challenge};
+//!
+//! # async {
+//! let acme = Acme::new(["localhost"])
+//!     .with_directory("https://localhost:14000/dir")
+//!     .with_root_certificate(std::fs::read("pebble/test/certs/pebble.minica.pem").unwrap())
+//!     .with_challenge(Challenge::TlsAlpn01)
+//!     .build();
+//!
+//! let mut host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), host::Options::default());
+//! host.enable_acme(Arc::clone(&acme));
+//! let data = Data::builder(host).build();
+//! let shutdown = run(RunConfig::new().add(PortDescriptor::new(5001, data))).await;
+//!
+//! acme.renew(Path::new("web")).await.unwrap();
+//! assert!(acme.certificate().is_some());
+//! # };
+//! ```
+
+use crate::prelude::*;
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, KeyPair},
+};
+use rustls::{sign, ClientHello};
+use std::sync::{PoisonError, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The directory of the production environment of Let's Encrypt.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// The directory of the staging environment of Let's Encrypt.
+///
+/// Use this while testing; the rate limits are much higher,
+/// but the certificates aren't trusted by browsers.
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+/// The path prefix of HTTP-01 challenges.
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// The ALPN protocol of TLS-ALPN-01 challenges.
+pub const ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+/// The default time before the certificate expires when it's renewed; 30 days.
+pub const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The time to wait before trying again after ordering a certificate failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The longest time to sleep before checking the expiry again.
+const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+/// The time between checking the status of authorizations and orders.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The number of times to check the status before giving up.
+const POLL_ATTEMPTS: u32 = 60;
+
+const DIRECTORY: &str = "acme";
+const ACCOUNT_KEY_FILE: &str = "account.der";
+const CERTIFICATE_FILE: &str = "certificate.pem";
+const PRIVATE_KEY_FILE: &str = "private-key.pem";
+
+/// The type of challenge used to prove control over the domains.
+///
+/// See the [module level documentation](self) for the requirements of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Challenge {
+    /// A request to [`CHALLENGE_PATH`] over HTTP on port 80.
+    #[default]
+    Http01,
+    /// A TLS handshake using the [`ALPN_PROTOCOL`] on port 443.
+    TlsAlpn01,
+}
+impl Challenge {
+    /// The name of the challenge in the ACME protocol.
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// An error from ordering a certificate.
+#[derive(Debug)]
+pub enum Error {
+    /// An error occurred while reading from or writing to the fs.
+    Io(io::Error),
+    /// Failed to send a request to the certificate authority.
+    Http(reqwest::Error),
+    /// The certificate authority responded with an error.
+    Problem {
+        /// The status code of the response.
+        status: u16,
+        /// The type of the problem, e.g. `urn:ietf:params:acme:error:rateLimited`.
+        kind: String,
+        /// A description of the problem.
+        detail: String,
+    },
+    /// A response of the certificate authority isn't on the expected format.
+    InvalidResponse(&'static str),
+    /// An authorization or the order failed, or didn't finish in time.
+    Failed(String),
+    /// Failed to generate, parse, or use a key or certificate.
+    Key(String),
+}
+impl From<io::Error> for Error {
+    #[inline]
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl From<reqwest::Error> for Error {
+    #[inline]
+    fn from(error: reqwest::Error) -> Self {
+        Self::Http(error)
+    }
+}
+impl From<rcgen::RcgenError> for Error {
+    #[inline]
+    fn from(error: rcgen::RcgenError) -> Self {
+        Self::Key(error.to_string())
+    }
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {}", err),
+            Self::Http(err) => write!(f, "request failed: {}", err),
+            Self::Problem {
+                status,
+                kind,
+                detail,
+            } => write!(
+                f,
+                "certificate authority responded {} ({}): {}",
+                status, kind, detail
+            ),
+            Self::InvalidResponse(message) => write!(f, "invalid response: {}", message),
+            Self::Failed(message) => f.write_str(message),
+            Self::Key(message) => write!(f, "key error: {}", message),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Gets and renews a certificate using ACME.
+///
+/// See the [module level documentation](self) for an example and more info.
+#[must_use]
+pub struct Acme {
+    directory: String,
+    domains: Vec<String>,
+    contact: Vec<String>,
+    challenge: Challenge,
+    renew_before: Duration,
+    root_certificate: Option<Vec<u8>>,
+
+    certificate: RwLock<Option<sign::CertifiedKey>>,
+    /// Key authorizations by token.
+    http_challenges: RwLock<HashMap<String, String>>,
+    /// Challenge certificates by domain.
+    tls_challenges: RwLock<HashMap<String, sign::CertifiedKey>>,
+    started: threading::atomic::AtomicBool,
+}
+impl Acme {
+    /// Creates a new [`Acme`] getting a certificate for `domains` from [`LETS_ENCRYPT`].
+    ///
+    /// The domains should be the name of the [`Host`] and its aliases.
+    pub fn new(domains: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            directory: LETS_ENCRYPT.to_owned(),
+            domains: domains.into_iter().map(Into::into).collect(),
+            contact: Vec::new(),
+            challenge: Challenge::default(),
+            renew_before: DEFAULT_RENEW_BEFORE,
+            root_certificate: None,
+            certificate: RwLock::new(None),
+            http_challenges: RwLock::new(HashMap::new()),
+            tls_challenges: RwLock::new(HashMap::new()),
+            started: threading::atomic::AtomicBool::new(false),
+        }
+    }
+    /// Uses the certificate authority with the directory at `url`,
+    /// e.g. [`LETS_ENCRYPT_STAGING`] or a local test server.
+    pub fn with_directory(mut self, url: impl Into<String>) -> Self {
+        self.directory = url.into();
+        self
+    }
+    /// Adds a contact `email` to the account.
+    ///
+    /// The certificate authority may use it to notify you about problems with your certificates.
+    pub fn with_contact(mut self, email: impl AsRef<str>) -> Self {
+        self.contact.push(format!("mailto:{}", email.as_ref()));
+        self
+    }
+    /// Sets the type of challenge used. The default is [`Challenge::Http01`].
+    pub fn with_challenge(mut self, challenge: Challenge) -> Self {
+        self.challenge = challenge;
+        self
+    }
+    /// Renews the certificate when it expires in less than `duration`.
+    /// The default is [`DEFAULT_RENEW_BEFORE`].
+    pub fn with_renew_before(mut self, duration: Duration) -> Self {
+        self.renew_before = duration;
+        self
+    }
+    /// Trusts the PEM encoded root certificate `pem` when connecting to the certificate authority.
+    ///
+    /// Needed for test servers, such as Pebble.
+    pub fn with_root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificate = Some(pem.into());
+        self
+    }
+    /// Puts `self` in a [`Arc`].
+    /// Useful for passing it to [`Host::enable_acme`].
+    #[must_use]
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Gets the domains the certificate is for.
+    #[must_use]
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+    /// Gets the current certificate, if one has been issued.
+    #[must_use]
+    pub fn certificate(&self) -> Option<sign::CertifiedKey> {
+        self.certificate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    fn set_certificate(&self, certificate: sign::CertifiedKey) {
+        *self
+            .certificate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(certificate);
+    }
+    /// Gets the certificate for a TLS-ALPN-01 challenge, if `client_hello` is from the validation.
+    pub(crate) fn challenge_certificate(
+        &self,
+        client_hello: &ClientHello<'_>,
+    ) -> Option<sign::CertifiedKey> {
+        let protocols = client_hello.alpn()?;
+        if !protocols.contains(&ALPN_PROTOCOL) {
+            return None;
+        }
+        let name: &str = client_hello.server_name()?.into();
+        self.tls_challenges
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+    /// Gets the key authorization of the HTTP-01 challenge with `token`.
+    pub(crate) fn key_authorization(&self, token: &str) -> Option<String> {
+        self.http_challenges
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)
+            .cloned()
+    }
+
+    /// Starts getting and renewing the certificate in the background,
+    /// storing it in the ACME directory in `host_path`.
+    ///
+    /// This is called by [`run`]; subsequent calls do nothing.
+    pub(crate) fn start(self: &Arc<Self>, host_path: &Path) {
+        if self.started.swap(true, threading::atomic::Ordering::AcqRel) {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let acme = Arc::downgrade(self);
+            let host_path = host_path.to_path_buf();
+            runtime.spawn(Self::keep_renewed(acme, host_path));
+        } else {
+            self.started
+                .store(false, threading::atomic::Ordering::Release);
+            error!(
+                "Can't start ACME for {:?} outside of a Tokio runtime.",
+                self.domains
+            );
+        }
+    }
+    /// Renews the certificate when it's about to expire.
+    ///
+    /// Ends when `acme` is dropped, e.g. when the [`Host`] is replaced by a reload.
+    async fn keep_renewed(acme: Weak<Self>, host_path: PathBuf) {
+        match acme.upgrade() {
+            Some(acme) => acme.read_stored(&host_path),
+            None => return,
+        }
+
+        loop {
+            let wait = {
+                // Only upgraded while it's used, so `acme` can be dropped while this sleeps.
+                let acme = match acme.upgrade() {
+                    Some(acme) => acme,
+                    None => break,
+                };
+                let remaining = acme
+                    .certificate()
+                    .and_then(|certificate| not_after(&certificate.cert.first()?.0))
+                    .and_then(|expires| expires.duration_since(SystemTime::now()).ok())
+                    .unwrap_or(Duration::ZERO);
+                match remaining
+                    .checked_sub(acme.renew_before)
+                    .filter(|wait| *wait > Duration::ZERO)
+                {
+                    Some(wait) => wait.min(MAX_SLEEP),
+                    None => match acme.renew(&host_path).await {
+                        Ok(()) => continue,
+                        Err(err) => {
+                            error!(
+                                "Failed to get a certificate for {:?}. Trying again in an hour. {}",
+                                acme.domains, err
+                            );
+                            RETRY_INTERVAL
+                        }
+                    },
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+        debug!("Stopped renewing the certificate of a dropped ACME configuration.");
+    }
+    /// Uses the certificate stored in the ACME directory in `host_path`, if any.
+    fn read_stored(&self, host_path: &Path) {
+        let directory = host_path.join(DIRECTORY);
+        match host::get_certified_key(
+            directory.join(CERTIFICATE_FILE),
+            directory.join(PRIVATE_KEY_FILE),
+        ) {
+            Ok((chain, key)) => self.set_certificate(sign::CertifiedKey::new(chain, key)),
+            Err(host::CertificateError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(
+                "Failed to read the stored certificate for {:?}. Ordering a new one. {:?}",
+                self.domains, err
+            ),
+        }
+    }
+
+    /// Orders a new certificate and uses it for new connections.
+    ///
+    /// The account key, certificate, and private key are stored in the ACME directory
+    /// in `host_path`, which should be the [`Host::path`].
+    /// The challenges are answered by the [`Host`] this is enabled on, so Kvarn has to be running.
+    ///
+    /// This is done automatically by [`run`], when the certificate is about to expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any request to the certificate authority failed,
+    /// if the domains couldn't be validated, or if the files couldn't be written.
+    pub async fn renew(&self, host_path: &Path) -> Result<(), Error> {
+        let directory = host_path.join(DIRECTORY);
+        tokio::fs::create_dir_all(&directory).await?;
+
+        info!("Ordering a certificate for {:?}.", self.domains);
+        let (certificate, chain, key) = self.order(&directory).await?;
+
+        write_private(&directory.join(PRIVATE_KEY_FILE), key.as_bytes()).await?;
+        tokio::fs::write(directory.join(CERTIFICATE_FILE), &chain).await?;
+        self.set_certificate(certificate);
+        info!("Got a new certificate for {:?}.", self.domains);
+        Ok(())
+    }
+    /// Returns the certificate, the PEM encoded chain, and the PEM encoded private key.
+    async fn order(&self, directory: &Path) -> Result<(sign::CertifiedKey, Bytes, String), Error> {
+        let mut client = Client::new(self, directory).await?;
+
+        let identifiers = self
+            .domains
+            .iter()
+            .map(|domain| format!(r#"{{"type":"dns","value":{}}}"#, json::string(domain)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let new_order = client.resource("newOrder")?;
+        let (order_url, order) = client
+            .post_json(
+                &new_order,
+                Some(&format!(r#"{{"identifiers":[{}]}}"#, identifiers)),
+            )
+            .await?;
+        let order_url = order_url.ok_or(Error::InvalidResponse("order has no location"))?;
+        let authorizations: Vec<String> = order
+            .get("authorizations")
+            .and_then(json::Value::as_array)
+            .ok_or(Error::InvalidResponse("order has no authorizations"))?
+            .iter()
+            .filter_map(json::Value::as_str)
+            .map(str::to_owned)
+            .collect();
+        let finalize = field(&order, "finalize")?.to_owned();
+
+        for authorization in &authorizations {
+            self.authorize(&mut client, authorization).await?;
+        }
+
+        let mut params = rcgen::CertificateParams::new(self.domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let key = rcgen::Certificate::from_params(params)?;
+        let csr = key.serialize_request_der()?;
+        client
+            .post(
+                &finalize,
+                Some(&format!(r#"{{"csr":"{}"}}"#, base64url(&csr))),
+            )
+            .await?;
+        let order = client.poll(&order_url, "valid").await?;
+        let certificate_url = field(&order, "certificate")?.to_owned();
+        let (_, chain) = client.post(&certificate_url, None).await?;
+
+        let certificates = rustls::internal::pemfile::certs(&mut &chain[..])
+            .map_err(|()| Error::InvalidResponse("certificate chain isn't PEM"))?;
+        if certificates.is_empty() {
+            return Err(Error::InvalidResponse("certificate chain is empty"));
+        }
+        let signing_key =
+            sign::any_supported_type(&rustls::PrivateKey(key.serialize_private_key_der()))
+                .map_err(|()| Error::Key("unsupported private key".to_owned()))?;
+        let certificate = sign::CertifiedKey::new(certificates, Arc::new(signing_key));
+        Ok((certificate, chain, key.serialize_private_key_pem()))
+    }
+    /// Completes the challenge of the authorization at `url`, if it isn't already valid.
+    async fn authorize(&self, client: &mut Client<'_>, url: &str) -> Result<(), Error> {
+        let (_, authorization) = client.post_json(url, None).await?;
+        if field(&authorization, "status")? == "valid" {
+            return Ok(());
+        }
+        let domain = authorization
+            .get("identifier")
+            .map(|identifier| field(identifier, "value"))
+            .ok_or(Error::InvalidResponse("authorization has no identifier"))??
+            .to_owned();
+        let challenge = authorization
+            .get("challenges")
+            .and_then(json::Value::as_array)
+            .and_then(|challenges| {
+                challenges.iter().find(|challenge| {
+                    challenge.get("type").and_then(json::Value::as_str)
+                        == Some(self.challenge.as_str())
+                })
+            })
+            .ok_or_else(|| {
+                Error::Failed(format!(
+                    "no {} challenge offered for {}",
+                    self.challenge.as_str(),
+                    domain
+                ))
+            })?;
+        let token = field(challenge, "token")?.to_owned();
+        let challenge_url = field(challenge, "url")?.to_owned();
+        let key_authorization = format!("{}.{}", token, client.thumbprint());
+
+        match self.challenge {
+            Challenge::Http01 => {
+                self.http_challenges
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(token.clone(), key_authorization);
+            }
+            Challenge::TlsAlpn01 => {
+                let certificate = challenge_certificate(&domain, &key_authorization)?;
+                self.tls_challenges
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(domain.clone(), certificate);
+            }
+        }
+        debug!(
+            "Answering the {} challenge for {}.",
+            self.challenge.as_str(),
+            domain
+        );
+
+        let result = match client.post(&challenge_url, Some("{}")).await {
+            Ok(_) => client.poll(url, "valid").await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+
+        self.http_challenges
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&token);
+        self.tls_challenges
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&domain);
+        result
+    }
+}
+impl Debug for Acme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Acme")
+            .field("directory", &self.directory)
+            .field("domains", &self.domains)
+            .field("contact", &self.contact)
+            .field("challenge", &self.challenge)
+            .field("renew_before", &self.renew_before)
+            .field(
+                "root_certificate",
+                &self
+                    .root_certificate
+                    .as_ref()
+                    .map(|_| "[certificate]".as_clean()),
+            )
+            .field(
+                "certificate",
+                &self
+                    .certificate()
+                    .map(|_| "[internal certificate]".as_clean()),
+            )
+            .finish()
+    }
+}
+
+/// A session with the certificate authority, signing requests using the account key.
+struct Client<'a> {
+    acme: &'a Acme,
+    http: reqwest::Client,
+    directory: json::Value,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// The public key, as a JSON Web Key.
+    jwk: String,
+    /// The URL of the account, used to identify it after it's created.
+    account: Option<String>,
+    nonce: Option<String>,
+}
+impl<'a> Client<'a> {
+    /// Gets the directory of the certificate authority and creates or finds the account,
+    /// using the key stored in `directory`.
+    async fn new(acme: &'a Acme, directory: &Path) -> Result<Client<'a>, Error> {
+        let mut builder = reqwest::Client::builder().user_agent(SERVER);
+        if let Some(pem) = &acme.root_certificate {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        let http = builder.build()?;
+        let resources = http
+            .get(&acme.directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let resources =
+            json::parse(&resources).ok_or(Error::InvalidResponse("directory isn't JSON"))?;
+
+        let rng = SystemRandom::new();
+        let key_path = directory.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match tokio::fs::read(&key_path).await {
+            Ok(pkcs8) => pkcs8,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 =
+                    EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                        .map_err(|_| Error::Key("failed to generate account key".to_owned()))?;
+                write_private(&key_path, pkcs8.as_ref()).await?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+            .map_err(|err| Error::Key(format!("invalid account key: {}", err)))?;
+        // The public key is uncompressed; a `0x04` followed by the coordinates.
+        let public_key = key.public_key().as_ref();
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            base64url(&public_key[1..33]),
+            base64url(&public_key[33..65])
+        );
+
+        let mut client = Self {
+            acme,
+            http,
+            directory: resources,
+            key,
+            rng,
+            jwk,
+            account: None,
+            nonce: None,
+        };
+
+        let contact = acme
+            .contact
+            .iter()
+            .map(|contact| json::string(contact))
+            .collect::<Vec<_>>()
+            .join(",");
+        let new_account = client.resource("newAccount")?;
+        let (account, _) = client
+            .post(
+                &new_account,
+                Some(&format!(
+                    r#"{{"termsOfServiceAgreed":true,"contact":[{}]}}"#,
+                    contact
+                )),
+            )
+            .await?;
+        client.account = Some(account.ok_or(Error::InvalidResponse("account has no location"))?);
+        Ok(client)
+    }
+
+    /// Gets the URL of the resource `name` from the directory.
+    fn resource(&self, name: &'static str) -> Result<String, Error> {
+        self.directory
+            .get(name)
+            .and_then(json::Value::as_str)
+            .map(str::to_owned)
+            .ok_or(Error::InvalidResponse("directory is missing a resource"))
+    }
+    /// The thumbprint of the account key, used in key authorizations.
+    fn thumbprint(&self) -> String {
+        base64url(digest::digest(&digest::SHA256, self.jwk.as_bytes()).as_ref())
+    }
+
+    async fn new_nonce(&self) -> Result<String, Error> {
+        let url = self.resource("newNonce")?;
+        let response = self.http.head(&url).send().await?.error_for_status()?;
+        replay_nonce(&response).ok_or(Error::InvalidResponse("no nonce was returned"))
+    }
+    /// Signs `payload` for a request to `url` as a JSON Web Signature.
+    ///
+    /// A `payload` of `None` makes a POST-as-GET request.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&str>) -> Result<String, Error> {
+        let key = match &self.account {
+            Some(account) => format!(r#""kid":{}"#, json::string(account)),
+            None => format!(r#""jwk":{}"#, self.jwk),
+        };
+        let protected = base64url(
+            format!(
+                r#"{{"alg":"ES256",{},"nonce":{},"url":{}}}"#,
+                key,
+                json::string(nonce),
+                json::string(url)
+            )
+            .as_bytes(),
+        );
+        let payload = base64url(payload.unwrap_or("").as_bytes());
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| Error::Key("failed to sign request".to_owned()))?;
+        Ok(format!(
+            r#"{{"protected":"{}","payload":"{}","signature":"{}"}}"#,
+            protected,
+            payload,
+            base64url(signature.as_ref())
+        ))
+    }
+    /// Sends a signed request to `url`, returning the `location` header and the body.
+    ///
+    /// A `payload` of `None` makes a POST-as-GET request.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&str>,
+    ) -> Result<(Option<String>, Bytes), Error> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let response = self
+                .http
+                .post(url)
+                .header("content-type", "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            let status = response.status();
+            let location = response
+                .headers()
+                .get("location")
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_owned);
+            let body = response.bytes().await?;
+            if status.is_success() {
+                return Ok((location, body));
+            }
+
+            let problem = json::parse(&body);
+            let problem_field = |name| {
+                problem
+                    .as_ref()
+                    .and_then(|problem| problem.get(name))
+                    .and_then(json::Value::as_str)
+                    .unwrap_or("")
+                    .to_owned()
+            };
+            let kind = problem_field("type");
+            // The nonce can expire; get a new one.
+            if kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::Problem {
+                status: status.as_u16(),
+                kind,
+                detail: problem_field("detail"),
+            });
+        }
+    }
+    /// Same as [`Self::post`], but parses the body as JSON.
+    async fn post_json(
+        &mut self,
+        url: &str,
+        payload: Option<&str>,
+    ) -> Result<(Option<String>, json::Value), Error> {
+        let (location, body) = self.post(url, payload).await?;
+        let body = json::parse(&body).ok_or(Error::InvalidResponse("body isn't JSON"))?;
+        Ok((location, body))
+    }
+    /// Gets the authorization or order at `url` until its status is `done`.
+    async fn poll(&mut self, url: &str, done: &str) -> Result<json::Value, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (_, resource) = self.post_json(url, None).await?;
+            match field(&resource, "status")? {
+                "pending" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status if status == done => return Ok(resource),
+                status => {
+                    return Err(Error::Failed(format!(
+                        "{} is {}: {}",
+                        url,
+                        status,
+                        error_detail(&resource).unwrap_or("no details")
+                    )))
+                }
+            }
+        }
+        Err(Error::Failed(format!(
+            "{} wasn't {} in time for {:?}",
+            url, done, self.acme.domains
+        )))
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(str::to_owned)
+}
+fn field<'a>(value: &'a json::Value, name: &'static str) -> Result<&'a str, Error> {
+    value
+        .get(name)
+        .and_then(json::Value::as_str)
+        .ok_or(Error::InvalidResponse("a field is missing"))
+}
+/// Gets the description of why an order or authorization failed.
+fn error_detail(resource: &json::Value) -> Option<&str> {
+    fn detail(error: &json::Value) -> Option<&str> {
+        error.get("detail").and_then(json::Value::as_str)
+    }
+    resource.get("error").and_then(detail).or_else(|| {
+        resource
+            .get("challenges")
+            .and_then(json::Value::as_array)?
+            .iter()
+            .find_map(|challenge| challenge.get("error").and_then(detail))
+    })
+}
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+/// Writes `contents` to `path`, only readable by the current user on Unix.
+async fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await
+}
+
+/// Creates the self-signed certificate for the TLS-ALPN-01 challenge of `domain`.
+fn challenge_certificate(
+    domain: &str,
+    key_authorization: &str,
+) -> Result<sign::CertifiedKey, Error> {
+    let digest = digest::digest(&digest::SHA256, key_authorization.as_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+    let certificate = rcgen::Certificate::from_params(params)?;
+    let der = certificate.serialize_der()?;
+    let key =
+        sign::any_supported_type(&rustls::PrivateKey(certificate.serialize_private_key_der()))
+            .map_err(|()| Error::Key("unsupported private key".to_owned()))?;
+    Ok(sign::CertifiedKey::new(
+        vec![rustls::Certificate(der)],
+        Arc::new(key),
+    ))
+}
+
+/// Gets the end of the validity period of the DER encoded X.509 `certificate`.
+fn not_after(certificate: &[u8]) -> Option<SystemTime> {
+    /// Splits the first DER element from `der`, returning the tag, contents, and the rest.
+    fn element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, der) = der.split_first()?;
+        let (&first, mut der) = der.split_first()?;
+        let len = if first < 0x80 {
+            usize::from(first)
+        } else {
+            let bytes = usize::from(first & 0x7f);
+            if bytes > 4 || der.len() < bytes {
+                return None;
+            }
+            let (len, rest) = der.split_at(bytes);
+            der = rest;
+            len.iter()
+                .fold(0, |acc, &byte| (acc << 8) | usize::from(byte))
+        };
+        if der.len() < len {
+            return None;
+        }
+        let (contents, rest) = der.split_at(len);
+        Some((tag, contents, rest))
+    }
+
+    let (_, certificate, _) = element(certificate)?;
+    let (_, tbs_certificate, _) = element(certificate)?;
+    let (tag, _, mut rest) = element(tbs_certificate)?;
+    // Skip the (optional) version, serial number, signature algorithm, and issuer.
+    let skip = if tag == 0xa0 { 3 } else { 2 };
+    for _ in 0..skip {
+        rest = element(rest)?.2;
+    }
+    let (_, validity, _) = element(rest)?;
+    let (_, _, validity) = element(validity)?;
+    let (tag, time, _) = element(validity)?;
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    let time = chrono::NaiveDateTime::parse_from_str(std::str::from_utf8(time).ok()?, format)
+        .ok()?
+        .and_utc()
+        .timestamp();
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(time).ok()?))
+}
+
+/// A minimal JSON parser and encoder for the messages of the certificate authority.
+mod json {
+    use std::fmt::Write;
+
+    #[derive(Debug)]
+    pub(super) enum Value {
+        /// `null`, a boolean, or a number. We don't read any of those.
+        Other,
+        String(String),
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+    impl Value {
+        pub(super) fn get(&self, key: &str) -> Option<&Self> {
+            match self {
+                Self::Object(entries) => entries
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, value)| value),
+                _ => None,
+            }
+        }
+        pub(super) fn as_str(&self) -> Option<&str> {
+            match self {
+                Self::String(s) => Some(s),
+                _ => None,
+            }
+        }
+        pub(super) fn as_array(&self) -> Option<&[Self]> {
+            match self {
+                Self::Array(values) => Some(values),
+                _ => None,
+            }
+        }
+    }
+
+    /// Encodes `s` as a JSON string, including the quotes.
+    pub(super) fn string(s: &str) -> String {
+        let mut encoded = String::with_capacity(s.len() + 2);
+        encoded.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => encoded.push_str("\\\""),
+                '\\' => encoded.push_str("\\\\"),
+                // Writing to a `String` can't fail.
+                c if c < ' ' => write!(encoded, "\\u{:04x}", u32::from(c)).unwrap(),
+                c => encoded.push(c),
+            }
+        }
+        encoded.push('"');
+        encoded
+    }
+
+    /// How deep arrays and objects may be nested, to bound the recursion.
+    const MAX_DEPTH: usize = 32;
+
+    pub(super) fn parse(bytes: &[u8]) -> Option<Value> {
+        let mut parser = Parser {
+            bytes,
+            position: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.position == bytes.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    struct Parser<'a> {
+        bytes: &'a [u8],
+        position: usize,
+        depth: usize,
+    }
+    impl Parser<'_> {
+        fn peek(&self) -> Option<u8> {
+            self.bytes.get(self.position).copied()
+        }
+        fn next(&mut self) -> Option<u8> {
+            let byte = self.peek()?;
+            self.position += 1;
+            Some(byte)
+        }
+        fn whitespace(&mut self) {
+            while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+                self.position += 1;
+            }
+        }
+        fn literal(&mut self, literal: &[u8]) -> Option<Value> {
+            if self.bytes[self.position..].starts_with(literal) {
+                self.position += literal.len();
+                Some(Value::Other)
+            } else {
+                None
+            }
+        }
+        fn value(&mut self) -> Option<Value> {
+            if self.depth == MAX_DEPTH {
+                return None;
+            }
+            self.depth += 1;
+            let value = self.nested_value();
+            self.depth -= 1;
+            value
+        }
+        fn nested_value(&mut self) -> Option<Value> {
+            self.whitespace();
+            match self.peek()? {
+                b'n' => self.literal(b"null"),
+                b't' => self.literal(b"true"),
+                b'f' => self.literal(b"false"),
+                b'"' => self.string().map(Value::String),
+                b'[' => {
+                    self.position += 1;
+                    let mut values = Vec::new();
+                    self.whitespace();
+                    if self.peek() == Some(b']') {
+                        self.position += 1;
+                        return Some(Value::Array(values));
+                    }
+                    loop {
+                        values.push(self.value()?);
+                        self.whitespace();
+                        match self.next()? {
+                            b',' => {}
+                            b']' => return Some(Value::Array(values)),
+                            _ => return None,
+                        }
+                    }
+                }
+                b'{' => {
+                    self.position += 1;
+                    let mut entries = Vec::new();
+                    self.whitespace();
+                    if self.peek() == Some(b'}') {
+                        self.position += 1;
+                        return Some(Value::Object(entries));
+                    }
+                    loop {
+                        self.whitespace();
+                        let name = self.string()?;
+                        self.whitespace();
+                        if self.next()? != b':' {
+                            return None;
+                        }
+                        entries.push((name, self.value()?));
+                        self.whitespace();
+                        match self.next()? {
+                            b',' => {}
+                            b'}' => return Some(Value::Object(entries)),
+                            _ => return None,
+                        }
+                    }
+                }
+                b'-' | b'0'..=b'9' => {
+                    let start = self.position;
+                    while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+                        self.position += 1;
+                    }
+                    std::str::from_utf8(&self.bytes[start..self.position])
+                        .ok()?
+                        .parse::<f64>()
+                        .ok()
+                        .map(|_| Value::Other)
+                }
+                _ => None,
+            }
+        }
+        fn hex(&mut self) -> Option<u32> {
+            let digits = self.bytes.get(self.position..self.position + 4)?;
+            self.position += 4;
+            u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+        }
+        fn string(&mut self) -> Option<String> {
+            if self.next()? != b'"' {
+                return None;
+            }
+            let mut bytes = Vec::new();
+            loop {
+                match self.next()? {
+                    b'"' => break,
+                    b'\\' => {
+                        let c = match self.next()? {
+                            b'"' => '"',
+                            b'\\' => '\\',
+                            b'/' => '/',
+                            b'b' => '\u{8}',
+                            b'f' => '\u{c}',
+                            b'n' => '\n',
+                            b'r' => '\r',
+                            b't' => '\t',
+                            b'u' => {
+                                let mut code = self.hex()?;
+                                // A surrogate pair.
+                                if (0xd800..0xdc00).contains(&code) {
+                                    if self.next()? != b'\\' || self.next()? != b'u' {
+                                        return None;
+                                    }
+                                    let low = self.hex()?;
+                                    code = 0x10000
+                                        + ((code - 0xd800) << 10)
+                                        + low.checked_sub(0xdc00)?;
+                                }
+                                char::from_u32(code)?
+                            }
+                            _ => return None,
+                        };
+                        let mut buffer = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                    }
+                    byte => bytes.push(byte),
+                }
+            }
+            String::from_utf8(bytes).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_parse() {
+        let value = json::parse(
+            br#" {"status": "valid", "expires": null, "identifiers": [{"type": "dns", "value": "example.org"}],
+            "wildcard": false, "retries": -1.5e3, "detail": "a \"b\" \\ \n \u00e9 \ud83d\ude00"} "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value.get("status").and_then(json::Value::as_str),
+            Some("valid")
+        );
+        assert!(value.get("expires").unwrap().as_str().is_none());
+        let identifiers = value.get("identifiers").and_then(json::Value::as_array);
+        assert_eq!(
+            identifiers.unwrap()[0]
+                .get("value")
+                .and_then(json::Value::as_str),
+            Some("example.org")
+        );
+        assert_eq!(
+            value.get("detail").and_then(json::Value::as_str),
+            Some("a \"b\" \\ \n \u{e9} \u{1f600}")
+        );
+        assert!(value.get("missing").is_none());
+        assert!(json::parse(b"[]").unwrap().as_array().unwrap().is_empty());
+    }
+    #[test]
+    fn json_malformed() {
+        for input in [
+            &b""[..],
+            b"{",
+            b"[1,]",
+            b"[1 2]",
+            b"{\"a\" 1}",
+            b"{\"a\":1,}",
+            b"{1:2}",
+            b"\"unterminated",
+            b"\"\\x\"",
+            b"\"\\u12\"",
+            b"\"\\udc00\"",
+            b"\"\\ud83dx\"",
+            b"\"\xff\"",
+            b"nul",
+            b"-",
+            b"1.2.3",
+            b"{} {}",
+        ] {
+            assert!(json::parse(input).is_none(), "{:?}", input);
+        }
+    }
+    #[test]
+    fn json_depth() {
+        let nested = |depth| {
+            let mut json = "[".repeat(depth);
+            json.push_str(&"]".repeat(depth));
+            json
+        };
+        assert!(json::parse(nested(32).as_bytes()).is_some());
+        assert!(json::parse(nested(33).as_bytes()).is_none());
+        assert!(json::parse(nested(100_000).as_bytes()).is_none());
+    }
+    #[test]
+    fn json_string() {
+        let encoded = json::string("a\"b\\c\u{1}é");
+        assert_eq!(encoded, r#""a\"b\\c\u0001é""#);
+        assert_eq!(
+            json::parse(encoded.as_bytes()).unwrap().as_str(),
+            Some("a\"b\\c\u{1}é")
+        );
+    }
+    #[test]
+    fn certificate_not_after() {
+        // UTCTime before 2050, GeneralizedTime after.
+        for year in [2030, 2060] {
+            let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]);
+            params.not_after = rcgen::date_time_ymd(year, 6, 15);
+            let der = rcgen::Certificate::from_params(params)
+                .unwrap()
+                .serialize_der()
+                .unwrap();
+            let expected = rcgen::date_time_ymd(year, 6, 15).timestamp();
+            assert_eq!(
+                not_after(&der),
+                Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(expected).unwrap()))
+            );
+            assert_eq!(not_after(&der[..der.len() / 2]), None);
+        }
+        assert_eq!(not_after(b""), None);
+        assert_eq!(not_after(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+
+    /// Runs Kvarn on `port` and waits for the certificate for `localhost` from Pebble.
+    ///
+    /// These tests need a running Pebble and are therefore ignored by default.
+    /// Start it with `PEBBLE_VA_NOSLEEP=1 pebble -config test/config/pebble-config.json`
+    /// in the Pebble repository, then run
+    /// `KVARN_PEBBLE_ROOT=<pebble>/test/certs/pebble.minica.pem cargo test --features acme -- --ignored pebble`.
+    /// Set `KVARN_PEBBLE_DIRECTORY` if Pebble isn't listening on `https://localhost:14000/dir`.
+    async fn pebble(challenge: Challenge, port: u16) {
+        let directory = std::env::var("KVARN_PEBBLE_DIRECTORY")
+            .unwrap_or_else(|_| "https://localhost:14000/dir".to_owned());
+        let root = std::env::var("KVARN_PEBBLE_ROOT")
+            .expect("set KVARN_PEBBLE_ROOT to the path of Pebble's `pebble.minica.pem`");
+        let acme = Acme::new(["localhost"])
+            .with_directory(directory)
+            .with_root_certificate(std::fs::read(root).unwrap())
+            .with_challenge(challenge)
+            .build();
+
+        let path = std::env::temp_dir().join(format!("kvarn-pebble-{}", challenge.as_str()));
+        // Don't reuse a certificate from a previous run.
+        let _ = std::fs::remove_dir_all(&path);
+        let mut host = Host::non_secure(
+            "localhost",
+            &path,
+            Extensions::default(),
+            host::Options::default(),
+        );
+        host.enable_acme(Arc::clone(&acme));
+        let data = Data::builder(host).build();
+        let descriptor = match challenge {
+            Challenge::Http01 => PortDescriptor::non_secure(port, data),
+            Challenge::TlsAlpn01 => PortDescriptor::new(port, data),
+        };
+        // `run` starts ordering the certificate.
+        let _shutdown = run(RunConfig::new().add(descriptor).disable_handover()).await;
+
+        let mut attempts = 0;
+        let certificate = loop {
+            if let Some(certificate) = acme.certificate() {
+                break certificate;
+            }
+            attempts += 1;
+            assert!(attempts < 60, "no certificate from Pebble after a minute");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        assert!(not_after(&certificate.cert[0].0).is_some());
+        assert!(path.join(DIRECTORY).join(CERTIFICATE_FILE).exists());
+        assert!(path.join(DIRECTORY).join(PRIVATE_KEY_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn renewal_ends_when_dropped() {
+        // Nothing listens on port 1, so the order fails right away.
+        let acme = Acme::new(["localhost"])
+            .with_directory("http://127.0.0.1:1/dir")
+            .build();
+        let path = std::env::temp_dir().join("kvarn-acme-dropped");
+        acme.start(&path);
+        // Let it fail to order the certificate.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let weak = Arc::downgrade(&acme);
+        drop(acme);
+        assert!(weak.upgrade().is_none());
+    }
+    #[tokio::test]
+    #[ignore = "needs a running Pebble"]
+    async fn pebble_http_01() {
+        pebble(Challenge::Http01, 5002).await;
+    }
+    #[tokio::test]
+    #[ignore = "needs a running Pebble"]
+    async fn pebble_tls_alpn_01() {
+        pebble(Challenge::TlsAlpn01, 5001).await;
+    }
+}
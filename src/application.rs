@@ -8,12 +8,17 @@
 //! When accepting on the [`HttpConnection`], you get a [`FatRequest`]; a [`http::Request`] with a [`Body`].
 //! The [`Body`] is a stream providing the body of a response if you need it, to avoid unnecessary allocations.
 use crate::prelude::{internals::*, *};
-pub use response::Http1Body;
+#[cfg(feature = "http3")]
+pub use http3::{Http3Body, Http3Connection, Http3Stream};
+#[cfg(feature = "http2")]
+pub use response::Http2Body;
+pub use response::{Http1Body, Http1ChunkedPipe};
+use std::time::Duration;
 
 /// General error for application-level logic.
 ///
-/// Mostly, the [`Error::Parse`], [`Error::Io`], and [`Error::H2`]
-/// signal errors with the request emitted from respective library.
+/// Mostly, the [`Error::Parse`], [`Error::Io`], `Error::H2`, and the `Error::H3*`
+/// variants signal errors with the request emitted from respective library.
 #[derive(Debug)]
 pub enum Error {
     /// A parse error from the module [`parse`].
@@ -23,6 +28,12 @@ pub enum Error {
     /// [`h2`] emitted an error
     #[cfg(feature = "http2")]
     H2(h2::Error),
+    /// [`h3`] emitted an error on the connection
+    #[cfg(feature = "http3")]
+    H3Connection(h3::error::ConnectionError),
+    /// [`h3`] emitted an error on a stream
+    #[cfg(feature = "http3")]
+    H3Stream(h3::error::StreamError),
     /// The HTTP version assumed by the client is not supported.
     /// Invalid ALPN config is a candidate.
     VersionNotSupported,
@@ -50,6 +61,20 @@ impl From<h2::Error> for Error {
         Self::H2(err)
     }
 }
+#[cfg(feature = "http3")]
+impl From<h3::error::ConnectionError> for Error {
+    #[inline]
+    fn from(err: h3::error::ConnectionError) -> Self {
+        Self::H3Connection(err)
+    }
+}
+#[cfg(feature = "http3")]
+impl From<h3::error::StreamError> for Error {
+    #[inline]
+    fn from(err: h3::error::StreamError) -> Self {
+        Self::H3Stream(err)
+    }
+}
 impl From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
         match err {
@@ -57,6 +82,10 @@ impl From<Error> for io::Error {
             Error::Io(io) => io,
             #[cfg(feature = "http2")]
             Error::H2(h2) => io::Error::new(io::ErrorKind::InvalidData, h2),
+            #[cfg(feature = "http3")]
+            Error::H3Connection(h3) => io::Error::new(io::ErrorKind::InvalidData, h3),
+            #[cfg(feature = "http3")]
+            Error::H3Stream(h3) => io::Error::new(io::ErrorKind::InvalidData, h3),
 
             Error::VersionNotSupported => io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -88,15 +117,21 @@ pub enum HttpConnection {
     /// We'll see how we move forward once HTTP/3 support lands.
     #[cfg(feature = "http2")]
     Http2(Box<h2::server::Connection<Encryption, bytes::Bytes>>),
+    /// A HTTP/3 connection over QUIC
+    ///
+    /// This is boxed for the same reason as [`HttpConnection::Http2`].
+    #[cfg(feature = "http3")]
+    Http3(Box<Http3Connection>),
 }
 
 /// A body of a [`Request`].
 ///
 /// The inner variables are streams. To get the bytes, use [`Body::read_to_bytes()`] when needed.
+/// To process the body as it's received, use it as an [`AsyncRead`] or a [`futures::Stream`] of chunks.
+/// Both stop with an error when the body is larger than allowed by [`Body::set_max_size`].
+/// Any trailers sent after the body are available from [`Body::trailers`].
 ///
 /// Also see [`FatRequest`].
-///
-/// `ToDo`: trailers
 #[derive(Debug)]
 pub enum Body {
     /// An empty body.
@@ -113,7 +148,10 @@ pub enum Body {
     Http1(response::Http1Body<Encryption>),
     /// A HTTP/2 body provided by [`h2`].
     #[cfg(feature = "http2")]
-    Http2(h2::RecvStream),
+    Http2(Http2Body),
+    /// A HTTP/3 body provided by [`h3`].
+    #[cfg(feature = "http3")]
+    Http3(Http3Body),
 }
 
 /// A pipe to send a [`Response`] through.
@@ -128,6 +166,9 @@ pub enum ResponsePipe {
     /// A HTTP/2 response pipe.
     #[cfg(feature = "http2")]
     Http2(h2::server::SendResponse<Bytes>),
+    /// A HTTP/3 response pipe.
+    #[cfg(feature = "http3")]
+    Http3(Http3Stream),
 }
 /// A pipe to send a body after the [`Response`] is sent by
 /// [`ResponsePipe::send_response`].
@@ -137,9 +178,20 @@ pub enum ResponsePipe {
 pub enum ResponseBodyPipe {
     /// HTTP/1 pipe
     Http1(Arc<Mutex<Encryption>>),
+    /// HTTP/1 pipe sending the body in chunks, used when the length isn't known.
+    ///
+    /// See [`ResponsePipe::ensure_version_and_length`].
+    Http1Chunked(Http1ChunkedPipe),
     /// HTTP/2 pipe
     #[cfg(feature = "http2")]
     Http2(h2::SendStream<Bytes>),
+    /// HTTP/3 pipe
+    #[cfg(feature = "http3")]
+    Http3(Http3Stream),
+    /// The body is ended by [`ResponseBodyPipe::close`] or [`ResponseBodyPipe::send_trailers`].
+    ///
+    /// Writing to it returns an error of kind [`io::ErrorKind::BrokenPipe`].
+    Ended,
 }
 /// A [`ResponsePipe`]-like for a pushed request-response pair.
 ///
@@ -170,7 +222,17 @@ impl HttpConnection {
             Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => {
                 Ok(Self::Http1(Arc::new(Mutex::new(stream))))
             }
-            #[cfg(feature = "http2")]
+            #[cfg(all(feature = "http2", feature = "websocket"))]
+            Version::HTTP_2 => match h2::server::Builder::new()
+                // Enables WebSockets over HTTP/2 (RFC 8441)
+                .enable_connect_protocol()
+                .handshake(stream)
+                .await
+            {
+                Ok(connection) => Ok(HttpConnection::Http2(Box::new(connection))),
+                Err(err) => Err(Error::H2(err)),
+            },
+            #[cfg(all(feature = "http2", not(feature = "websocket")))]
             Version::HTTP_2 => match h2::server::handshake(stream).await {
                 Ok(connection) => Ok(HttpConnection::Http2(Box::new(connection))),
                 Err(err) => Err(Error::H2(err)),
@@ -181,49 +243,145 @@ impl HttpConnection {
         }
     }
 
+    /// Creates a new HTTP/3 [`HttpConnection`] from a QUIC `connection`.
+    ///
+    /// # Errors
+    ///
+    /// Passes errors from [`h3::server::Connection::new`].
+    #[cfg(feature = "http3")]
+    pub async fn new_http3(connection: quinn::Connection) -> Result<Self, Error> {
+        let connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+        Ok(Self::Http3(Box::new(Http3Connection(connection))))
+    }
+
+    /// Drives the connection, so the [`Body`] of an accepted HTTP/2 request is received.
+    /// Run this while handling a request.
+    ///
+    /// This never returns; stop polling it when the request is handled.
+    pub async fn drive(&mut self) -> std::convert::Infallible {
+        match self {
+            #[cfg(feature = "http2")]
+            Self::Http2(connection) => {
+                // The error is returned when accepting the next request.
+                let _ = futures::future::poll_fn(|cx| connection.poll_closed(cx)).await;
+            }
+            // The other versions read the request bodies directly from the stream.
+            _ => {}
+        }
+        futures::future::pending().await
+    }
     /// Accept a single request.
     /// `default_host` will be used if the `Host` header is not
     /// present on a HTTP/1.x request.
     ///
+    /// If no request is started within `idle_timeout`, [`utils::parse::Error::Done`] is returned.
+    /// On HTTP/1, the head of the request then has to be received within `header_timeout`,
+    /// else [`utils::parse::Error::Timeout`] is returned.
+    ///
     /// # Errors
     ///
     /// Returns any errors emitted from [`h2::server::Connection::accept()`].
     pub async fn accept(
         &mut self,
         default_host: &[u8],
+        idle_timeout: Duration,
+        header_timeout: Duration,
     ) -> Result<(Request<Body>, ResponsePipe), Error> {
         match self {
             Self::Http1(stream) => {
                 let response = ResponsePipe::Http1(Arc::clone(stream));
-                request::parse_http_1(Arc::clone(stream), 16 * 1024, default_host)
-                    .await
-                    .map(|request| (request, response))
+                request::parse_http_1(
+                    Arc::clone(stream),
+                    16 * 1024,
+                    default_host,
+                    idle_timeout,
+                    header_timeout,
+                )
+                .await
+                .map(|request| (request, response))
             }
             #[cfg(feature = "http2")]
-            Self::Http2(connection) => match connection.accept().await {
-                Some(connection) => match connection {
-                    Ok((request, response)) => {
-                        Ok((request.map(Body::Http2), ResponsePipe::Http2(response)))
-                    }
+            Self::Http2(connection) => match timeout(idle_timeout, connection.accept()).await {
+                Ok(Some(connection)) => match connection {
+                    Ok((request, response)) => Ok((
+                        request.map(|body| Body::Http2(Http2Body::new(body))),
+                        ResponsePipe::Http2(response),
+                    )),
                     Err(err) => Err(Error::H2(err)),
                 },
-                None => Err(utils::parse::Error::Done.into()),
+                Ok(None) | Err(_) => Err(utils::parse::Error::Done.into()),
+            },
+            #[cfg(feature = "http3")]
+            Self::Http3(connection) => match timeout(idle_timeout, connection.accept()).await {
+                Ok(result) => result,
+                Err(_) => Err(utils::parse::Error::Done.into()),
             },
         }
     }
 }
 
+fn body_timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the request body wasn't received in time",
+    )
+}
+fn body_too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the request body is larger than allowed",
+    )
+}
+fn invalid_chunked_body(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+fn body_ended() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the response body is already ended",
+    )
+}
+
+/// Waiting for the lock of a HTTP/1 stream, kept between polls.
+pub(crate) type Locking<T> =
+    Pin<Box<dyn Future<Output = tokio::sync::OwnedMutexGuard<T>> + Send + Sync>>;
+/// Locks `mutex`, waiting in `locking` if it's held elsewhere.
+///
+/// The task is woken when the lock is released, instead of being polled again right away.
+pub(crate) fn poll_lock<T: Send + 'static>(
+    mutex: &Arc<Mutex<T>>,
+    locking: &mut Option<Locking<T>>,
+    cx: &mut Context<'_>,
+) -> Poll<tokio::sync::OwnedMutexGuard<T>> {
+    if locking.is_none() {
+        match Arc::clone(mutex).try_lock_owned() {
+            Ok(guard) => return Poll::Ready(guard),
+            Err(_) => *locking = Some(Box::pin(Arc::clone(mutex).lock_owned())),
+        }
+    }
+    // It's set above.
+    let guard = match locking.as_mut().unwrap().as_mut().poll(cx) {
+        Poll::Ready(guard) => guard,
+        Poll::Pending => return Poll::Pending,
+    };
+    *locking = None;
+    Poll::Ready(guard)
+}
+
 mod request {
     use super::{
-        async_bits::read, io, response, utils, Arc, AsyncRead, Body, Bytes, Context, Encryption,
-        Error, Mutex, Pin, Poll, ReadBuf, Request,
+        async_bits::read, io, response, utils, Arc, AsyncRead, Body, Bytes, Context, Duration,
+        Encryption, Error, HeaderMap, Mutex, Pin, Poll, ReadBuf, Request,
     };
+    use futures::Stream;
 
     #[inline]
     pub(crate) async fn parse_http_1(
         stream: Arc<Mutex<Encryption>>,
         max_len: usize,
         default_host: &[u8],
+        idle_timeout: Duration,
+        header_timeout: Duration,
     ) -> Result<Request<Body>, Error> {
         let scheme = match &*stream.lock().await {
             Encryption::Tcp(_) => "http",
@@ -232,13 +390,27 @@ mod request {
         };
         let lock = stream.lock().await;
 
-        let (head, bytes) = read::request(lock, max_len, default_host, scheme).await?;
-        let body = Body::Http1(response::Http1Body::new(
-            stream,
-            bytes,
-            utils::get_body_length_request(&head),
-        ));
-        Ok(head.map(|()| body))
+        let (mut head, bytes) = read::request(
+            lock,
+            max_len,
+            default_host,
+            scheme,
+            idle_timeout,
+            header_timeout,
+        )
+        .await?;
+        let body = if utils::is_chunked(head.headers()) {
+            // The chunks decide the length.
+            utils::remove_all_headers(head.headers_mut(), "content-length");
+            response::Http1Body::new_chunked(stream, bytes)
+        } else if head.headers().contains_key("transfer-encoding") {
+            // We can't know where the body ends.
+            return Err(utils::parse::Error::Syntax.into());
+        } else {
+            let len = utils::get_body_length_request(&head);
+            response::Http1Body::new(stream, bytes, len)
+        };
+        Ok(head.map(|()| Body::Http1(body)))
     }
 
     impl Body {
@@ -248,18 +420,112 @@ mod request {
         ///
         /// Passes any errors returned from the inner reader.
         /// See [`super::Http1Body::read_to_bytes()`] and [`h2::RecvStream::poll_data()`] for more info.
+        ///
+        /// If the body isn't received within the timeout set by [`Self::set_timeout`],
+        /// an error of kind [`io::ErrorKind::TimedOut`] is returned.
+        /// If the body is larger than [`Self::set_max_size`],
+        /// an error of kind [`io::ErrorKind::InvalidData`] is returned.
         #[inline]
         pub async fn read_to_bytes(&mut self) -> io::Result<Bytes> {
             match self {
                 Self::Empty => Ok(Bytes::new()),
                 Self::Http1(h1) => h1.read_to_bytes().await,
                 #[cfg(feature = "http2")]
-                Self::Http2(h2) => futures::future::poll_fn(|cx| h2.poll_data(cx))
-                    .await
-                    .unwrap_or_else(|| Ok(Bytes::new()))
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                Self::Http2(h2) => h2.read_to_bytes().await,
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.read_to_bytes().await,
             }
         }
+        /// Sets the time [`Self::read_to_bytes`] may take to receive the body.
+        ///
+        /// Kvarn sets this to [`crate::host::Options::get_body_timeout`] before the request is handled.
+        #[inline]
+        pub fn set_timeout(&mut self, timeout: Duration) {
+            match self {
+                Self::Empty => {}
+                Self::Http1(h1) => h1.set_timeout(timeout),
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => h2.set_timeout(timeout),
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.set_timeout(timeout),
+            }
+        }
+        /// Sets the maximum size of the body, in bytes.
+        /// Reading more returns an error of kind [`io::ErrorKind::InvalidData`].
+        ///
+        /// Kvarn sets this to [`crate::host::Options::get_max_body_size`] before the request is handled.
+        #[inline]
+        pub fn set_max_size(&mut self, max_size: u64) {
+            match self {
+                Self::Empty => {}
+                Self::Http1(h1) => h1.set_max_size(max_size),
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => h2.set_max_size(max_size),
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.set_max_size(max_size),
+            }
+        }
+        /// If more than [`Self::set_max_size`] bytes were sent, which means reading failed.
+        ///
+        /// Kvarn responds with [`StatusCode::PAYLOAD_TOO_LARGE`] when an extension read such a body.
+        #[inline]
+        pub(crate) fn is_too_large(&self) -> bool {
+            match self {
+                Self::Empty => false,
+                Self::Http1(h1) => h1.is_too_large(),
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => h2.is_too_large(),
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.is_too_large(),
+            }
+        }
+        /// If all of the body has been read from the connection.
+        ///
+        /// Only a HTTP/1 body is read from the connection itself;
+        /// unless it's fully read, the next request can't be parsed.
+        /// The other versions always return `true`.
+        #[inline]
+        pub(crate) fn is_fully_read(&self) -> bool {
+            match self {
+                Self::Http1(h1) => h1.is_fully_read(),
+                _ => true,
+            }
+        }
+        /// Gets the trailers sent after the body, if any.
+        ///
+        /// The trailers are received after the body, so read it first.
+        /// On HTTP/1, only bodies sent with `transfer-encoding: chunked` have trailers.
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from receiving the trailers.
+        /// See [`super::Http2Body::trailers`] and [`super::Http3Body::trailers`].
+        pub async fn trailers(&mut self) -> io::Result<Option<HeaderMap>> {
+            match self {
+                Self::Empty => Ok(None),
+                Self::Http1(h1) => Ok(h1.trailers().cloned()),
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => h2.trailers().await,
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.trailers().await,
+            }
+        }
+    }
+
+    impl Stream for Body {
+        type Item = io::Result<Bytes>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let frame = match self.get_mut() {
+                Self::Empty => return Poll::Ready(None),
+                Self::Http1(h1) => h1.poll_frame(cx),
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => h2.poll_frame(cx),
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.poll_frame(cx),
+            };
+            frame.map(Result::transpose)
+        }
     }
 
     impl AsyncRead for Body {
@@ -271,23 +537,9 @@ mod request {
             match self.get_mut() {
                 Self::Http1(s) => unsafe { Pin::new_unchecked(s).poll_read(cx, buf) },
                 #[cfg(feature = "http2")]
-                Self::Http2(tls) => {
-                    let data = match tls.poll_data(cx) {
-                        Poll::Ready(data) => data,
-                        Poll::Pending => return Poll::Pending,
-                    };
-                    match data {
-                        Some(d) => match d {
-                            Ok(data) => buf.put_slice(&data),
-                            Err(err) => {
-                                let err = io::Error::new(io::ErrorKind::InvalidData, err);
-                                return Poll::Ready(Err(err));
-                            }
-                        },
-                        None => return Poll::Ready(Ok(())),
-                    }
-                    Poll::Ready(Ok(()))
-                }
+                Self::Http2(h2) => Pin::new(h2).poll_read(cx, buf),
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => Pin::new(h3).poll_read(cx, buf),
                 Self::Empty => Poll::Ready(Ok(())),
             }
         }
@@ -300,15 +552,21 @@ mod response {
     /// A HTTP/1 body.
     ///
     /// The reason of this type and the inner buffer is described in [`super::Body::Http1`]
+    ///
+    /// If the request is sent with `transfer-encoding: chunked`, the chunks are decoded.
     #[must_use]
     pub struct Http1Body<R: AsyncRead + Unpin> {
         reader: Arc<Mutex<R>>,
+        locking: Option<Locking<R>>,
         bytes: Bytes,
         offset: usize,
 
         content_length: usize,
+        chunked: Option<Chunked>,
+        timeout: Duration,
+        max_size: u64,
     }
-    impl<R: AsyncRead + Unpin> Http1Body<R> {
+    impl<R: AsyncRead + Unpin + Send + 'static> Http1Body<R> {
         /// Creates a new body.
         ///
         /// `content_length` should be the total length of the body, found in the [`Request::headers`].
@@ -316,77 +574,653 @@ mod response {
         pub fn new(reader: Arc<Mutex<R>>, bytes: Bytes, content_length: usize) -> Self {
             Self {
                 reader,
+                locking: None,
                 bytes,
                 offset: 0,
 
                 content_length,
+                chunked: None,
+                timeout: host::Options::DEFAULT_BODY_TIMEOUT,
+                max_size: host::Options::DEFAULT_MAX_BODY_SIZE,
             }
         }
+        /// Creates a new body sent with `transfer-encoding: chunked`.
+        ///
+        /// The chunks are decoded when reading from the body.
+        #[inline]
+        pub fn new_chunked(reader: Arc<Mutex<R>>, bytes: Bytes) -> Self {
+            let mut body = Self::new(reader, bytes, 0);
+            body.chunked = Some(Chunked::new());
+            body
+        }
         /// Reads all bytes from `self` to a [`Bytes`].
         ///
         /// # Errors
         ///
         /// Returns any errors from the underlying reader.
+        /// If the body isn't received within the timeout, an error of kind
+        /// [`io::ErrorKind::TimedOut`] is returned.
+        /// If the `content-length` (or the size of the chunks) is larger than the maximum size,
+        /// an error of kind [`io::ErrorKind::InvalidData`] is returned.
+        /// Invalid chunks also return an error of kind [`io::ErrorKind::InvalidData`].
         #[inline]
         pub async fn read_to_bytes(&mut self) -> io::Result<Bytes> {
-            let mut buffer = BytesMut::with_capacity(self.bytes.len() + 512);
-            buffer.extend(&self.bytes);
-            let len = self.content_length;
-            if let Ok(result) = timeout(
-                std::time::Duration::from_millis(250),
-                async_bits::read_to_end_or_max(&mut buffer, &mut *self, len),
+            self.check_size()?;
+            let remaining = self.content_length.saturating_sub(self.offset);
+            // The size is checked above, so this is at most the maximum size.
+            let mut buffer = BytesMut::with_capacity(remaining + 512);
+            match timeout(
+                self.timeout,
+                async_bits::read_to_end(&mut buffer, &mut *self),
             )
             .await
             {
-                result?
+                Ok(result) => result?,
+                Err(_) => return Err(body_timed_out()),
             }
             Ok(buffer.freeze())
         }
+        /// Sets the time [`Self::read_to_bytes`] may take.
+        #[inline]
+        pub fn set_timeout(&mut self, timeout: Duration) {
+            self.timeout = timeout;
+        }
+        /// Sets the maximum size of the body, in bytes.
+        #[inline]
+        pub fn set_max_size(&mut self, max_size: u64) {
+            self.max_size = max_size;
+        }
+        /// Gets the trailers sent after a chunked body.
+        ///
+        /// Returns [`None`] if the body isn't chunked or not yet fully read.
+        #[inline]
+        #[must_use]
+        pub fn trailers(&self) -> Option<&HeaderMap> {
+            self.chunked
+                .as_ref()
+                .filter(|chunked| chunked.state == ChunkState::Done)
+                .map(|chunked| &chunked.trailers)
+        }
+        pub(super) fn is_too_large(&self) -> bool {
+            self.content_length as u64 > self.max_size
+                || self
+                    .chunked
+                    .as_ref()
+                    .map_or(false, |chunked| chunked.received > self.max_size)
+        }
+        pub(super) fn is_fully_read(&self) -> bool {
+            match &self.chunked {
+                Some(chunked) => chunked.state == ChunkState::Done,
+                None => self.offset >= self.content_length,
+            }
+        }
+        fn check_size(&self) -> io::Result<()> {
+            if self.content_length as u64 > self.max_size {
+                Err(body_too_large())
+            } else {
+                Ok(())
+            }
+        }
+        pub(super) fn poll_frame(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<Option<Bytes>>> {
+            self.check_size()?;
+            // Don't copy the bytes read with the request head.
+            if self.chunked.is_none()
+                && self.offset < self.bytes.len()
+                && self.offset < self.content_length
+            {
+                let end = self.bytes.len().min(self.content_length);
+                let frame = self.bytes.slice(self.offset..end);
+                self.offset = end;
+                return Poll::Ready(Ok(Some(frame)));
+            }
+            let remaining = if self.chunked.is_some() {
+                16 * 1024
+            } else {
+                self.content_length.saturating_sub(self.offset)
+            };
+            let mut buffer = vec![0; remaining.min(16 * 1024)];
+            if buffer.is_empty() {
+                return Poll::Ready(Ok(None));
+            }
+            let mut buf = ReadBuf::new(&mut buffer);
+            match Pin::new(&mut *self).poll_read(cx, &mut buf) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Ready(Ok(())) => {
+                    let len = buf.filled().len();
+                    if len == 0 {
+                        return Poll::Ready(Ok(None));
+                    }
+                    buffer.truncate(len);
+                    Poll::Ready(Ok(Some(buffer.into())))
+                }
+            }
+        }
+        /// Gets the inner reader and the bytes read with the request head not yet consumed.
+        ///
+        /// Used when the connection is upgraded to another protocol.
+        pub(crate) fn into_inner(self) -> (Arc<Mutex<R>>, Bytes) {
+            let bytes = self.bytes.slice(self.offset.min(self.bytes.len())..);
+            (self.reader, bytes)
+        }
     }
     impl<R: AsyncRead + Unpin + Debug> Debug for Http1Body<R> {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
             f.debug_struct("Http1Body")
                 .field("reader", &self.reader)
+                .field("locking", &self.locking.is_some())
                 .field("buffer", &"[internal buffer]".as_clean())
                 .field("offset", &self.offset)
                 .field("content_length", &self.content_length)
+                .field("chunked", &self.chunked)
+                .field("timeout", &self.timeout)
+                .field("max_size", &self.max_size)
                 .finish()
         }
     }
-    impl<R: AsyncRead + Unpin> AsyncRead for Http1Body<R> {
+    impl<R: AsyncRead + Unpin + Send + 'static> AsyncRead for Http1Body<R> {
         fn poll_read(
             mut self: Pin<&mut Self>,
             cx: &mut Context<'_>,
             buf: &mut ReadBuf<'_>,
         ) -> Poll<io::Result<()>> {
-            if self.offset < self.bytes.len() {
-                let remaining = buf.remaining();
-                if self.bytes.len() - self.offset > remaining {
-                    buf.put_slice(&self.bytes[self.offset..self.offset + remaining]);
-                    self.offset += remaining;
-                } else {
-                    buf.put_slice(&self.bytes[self.offset..]);
-                    self.offset = self.bytes.len();
-                }
-                Poll::Pending
+            self.check_size()?;
+            let me = &mut *self;
+            if let Some(chunked) = &mut me.chunked {
+                let mut source = Source {
+                    reader: &me.reader,
+                    locking: &mut me.locking,
+                    bytes: &mut me.bytes,
+                    offset: &mut me.offset,
+                };
+                return chunked.poll_read(&mut source, me.max_size, cx, buf);
+            }
+            // Never read past the body; the next request may follow.
+            let remaining = me.content_length.saturating_sub(me.offset);
+            if remaining == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if me.offset < me.bytes.len() {
+                let len = (me.bytes.len() - me.offset)
+                    .min(remaining)
+                    .min(buf.remaining());
+                buf.put_slice(&me.bytes[me.offset..me.offset + len]);
+                me.offset += len;
+                Poll::Ready(Ok(()))
             } else {
-                let mut reader = match self.reader.try_lock() {
-                    Err(_) => return Poll::Pending,
-                    Ok(r) => r,
+                let mut reader = match poll_lock(&me.reader, &mut me.locking, cx) {
+                    Poll::Ready(reader) => reader,
+                    Poll::Pending => return Poll::Pending,
                 };
-                let size = buf.filled().len();
-                let result = unsafe { Pin::new_unchecked(&mut *reader).poll_read(cx, buf) };
+                let mut limited = buf.take(remaining);
+                let result = Pin::new(&mut *reader).poll_read(cx, &mut limited);
                 drop(reader);
-                let difference = buf.filled().len() - size;
-                self.offset += difference;
-                if self.offset == self.content_length {
-                    return Poll::Ready(Ok(()));
-                }
+                let read = limited.filled().len();
+                // SAFETY: `limited` initialized and filled `read` bytes of the unfilled part of `buf`.
+                unsafe { buf.assume_init(read) };
+                buf.advance(read);
+                me.offset += read;
                 result
             }
         }
     }
 
+    /// The number of bytes [`Source::poll_fill`] reads at most.
+    const FILL_SIZE: usize = 4 * 1024;
+    /// The bytes of a HTTP/1 connection; first the buffered ones, then the reader.
+    ///
+    /// The buffer initially contains the bytes read with the request head.
+    struct Source<'a, R> {
+        reader: &'a Arc<Mutex<R>>,
+        locking: &'a mut Option<Locking<R>>,
+        bytes: &'a mut Bytes,
+        offset: &'a mut usize,
+    }
+    impl<R: AsyncRead + Unpin + Send + 'static> Source<'_, R> {
+        fn poll_read(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let buffered = self.buffered();
+            if !buffered.is_empty() {
+                let len = buffered.len().min(buf.remaining());
+                buf.put_slice(&buffered[..len]);
+                self.consume(len);
+                return Poll::Ready(Ok(()));
+            }
+            let mut reader = match poll_lock(self.reader, self.locking, cx) {
+                Poll::Ready(reader) => reader,
+                Poll::Pending => return Poll::Pending,
+            };
+            Pin::new(&mut *reader).poll_read(cx, buf)
+        }
+        /// The buffered bytes not yet consumed.
+        fn buffered(&self) -> &[u8] {
+            self.bytes.get(*self.offset..).unwrap_or_default()
+        }
+        fn consume(&mut self, len: usize) {
+            *self.offset += len;
+        }
+        /// Replaces the (consumed) buffer with bytes from the reader.
+        ///
+        /// Returns the number of bytes read; `0` at the end of the stream.
+        fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+            let mut buffer = [0; FILL_SIZE];
+            let mut buf = ReadBuf::new(&mut buffer);
+            match self.poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let read = buf.filled().len();
+            *self.bytes = Bytes::copy_from_slice(buf.filled());
+            *self.offset = 0;
+            Poll::Ready(Ok(read))
+        }
+    }
+
+    /// What's read next from a chunked body.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ChunkState {
+        /// The line with the size of the next chunk.
+        Size,
+        /// The data of a chunk, of which this many bytes remain.
+        Data(u64),
+        /// The line break after the data of a chunk.
+        DataEnd,
+        /// The trailers after the last chunk.
+        Trailers,
+        /// The body is fully read.
+        Done,
+    }
+    /// Decodes a body sent with `transfer-encoding: chunked`.
+    ///
+    /// Lines are read through the buffer of the [`Http1Body`].
+    /// Bytes read after the body are kept there, see [`Http1Body::into_inner`].
+    #[derive(Debug)]
+    struct Chunked {
+        state: ChunkState,
+        line: Vec<u8>,
+        received: u64,
+        trailer_bytes: BytesMut,
+        trailers: HeaderMap,
+    }
+    impl Chunked {
+        /// The maximum length of a line with the size of a chunk.
+        const MAX_SIZE_LINE: usize = 1024;
+        /// The maximum length of the trailers.
+        const MAX_TRAILERS: usize = 16 * 1024;
+        /// The maximum number of hexadecimal digits in the size of a chunk; it fits a `u64`.
+        const MAX_SIZE_DIGITS: usize = 16;
+
+        fn new() -> Self {
+            Self {
+                state: ChunkState::Size,
+                line: Vec::new(),
+                received: 0,
+                trailer_bytes: BytesMut::new(),
+                trailers: HeaderMap::new(),
+            }
+        }
+        fn poll_read<R: AsyncRead + Unpin + Send + 'static>(
+            &mut self,
+            source: &mut Source<'_, R>,
+            max_size: u64,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                match self.state {
+                    ChunkState::Done => return Poll::Ready(Ok(())),
+                    ChunkState::Data(remaining) => {
+                        if buf.remaining() == 0 {
+                            return Poll::Ready(Ok(()));
+                        }
+                        let len = usize::try_from(remaining)
+                            .unwrap_or(usize::MAX)
+                            .min(buf.remaining());
+                        let mut limited = buf.take(len);
+                        match source.poll_read(cx, &mut limited) {
+                            Poll::Ready(Ok(())) => {}
+                            poll => return poll,
+                        }
+                        let read = limited.filled().len();
+                        if read == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        // SAFETY: `limited` initialized and filled `read` bytes of the unfilled part of `buf`.
+                        unsafe { buf.assume_init(read) };
+                        buf.advance(read);
+                        let remaining = remaining - read as u64;
+                        self.state = if remaining == 0 {
+                            ChunkState::DataEnd
+                        } else {
+                            ChunkState::Data(remaining)
+                        };
+                        return Poll::Ready(Ok(()));
+                    }
+                    ChunkState::Size | ChunkState::DataEnd | ChunkState::Trailers => {
+                        let buffered = source.buffered();
+                        if buffered.is_empty() {
+                            match source.poll_fill(cx) {
+                                Poll::Ready(Ok(0)) => {
+                                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                                }
+                                Poll::Ready(Ok(_)) => continue,
+                                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                                Poll::Pending => return Poll::Pending,
+                            }
+                        }
+                        let end = buffered.iter().position(|byte| *byte == b'\n');
+                        let line = &buffered[..end.unwrap_or(buffered.len())];
+                        if self.line.len() + line.len() > Self::MAX_SIZE_LINE {
+                            return Poll::Ready(Err(invalid_chunked_body(
+                                "line in chunked body is too long",
+                            )));
+                        }
+                        self.line.extend_from_slice(line);
+                        let len = line.len();
+                        if end.is_some() {
+                            source.consume(len + 1);
+                            self.end_line(max_size)?;
+                        } else {
+                            source.consume(len);
+                        }
+                    }
+                }
+            }
+        }
+        fn end_line(&mut self, max_size: u64) -> io::Result<()> {
+            let line = self.line.strip_suffix(b"\r").ok_or_else(|| {
+                invalid_chunked_body("line in chunked body doesn't end with CRLF")
+            })?;
+            self.state = match self.state {
+                ChunkState::Size => {
+                    // Chunk extensions are ignored.
+                    let size = line.split(|byte| *byte == b';').next().unwrap_or(line);
+                    if size.is_empty()
+                        || size.len() > Self::MAX_SIZE_DIGITS
+                        || !size.iter().all(u8::is_ascii_hexdigit)
+                    {
+                        return Err(invalid_chunked_body("invalid chunk size"));
+                    }
+                    // We checked it's 1 to 16 hexadecimal digits.
+                    let size = u64::from_str_radix(std::str::from_utf8(size).unwrap(), 16).unwrap();
+                    self.received = self.received.saturating_add(size);
+                    if self.received > max_size {
+                        return Err(body_too_large());
+                    }
+                    if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size)
+                    }
+                }
+                ChunkState::DataEnd => {
+                    if !line.is_empty() {
+                        return Err(invalid_chunked_body("chunk is longer than its size"));
+                    }
+                    ChunkState::Size
+                }
+                ChunkState::Trailers if line.is_empty() => {
+                    if !self.trailer_bytes.is_empty() {
+                        self.trailer_bytes.extend_from_slice(b"\r\n");
+                        let bytes = std::mem::take(&mut self.trailer_bytes).freeze();
+                        let (trailers, _) = utils::parse::headers(&bytes)?;
+                        self.trailers = trailers;
+                    }
+                    ChunkState::Done
+                }
+                ChunkState::Trailers => {
+                    if self.trailer_bytes.len() + line.len() > Self::MAX_TRAILERS {
+                        return Err(invalid_chunked_body("trailers are too long"));
+                    }
+                    self.trailer_bytes.extend_from_slice(line);
+                    self.trailer_bytes.extend_from_slice(b"\r\n");
+                    ChunkState::Trailers
+                }
+                state @ (ChunkState::Data(_) | ChunkState::Done) => state,
+            };
+            self.line.clear();
+            Ok(())
+        }
+    }
+
+    /// A HTTP/2 body provided by [`h2`].
+    ///
+    /// The data is received in frames, of which the rest is kept if
+    /// the buffer of [`AsyncRead::poll_read`] is too small.
+    #[cfg(feature = "http2")]
+    #[must_use]
+    pub struct Http2Body {
+        stream: h2::RecvStream,
+        buffer: Bytes,
+        timeout: Duration,
+        max_size: u64,
+        received: u64,
+    }
+    #[cfg(feature = "http2")]
+    impl Http2Body {
+        pub(crate) fn new(stream: h2::RecvStream) -> Self {
+            Self {
+                stream,
+                buffer: Bytes::new(),
+                timeout: host::Options::DEFAULT_BODY_TIMEOUT,
+                max_size: host::Options::DEFAULT_MAX_BODY_SIZE,
+                received: 0,
+            }
+        }
+        pub(super) fn is_too_large(&self) -> bool {
+            self.received > self.max_size
+        }
+        pub(super) fn poll_frame(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<Option<Bytes>>> {
+            if !self.buffer.is_empty() {
+                return Poll::Ready(Ok(Some(std::mem::take(&mut self.buffer))));
+            }
+            match self.stream.poll_data(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(None) => Poll::Ready(Ok(None)),
+                Poll::Ready(Some(Ok(data))) => {
+                    self.received += data.len() as u64;
+                    if self.received > self.max_size {
+                        return Poll::Ready(Err(body_too_large()));
+                    }
+                    // Let the client send more data.
+                    let _ = self.stream.flow_control().release_capacity(data.len());
+                    Poll::Ready(Ok(Some(data)))
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)))
+                }
+            }
+        }
+        /// Reads all bytes from the stream to a [`Bytes`].
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from [`h2::RecvStream::poll_data`].
+        /// If the body isn't received within the timeout, an error of kind
+        /// [`io::ErrorKind::TimedOut`] is returned.
+        /// If the body is larger than the maximum size,
+        /// an error of kind [`io::ErrorKind::InvalidData`] is returned.
+        pub async fn read_to_bytes(&mut self) -> io::Result<Bytes> {
+            let mut bytes = BytesMut::new();
+            let duration = self.timeout;
+            let read = async {
+                while let Some(frame) = futures::future::poll_fn(|cx| self.poll_frame(cx)).await? {
+                    bytes.extend_from_slice(&frame);
+                }
+                io::Result::Ok(())
+            };
+            match timeout(duration, read).await {
+                Ok(result) => result?,
+                Err(_) => return Err(body_timed_out()),
+            }
+            Ok(bytes.freeze())
+        }
+        /// Sets the time [`Self::read_to_bytes`] may take.
+        #[inline]
+        pub fn set_timeout(&mut self, timeout: Duration) {
+            self.timeout = timeout;
+        }
+        /// Sets the maximum size of the body, in bytes.
+        #[inline]
+        pub fn set_max_size(&mut self, max_size: u64) {
+            self.max_size = max_size;
+        }
+        /// Receives the trailers sent after the body, if any.
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from [`h2::RecvStream::trailers`].
+        /// If the trailers aren't received within the timeout, an error of kind
+        /// [`io::ErrorKind::TimedOut`] is returned.
+        pub async fn trailers(&mut self) -> io::Result<Option<HeaderMap>> {
+            match timeout(self.timeout, self.stream.trailers()).await {
+                Ok(Ok(trailers)) => Ok(trailers),
+                Ok(Err(err)) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                Err(_) => Err(body_timed_out()),
+            }
+        }
+        /// Gets the inner stream and the data received but not yet consumed.
+        ///
+        /// Used when the stream is upgraded to another protocol.
+        pub(crate) fn into_inner(self) -> (h2::RecvStream, Bytes) {
+            (self.stream, self.buffer)
+        }
+    }
+    #[cfg(feature = "http2")]
+    impl AsyncRead for Http2Body {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut frame = match self.poll_frame(cx) {
+                Poll::Ready(Ok(Some(frame))) => frame,
+                // End of stream
+                Poll::Ready(Ok(None)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let len = frame.len().min(buf.remaining());
+            buf.put_slice(&frame.split_to(len));
+            self.buffer = frame;
+            Poll::Ready(Ok(()))
+        }
+    }
+    #[cfg(feature = "http2")]
+    impl Debug for Http2Body {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Http2Body")
+                .field("stream", &self.stream)
+                .field("buffer", &self.buffer.len())
+                .field("timeout", &self.timeout)
+                .field("max_size", &self.max_size)
+                .finish()
+        }
+    }
+
+    /// A HTTP/1 response body sent with `transfer-encoding: chunked`.
+    ///
+    /// Every write is sent as a chunk. The body is ended by
+    /// [`ResponseBodyPipe::close`] or [`ResponseBodyPipe::send_trailers`].
+    #[must_use]
+    pub struct Http1ChunkedPipe {
+        stream: Arc<Mutex<Encryption>>,
+        locking: Option<Locking<Encryption>>,
+        /// A chunk written using [`AsyncWrite`] which isn't yet written to the stream.
+        pending: Bytes,
+    }
+    impl Http1ChunkedPipe {
+        fn new(stream: Arc<Mutex<Encryption>>) -> Self {
+            Self {
+                stream,
+                locking: None,
+                pending: Bytes::new(),
+            }
+        }
+        fn chunk(data: &[u8]) -> Bytes {
+            let size = format!("{:x}\r\n", data.len());
+            let mut chunk = BytesMut::with_capacity(size.len() + data.len() + 2);
+            chunk.extend_from_slice(size.as_bytes());
+            chunk.extend_from_slice(data);
+            chunk.extend_from_slice(b"\r\n");
+            chunk.freeze()
+        }
+        async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+            futures::future::poll_fn(|cx| self.poll_pending(cx)).await?;
+            // An empty chunk would end the body.
+            if data.is_empty() {
+                return Ok(());
+            }
+            self.stream.lock().await.write_all(&Self::chunk(data)).await
+        }
+        async fn finish(&mut self, trailers: &HeaderMap) -> io::Result<()> {
+            futures::future::poll_fn(|cx| self.poll_pending(cx)).await?;
+            let mut stream = self.stream.lock().await;
+            let mut writer = tokio::io::BufWriter::with_capacity(512, &mut *stream);
+            async_bits::write::last_chunk(trailers, &mut writer).await?;
+            writer.flush().await
+        }
+        /// Writes the [`Self::pending`] chunk.
+        fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while !self.pending.is_empty() {
+                let mut stream = match poll_lock(&self.stream, &mut self.locking, cx) {
+                    Poll::Ready(stream) => stream,
+                    Poll::Pending => return Poll::Pending,
+                };
+                match Pin::new(&mut *stream).poll_write(cx, &self.pending) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    Poll::Ready(Ok(written)) => {
+                        let _ = self.pending.split_to(written);
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+        fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            match self.poll_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                poll => return poll.map(|result| result.map(|()| 0)),
+            }
+            if !buf.is_empty() {
+                self.pending = Self::chunk(buf);
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.poll_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                poll => return poll,
+            }
+            match poll_lock(&self.stream, &mut self.locking, cx) {
+                Poll::Ready(mut stream) => Pin::new(&mut *stream).poll_flush(cx),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+    impl Debug for Http1ChunkedPipe {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Http1ChunkedPipe")
+                .field("stream", &self.stream)
+                .field("locking", &self.locking.is_some())
+                .field("pending", &self.pending.len())
+                .finish()
+        }
+    }
+
     impl ResponsePipe {
         /// You must ensure the [`Response::version()`] is correct before calling this function.
         /// It can be guaranteed by first calling [`Self::ensure_version_and_length()`].
@@ -406,18 +1240,13 @@ mod response {
             match self {
                 Self::Http1(s) => {
                     let mut writer = s.lock().await;
-                    match response
-                        .headers()
-                        .get("connection")
-                        .map(HeaderValue::to_str)
-                        .and_then(Result::ok)
-                    {
-                        Some("close") | None => utils::replace_header_static(
+                    // Kvarn closes the connection after responses with `connection: close`.
+                    if !response.headers().contains_key("connection") {
+                        utils::replace_header_static(
                             response.headers_mut(),
                             "connection",
                             "keep-alive",
-                        ),
-                        _ => {}
+                        );
                     }
                     let mut writer = tokio::io::BufWriter::with_capacity(512, &mut *writer);
                     async_bits::write::response(&response, b"", &mut writer)
@@ -426,13 +1255,29 @@ mod response {
                     writer.flush().await.map_err(Error::Io)?;
                     writer.into_inner();
 
-                    Ok(ResponseBodyPipe::Http1(Arc::clone(s)))
+                    if utils::is_chunked(response.headers())
+                        && !response.status().is_informational()
+                    {
+                        Ok(ResponseBodyPipe::Http1Chunked(Http1ChunkedPipe::new(
+                            Arc::clone(s),
+                        )))
+                    } else {
+                        Ok(ResponseBodyPipe::Http1(Arc::clone(s)))
+                    }
                 }
                 #[cfg(feature = "http2")]
                 Self::Http2(s) => match s.send_response(response, end_of_stream) {
                     Err(err) => Err(Error::H2(err)),
                     Ok(pipe) => Ok(ResponseBodyPipe::Http2(pipe)),
                 },
+                #[cfg(feature = "http3")]
+                Self::Http3(s) => {
+                    s.send_response(response).await?;
+                    if end_of_stream {
+                        s.finish().await?;
+                    }
+                    Ok(ResponseBodyPipe::Http3(s.clone()))
+                }
             }
         }
         /// Pushes `request` to client.
@@ -440,6 +1285,7 @@ mod response {
         /// # Errors
         ///
         /// If you try to push if `self` is [`ResponsePipe::Http1`], an [`Error::PushOnHttp1`] is returned.
+        /// Pushing isn't supported on HTTP/3 either; the same error is returned for [`ResponsePipe::Http3`].
         /// Returns errors from [`h2::server::SendResponse::push_request()`].
         #[inline]
         pub fn push_request(
@@ -448,6 +1294,8 @@ mod response {
         ) -> Result<PushedResponsePipe, Error> {
             match self {
                 Self::Http1(_) => Err(Error::PushOnHttp1),
+                #[cfg(feature = "http3")]
+                Self::Http3(_) => Err(Error::PushOnHttp1),
                 #[cfg(feature = "http2")]
                 Self::Http2(h2) => match h2.push_request(request) {
                     Ok(pipe) => Ok(PushedResponsePipe::Http2(pipe)),
@@ -456,18 +1304,44 @@ mod response {
             }
         }
         /// Ensures the version and length of the `response` using the variant of [`ResponsePipe`].
+        ///
+        /// Informational (`1xx`) responses never get a `content-length`.
+        ///
+        /// If the `response` has a `transfer-encoding: chunked` header
+        /// (see [`utils::is_chunked`]), the length isn't known.
+        /// On HTTP/1.1, the body is then sent in chunks by the [`ResponseBodyPipe::Http1Chunked`]
+        /// returned from [`Self::send_response`]. Other versions remove the header.
         #[inline]
         pub fn ensure_version_and_length<T>(&self, response: &mut Response<T>, len: usize) {
             match self {
                 Self::Http1(_) => match response.version() {
                     Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => {
-                        utils::set_content_length(response.headers_mut(), len);
+                        if !response.status().is_informational() {
+                            let http_11 = response.version() == Version::HTTP_11;
+                            let headers = response.headers_mut();
+                            if http_11 && utils::is_chunked(headers) {
+                                utils::remove_all_headers(headers, "content-length");
+                            } else {
+                                utils::remove_all_headers(headers, "transfer-encoding");
+                                utils::set_content_length(headers, len);
+                            }
+                        }
                     }
 
                     _ => *response.version_mut() = Version::HTTP_11,
                 },
                 #[cfg(feature = "http2")]
-                Self::Http2(_) => *response.version_mut() = Version::HTTP_2,
+                Self::Http2(_) => {
+                    *response.version_mut() = Version::HTTP_2;
+                    // Connection-specific headers aren't allowed in HTTP/2.
+                    utils::remove_all_headers(response.headers_mut(), "transfer-encoding");
+                }
+                #[cfg(feature = "http3")]
+                Self::Http3(_) => {
+                    *response.version_mut() = Version::HTTP_3;
+                    // Nor are they in HTTP/3.
+                    utils::remove_all_headers(response.headers_mut(), "transfer-encoding");
+                }
             }
         }
     }
@@ -539,13 +1413,104 @@ mod response {
                         lock.flush().await?;
                     }
                 }
+                Self::Http1Chunked(h1) => {
+                    h1.send(&data).await?;
+                    if end_of_stream {
+                        return self.close().await;
+                    }
+                }
+                #[cfg(feature = "http2")]
+                Self::Http2(h2) => {
+                    h2.send_data(data, end_of_stream)?;
+                    if end_of_stream {
+                        *self = Self::Ended;
+                    }
+                }
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => {
+                    h3.send_data(data).await?;
+                    if end_of_stream {
+                        return self.close().await;
+                    }
+                }
+                Self::Ended => return Err(body_ended().into()),
+            }
+            Ok(())
+        }
+        /// Sends the rest of `file` as the body, in chunks.
+        ///
+        /// On HTTP/2, this waits for the client to accept more data before reading
+        /// the next chunk, so the file is never fully read into memory.
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from reading the file and writing to the stream.
+        /// See [`crate::read::FileBody::next_chunk()`] and [`Self::send`].
+        pub async fn send_file(&mut self, file: &mut crate::read::FileBody) -> Result<(), Error> {
+            while let Some(chunk) = file.next_chunk().await? {
+                match self {
+                    Self::Http1(h1) => h1.lock().await.write_all(&chunk).await?,
+                    Self::Http1Chunked(h1) => h1.send(&chunk).await?,
+                    #[cfg(feature = "http2")]
+                    Self::Http2(h2) => {
+                        let mut chunk = chunk;
+                        while !chunk.is_empty() {
+                            h2.reserve_capacity(chunk.len());
+                            let capacity = match futures::future::poll_fn(|cx| h2.poll_capacity(cx))
+                                .await
+                            {
+                                Some(capacity) => capacity?,
+                                None => {
+                                    return Err(io::Error::from(io::ErrorKind::BrokenPipe).into())
+                                }
+                            };
+                            if capacity == 0 {
+                                continue;
+                            }
+                            let data = chunk.split_to(capacity.min(chunk.len()));
+                            h2.send_data(data, false)?;
+                        }
+                    }
+                    // `h3` waits for the client to accept more data.
+                    #[cfg(feature = "http3")]
+                    Self::Http3(h3) => h3.send_data(chunk).await?,
+                    Self::Ended => return Err(body_ended().into()),
+                }
+            }
+            Ok(())
+        }
+        /// Sends `trailers` after the body and ends it.
+        ///
+        /// On HTTP/1, trailers can only be sent if the body is sent in chunks,
+        /// see [`ResponsePipe::ensure_version_and_length`].
+        /// They are otherwise ignored, and the body isn't ended.
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from writing to the stream.
+        /// See [`h2::SendStream::send_trailers()`].
+        /// If the body is already ended, an error of kind [`io::ErrorKind::BrokenPipe`] is returned.
+        pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Error> {
+            match self {
+                Self::Http1(_) => return Ok(()),
+                Self::Http1Chunked(h1) => h1.finish(&trailers).await?,
                 #[cfg(feature = "http2")]
-                Self::Http2(h2) => h2.send_data(data, end_of_stream)?,
+                Self::Http2(h2) => h2.send_trailers(trailers)?,
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => {
+                    h3.send_trailers(&trailers).await?;
+                    h3.finish().await?;
+                }
+                Self::Ended => return Err(body_ended().into()),
             }
+            *self = Self::Ended;
             Ok(())
         }
         /// Closes the pipe.
         ///
+        /// Except on [`Self::Http1`], which only flushes the stream, this ends the body.
+        /// Closing an ended body does nothing.
+        ///
         /// # Errors
         ///
         /// Passes any errors emitted when closing the writer.
@@ -553,10 +1518,16 @@ mod response {
         #[inline]
         pub async fn close(&mut self) -> Result<(), Error> {
             match self {
-                Self::Http1(h1) => h1.lock().await.flush().await.map_err(Error::from),
+                Self::Http1(h1) => return h1.lock().await.flush().await.map_err(Error::from),
+                Self::Http1Chunked(h1) => h1.finish(&HeaderMap::new()).await?,
                 #[cfg(feature = "http2")]
-                Self::Http2(h2) => h2.send_data(Bytes::new(), true).map_err(Error::from),
+                Self::Http2(h2) => h2.send_data(Bytes::new(), true)?,
+                #[cfg(feature = "http3")]
+                Self::Http3(h3) => h3.finish().await?,
+                Self::Ended => return Ok(()),
             }
+            *self = Self::Ended;
+            Ok(())
         }
     }
     impl AsyncRead for ResponseBodyPipe {
@@ -570,8 +1541,15 @@ mod response {
                     Err(_) => Poll::Pending,
                     Ok(mut s) => Pin::new(&mut *s).poll_read(cx, buf),
                 },
+                Self::Http1Chunked(s) => match s.stream.try_lock() {
+                    Err(_) => Poll::Pending,
+                    Ok(mut s) => Pin::new(&mut *s).poll_read(cx, buf),
+                },
                 #[cfg(feature = "http2")]
                 Self::Http2(_) => Poll::Ready(Ok(())),
+                #[cfg(feature = "http3")]
+                Self::Http3(_) => Poll::Ready(Ok(())),
+                Self::Ended => Poll::Ready(Ok(())),
             }
         }
     }
@@ -586,6 +1564,7 @@ mod response {
                     Err(_) => Poll::Pending,
                     Ok(mut s) => Pin::new(&mut *s).poll_write(cx, buf),
                 },
+                Self::Http1Chunked(s) => s.poll_write(cx, buf),
                 #[cfg(feature = "http2")]
                 Self::Http2(s) => Poll::Ready(
                     s.send_data(Bytes::copy_from_slice(buf), false)
@@ -599,21 +1578,470 @@ mod response {
                         })
                         .map(|()| buf.len()),
                 ),
+                #[cfg(feature = "http3")]
+                Self::Http3(s) => s.poll_write(cx, buf),
+                Self::Ended => Poll::Ready(Err(body_ended())),
             }
         }
         fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
             Poll::Ready(Ok(()))
         }
         fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-            if let Self::Http1(s) = self.get_mut() {
-                if let Ok(mut s) = s.try_lock() {
-                    Pin::new(&mut *s).poll_flush(cx)
-                } else {
-                    Poll::Pending
+            match self.get_mut() {
+                Self::Http1(s) => {
+                    if let Ok(mut s) = s.try_lock() {
+                        Pin::new(&mut *s).poll_flush(cx)
+                    } else {
+                        Poll::Pending
+                    }
                 }
-            } else {
-                Poll::Ready(Ok(()))
+                Self::Http1Chunked(s) => s.poll_flush(cx),
+                #[cfg(feature = "http3")]
+                Self::Http3(s) => s.poll_flush(cx),
+                #[allow(unreachable_patterns)]
+                _ => Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http3")]
+mod http3 {
+    use crate::prelude::{application::*, *};
+    use bytes::Buf;
+    use h3::server::RequestStream;
+
+    type Pending = Pin<Box<dyn Future<Output = io::Result<()>> + Send + Sync>>;
+
+    /// A HTTP/3 connection provided by [`h3`] over a [`quinn`] connection.
+    ///
+    /// Requests are accepted using [`HttpConnection::accept`].
+    pub struct Http3Connection(pub(super) h3::server::Connection<h3_quinn::Connection, Bytes>);
+    impl Http3Connection {
+        pub(super) async fn accept(&mut self) -> Result<(Request<Body>, ResponsePipe), Error> {
+            let resolver = match self.0.accept().await {
+                Ok(Some(resolver)) => resolver,
+                Ok(None) => return Err(utils::parse::Error::Done.into()),
+                Err(err) if err.is_h3_no_error() => return Err(utils::parse::Error::Done.into()),
+                Err(err) => return Err(err.into()),
+            };
+            let (request, stream) = resolver.resolve_request().await?;
+            let (send, recv) = stream.split();
+            let request = convert_request(request)?;
+            let body = Body::Http3(Http3Body {
+                stream: recv,
+                buffer: Bytes::new(),
+                timeout: host::Options::DEFAULT_BODY_TIMEOUT,
+                max_size: host::Options::DEFAULT_MAX_BODY_SIZE,
+                received: 0,
+            });
+            Ok((
+                request.map(|()| body),
+                ResponsePipe::Http3(Http3Stream::new(send)),
+            ))
+        }
+    }
+    impl Debug for Http3Connection {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("Http3Connection")
+                .field(&"[internal h3 connection]".as_clean())
+                .finish()
+        }
+    }
+
+    /// A HTTP/3 request body.
+    ///
+    /// The data is received in frames, of which the rest is kept if
+    /// the buffer of [`AsyncRead::poll_read`] is too small.
+    pub struct Http3Body {
+        stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+        buffer: Bytes,
+        timeout: Duration,
+        max_size: u64,
+        received: u64,
+    }
+    impl Http3Body {
+        pub(super) fn is_too_large(&self) -> bool {
+            self.received > self.max_size
+        }
+        pub(super) fn poll_frame(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<Option<Bytes>>> {
+            if !self.buffer.is_empty() {
+                return Poll::Ready(Ok(Some(std::mem::take(&mut self.buffer))));
+            }
+            match self.stream.poll_recv_data(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Ok(None)) => Poll::Ready(Ok(None)),
+                Poll::Ready(Ok(Some(mut data))) => {
+                    self.received += data.remaining() as u64;
+                    if self.received > self.max_size {
+                        return Poll::Ready(Err(body_too_large()));
+                    }
+                    Poll::Ready(Ok(Some(data.copy_to_bytes(data.remaining()))))
+                }
+                Poll::Ready(Err(err)) => {
+                    Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)))
+                }
+            }
+        }
+        /// Reads all bytes from the stream to a [`Bytes`].
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from [`RequestStream::poll_recv_data`].
+        /// If the body isn't received within the timeout, an error of kind
+        /// [`io::ErrorKind::TimedOut`] is returned.
+        /// If the body is larger than the maximum size,
+        /// an error of kind [`io::ErrorKind::InvalidData`] is returned.
+        pub async fn read_to_bytes(&mut self) -> io::Result<Bytes> {
+            let mut bytes = BytesMut::new();
+            let duration = self.timeout;
+            let read = async {
+                while let Some(frame) = futures::future::poll_fn(|cx| self.poll_frame(cx)).await? {
+                    bytes.extend_from_slice(&frame);
+                }
+                io::Result::Ok(())
+            };
+            match timeout(duration, read).await {
+                Ok(result) => result?,
+                Err(_) => return Err(body_timed_out()),
+            }
+            Ok(bytes.freeze())
+        }
+        /// Sets the time [`Self::read_to_bytes`] may take.
+        #[inline]
+        pub fn set_timeout(&mut self, timeout: Duration) {
+            self.timeout = timeout;
+        }
+        /// Sets the maximum size of the body, in bytes.
+        #[inline]
+        pub fn set_max_size(&mut self, max_size: u64) {
+            self.max_size = max_size;
+        }
+        /// Receives the trailers sent after the body, if any.
+        ///
+        /// # Errors
+        ///
+        /// Passes any errors from [`RequestStream::recv_trailers`].
+        /// If the trailers aren't received within the timeout, an error of kind
+        /// [`io::ErrorKind::TimedOut`] is returned.
+        pub async fn trailers(&mut self) -> io::Result<Option<HeaderMap>> {
+            let trailers = match timeout(self.timeout, self.stream.recv_trailers()).await {
+                Ok(Ok(trailers)) => trailers,
+                Ok(Err(err)) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                Err(_) => return Err(body_timed_out()),
+            };
+            trailers
+                .map(|trailers| convert_headers(&trailers).map_err(io::Error::from))
+                .transpose()
+        }
+    }
+    impl AsyncRead for Http3Body {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut frame = match self.poll_frame(cx) {
+                Poll::Ready(Ok(Some(frame))) => frame,
+                // End of stream
+                Poll::Ready(Ok(None)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let len = frame.len().min(buf.remaining());
+            buf.put_slice(&frame.split_to(len));
+            self.buffer = frame;
+            Poll::Ready(Ok(()))
+        }
+    }
+    impl Debug for Http3Body {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Http3Body")
+                .field("stream", &"[internal h3 stream]".as_clean())
+                .field("buffer", &self.buffer.len())
+                .field("timeout", &self.timeout)
+                .field("max_size", &self.max_size)
+                .finish()
+        }
+    }
+
+    /// The sending half of a HTTP/3 request stream.
+    ///
+    /// This is shared between the [`ResponsePipe`] and [`ResponseBodyPipe`] of a request.
+    /// When written to using [`AsyncWrite`], the data is sent in the background
+    /// and the next write or flush waits for it to complete.
+    pub struct Http3Stream {
+        stream: Arc<Mutex<RequestStream<h3_quinn::SendStream<Bytes>, Bytes>>>,
+        pending: Option<Pending>,
+    }
+    impl Http3Stream {
+        fn new(stream: RequestStream<h3_quinn::SendStream<Bytes>, Bytes>) -> Self {
+            Self {
+                stream: Arc::new(Mutex::new(stream)),
+                pending: None,
+            }
+        }
+        async fn flush(&mut self) -> io::Result<()> {
+            futures::future::poll_fn(|cx| self.poll_flush(cx)).await
+        }
+        pub(super) async fn send_response(&mut self, response: Response<()>) -> Result<(), Error> {
+            self.flush().await?;
+            let response = convert_response(&response);
+            self.stream.lock().await.send_response(response).await?;
+            Ok(())
+        }
+        pub(super) async fn send_data(&mut self, data: Bytes) -> Result<(), Error> {
+            self.flush().await?;
+            self.stream.lock().await.send_data(data).await?;
+            Ok(())
+        }
+        pub(super) async fn send_trailers(&mut self, trailers: &HeaderMap) -> Result<(), Error> {
+            self.flush().await?;
+            let trailers = convert_headers_to_h3(trailers);
+            self.stream.lock().await.send_trailers(trailers).await?;
+            Ok(())
+        }
+        pub(super) async fn finish(&mut self) -> Result<(), Error> {
+            self.flush().await?;
+            self.stream.lock().await.finish().await?;
+            Ok(())
+        }
+        pub(super) fn poll_write(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.poll_flush(cx) {
+                Poll::Ready(Ok(())) => {}
+                poll => return poll.map(|result| result.map(|()| 0)),
+            }
+            let stream = Arc::clone(&self.stream);
+            let data = Bytes::copy_from_slice(buf);
+            self.pending = Some(Box::pin(async move {
+                stream
+                    .lock()
+                    .await
+                    .send_data(data)
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+            }));
+            Poll::Ready(Ok(buf.len()))
+        }
+        pub(super) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match &mut self.pending {
+                Some(pending) => {
+                    let poll = pending.as_mut().poll(cx);
+                    if poll.is_ready() {
+                        self.pending = None;
+                    }
+                    poll
+                }
+                None => Poll::Ready(Ok(())),
+            }
+        }
+    }
+    impl Clone for Http3Stream {
+        /// Clones the reference to the stream. Any pending write isn't cloned.
+        fn clone(&self) -> Self {
+            Self {
+                stream: Arc::clone(&self.stream),
+                pending: None,
+            }
+        }
+    }
+    impl Debug for Http3Stream {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Http3Stream")
+                .field("stream", &"[internal h3 stream]".as_clean())
+                .field("pending", &self.pending.is_some())
+                .finish()
+        }
+    }
+
+    /// Converts a request from [`h3`], which uses version 1 of [`http`].
+    fn convert_request(request: h3_http::Request<()>) -> Result<Request<()>, Error> {
+        let (parts, ()) = request.into_parts();
+        let mut request = Request::builder()
+            .method(
+                Method::from_bytes(parts.method.as_str().as_bytes())
+                    .map_err(|_| utils::parse::Error::InvalidMethod)?,
+            )
+            .uri(
+                Uri::try_from(parts.uri.to_string())
+                    .map_err(|_| utils::parse::Error::InvalidPath)?,
+            )
+            .version(Version::HTTP_3)
+            .body(())
+            .map_err(utils::parse::Error::Http)?;
+        *request.headers_mut() = convert_headers(&parts.headers)?;
+        Ok(request)
+    }
+    /// Converts `headers` from version 1 of [`http`], used by [`h3`].
+    fn convert_headers(headers: &h3_http::HeaderMap) -> Result<HeaderMap, Error> {
+        let mut converted = HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_str().as_bytes())
+                .map_err(|_| utils::parse::Error::IllegalName)?;
+            let value = HeaderValue::from_bytes(value.as_bytes())
+                .map_err(|_| utils::parse::Error::IllegalValue)?;
+            converted.append(name, value);
+        }
+        Ok(converted)
+    }
+    /// Converts `response` to version 1 of [`http`], used by [`h3`].
+    fn convert_response(response: &Response<()>) -> h3_http::Response<()> {
+        let mut converted = h3_http::Response::new(());
+        // The status code is valid in both versions.
+        *converted.status_mut() =
+            h3_http::StatusCode::from_u16(response.status().as_u16()).unwrap();
+        *converted.headers_mut() = convert_headers_to_h3(response.headers());
+        converted
+    }
+    /// Converts `headers` to version 1 of [`http`], used by [`h3`].
+    ///
+    /// Connection-specific headers aren't allowed in HTTP/3, and are therefore removed.
+    fn convert_headers_to_h3(headers: &HeaderMap) -> h3_http::HeaderMap {
+        let mut converted = h3_http::HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers {
+            if matches!(
+                name.as_str(),
+                "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+            ) {
+                continue;
             }
+            // Header names and values are valid in both versions.
+            let name = h3_http::HeaderName::from_bytes(name.as_str().as_bytes()).unwrap();
+            let value = h3_http::HeaderValue::from_bytes(value.as_bytes()).unwrap();
+            converted.append(name, value);
+        }
+        converted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(body: &'static [u8]) -> Http1Body<&'static [u8]> {
+        Http1Body::new_chunked(Arc::new(Mutex::new(body)), Bytes::new())
+    }
+    async fn read_chunked(body: &'static [u8], max_size: u64) -> io::Result<Bytes> {
+        let mut body = chunked(body);
+        body.set_max_size(max_size);
+        body.read_to_bytes().await
+    }
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let mut body = chunked(b"5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\n\r\nGET / HTTP/1.1");
+        assert!(body.trailers().is_none());
+        assert_eq!(body.read_to_bytes().await.unwrap(), "hello, world");
+        assert!(body.is_fully_read());
+        assert!(!body.is_too_large());
+        assert!(body.trailers().unwrap().is_empty());
+        // The next request is kept in the buffer.
+        let (reader, bytes) = body.into_inner();
+        assert_eq!(bytes, "GET / HTTP/1.1");
+        assert!(reader.lock().await.is_empty());
+    }
+    #[tokio::test]
+    async fn chunked_line_across_reads() {
+        // The CR after the data is the last byte of the first read, the LF the first of the next.
+        let data = "a".repeat(0xffa);
+        let body = format!("ffa\r\n{}\r\n3\r\nabc\r\n0\r\n\r\n", data);
+        let body: &'static [u8] = Box::leak(body.into_bytes().into_boxed_slice());
+        let read = read_chunked(body, u64::MAX).await.unwrap();
+        assert_eq!(read, format!("{}abc", data));
+    }
+    #[tokio::test]
+    async fn body_waits_for_lock() {
+        let reader = Arc::new(Mutex::new(&b"3\r\nabc\r\n0\r\n\r\n"[..]));
+        let guard = Arc::clone(&reader).lock_owned().await;
+        let mut body = Http1Body::new_chunked(reader, Bytes::new());
+        let read = tokio::spawn(async move { body.read_to_bytes().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(guard);
+        assert_eq!(read.await.unwrap().unwrap(), "abc");
+    }
+    #[tokio::test]
+    async fn chunked_trailers() {
+        let mut body = chunked(b"3\r\nabc\r\n0\r\nchecksum: 42\r\nx-extra: yes\r\n\r\n");
+        assert_eq!(body.read_to_bytes().await.unwrap(), "abc");
+        let trailers = body.trailers().unwrap();
+        assert_eq!(trailers.get("checksum").unwrap(), "42");
+        assert_eq!(trailers.get("x-extra").unwrap(), "yes");
+    }
+    #[tokio::test]
+    async fn chunked_without_carriage_return() {
+        for body in [
+            &b"3\nabc\r\n0\r\n\r\n"[..],
+            b"3\r\nabc\n0\r\n\r\n",
+            b"3\r\nabc\r\n0\r\n\n",
+        ] {
+            let err = read_chunked(body, 1024).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+    #[tokio::test]
+    async fn chunked_too_large() {
+        let mut body = chunked(b"8\r\n01234567\r\n8\r\n01234567\r\n0\r\n\r\n");
+        body.set_max_size(10);
+        let err = body.read_to_bytes().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(body.is_too_large());
+        assert!(!body.is_fully_read());
+    }
+    #[tokio::test]
+    async fn chunked_invalid() {
+        let long_line: &'static [u8] = Box::leak(
+            format!("1;{}\r\na\r\n0\r\n\r\n", "a".repeat(2000))
+                .into_bytes()
+                .into_boxed_slice(),
+        );
+        for body in [
+            // The size line is too long.
+            long_line,
+            // The size doesn't fit in a `u64`.
+            b"10000000000000000\r\n",
+            // The size has too many digits, even if its value is small.
+            b"00000000000000003\r\nabc\r\n0\r\n\r\n",
+            // The size isn't hexadecimal.
+            b"x\r\nabc\r\n0\r\n\r\n",
+            b"\r\nabc\r\n0\r\n\r\n",
+            b"+3\r\nabc\r\n0\r\n\r\n",
+            b" 3\r\nabc\r\n0\r\n\r\n",
+            b"3 ;ext\r\nabc\r\n0\r\n\r\n",
+            // The data is longer than the size.
+            b"2\r\nabc\r\n0\r\n\r\n",
+        ] {
+            let err = read_chunked(body, u64::MAX).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
         }
+        // The body ends before the last chunk.
+        let err = read_chunked(b"3\r\nabc\r\n", u64::MAX).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+    #[tokio::test]
+    async fn content_length_body() {
+        let mut body = Http1Body::new(
+            Arc::new(Mutex::new(&b"lo, worldGET / HTTP/1.1"[..])),
+            Bytes::from_static(b"hel"),
+            12,
+        );
+        assert!(!body.is_fully_read());
+        assert_eq!(body.read_to_bytes().await.unwrap(), "hello, world");
+        assert!(body.is_fully_read());
+        let (reader, bytes) = body.into_inner();
+        assert!(bytes.is_empty());
+        assert_eq!(*reader.lock().await, b"GET / HTTP/1.1");
+
+        let mut body = Http1Body::new(Arc::new(Mutex::new(&b""[..])), Bytes::new(), 12);
+        body.set_max_size(10);
+        assert!(body.is_too_large());
+        let err = body.read_to_bytes().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
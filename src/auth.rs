@@ -0,0 +1,658 @@
+//! Authentication using signed, expiring tokens.
+//!
+//! An [`Auth`] issues tokens for a subject (e.g. a user name) and verifies them.
+//! The token is signed using HMAC-SHA256 or Ed25519 (see [`Key`]), so no sessions
+//! have to be stored on the server.
+//! Clients send the token either in a cookie or in the `authorization` header
+//! using the `Bearer` scheme.
+//!
+//! Use [`Extensions::add_auth`] to guard path prefixes and optionally add a login endpoint,
+//! which checks the credentials using a [`Validate`] function you provide.
+//! Extensions get the identity of the client using [`Auth::identity`].
+//!
+//! # Examples
+//!
+//! ```
+//! # use kvarn::prelude::*;
+//! use kvarn::auth::{Auth, Key};
+//!
+//! let auth = Auth::new(Key::hmac(b"a long, random and secret key"))
+//!     .guard("/admin/")
+//!     .with_login(
+//!         "/login",
+//!         Box::new(|credentials| {
+//!             let valid = credentials.username() == "admin" && credentials.password() == "hunter2";
+//!             extensions::ready(if valid {
+//!                 Some(credentials.username().to_owned())
+//!             } else {
+//!                 None
+//!             })
+//!         }),
+//!     )
+//!     .build();
+//!
+//! let mut extensions = Extensions::new();
+//! extensions.add_auth(Arc::clone(&auth));
+//! extensions.add_prepare_single(
+//!     "/admin/whoami".to_owned(),
+//!     prepare!(request, host, _path, _addr, move |auth| {
+//!         // The guard only lets authenticated requests through.
+//!         let identity = auth.identity(request).unwrap();
+//!         let response = Response::new(Bytes::from(identity.subject().to_owned()));
+//!         FatResponse::no_cache(response)
+//!     }),
+//! );
+//! ```
+
+use crate::prelude::{internals::*, *};
+use ring::{hmac, signature};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The default lifetime of issued tokens; 12 hours.
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// The default name of the cookie containing the token.
+pub const DEFAULT_COOKIE_NAME: &str = "kvarn-auth";
+
+/// A function which validates [`Credentials`].
+///
+/// Return the subject of the authenticated client (e.g. the user name) if the credentials
+/// are valid, else `None`.
+/// The subject must not contain any `:`.
+pub type Validate = Box<dyn Fn(Credentials) -> RetFut<Option<String>> + Send + Sync>;
+
+/// The key used to sign and verify tokens.
+#[must_use]
+pub enum Key {
+    /// A secret HMAC-SHA256 key.
+    Hmac(hmac::Key),
+    /// An Ed25519 key pair.
+    Ed25519(signature::Ed25519KeyPair),
+    /// The public key of a Ed25519 key pair.
+    ///
+    /// This can only verify tokens; [`Auth::issue`] always returns `None`.
+    /// Useful when one server issues the tokens and others only check them.
+    Ed25519Public(signature::UnparsedPublicKey<Vec<u8>>),
+}
+impl Key {
+    /// Creates a HMAC-SHA256 key from `secret`.
+    ///
+    /// `secret` should be at least 32 random bytes.
+    pub fn hmac(secret: &[u8]) -> Self {
+        Self::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret))
+    }
+    /// Generates a random HMAC-SHA256 key.
+    ///
+    /// All tokens are invalidated when the key is dropped, often when the server restarts.
+    pub fn random_hmac() -> Self {
+        let rng = ring::rand::SystemRandom::new();
+        Self::Hmac(
+            hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+                .expect("failed to get randomness from the OS"),
+        )
+    }
+    /// Reads a Ed25519 key pair from a PKCS#8 v2 document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document isn't a valid Ed25519 key pair.
+    pub fn ed25519(pkcs8: &[u8]) -> Result<Self, ring::error::KeyRejected> {
+        signature::Ed25519KeyPair::from_pkcs8(pkcs8).map(Self::Ed25519)
+    }
+    /// Creates a verifying-only key from the 32-byte Ed25519 `public_key`.
+    pub fn ed25519_public(public_key: impl Into<Vec<u8>>) -> Self {
+        Self::Ed25519Public(signature::UnparsedPublicKey::new(
+            &signature::ED25519,
+            public_key.into(),
+        ))
+    }
+
+    fn sign(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Self::Hmac(key) => Some(hmac::sign(key, data).as_ref().to_vec()),
+            Self::Ed25519(key) => Some(key.sign(data).as_ref().to_vec()),
+            Self::Ed25519Public(_) => None,
+        }
+    }
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        use signature::KeyPair;
+        match self {
+            Self::Hmac(key) => hmac::verify(key, data, signature).is_ok(),
+            Self::Ed25519(key) => {
+                signature::UnparsedPublicKey::new(&signature::ED25519, key.public_key())
+                    .verify(data, signature)
+                    .is_ok()
+            }
+            Self::Ed25519Public(key) => key.verify(data, signature).is_ok(),
+        }
+    }
+}
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Hmac(_) => "Hmac",
+            Self::Ed25519(_) => "Ed25519",
+            Self::Ed25519Public(_) => "Ed25519Public",
+        };
+        f.debug_tuple(name).field(&"[secret]".as_clean()).finish()
+    }
+}
+
+/// An error from verifying a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The token isn't on the format Kvarn issues.
+    Malformed,
+    /// The signature doesn't match the contents of the token.
+    InvalidSignature,
+    /// The token has expired.
+    Expired,
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Malformed => "malformed token",
+            Self::InvalidSignature => "invalid token signature",
+            Self::Expired => "token has expired",
+        })
+    }
+}
+impl std::error::Error for Error {}
+
+/// The identity of an authenticated client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    subject: String,
+    expires: SystemTime,
+}
+impl Identity {
+    /// The subject of the token, as returned by the [`Validate`] function
+    /// or passed to [`Auth::issue`].
+    #[must_use]
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+    /// When the token expires.
+    #[must_use]
+    pub fn expires(&self) -> SystemTime {
+        self.expires
+    }
+}
+
+/// The credentials sent to the login endpoint.
+///
+/// They are read from the `authorization` header using the `Basic` scheme
+/// or from a `application/x-www-form-urlencoded` body with the fields `username` and `password`.
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+impl Credentials {
+    /// The user name.
+    #[must_use]
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    /// The password.
+    #[must_use]
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    fn from_basic(header: &HeaderValue) -> Option<Self> {
+        let header = header.to_str().ok()?;
+        let scheme_end = header.find(' ')?;
+        if !header[..scheme_end].eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::decode(header[scheme_end + 1..].trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let separator = decoded.find(':')?;
+        Some(Self {
+            username: decoded[..separator].to_owned(),
+            password: decoded[separator + 1..].to_owned(),
+        })
+    }
+    fn from_form(body: &[u8]) -> Option<Self> {
+        let body = str::from_utf8(body).ok()?;
+        let fields = parse::query(body);
+        Some(Self {
+            username: form_decode(fields.get("username")?)?,
+            password: form_decode(fields.get("password")?)?,
+        })
+    }
+}
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"[hidden]".as_clean())
+            .finish()
+    }
+}
+
+/// Decodes a value of a `application/x-www-form-urlencoded` body.
+fn form_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let high = char::from(iter.next()?).to_digit(16)?;
+                let low = char::from(iter.next()?).to_digit(16)?;
+                // Two hex digits are at most 255.
+                #[allow(clippy::cast_possible_truncation)]
+                bytes.push((high * 16 + low) as u8);
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Issues and verifies tokens, and holds the settings of the guard and login endpoint.
+///
+/// See the [module level documentation](self) for an example.
+#[must_use]
+pub struct Auth {
+    key: Key,
+    lifetime: Duration,
+    cookie_name: String,
+    secure_cookie: bool,
+    guarded: Vec<String>,
+    login: Option<(String, Validate)>,
+}
+impl Auth {
+    /// Creates a new [`Auth`] signing tokens with `key`.
+    ///
+    /// By default, no paths are guarded and there is no login endpoint.
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            lifetime: DEFAULT_LIFETIME,
+            cookie_name: DEFAULT_COOKIE_NAME.to_owned(),
+            secure_cookie: true,
+            guarded: Vec::new(),
+            login: None,
+        }
+    }
+    /// Sets the lifetime of issued tokens.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+    /// Sets the name of the cookie the token is stored in.
+    pub fn with_cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+    /// Doesn't set the `Secure` attribute of the cookie,
+    /// which makes the browser send it over unencrypted HTTP.
+    ///
+    /// Only use this for testing.
+    pub fn with_insecure_cookie(mut self) -> Self {
+        self.secure_cookie = false;
+        self
+    }
+    /// Requires requests with a path starting with `prefix` to be authenticated.
+    ///
+    /// Unauthenticated requests are responded to with `401 Unauthorized`.
+    pub fn guard(mut self, prefix: impl Into<String>) -> Self {
+        self.guarded.push(prefix.into());
+        self
+    }
+    /// Adds a login endpoint at `path`.
+    ///
+    /// A `POST` request with [`Credentials`] validated by `validate` gets a token,
+    /// both in the body and as a cookie.
+    pub fn with_login(mut self, path: impl Into<String>, validate: Validate) -> Self {
+        self.login = Some((path.into(), validate));
+        self
+    }
+    /// Puts `self` in a [`Arc`].
+    /// Useful for adding the guard with [`Extensions::add_auth`].
+    #[must_use]
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Gets the name of the cookie containing the token.
+    #[must_use]
+    pub fn cookie_name(&self) -> &str {
+        &self.cookie_name
+    }
+    /// Gets the path of the login endpoint, if any.
+    #[must_use]
+    pub fn login_path(&self) -> Option<&str> {
+        self.login.as_ref().map(|(path, _)| path.as_str())
+    }
+    /// Checks if `path` is guarded by this [`Auth`].
+    #[must_use]
+    pub fn is_guarded(&self, path: &str) -> bool {
+        self.guarded
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// Issues a token for `subject`, valid for the configured lifetime.
+    ///
+    /// Returns `None` if the [`Key`] can only verify tokens or if `subject` contains a `:`.
+    #[must_use]
+    pub fn issue(&self, subject: &str) -> Option<String> {
+        if subject.contains(':') {
+            return None;
+        }
+        let expires = (SystemTime::now() + self.lifetime)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let payload =
+            base64::encode_config(format!("{}:{}", expires, subject), base64::URL_SAFE_NO_PAD);
+        let signature = self.key.sign(payload.as_bytes())?;
+        let signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
+        Some(format!("{}.{}", payload, signature))
+    }
+    /// Verifies `token` and returns the [`Identity`] it contains.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is malformed, not signed by our [`Key`], or has expired.
+    pub fn verify(&self, token: &str) -> Result<Identity, Error> {
+        let separator = token.find('.').ok_or(Error::Malformed)?;
+        let (payload, signature) = (&token[..separator], &token[separator + 1..]);
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| Error::Malformed)?;
+        if !self.key.verify(payload.as_bytes(), &signature) {
+            return Err(Error::InvalidSignature);
+        }
+
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| Error::Malformed)?;
+        let payload = str::from_utf8(&payload).map_err(|_| Error::Malformed)?;
+        let separator = payload.find(':').ok_or(Error::Malformed)?;
+        let expires: u64 = payload[..separator].parse().map_err(|_| Error::Malformed)?;
+        let expires = UNIX_EPOCH + Duration::from_secs(expires);
+        if expires <= SystemTime::now() {
+            return Err(Error::Expired);
+        }
+        Ok(Identity {
+            subject: payload[separator + 1..].to_owned(),
+            expires,
+        })
+    }
+    /// Gets the token of `request`.
+    ///
+    /// The `authorization` header is checked first, then the cookie.
+    #[must_use]
+    pub fn token<'a, T>(&self, request: &'a Request<T>) -> Option<&'a str> {
+        let headers = request.headers();
+        let bearer = headers
+            .get("authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| {
+                let scheme_end = header.find(' ')?;
+                if header[..scheme_end].eq_ignore_ascii_case("bearer") {
+                    Some(header[scheme_end + 1..].trim())
+                } else {
+                    None
+                }
+            });
+        bearer.or_else(|| {
+            headers
+                .get_all("cookie")
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .flat_map(|header| header.split(';'))
+                .find_map(|cookie| {
+                    let cookie = cookie.trim();
+                    let separator = cookie.find('=')?;
+                    if cookie[..separator] == self.cookie_name {
+                        Some(&cookie[separator + 1..])
+                    } else {
+                        None
+                    }
+                })
+        })
+    }
+    /// Gets the verified [`Identity`] of the client who sent `request`.
+    ///
+    /// Returns `None` if no valid token was sent.
+    #[must_use]
+    pub fn identity<T>(&self, request: &Request<T>) -> Option<Identity> {
+        self.token(request)
+            .and_then(|token| self.verify(token).ok())
+    }
+    /// Creates a `set-cookie` header storing `token` for the lifetime of the tokens.
+    #[must_use]
+    pub fn set_cookie(&self, token: &str) -> HeaderValue {
+        self.cookie(token, self.lifetime.as_secs())
+    }
+    /// Creates a `set-cookie` header removing the token from the client, logging it out.
+    #[must_use]
+    pub fn clear_cookie(&self) -> HeaderValue {
+        self.cookie("", 0)
+    }
+    fn cookie(&self, value: &str, max_age: u64) -> HeaderValue {
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+            self.cookie_name,
+            value,
+            max_age,
+            if self.secure_cookie { "; Secure" } else { "" }
+        );
+        // The token is base64 and the name is set by the developer.
+        HeaderValue::from_str(&cookie).expect("invalid cookie name")
+    }
+
+    /// The response to requests to guarded paths without a valid token.
+    pub(crate) async fn unauthorized(host: &Host) -> FatResponse {
+        // Don't let the client cache this; it'll be able to access it after logging in.
+        let mut response =
+            default_error_response(StatusCode::UNAUTHORIZED, host, Some("invalid token"))
+                .await
+                .with_client_cache(ClientCachePreference::None);
+        utils::replace_header_static(
+            response.response.headers_mut(),
+            "www-authenticate",
+            "Bearer",
+        );
+        response
+    }
+    /// The response of the login endpoint.
+    pub(crate) async fn login(&self, request: &mut FatRequest, host: &Host) -> FatResponse {
+        let validate = match &self.login {
+            Some((_, validate)) => validate,
+            None => return default_error_response(StatusCode::NOT_FOUND, host, None).await,
+        };
+        if request.method() != Method::POST {
+            let mut response =
+                default_error_response(StatusCode::METHOD_NOT_ALLOWED, host, None).await;
+            utils::replace_header_static(response.response.headers_mut(), "allow", "POST");
+            return response;
+        }
+
+        let credentials = match request.headers().get("authorization") {
+            Some(header) => Credentials::from_basic(header),
+            None => match request.body_mut().read_to_bytes().await {
+                Ok(body) => Credentials::from_form(&body),
+                Err(_) => None,
+            },
+        };
+        let subject = match credentials {
+            Some(credentials) => validate(credentials).await,
+            None => {
+                return default_error_response(
+                    StatusCode::BAD_REQUEST,
+                    host,
+                    Some("no credentials were sent"),
+                )
+                .await
+            }
+        };
+        let token = match subject.as_deref().and_then(|subject| self.issue(subject)) {
+            Some(token) => token,
+            None => {
+                let mut response = default_error_response(
+                    StatusCode::UNAUTHORIZED,
+                    host,
+                    Some("invalid credentials"),
+                )
+                .await
+                .with_client_cache(ClientCachePreference::None);
+                utils::replace_header_static(
+                    response.response.headers_mut(),
+                    "www-authenticate",
+                    "Basic",
+                );
+                return response;
+            }
+        };
+
+        let mut response = Response::new(Bytes::from(token.clone()));
+        let headers = response.headers_mut();
+        headers.insert("set-cookie", self.set_cookie(&token));
+        headers.insert(
+            "content-type",
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        FatResponse::no_cache(response).with_compress(CompressPreference::None)
+    }
+}
+impl Debug for Auth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("key", &self.key)
+            .field("lifetime", &self.lifetime)
+            .field("cookie_name", &self.cookie_name)
+            .field("secure_cookie", &self.secure_cookie)
+            .field("guarded", &self.guarded)
+            .field("login", &self.login_path())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ed25519_pair() -> Key {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        Key::ed25519(pkcs8.as_ref()).unwrap()
+    }
+    fn public_of(key: &Key) -> Key {
+        use signature::KeyPair;
+        match key {
+            Key::Ed25519(pair) => Key::ed25519_public(pair.public_key().as_ref()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        let auth = Auth::new(Key::hmac(b"secret")).with_lifetime(Duration::from_secs(60));
+        let token = auth.issue("alice").unwrap();
+        let identity = auth.verify(&token).unwrap();
+        assert_eq!(identity.subject(), "alice");
+        let remaining = identity
+            .expires()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(55));
+
+        assert_eq!(auth.issue("ali:ce"), None);
+    }
+    #[test]
+    fn expired() {
+        let auth = Auth::new(Key::hmac(b"secret")).with_lifetime(Duration::from_secs(0));
+        let token = auth.issue("alice").unwrap();
+        assert_eq!(auth.verify(&token), Err(Error::Expired));
+    }
+    #[test]
+    fn tampered() {
+        let auth = Auth::new(Key::hmac(b"secret"));
+        let token = auth.issue("alice").unwrap();
+        let signature = &token[token.find('.').unwrap()..];
+
+        let payload =
+            base64::encode_config(format!("{}:admin", u64::MAX / 2), base64::URL_SAFE_NO_PAD);
+        let forged = format!("{}{}", payload, signature);
+        assert_eq!(auth.verify(&forged), Err(Error::InvalidSignature));
+
+        let other = Auth::new(Key::hmac(b"another secret"));
+        assert_eq!(other.verify(&token), Err(Error::InvalidSignature));
+
+        assert_eq!(auth.verify("no signature"), Err(Error::Malformed));
+        assert_eq!(auth.verify("payload.!!"), Err(Error::Malformed));
+    }
+    #[test]
+    fn ed25519() {
+        let pair = ed25519_pair();
+        let public = Auth::new(public_of(&pair));
+        let auth = Auth::new(pair);
+        let token = auth.issue("alice").unwrap();
+        assert_eq!(auth.verify(&token).unwrap().subject(), "alice");
+
+        // The public key only verifies.
+        assert_eq!(public.verify(&token).unwrap().subject(), "alice");
+        assert_eq!(public.issue("alice"), None);
+
+        let other = Auth::new(public_of(&ed25519_pair()));
+        assert_eq!(other.verify(&token), Err(Error::InvalidSignature));
+    }
+    #[test]
+    fn token() {
+        let auth = Auth::new(Key::hmac(b"secret")).with_cookie_name("session");
+        let request = Request::get("/")
+            .header("cookie", "theme=dark; session=from-cookie")
+            .body(())
+            .unwrap();
+        assert_eq!(auth.token(&request), Some("from-cookie"));
+        let request = Request::get("/")
+            .header("authorization", "bearer from-header")
+            .header("cookie", "session=from-cookie")
+            .body(())
+            .unwrap();
+        assert_eq!(auth.token(&request), Some("from-header"));
+        let request = Request::get("/")
+            .header("cookie", "kvarn-auth=other")
+            .body(())
+            .unwrap();
+        assert_eq!(auth.token(&request), None);
+
+        let token = auth.issue("alice").unwrap();
+        let request = Request::get("/")
+            .header("cookie", format!("session={}", token))
+            .body(())
+            .unwrap();
+        assert_eq!(auth.identity(&request).unwrap().subject(), "alice");
+    }
+    #[test]
+    fn basic_credentials() {
+        let basic = |value| Credentials::from_basic(&HeaderValue::from_static(value));
+        // admin:hunter2
+        let credentials = basic("Basic YWRtaW46aHVudGVyMg==").unwrap();
+        assert_eq!(credentials.username(), "admin");
+        assert_eq!(credentials.password(), "hunter2");
+        // admin:a:b
+        let credentials = basic("basic YWRtaW46YTpi").unwrap();
+        assert_eq!(credentials.password(), "a:b");
+
+        assert!(basic("Bearer YWRtaW46aHVudGVyMg==").is_none());
+        // admin
+        assert!(basic("Basic YWRtaW4=").is_none());
+        assert!(basic("Basic !!").is_none());
+        assert!(basic("Basic").is_none());
+    }
+    #[test]
+    fn form_credentials() {
+        let credentials =
+            Credentials::from_form(b"username=ad+min&password=p%40ss%3D%c3%a5&other=1").unwrap();
+        assert_eq!(credentials.username(), "ad min");
+        assert_eq!(credentials.password(), "p@ss=\u{e5}");
+
+        assert!(Credentials::from_form(b"username=admin").is_none());
+        assert!(Credentials::from_form(b"username=admin&password=%4").is_none());
+        assert!(Credentials::from_form(b"username=admin&password=%ff").is_none());
+    }
+}
@@ -1,6 +1,6 @@
 //! ***Compr***ess and c***ach***e.
 //!
-//! Provides the [`Cache`] for Kvarn.
+//! Provides the [`Cache`] for Kvarn, which is split into a [`ShardedCache`] to be used concurrently.
 //! When a response is made cacheable, several important headers are appended.
 //! See [`FatResponse`] for more info.
 //!
@@ -10,13 +10,18 @@ use crate::prelude::{time::*, *};
 use std::{
     borrow::Borrow,
     collections::hash_map::DefaultHasher,
+    fmt::Write as _,
     hash::{Hash, Hasher},
+    sync::{Mutex as StdMutex, MutexGuard, PoisonError},
 };
+use tokio::sync::watch;
 
-/// A [`Cache`] inside a [`Mutex`] with appropriate type parameters for a file cache.
-pub type FileCache = Mutex<Cache<PathBuf, Bytes>>;
-/// A [`Cache`] inside a [`Mutex`] with appropriate type parameters for a response cache.
-pub type ResponseCache = Mutex<Cache<UriKey, CompressedResponse>>;
+/// A [`ShardedCache`] with appropriate type parameters for a file cache.
+pub type FileCache = ShardedCache<PathBuf, Bytes>;
+/// A [`ShardedCache`] with appropriate type parameters for a response cache.
+///
+/// The responses are stored in a [`VariedResponse`] to handle the `vary` header.
+pub type ResponseCache = ShardedCache<UriKey, VariedResponse>;
 
 /// A path an optional query used in [`UriKey`]
 ///
@@ -164,21 +169,93 @@ pub fn do_compress(mime: &Mime, check_utf8: impl Fn() -> bool) -> bool {
                 || check_utf8()))
 }
 
+/// Compressed versions of a body, which don't have to be compressed at runtime.
+///
+/// See [`read::precompressed`] for reading them from files next to the original.
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct Precompressed {
+    /// The gzip compressed body.
+    pub gzip: Option<Bytes>,
+    /// The Brotli compressed body.
+    pub br: Option<Bytes>,
+    /// The Zstandard compressed body.
+    pub zstd: Option<Bytes>,
+}
+impl Precompressed {
+    /// Creates an empty set of compressed bodies.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// If no compressed bodies are set.
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.gzip.is_none() && self.br.is_none() && self.zstd.is_none()
+    }
+}
+
+/// The levels used when compressing a [`CompressedResponse`].
+///
+/// Higher levels give smaller bodies, but take longer to compress.
+/// Levels out of range for an algorithm are clamped.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct CompressionLevels {
+    /// The gzip level, from `0` to `9`.
+    pub gzip: u32,
+    /// The Brotli quality, from `0` to `11`.
+    pub br: u32,
+    /// The Zstandard level, from `1` to `22`.
+    /// Negative levels are even faster.
+    pub zstd: i32,
+}
+impl CompressionLevels {
+    /// The default gzip level, the fastest.
+    pub const DEFAULT_GZIP: u32 = 1;
+    /// The default Brotli quality.
+    pub const DEFAULT_BR: u32 = 8;
+    /// The default Zstandard level.
+    pub const DEFAULT_ZSTD: i32 = 3;
+
+    /// Creates a new set of levels with the defaults.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            gzip: Self::DEFAULT_GZIP,
+            br: Self::DEFAULT_BR,
+            zstd: Self::DEFAULT_ZSTD,
+        }
+    }
+}
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A compressed body, set once when it's first needed.
+///
+/// It's shared between clones of a [`CompressedResponse`], so a body compressed
+/// outside the [`ResponseCache`] lock is stored in the cached response.
+type CompressedBody = Arc<tokio::sync::OnceCell<Bytes>>;
+
 /// A response with a lazily compressed body.
 ///
-/// The compressed body is cached.
-/// It therefore uses `unsafe` to mutate the [`Option`]s containing the compressed data.
-/// This should be fine; we only write once, if the value is [`None].
+/// The compressed bodies are stored with the response, and are shared with all clones of it.
+/// Cloning is cheap, as all the parts are reference counted.
 ///
 /// This should (maybe) be replaced by a more general `vary` header solution soon.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[must_use]
 pub struct CompressedResponse {
-    identity: Response<Bytes>,
-    gzip: Option<Bytes>,
-    br: Option<Bytes>,
+    identity: Arc<Response<Bytes>>,
+    gzip: CompressedBody,
+    br: CompressedBody,
+    zstd: CompressedBody,
 
     compress: CompressPreference,
+    levels: CompressionLevels,
 }
 impl CompressedResponse {
     pub(crate) fn new(
@@ -187,17 +264,39 @@ impl CompressedResponse {
         client_cache: ClientCachePreference,
         extension: &str,
         disable_client_cache: bool,
+        levels: CompressionLevels,
     ) -> Self {
         let headers = identity.headers_mut();
         Self::set_client_cache(headers, client_cache, disable_client_cache);
         Self::add_server_header(headers);
         Self::check_content_type(&mut identity, extension);
+        Self::add_etag(&mut identity);
         Self {
-            identity,
-            gzip: None,
-            br: None,
+            identity: Arc::new(identity),
+            gzip: CompressedBody::default(),
+            br: CompressedBody::default(),
+            zstd: CompressedBody::default(),
 
             compress,
+            levels,
+        }
+    }
+    /// Uses the bodies of `precompressed` instead of compressing [`Self::get_identity`].
+    ///
+    /// The bodies have to be compressed versions of the identity body.
+    /// They are used even if the `content-type` isn't one which is usually compressed,
+    /// but never if the [`CompressPreference`] is [`CompressPreference::None`].
+    pub(crate) fn set_precompressed(&mut self, precompressed: Precompressed) {
+        let bodies = [
+            (&self.gzip, precompressed.gzip),
+            (&self.br, precompressed.br),
+            (&self.zstd, precompressed.zstd),
+        ];
+        for (body, precompressed) in bodies.iter().cloned() {
+            if let Some(precompressed) = precompressed {
+                // If it's already set, it's a compressed version of the same body.
+                drop(body.set(precompressed));
+            }
         }
     }
     /// Gets the response with an uncompressed body.
@@ -210,8 +309,18 @@ impl CompressedResponse {
     /// `accept-encoding` header in `request`
     /// and already cached bodies.
     ///
+    /// The encoding with the highest quality (`q`) is chosen.
+    /// Encodings which aren't listed aren't used, unless `*` is;
+    /// `identity` is acceptable unless it's given a quality of `0`,
+    /// but if it isn't listed, any other acceptable encoding is preferred.
+    /// Of equally preferred encodings, already compressed bodies are chosen first,
+    /// then Zstandard, Brotli, gzip, and lastly identity.
+    ///
     /// If an error occurs, you should respond with an [`StatusCode::NOT_ACCEPTABLE`].
     ///
+    /// The body is compressed on the current thread if it isn't already.
+    /// In async code, use [`Self::clone_preferred_nonblocking`] instead.
+    ///
     /// # Errors
     ///
     /// May return a &str to be used to inform the client what error occurred in content negotiation.
@@ -219,6 +328,93 @@ impl CompressedResponse {
         &self,
         request: &Request<T>,
     ) -> Result<Response<Bytes>, &'static str> {
+        let compression = self.preferred_encoding(request)?;
+        let bytes = match compression {
+            #[cfg(feature = "zstd")]
+            "zstd" => self.get_zstd(),
+            #[cfg(feature = "br")]
+            "br" => self.get_br(),
+            #[cfg(feature = "gzip")]
+            "gzip" => self.get_gzip(),
+            _ => self.get_identity().body(),
+        };
+        Ok(self.clone_identity_set_compression(
+            Bytes::clone(bytes),
+            HeaderValue::from_static(compression),
+        ))
+    }
+    /// Clones the preferred compression type, as [`Self::clone_preferred`] does,
+    /// but compresses on the blocking thread pool instead of the current thread.
+    ///
+    /// Concurrent calls wait for the same compression to finish.
+    /// If `identity_meanwhile` is `true`, the compression continues in the background,
+    /// and the uncompressed response is returned if the body isn't yet compressed.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::clone_preferred`].
+    pub async fn clone_preferred_nonblocking<T>(
+        &self,
+        request: &Request<T>,
+        identity_meanwhile: bool,
+    ) -> Result<Response<Bytes>, &'static str> {
+        let compression = self.preferred_encoding(request)?;
+        let body: Option<&CompressedBody> = match compression {
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(&self.zstd),
+            #[cfg(feature = "br")]
+            "br" => Some(&self.br),
+            #[cfg(feature = "gzip")]
+            "gzip" => Some(&self.gzip),
+            _ => None,
+        };
+        #[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+        if let Some(body) = body {
+            if let Some(bytes) = body.get() {
+                return Ok(self.clone_identity_set_compression(
+                    Bytes::clone(bytes),
+                    HeaderValue::from_static(compression),
+                ));
+            }
+            let compressing = {
+                let body = Arc::clone(body);
+                let identity = Arc::clone(&self.identity);
+                let levels = self.levels;
+                async move {
+                    body.get_or_try_init(|| {
+                        tokio::task::spawn_blocking(move || {
+                            compress(compression, identity.body(), levels)
+                        })
+                    })
+                    .await
+                    .cloned()
+                }
+            };
+            if identity_meanwhile {
+                tokio::spawn(compressing);
+            } else {
+                match compressing.await {
+                    Ok(bytes) => {
+                        return Ok(self.clone_identity_set_compression(
+                            bytes,
+                            HeaderValue::from_static(compression),
+                        ))
+                    }
+                    Err(err) => error!("Failed to compress using {}: {:?}", compression, err),
+                }
+            }
+        }
+        #[cfg(not(any(feature = "gzip", feature = "br", feature = "zstd")))]
+        let _ = (body, identity_meanwhile);
+        Ok(self.clone_identity_set_compression(
+            Bytes::clone(self.get_identity().body()),
+            HeaderValue::from_static("identity"),
+        ))
+    }
+    /// Gets the name of the preferred encoding of the client sending `request`.
+    ///
+    /// See [`Self::clone_preferred`].
+    fn preferred_encoding<T>(&self, request: &Request<T>) -> Result<&'static str, &'static str> {
         let values = match request
             .headers()
             .get("accept-encoding")
@@ -228,102 +424,95 @@ impl CompressedResponse {
             Some(header) => utils::list_header(header),
             None => Vec::new(),
         };
-
-        let disable_identity = values
-            .iter()
-            .any(|v| v.value == "identity" && v.quality == 0.0);
-
-        #[cfg(all(feature = "gzip", feature = "br"))]
-        let prefer_br = values
-            .iter()
-            .find(|v| v.value == "gzip")
-            .map_or(0.0, |v| v.quality)
-            <= 0.5
-            && values.iter().find_map(|v| {
-                if v.value == "br" {
-                    Some(v.quality)
-                } else {
-                    None
-                }
-            }) == Some(1.0);
-
-        let only_identity = values.len() == 1
-            && values[0]
-                == utils::ValueQualitySet {
-                    value: "identity",
-                    quality: 1.0,
-                };
-
-        // Only identity makes sure identity quality is `1.0`; identity encoding can't be disabled.
-        if only_identity {
-            return Ok(self.clone_identity_set_compression(
-                Bytes::clone(self.get_identity().body()),
-                HeaderValue::from_static("identity"),
-            ));
-        }
-
-        #[cfg(any(feature = "gzip", feature = "br"))]
-        let contains = |name| values.iter().any(|v| v.value == name && v.quality != 0.0);
+        let quality = |name: &str| {
+            values
+                .iter()
+                .find(|v| v.value.eq_ignore_ascii_case(name))
+                .or_else(|| values.iter().find(|v| v.value == "*"))
+                .map(|v| v.quality)
+        };
+        // `identity` is always acceptable, but other encodings are preferred unless it's listed.
+        let identity_quality = quality("identity").unwrap_or(f32::MIN_POSITIVE);
 
         let mime = self
             .get_identity()
             .headers()
             .get("content-type")
             .and_then(|header| header.to_str().ok())
-            .and_then(|header| header.parse().ok());
+            .and_then(|header| header.parse::<Mime>().ok());
         debug!("Recognised mime {:?}", &mime);
-        let (bytes, compression) = match &mime {
-            Some(mime) => {
-                if do_compress(mime, || str::from_utf8(self.get_identity().body()).is_ok()) {
-                    // You are wrong. We have `true if ...`.
-                    #[allow(clippy::match_bool)]
-                    match self.compress {
-                        CompressPreference::None => (self.get_identity().body(), "identity"),
-                        CompressPreference::Full => {
-                            #[cfg(all(feature = "gzip", feature = "br"))]
-                            match (self.br.is_some() && self.gzip.is_none()) || prefer_br {
-                                true if contains("br") => (self.get_br(), "br"),
-                                true if contains("gzip") => (self.get_gzip(), "gzip"),
-                                false if contains("gzip") => (self.get_gzip(), "gzip"),
-                                false if contains("br") => (self.get_br(), "br"),
-                                _ => (self.get_identity().body(), "identity"),
-                            }
-                            #[cfg(all(feature = "gzip", not(feature = "br")))]
-                            {
-                                match contains("gzip") {
-                                    true => (self.get_gzip(), "gzip"),
-                                    false => (self.get_identity().body(), "identity"),
-                                }
-                            }
-                            #[cfg(all(feature = "br", not(feature = "gzip")))]
-                            {
-                                match contains("br") {
-                                    true => (self.get_br(), "br"),
-                                    false => (self.get_identity().body(), "identity"),
-                                }
-                            }
-                            #[cfg(not(any(feature = "gzip", feature = "br")))]
-                            {
-                                (self.get_identity().body(), "identity")
-                            }
-                        }
-                    }
-                } else {
-                    debug!("Not compressing; filtered out.");
-                    (self.get_identity().body(), "identity")
-                }
-            }
-            None => (self.get_identity().body(), "identity"),
+        let compress = match self.compress {
+            CompressPreference::None => false,
+            CompressPreference::Full => mime.map_or(false, |mime| {
+                let compressed =
+                    self.gzip.initialized() || self.br.initialized() || self.zstd.initialized();
+                compressed
+                    || do_compress(&mime, || str::from_utf8(self.get_identity().body()).is_ok())
+            }),
         };
-        if disable_identity && compression == "identity" {
-            return Err(
-                "identity compression is the only option, but the client refused to accept it",
-            );
+        if !compress {
+            debug!("Not compressing; filtered out.");
         }
-        Ok(self.clone_identity_set_compression(
-            Bytes::clone(bytes),
-            HeaderValue::from_static(compression),
-        ))
+
+        // The encodings we can provide and if they are already compressed, in order of preference.
+        let encodings: &[(&'static str, bool)] = &[
+            #[cfg(feature = "zstd")]
+            ("zstd", self.zstd.initialized()),
+            #[cfg(feature = "br")]
+            ("br", self.br.initialized()),
+            #[cfg(feature = "gzip")]
+            ("gzip", self.gzip.initialized()),
+        ];
+        let mut best: Option<(f32, bool, &'static str)> = None;
+        for &(name, compressed) in encodings.iter().filter(|_| compress) {
+            let quality = match quality(name) {
+                Some(quality) if quality > 0.0 => quality,
+                _ => continue,
+            };
+            let better = best.map_or(true, |(best_quality, best_compressed, _)| {
+                (quality, compressed) > (best_quality, best_compressed)
+            });
+            if better {
+                best = Some((quality, compressed, name));
+            }
+        }
+        match best {
+            Some((quality, _, name)) if quality >= identity_quality => Ok(name),
+            _ if identity_quality > 0.0 => Ok("identity"),
+            _ => Err("no encoding is acceptable to the client, not even identity"),
+        }
+    }
+
+    /// Adds a strong `etag` derived from the identity body, if no `etag` is already set.
+    ///
+    /// Empty bodies (which may be streamed) and unsuccessful responses don't get one.
+    fn add_etag(response: &mut Response<Bytes>) {
+        if response.body().is_empty()
+            || !response.status().is_success()
+            || response.headers().contains_key("etag")
+        {
+            return;
+        }
+        // The hash has to be stable, so the tags stay the same across restarts and releases.
+        let digest = ring::digest::digest(&ring::digest::SHA256, response.body());
+        let mut etag = String::with_capacity(52);
+        etag.push('"');
+        for byte in &digest.as_ref()[..16] {
+            write!(etag, "{:02x}", byte).unwrap();
+        }
+        write!(etag, "-{:x}\"", response.body().len()).unwrap();
+        // Hex digits and `"` are valid.
+        let etag = HeaderValue::from_str(&etag).unwrap();
+        response.headers_mut().insert("etag", etag);
+    }
+    /// Gets the `etag` of the identity response.
+    ///
+    /// Compressed responses get the encoding appended to the entity tag,
+    /// as they are different representations.
+    #[inline]
+    #[must_use]
+    pub fn etag(&self) -> Option<&HeaderValue> {
+        self.identity.headers().get("etag")
     }
 
     #[inline]
@@ -354,7 +543,10 @@ impl CompressedResponse {
             .unwrap();
             utils::replace_header(headers, "content-type", content_type);
         }
-        let utf_8 = response.body().len() < 16 * 1024 && str::from_utf8(&response.body()).is_ok();
+        // An empty body is probably streamed (see [`crate::read::FileBody`]); we know nothing of it's encoding.
+        let utf_8 = !response.body().is_empty()
+            && response.body().len() < 16 * 1024
+            && str::from_utf8(&response.body()).is_ok();
 
         // Looks a lot better.
         #[allow(clippy::single_match_else)]
@@ -415,6 +607,21 @@ impl CompressedResponse {
             headers.get("content-encoding"),
             headers.get("content-type"),
         );
+        if compression != "identity" {
+            // Strong entity tags have to differ between encodings.
+            let etag = headers
+                .get("etag")
+                .map(HeaderValue::as_bytes)
+                .filter(|etag| !etag.starts_with(b"W/") && etag.ends_with(b"\""))
+                .map(|etag| {
+                    build_bytes!(&etag[..etag.len() - 1], b"-", compression.as_bytes(), b"\"")
+                });
+            if let Some(etag) = etag {
+                // The old entity tag and the encoding are valid.
+                let etag = HeaderValue::from_maybe_shared(etag).unwrap();
+                utils::replace_header(headers, "etag", etag);
+            }
+        }
         utils::replace_header(headers, "content-encoding", compression);
         *builder.headers_mut().unwrap() = map;
         builder.body(new_data).unwrap()
@@ -426,25 +633,7 @@ impl CompressedResponse {
     /// as it is available with any set of features
     #[cfg(feature = "gzip")]
     pub fn get_gzip(&self) -> &Bytes {
-        if self.gzip.is_none() {
-            let bytes = self.identity.body().as_ref();
-
-            let mut buffer = utils::WriteableBytes::with_capacity(bytes.len() / 2 + 64);
-
-            let mut c = flate2::write::GzEncoder::new(&mut buffer, flate2::Compression::fast());
-            c.write_all(bytes).expect("Failed to compress using gzip!");
-            c.finish().expect("Failed to compress using gzip!");
-
-            let buffer = buffer.into_inner();
-            let buffer = buffer.freeze();
-
-            if self.gzip.is_none() {
-                // maybe shooting myself in the foot...
-                // but should be OK, since we only set it once, otherwise it's None.
-                unsafe { (&mut *{ &self.gzip as *const _ as *mut Option<Bytes> }).replace(buffer) };
-            }
-        }
-        self.gzip.as_ref().unwrap()
+        self.get_or_compress(&self.gzip, "gzip")
     }
     /// Gets the Brotli compressed version of [`CompressedResponse::get_identity()`]
     ///
@@ -452,27 +641,216 @@ impl CompressedResponse {
     /// as it is available with any set of features
     #[cfg(feature = "br")]
     pub fn get_br(&self) -> &Bytes {
-        if self.br.is_none() {
-            let bytes = self.identity.body().as_ref();
+        self.get_or_compress(&self.br, "br")
+    }
+    /// Gets the Zstandard compressed version of [`CompressedResponse::get_identity()`]
+    ///
+    /// You should use [`Self::clone_preferred`] to get the preferred compression instead,
+    /// as it is available with any set of features
+    #[cfg(feature = "zstd")]
+    pub fn get_zstd(&self) -> &Bytes {
+        self.get_or_compress(&self.zstd, "zstd")
+    }
+    #[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+    fn get_or_compress<'a>(&'a self, body: &'a CompressedBody, encoding: &str) -> &'a Bytes {
+        if let Some(bytes) = body.get() {
+            return bytes;
+        }
+        let bytes = compress(encoding, self.identity.body(), self.levels);
+        // If another thread compressed it meanwhile, the bodies are equal.
+        drop(body.set(bytes));
+        body.get().unwrap()
+    }
+}
 
-            let mut buffer = utils::WriteableBytes::with_capacity(bytes.len() / 2 + 64);
+/// Compresses `bytes` using `encoding`; `gzip`, `br`, or `zstd`.
+///
+/// # Panics
+///
+/// Panics if the `encoding` isn't one of the above, or if it's feature isn't enabled.
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+fn compress(encoding: &str, bytes: &[u8], levels: CompressionLevels) -> Bytes {
+    let mut buffer = utils::WriteableBytes::with_capacity(bytes.len() / 2 + 64);
 
-            let mut c = brotli::CompressorWriter::new(&mut buffer, 4096, 8, 21);
+    match encoding {
+        #[cfg(feature = "gzip")]
+        "gzip" => {
+            let level = flate2::Compression::new(levels.gzip.min(9));
+            let mut c = flate2::write::GzEncoder::new(&mut buffer, level);
+            c.write_all(bytes).expect("Failed to compress using gzip!");
+            c.finish().expect("Failed to compress using gzip!");
+        }
+        #[cfg(feature = "br")]
+        "br" => {
+            let quality = levels.br.min(11);
+            let mut c = brotli::CompressorWriter::new(&mut buffer, 4096, quality, 21);
             c.write_all(bytes)
                 .expect("Failed to compress using Brotli!");
             c.flush().expect("Failed to compress using Brotli!");
             c.into_inner();
+        }
+        #[cfg(feature = "zstd")]
+        "zstd" => {
+            let range = zstd::compression_level_range();
+            let level = levels.zstd.max(*range.start()).min(*range.end());
+            let mut c = zstd::Encoder::new(&mut buffer, level)
+                .expect("Failed to compress using Zstandard!");
+            c.write_all(bytes)
+                .expect("Failed to compress using Zstandard!");
+            c.finish().expect("Failed to compress using Zstandard!");
+        }
+        _ => panic!("Can't compress using {}", encoding),
+    }
 
-            let buffer = buffer.into_inner();
-            let buffer = buffer.freeze();
+    buffer.into_inner().freeze()
+}
+
+/// The maximum number of variants stored in a single [`VariedResponse`].
+///
+/// When this is reached, the oldest variant is discarded.
+/// This keeps responses varying on e.g. `cookie` from filling the [`ResponseCache`].
+pub const MAX_VARIANTS: usize = 32;
 
-            if self.br.is_none() {
-                // maybe shooting myself in the foot...
-                // but should be OK, since we only set it once, otherwise it's None.
-                unsafe { (&mut *{ &self.br as *const _ as *mut Option<Bytes> }).replace(buffer) };
+/// A set of [`CompressedResponse`]s to the same [`UriKey`],
+/// varying on the request headers named in the `vary` header of the response.
+///
+/// `accept-encoding` is ignored, as [`CompressedResponse`] already handles it.
+/// A response with `vary: *` is never cached.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct VariedResponse {
+    vary: Vec<HeaderName>,
+    /// The values of the headers in `vary` the response was generated with, and the response.
+    variants: Vec<(Vec<Vec<HeaderValue>>, CompressedResponse)>,
+}
+impl VariedResponse {
+    /// Creates a new set of varied responses with `response` as the first variant.
+    ///
+    /// `request_headers` are the headers of the request `response` was generated from.
+    ///
+    /// Returns [`None`] if the `vary` header of `response` contains `*`;
+    /// the response varies on things other than request headers and can't be cached.
+    pub fn new(response: CompressedResponse, request_headers: &HeaderMap) -> Option<Self> {
+        let vary = Self::vary_of(response.get_identity().headers())?;
+        let key = Self::variant_key(&vary, request_headers);
+        Some(Self {
+            vary,
+            variants: vec![(key, response)],
+        })
+    }
+    /// Parses the `vary` header of `headers`.
+    ///
+    /// Returns [`None`] if the value contains `*`.
+    fn vary_of(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+        let mut vary = Vec::new();
+        for value in headers.get_all("vary") {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                // Treat unknown bytes as a wildcard.
+                Err(_) => return None,
+            };
+            for name in value.split(',').map(str::trim) {
+                if name == "*" {
+                    return None;
+                }
+                // `accept-encoding` is handled by `CompressedResponse`
+                if name.is_empty() || name.eq_ignore_ascii_case("accept-encoding") {
+                    continue;
+                }
+                // `HeaderName`s are lowercase, so the comparison is case-insensitive.
+                if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                    if !vary.contains(&name) {
+                        vary.push(name);
+                    }
+                }
+            }
+        }
+        Some(vary)
+    }
+    fn variant_key(vary: &[HeaderName], request_headers: &HeaderMap) -> Vec<Vec<HeaderValue>> {
+        vary.iter()
+            .map(|name| request_headers.get_all(name).iter().cloned().collect())
+            .collect()
+    }
+    /// Gets the request header names the responses vary on.
+    #[inline]
+    #[must_use]
+    pub fn vary(&self) -> &[HeaderName] {
+        &self.vary
+    }
+    /// Gets the variant matching `request_headers`, if any.
+    #[must_use]
+    pub fn get_by_headers(&self, request_headers: &HeaderMap) -> Option<&CompressedResponse> {
+        self.variants.iter().find_map(|(key, response)| {
+            let matches = self
+                .vary
+                .iter()
+                .zip(key.iter())
+                .all(|(name, values)| request_headers.get_all(name).iter().eq(values.iter()));
+            if matches {
+                Some(response)
+            } else {
+                None
             }
+        })
+    }
+    /// Gets the first variant.
+    ///
+    /// If the responses don't vary on any headers, this is the only variant.
+    #[inline]
+    pub fn first(&self) -> &CompressedResponse {
+        // There's always at least one variant; it's created with one
+        // and none are removed without pushing another.
+        &self.variants[0].1
+    }
+    /// Adds `response` as a variant for requests with `request_headers`.
+    ///
+    /// Returns the `response` if the `vary` header of it differs from the one of `self`.
+    /// Then, you should create a new [`VariedResponse`].
+    ///
+    /// # Errors
+    ///
+    /// See above. Returns `response` if it can't be added to `self`.
+    pub fn push(
+        &mut self,
+        response: CompressedResponse,
+        request_headers: &HeaderMap,
+    ) -> Result<(), CompressedResponse> {
+        match Self::vary_of(response.get_identity().headers()) {
+            Some(vary) if vary == self.vary => {}
+            _ => return Err(response),
+        }
+        let key = Self::variant_key(&self.vary, request_headers);
+        if let Some(variant) = self.variants.iter_mut().find(|(k, _)| k == &key) {
+            variant.1 = response;
+            return Ok(());
         }
-        self.br.as_ref().unwrap()
+        if self.variants.len() >= MAX_VARIANTS {
+            // The oldest variant is discarded.
+            let _evicted = self.variants.remove(0);
+        }
+        self.variants.push((key, response));
+        Ok(())
+    }
+    /// Gets the number of variants.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.variants.len()
+    }
+    /// If there are no variants. This is never the case for a [`VariedResponse`] in a [`Cache`].
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.variants.is_empty()
+    }
+    /// Gets the total length of the identity bodies of all the variants.
+    #[must_use]
+    pub fn identity_len(&self) -> usize {
+        self.variants
+            .iter()
+            .map(|(_, response)| response.get_identity().body().len())
+            .sum()
     }
 }
 
@@ -650,10 +1028,142 @@ impl<V> CacheOut<V> {
 /// A `Duration` value of `None` means the item will never expire.
 pub type CacheItem<T> = (T, (DateTime<Utc>, Option<Duration>));
 
+/// The number of hits, misses, and evictions of a [`Cache`].
+///
+/// Hits and misses are recorded by the parts of Kvarn looking up items,
+/// as [`Cache::get`] can be used to only peek at items.
+/// Get them using [`Cache::stats`]; the counters can be read without locking the cache.
+/// The shards of a [`ShardedCache`] share one [`CacheStats`].
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: threading::atomic::AtomicU64,
+    misses: threading::atomic::AtomicU64,
+    evictions: threading::atomic::AtomicU64,
+    size: threading::atomic::AtomicU64,
+}
+impl CacheStats {
+    /// The number of lookups which found the item.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(threading::Ordering::Relaxed)
+    }
+    /// The number of lookups which didn't find the item.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(threading::Ordering::Relaxed)
+    }
+    /// The number of items discarded to make room for new ones.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(threading::Ordering::Relaxed)
+    }
+    /// The total size of the items in the cache, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size.load(threading::Ordering::Relaxed)
+    }
+    /// Records a lookup, which was a hit if `hit` is true.
+    pub(crate) fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, threading::Ordering::Relaxed);
+    }
+}
+
+/// The number of bytes a value occupies in a [`Cache`].
+///
+/// This is used to keep the [`Cache`] within it's size limits.
+pub trait CacheSize {
+    /// The approximate number of bytes of `self`, including data it owns on the heap.
+    fn cache_size(&self) -> usize;
+}
+impl CacheSize for Bytes {
+    #[inline]
+    fn cache_size(&self) -> usize {
+        self.len()
+    }
+}
+impl CacheSize for CompressedResponse {
+    /// The identity and compressed bodies and the headers.
+    fn cache_size(&self) -> usize {
+        let headers: usize = self
+            .identity
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let compressed: usize = [&self.gzip, &self.br, &self.zstd]
+            .iter()
+            .filter_map(|body| body.get())
+            .map(Bytes::len)
+            .sum();
+        self.identity.body().len() + compressed + headers
+    }
+}
+impl CacheSize for VariedResponse {
+    fn cache_size(&self) -> usize {
+        self.variants
+            .iter()
+            .map(|(key, response)| {
+                let key: usize = key.iter().flatten().map(HeaderValue::len).sum();
+                key + response.cache_size()
+            })
+            .sum()
+    }
+}
+
+/// Which item a [`Cache`] discards when it's full.
+///
+/// Both policies choose the item in constant time.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum EvictionPolicy {
+    /// Discards the least recently used item.
+    ///
+    /// This is the default.
+    Lru,
+    /// Discards the least frequently used item.
+    /// Of equally used items, the least recently used is discarded.
+    ///
+    /// This keeps popular items, but items which were popular long ago are also kept.
+    Lfu,
+}
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        Self::Lru
+    }
+}
+
+/// The index of no entry or bucket.
+const NIL: usize = usize::MAX;
+
+/// An item in a [`Cache`] and it's position in the eviction order.
+#[derive(Debug)]
+struct Entry<K, V> {
+    key: K,
+    item: CacheItem<V>,
+    size: usize,
+    bucket: usize,
+    /// The more recently used entry in the bucket.
+    prev: usize,
+    /// The less recently used entry in the bucket.
+    next: usize,
+}
+/// The entries of a [`Cache`] which have been used `uses` times, most recently used first.
+///
+/// With [`EvictionPolicy::Lru`], all entries are in one bucket.
+/// The buckets are ordered by `uses`.
+#[derive(Debug)]
+struct Bucket {
+    uses: u64,
+    head: usize,
+    tail: usize,
+    prev: usize,
+    next: usize,
+}
+
 /// A general cache with size and item count limits.
 ///
-/// When size limit is reached, a pseudo-random element is removed and
-/// the new one inserted. See [`Cache::discard_one`].
+/// When a limit is reached, items are discarded as chosen by the [`EvictionPolicy`].
+/// See [`Cache::discard_one`].
+///
+/// The size of the items is measured using [`CacheSize`].
+/// Items can grow while in the cache (e.g. when a [`CompressedResponse`] is compressed),
+/// so they are measured again when they're used.
 ///
 /// The insert method, `Cache::cache`, has type-specific implementations.
 /// This enables clever inserting of data, independently from this struct.
@@ -661,193 +1171,1128 @@ pub type CacheItem<T> = (T, (DateTime<Utc>, Option<Duration>));
 /// those implementations of this struct.
 #[derive(Debug)]
 #[must_use]
-pub struct Cache<K, V, H = DefaultHasher> {
-    map: HashMap<K, CacheItem<V>>,
+pub struct Cache<K, V> {
+    map: HashMap<K, usize>,
+    entries: Vec<Option<Entry<K, V>>>,
+    free_entries: Vec<usize>,
+    buckets: Vec<Bucket>,
+    free_buckets: Vec<usize>,
+    /// The bucket of the least used entries.
+    first_bucket: usize,
+    policy: EvictionPolicy,
     max_items: usize,
     size_limit: usize,
-    inserts: usize,
-    hasher: H,
+    max_size: usize,
+    size: usize,
+    stats: Arc<CacheStats>,
 }
-impl<K, V> Cache<K, V, DefaultHasher> {
-    /// Creates a new [`Cache`]. See [`Cache`] and [`CacheOut`]
-    /// for more info about what the parameters do.
+impl<K, V> Cache<K, V> {
+    /// The default maximum number of items.
+    pub const DEFAULT_MAX_ITEMS: usize = 1024;
+    /// The default maximum size of one item, 4 MiB.
+    pub const DEFAULT_SIZE_LIMIT: usize = 4 * 1024 * 1024;
+    /// The default maximum size of all items, 256 MiB.
+    pub const DEFAULT_MAX_SIZE: usize = 256 * 1024 * 1024;
+
+    /// Creates a new [`Cache`] holding at most `max_items`
+    /// which are smaller than `size_limit` bytes.
+    ///
+    /// The total size is limited to [`Self::DEFAULT_MAX_SIZE`],
+    /// and [`EvictionPolicy::Lru`] is used.
+    /// Use [`Self::with_max_size`] and [`Self::with_eviction_policy`] to change them.
     #[inline]
     pub fn new(max_items: usize, size_limit: usize) -> Self {
         Self {
             map: HashMap::new(),
+            entries: Vec::new(),
+            free_entries: Vec::new(),
+            buckets: Vec::new(),
+            free_buckets: Vec::new(),
+            first_bucket: NIL,
+            policy: EvictionPolicy::default(),
             max_items,
             size_limit,
-            inserts: 0,
-            hasher: DefaultHasher::new(),
+            max_size: Self::DEFAULT_MAX_SIZE,
+            size: 0,
+            stats: Arc::new(CacheStats::default()),
         }
     }
     /// Creates a new [`Cache`] with `size_limit` and default `max_items`.
     #[inline]
     pub fn with_size_limit(size_limit: usize) -> Self {
-        Self::new(1024, size_limit)
+        Self::new(Self::DEFAULT_MAX_ITEMS, size_limit)
+    }
+    /// Limits the total size of all items to `bytes`.
+    #[inline]
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        self
+    }
+    /// Sets the [`EvictionPolicy`].
+    ///
+    /// This clears the cache.
+    #[inline]
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.clear();
+        self.policy = policy;
+        self
     }
     /// Clears the cache.
     #[inline]
     pub fn clear(&mut self) {
-        self.map.clear()
-    }
-}
-impl<K, V> Default for Cache<K, V> {
-    fn default() -> Self {
-        Self::new(1024, 4 * 1024 * 1024) // 4MiB
+        self.map.clear();
+        self.entries.clear();
+        self.free_entries.clear();
+        self.buckets.clear();
+        self.free_buckets.clear();
+        self.first_bucket = NIL;
+        self.set_size(0);
     }
-}
-impl<K: Eq + Hash, V, H> Cache<K, V, H> {
-    /// Get value at `key` from the cache.
-    ///
-    /// See [`HashMap::get`] for more info.
+    /// Gets the [`CacheStats`] of this cache.
     #[inline]
-    pub fn get<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<&V>
-    where
-        K: Borrow<Q>,
-    {
-        self.get_with_lifetime(key).map(|v| &v.0)
+    #[must_use]
+    pub fn stats(&self) -> &Arc<CacheStats> {
+        &self.stats
     }
-    /// Gets the [`CacheItem`] at `key` from the cache.
-    /// Consider using [`Self::get`] for most operations.
-    ///
-    /// This includes all lifetime information about the item in the cache.
-    /// See [`CacheItem`] for more info about this.
-    pub fn get_with_lifetime<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<&CacheItem<V>>
-    where
-        K: Borrow<Q>,
-    {
-        // maybe set tokio timers to remove items instead?
-        match self.map.get(key) {
-            Some(value_and_lifetime)
-                if value_and_lifetime.1 .1.map_or(true, |lifetime| {
-                    Utc::now() - value_and_lifetime.1 .0 <= lifetime
-                }) =>
-            {
-                CacheOut::Present(value_and_lifetime)
-            }
-            Some(_) => {
-                // SAFETY: No other have a reference to self; the other branches are just that,
-                // other branches, and their references are returned, so this code isn't ran.
-                #[allow(clippy::cast_ref_to_mut)]
-                unsafe { &mut *(self as *const _ as *mut Cache<K, V>) }.remove(key);
-                CacheOut::None
-            }
-            None => CacheOut::None,
-        }
+    /// The total size of all items, in bytes.
+    #[inline]
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
     }
-    /// Returns `true` if the cache contains `key`.
-    ///
-    /// See [`HashMap::contains_key`] for more info.
+    /// The number of items.
     #[inline]
-    pub fn contains<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
-    where
-        K: Borrow<Q>,
-    {
-        self.map.contains_key(key)
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.map.len()
     }
-    /// Removes a key-value pair from the cache, returning the value, if present.
-    ///
-    /// See [`HashMap::remove`] and [`CacheOut::Present`].
+    /// If the cache is empty.
     #[inline]
-    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<V>
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    /// The [`EvictionPolicy`] of the cache.
+    #[inline]
+    #[must_use]
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Makes this cache report to `stats`, which can be shared with other caches.
+    ///
+    /// Only call this when the cache is empty.
+    fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+    fn set_size(&mut self, size: usize) {
+        // The stats may be shared, so only the change is applied.
+        if size > self.size {
+            self.stats
+                .size
+                .fetch_add((size - self.size) as u64, threading::Ordering::Relaxed);
+        } else {
+            self.stats
+                .size
+                .fetch_sub((self.size - size) as u64, threading::Ordering::Relaxed);
+        }
+        self.size = size;
+    }
+    fn entry(&self, index: usize) -> &Entry<K, V> {
+        // The indices in the map and lists always point to entries.
+        self.entries[index].as_ref().unwrap()
+    }
+    fn entry_mut(&mut self, index: usize) -> &mut Entry<K, V> {
+        self.entries[index].as_mut().unwrap()
+    }
+    /// Creates a bucket between `prev` and `next`.
+    fn add_bucket(&mut self, uses: u64, prev: usize, next: usize) -> usize {
+        let bucket = Bucket {
+            uses,
+            head: NIL,
+            tail: NIL,
+            prev,
+            next,
+        };
+        let index = if let Some(index) = self.free_buckets.pop() {
+            self.buckets[index] = bucket;
+            index
+        } else {
+            self.buckets.push(bucket);
+            self.buckets.len() - 1
+        };
+        if prev == NIL {
+            self.first_bucket = index;
+        } else {
+            self.buckets[prev].next = index;
+        }
+        if next != NIL {
+            self.buckets[next].prev = index;
+        }
+        index
+    }
+    /// Removes `bucket` if it's empty.
+    fn remove_bucket_if_empty(&mut self, bucket: usize) {
+        let Bucket {
+            head, prev, next, ..
+        } = self.buckets[bucket];
+        if head != NIL {
+            return;
+        }
+        if prev == NIL {
+            self.first_bucket = next;
+        } else {
+            self.buckets[prev].next = next;
+        }
+        if next != NIL {
+            self.buckets[next].prev = prev;
+        }
+        self.free_buckets.push(bucket);
+    }
+    /// Puts the entry at `index` first in `bucket`.
+    fn link(&mut self, index: usize, bucket: usize) {
+        let head = self.buckets[bucket].head;
+        let entry = self.entry_mut(index);
+        entry.bucket = bucket;
+        entry.prev = NIL;
+        entry.next = head;
+        if head == NIL {
+            self.buckets[bucket].tail = index;
+        } else {
+            self.entry_mut(head).prev = index;
+        }
+        self.buckets[bucket].head = index;
+    }
+    /// Removes the entry at `index` from it's bucket, which may be left empty.
+    fn unlink(&mut self, index: usize) {
+        let Entry {
+            bucket, prev, next, ..
+        } = *self.entry(index);
+        if prev == NIL {
+            self.buckets[bucket].head = next;
+        } else {
+            self.entry_mut(prev).next = next;
+        }
+        if next == NIL {
+            self.buckets[bucket].tail = prev;
+        } else {
+            self.entry_mut(next).prev = prev;
+        }
+    }
+    /// Moves the entry at `index` to where the [`EvictionPolicy`] puts used entries.
+    fn touch(&mut self, index: usize) {
+        let bucket = self.entry(index).bucket;
+        let target = match self.policy {
+            EvictionPolicy::Lru => bucket,
+            EvictionPolicy::Lfu => {
+                let uses = self.buckets[bucket].uses + 1;
+                let next = self.buckets[bucket].next;
+                if next != NIL && self.buckets[next].uses == uses {
+                    next
+                } else {
+                    self.add_bucket(uses, bucket, next)
+                }
+            }
+        };
+        self.unlink(index);
+        self.link(index, target);
+        self.remove_bucket_if_empty(bucket);
+    }
+    /// Gets the entry which should be discarded first, other than `keep`.
+    fn victim(&self, keep: usize) -> Option<usize> {
+        let mut bucket = self.first_bucket;
+        while bucket != NIL {
+            let tail = self.buckets[bucket].tail;
+            if tail != keep {
+                return Some(tail);
+            }
+            // `keep` is the only entry, or the least used in this bucket.
+            let prev = self.entry(tail).prev;
+            if prev != NIL {
+                return Some(prev);
+            }
+            bucket = self.buckets[bucket].next;
+        }
+        None
+    }
+}
+impl<K: Eq + Hash, V> Cache<K, V> {
+    /// Removes the entry at `index`.
+    fn remove_index(&mut self, index: usize) -> CacheItem<V> {
+        let bucket = self.entry(index).bucket;
+        self.unlink(index);
+        self.remove_bucket_if_empty(bucket);
+        // The index is taken from the map or the lists.
+        let entry = self.entries[index].take().unwrap();
+        self.free_entries.push(index);
+        self.map.remove(&entry.key);
+        self.set_size(self.size - entry.size);
+        entry.item
+    }
+    /// Discards items, but not the one at `keep`, until the size and item limits allow `incoming`
+    /// more items with a total of `incoming_size` bytes.
+    fn shrink(&mut self, keep: usize, incoming: usize, incoming_size: usize) {
+        while self.map.len() + incoming > self.max_items
+            || self.size + incoming_size > self.max_size
+        {
+            match self.victim(keep) {
+                Some(index) => {
+                    self.remove_index(index);
+                    self.stats
+                        .evictions
+                        .fetch_add(1, threading::Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+    }
+    /// Discards one item, chosen by the [`EvictionPolicy`].
+    pub fn discard_one(&mut self) {
+        if let Some(index) = self.victim(NIL) {
+            self.remove_index(index);
+            self.stats
+                .evictions
+                .fetch_add(1, threading::Ordering::Relaxed);
+        }
+    }
+    /// Returns `true` if the cache contains `key`.
+    ///
+    /// See [`HashMap::contains_key`] for more info.
+    #[inline]
+    pub fn contains<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
     {
-        match self.map.remove(key) {
-            Some((item, _expiry)) => CacheOut::Present(item),
-            None => CacheOut::None,
-        }
+        self.map.contains_key(key)
     }
-    /// Inserts a `value` at `key` into this cache.
-    /// `value_length` should be the size, in bytes, of `value`.
+    /// Removes a key-value pair from the cache, returning the value, if present.
     ///
-    /// See bottom of [`Cache`] for more info about when to use this.
-    pub fn insert(
-        &mut self,
-        value_length: usize,
-        key: K,
-        value: V,
-        lifetime: Option<Duration>,
-    ) -> CacheOut<V>
+    /// See [`HashMap::remove`] and [`CacheOut::Present`].
+    #[inline]
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<V>
     where
-        H: Hasher,
+        K: Borrow<Q>,
     {
-        if value_length >= self.size_limit {
-            return CacheOut::NotInserted(value);
-        }
-        self.inserts += 1;
-        if self.map.len() >= self.max_items {
-            self.discard_one();
-        }
-        match self.map.insert(key, (value, (Utc::now(), lifetime))) {
-            Some((v, _expiry)) => CacheOut::Present(v),
+        match self.map.get(key) {
+            Some(&index) => CacheOut::Present(self.remove_index(index).0),
             None => CacheOut::None,
         }
     }
 }
-impl<K, V, H: Hasher> Cache<K, V, H> {
-    /// Writes to the internal hasher to increase quality of output.
+impl<K, V> Default for Cache<K, V> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_ITEMS, Self::DEFAULT_SIZE_LIMIT)
+    }
+}
+impl<K: Eq + Hash, V: CacheSize> Cache<K, V> {
+    /// Get value at `key` from the cache.
     ///
-    /// Should be used by implementors of the `cache` method to add
-    /// to the internal hasher with their data.
+    /// See [`HashMap::get`] for more info.
+    #[inline]
+    pub fn get<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_with_lifetime(key).map(|v| &v.0)
+    }
+    /// Gets the [`CacheItem`] at `key` from the cache.
+    /// Consider using [`Self::get`] for most operations.
     ///
-    /// The hasher is used when selecting a item to remove from the cache.
-    pub fn feed_hasher(&mut self, data: &[u8]) {
-        self.hasher.write(data);
+    /// This includes all lifetime information about the item in the cache.
+    /// See [`CacheItem`] for more info about this.
+    ///
+    /// The item is marked as used, and it's size is measured again.
+    pub fn get_with_lifetime<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> CacheOut<&CacheItem<V>>
+    where
+        K: Borrow<Q>,
+    {
+        // maybe set tokio timers to remove items instead?
+        let index = match self.map.get(key) {
+            Some(&index) => index,
+            None => return CacheOut::None,
+        };
+        let (creation, lifetime) = self.entry(index).item.1;
+        if lifetime.map_or(false, |lifetime| Utc::now() - creation > lifetime) {
+            self.remove_index(index);
+            return CacheOut::None;
+        }
+        self.touch(index);
+        // Compressed bodies may have been added since it was measured.
+        self.measure(index);
+        CacheOut::Present(&self.entry(index).item)
+    }
+    /// Measures the size of the entry at `index` again, and discards other entries if it grew too much.
+    fn measure(&mut self, index: usize) {
+        let entry = self.entry_mut(index);
+        let old_size = entry.size;
+        let new_size = entry.item.0.cache_size();
+        entry.size = new_size;
+        self.set_size(self.size - old_size + new_size);
+        self.shrink(index, 0, 0);
     }
 }
-impl<K: Eq + Hash, V, H: Hasher> Cache<K, V, H> {
-    /// Discards one key-value pair pseudo-randomly.
-    pub fn discard_one(&mut self) {
-        let pseudo_random = {
-            self.feed_hasher(&self.inserts.to_le_bytes());
-            self.hasher.finish()
-        };
-
-        // I don't care about normalized distribution
-        // also, it's safe to cast, modulo logic...
-        #[allow(clippy::cast_possible_truncation)]
-        let position = (pseudo_random % self.map.len() as u64) as usize;
+impl<K: Eq + Hash + Clone, V: CacheSize> Cache<K, V> {
+    /// Inserts a `value` at `key` into this cache.
+    ///
+    /// Other items are discarded to make room for `value`.
+    /// If it's larger than the size limits, it's not inserted.
+    ///
+    /// See bottom of [`Cache`] for more info about when to use this.
+    pub fn insert(&mut self, key: K, value: V, lifetime: Option<Duration>) -> CacheOut<V> {
+        let size = value.cache_size();
+        if size >= self.size_limit || size > self.max_size || self.max_items == 0 {
+            return CacheOut::NotInserted(value);
+        }
+        let previous = self.remove(&key);
+        self.shrink(NIL, 1, size);
 
-        let mut current_position = 0;
-        self.map.retain(|_, _| {
-            let result = current_position != position;
-            current_position += 1;
-            result
-        });
+        let entry = Entry {
+            key: key.clone(),
+            item: (value, (Utc::now(), lifetime)),
+            size,
+            bucket: NIL,
+            prev: NIL,
+            next: NIL,
+        };
+        let index = if let Some(index) = self.free_entries.pop() {
+            self.entries[index] = Some(entry);
+            index
+        } else {
+            self.entries.push(Some(entry));
+            self.entries.len() - 1
+        };
+        // New entries are the least used, but the most recent.
+        let uses = match self.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => 1,
+        };
+        let bucket = match self.first_bucket {
+            first if first != NIL && self.buckets[first].uses == uses => first,
+            first => self.add_bucket(uses, NIL, first),
+        };
+        self.link(index, bucket);
+        self.map.insert(key, index);
+        self.set_size(self.size + size);
+        previous
     }
 }
-impl<K: Eq + Hash, H: Hasher> Cache<K, CompressedResponse, H> {
-    /// Caches a [`CompressedResponse`] and returns the previous response, if any.
-    pub fn cache(&mut self, key: K, response: CompressedResponse) -> CacheOut<CompressedResponse> {
+impl<K: Eq + Hash + Clone> Cache<K, VariedResponse> {
+    /// Caches a [`CompressedResponse`] generated from a request with `request_headers`.
+    ///
+    /// If a [`VariedResponse`] varying on the same headers is present at `key`,
+    /// `response` is added as a variant of it. Else, a new [`VariedResponse`] is inserted
+    /// and the previous, if any, returned.
+    ///
+    /// If the `vary` header of `response` contains `*`, the `response` is not inserted.
+    pub fn cache(
+        &mut self,
+        key: K,
+        response: CompressedResponse,
+        request_headers: &HeaderMap,
+    ) -> CacheOut<VariedResponse> {
         let lifetime = parse::CacheControl::from_headers(response.get_identity().headers())
             .ok()
             .as_ref()
             .and_then(parse::CacheControl::as_freshness)
             .map(|s| Duration::seconds(s as i64));
 
-        let identity = response.get_identity().body();
-        let identity_fragment = &identity[identity.len().saturating_sub(512)..];
-        self.feed_hasher(identity_fragment);
+        let response = match self.map.get(&key).copied() {
+            Some(index)
+                if self.entry(index).item.1 .1.map_or(true, |lifetime| {
+                    Utc::now() - self.entry(index).item.1 .0 <= lifetime
+                }) =>
+            {
+                let size_limit = self.size_limit;
+                let entry = self.entry_mut(index);
+                let varied = &mut entry.item.0;
+                if response.get_identity().body().len() + varied.identity_len() >= size_limit {
+                    return CacheOut::None;
+                }
+                match varied.push(response, request_headers) {
+                    Ok(()) => {
+                        debug!("Added variant to cached response.");
+                        self.measure(index);
+                        return CacheOut::None;
+                    }
+                    Err(response) => response,
+                }
+            }
+            _ => response,
+        };
+
+        let varied = match VariedResponse::new(response, request_headers) {
+            Some(varied) => varied,
+            None => {
+                debug!("Not caching response with `vary: *`.");
+                return CacheOut::None;
+            }
+        };
 
         debug!("Inserted item to cache with lifetime {:?}", lifetime);
 
-        self.insert(
-            response.get_identity().body().len(),
-            key,
-            response,
-            lifetime,
-        )
+        self.insert(key, varied, lifetime)
     }
 }
-impl<K: Eq + Hash> Cache<K, Bytes> {
+impl<K: Eq + Hash + Clone> Cache<K, Bytes> {
     /// Caches a [`Bytes`] and returns the previous bytes, if any.
     pub fn cache(&mut self, key: K, contents: Bytes) -> CacheOut<Bytes> {
-        let fragment = &contents[contents.len().saturating_sub(512)..];
-        self.feed_hasher(fragment);
-
         // Bytes are not cleared from cache.
-        self.insert(contents.len(), key, contents, None)
+        self.insert(key, contents, None)
+    }
+}
+
+/// A [`Cache`] split into shards, each behind it's own lock.
+///
+/// Items are put in a shard by the hash of their key, so using different items
+/// rarely waits for another task. This lets cache hits proceed in parallel
+/// on a multi-threaded runtime.
+///
+/// The limits are split evenly between the shards.
+/// Therefore, the total size limit also limits the size of an item to
+/// the total size limit divided by the number of shards.
+///
+/// The locks are only held while a shard is used, so values are cloned out of the cache.
+/// [`Bytes`] and [`VariedResponse`] are both cheap to clone.
+/// Use [`Self::get_with`] to only clone parts of the value.
+///
+/// Concurrent misses can be coalesced using [`Self::flight`].
+#[derive(Debug)]
+#[must_use]
+pub struct ShardedCache<K, V> {
+    shards: Box<[StdMutex<Cache<K, V>>]>,
+    flights: Flights<K>,
+    stats: Arc<CacheStats>,
+    max_items: usize,
+    size_limit: usize,
+    max_size: usize,
+    policy: EvictionPolicy,
+}
+impl<K, V> ShardedCache<K, V> {
+    /// The default number of shards.
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    /// Creates a new [`ShardedCache`] holding at most `max_items`
+    /// which are smaller than `size_limit` bytes, split into [`Self::DEFAULT_SHARDS`] shards.
+    ///
+    /// See [`Cache::new`] for the other defaults.
+    pub fn new(max_items: usize, size_limit: usize) -> Self {
+        Self {
+            shards: Box::new([]),
+            flights: StdMutex::new(HashMap::new()),
+            stats: Arc::new(CacheStats::default()),
+            max_items,
+            size_limit,
+            max_size: Cache::<K, V>::DEFAULT_MAX_SIZE,
+            policy: EvictionPolicy::default(),
+        }
+        .with_shards(Self::DEFAULT_SHARDS)
+    }
+    /// Creates a new [`ShardedCache`] with `size_limit` and default `max_items`.
+    #[inline]
+    pub fn with_size_limit(size_limit: usize) -> Self {
+        Self::new(Cache::<K, V>::DEFAULT_MAX_ITEMS, size_limit)
+    }
+    /// Limits the total size of all items to `bytes`.
+    ///
+    /// The limit is per shard: each of the [`Self::shards`] holds at most
+    /// `bytes / shards` bytes. An item larger than that isn't cached, and a full shard
+    /// evicts its items even if other shards have room.
+    ///
+    /// This clears the cache.
+    #[inline]
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        let shards = self.shards.len();
+        self.with_shards(shards)
+    }
+    /// Sets the [`EvictionPolicy`].
+    ///
+    /// This clears the cache.
+    #[inline]
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        let shards = self.shards.len();
+        self.with_shards(shards)
+    }
+    /// Splits the cache into `shards` shards.
+    /// A single shard behaves like a [`Cache`] in a lock.
+    ///
+    /// There are never more shards than `max_items`, as each shard must hold at least one item.
+    /// The limits are divided by the number of shards; see [`Self::with_max_size`].
+    ///
+    /// This clears the cache.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.clear();
+        let shards = shards.min(self.max_items).max(1);
+        // Round up, so the total is at least the limit.
+        let split = |total: usize| total / shards + usize::from(total % shards != 0);
+        let (max_items, max_size) = (split(self.max_items), split(self.max_size));
+        self.shards = (0..shards)
+            .map(|_| {
+                let cache = Cache::new(max_items, self.size_limit)
+                    .with_max_size(max_size)
+                    .with_eviction_policy(self.policy)
+                    .with_stats(Arc::clone(&self.stats));
+                StdMutex::new(cache)
+            })
+            .collect();
+        self
+    }
+    /// Clears the cache.
+    pub fn clear(&self) {
+        for shard in &*self.shards {
+            lock(shard).clear();
+        }
+    }
+    /// Gets the [`CacheStats`] of this cache.
+    #[inline]
+    #[must_use]
+    pub fn stats(&self) -> &Arc<CacheStats> {
+        &self.stats
+    }
+    /// The total size of all items, in bytes.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).size()).sum()
+    }
+    /// The number of items.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+    /// If the cache is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock(shard).is_empty())
+    }
+    /// The number of shards.
+    #[inline]
+    #[must_use]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+    /// The [`EvictionPolicy`] of the cache.
+    #[inline]
+    #[must_use]
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Locks the shard which holds `key`.
+    fn shard<Q: ?Sized + Hash>(&self, key: &Q) -> MutexGuard<'_, Cache<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        // The remainder is less than the number of shards, which is an `usize`.
+        #[allow(clippy::cast_possible_truncation)]
+        let index = (hasher.finish() % self.shards.len() as u64) as usize;
+        lock(&self.shards[index])
+    }
+}
+/// Locks a shard of a [`ShardedCache`].
+///
+/// The cache is always left in a valid state, so a poisoned lock is ignored.
+fn lock<K, V>(shard: &StdMutex<Cache<K, V>>) -> MutexGuard<'_, Cache<K, V>> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+impl<K, V> Default for ShardedCache<K, V> {
+    fn default() -> Self {
+        Self::new(
+            Cache::<K, V>::DEFAULT_MAX_ITEMS,
+            Cache::<K, V>::DEFAULT_SIZE_LIMIT,
+        )
+    }
+}
+impl<K: Eq + Hash, V> ShardedCache<K, V> {
+    /// Returns `true` if the cache contains `key`.
+    ///
+    /// See [`Cache::contains`].
+    #[inline]
+    pub fn contains<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).contains(key)
+    }
+    /// Removes a key-value pair from the cache, returning the value, if present.
+    ///
+    /// See [`Cache::remove`].
+    #[inline]
+    pub fn remove<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> CacheOut<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).remove(key)
+    }
+}
+impl<K: Eq + Hash, V: CacheSize> ShardedCache<K, V> {
+    /// Calls `f` with the [`CacheItem`] at `key` while the shard is locked.
+    ///
+    /// Use this to only clone the parts of the value you need.
+    /// See [`Cache::get_with_lifetime`].
+    #[inline]
+    pub fn get_with<Q: ?Sized + Hash + Eq, T>(
+        &self,
+        key: &Q,
+        f: impl FnOnce(&CacheItem<V>) -> T,
+    ) -> CacheOut<T>
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).get_with_lifetime(key).map(f)
+    }
+}
+impl<K: Eq + Hash, V: CacheSize + Clone> ShardedCache<K, V> {
+    /// Get a clone of the value at `key` from the cache.
+    ///
+    /// See [`Cache::get`].
+    #[inline]
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> CacheOut<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_with(key, |item| item.0.clone())
+    }
+    /// Gets a clone of the [`CacheItem`] at `key` from the cache.
+    ///
+    /// See [`Cache::get_with_lifetime`].
+    #[inline]
+    pub fn get_with_lifetime<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> CacheOut<CacheItem<V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_with(key, Clone::clone)
+    }
+}
+impl<K: Eq + Hash + Clone, V> ShardedCache<K, V> {
+    /// Coalesces concurrent misses of `key`, so only one task generates the value.
+    ///
+    /// The first task gets a [`Flight::Leader`] and should insert the value, if it can,
+    /// before dropping the [`FlightGuard`].
+    /// Until then, others get a [`Flight::Follower`] and should [wait](FlightWait::wait)
+    /// before looking in the cache again.
+    /// If the value wasn't inserted, e.g. if the leader failed, they generate it themselves.
+    /// The leader should therefore drop the guard as soon as it knows the value won't be inserted.
+    pub fn flight(&self, key: &K) -> Flight<'_, K> {
+        let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(receiver) = flights.get(key) {
+            return Flight::Follower(FlightWait(receiver.clone()));
+        }
+        let (sender, receiver) = watch::channel(());
+        flights.insert(key.clone(), receiver);
+        Flight::Leader(FlightGuard {
+            flights: &self.flights,
+            key: key.clone(),
+            _sender: sender,
+        })
+    }
+}
+impl<K: Eq + Hash + Clone, V: CacheSize> ShardedCache<K, V> {
+    /// Inserts a `value` at `key` into this cache.
+    ///
+    /// See [`Cache::insert`].
+    #[inline]
+    pub fn insert(&self, key: K, value: V, lifetime: Option<Duration>) -> CacheOut<V> {
+        self.shard(&key).insert(key, value, lifetime)
+    }
+}
+impl<K: Eq + Hash + Clone> ShardedCache<K, VariedResponse> {
+    /// Caches a [`CompressedResponse`] generated from a request with `request_headers`.
+    ///
+    /// See [`Cache::cache`](Cache#method.cache).
+    #[inline]
+    pub fn cache(
+        &self,
+        key: K,
+        response: CompressedResponse,
+        request_headers: &HeaderMap,
+    ) -> CacheOut<VariedResponse> {
+        self.shard(&key).cache(key, response, request_headers)
+    }
+}
+impl<K: Eq + Hash + Clone> ShardedCache<K, Bytes> {
+    /// Caches a [`Bytes`] and returns the previous bytes, if any.
+    #[inline]
+    pub fn cache(&self, key: K, contents: Bytes) -> CacheOut<Bytes> {
+        self.shard(&key).cache(key, contents)
+    }
+}
+
+type Flights<K> = StdMutex<HashMap<K, watch::Receiver<()>>>;
+
+/// The role of a task in generating a value of a [`ShardedCache`].
+///
+/// See [`ShardedCache::flight`].
+#[derive(Debug)]
+#[must_use]
+pub enum Flight<'a, K: Eq + Hash> {
+    /// No other task is generating the value; this task should.
+    Leader(FlightGuard<'a, K>),
+    /// Another task is generating the value.
+    Follower(FlightWait),
+}
+/// Held by the task generating a value of a [`ShardedCache`].
+///
+/// When dropped, the waiting tasks are woken.
+#[derive(Debug)]
+#[must_use]
+pub struct FlightGuard<'a, K: Eq + Hash> {
+    flights: &'a Flights<K>,
+    key: K,
+    /// Dropping this wakes the [`FlightWait`]s.
+    _sender: watch::Sender<()>,
+}
+impl<K: Eq + Hash> Drop for FlightGuard<'_, K> {
+    fn drop(&mut self) {
+        self.flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+/// Waits for another task to generate a value of a [`ShardedCache`].
+#[derive(Debug)]
+#[must_use]
+pub struct FlightWait(watch::Receiver<()>);
+impl FlightWait {
+    /// Waits until the [`FlightGuard`] is dropped, but at most `timeout`.
+    ///
+    /// Returns `false` if it timed out.
+    pub async fn wait(mut self, timeout: std::time::Duration) -> bool {
+        // Nothing is ever sent; this returns an error when the sender is dropped.
+        tokio::time::timeout(timeout, self.0.changed())
+            .await
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestCache = Cache<&'static str, Bytes>;
+
+    fn bytes(len: usize) -> Bytes {
+        Bytes::from(vec![0; len])
+    }
+    fn keys(cache: &TestCache, all: &[&'static str]) -> Vec<&'static str> {
+        all.iter()
+            .copied()
+            .filter(|key| cache.contains(*key))
+            .collect()
+    }
+
+    fn response(vary: &str, body: &'static str) -> CompressedResponse {
+        let mut response = Response::new(Bytes::from_static(body.as_bytes()));
+        if !vary.is_empty() {
+            response
+                .headers_mut()
+                .insert("vary", HeaderValue::from_str(vary).unwrap());
+        }
+        CompressedResponse::new(
+            response,
+            CompressPreference::Full,
+            ClientCachePreference::Full,
+            "txt",
+            false,
+            CompressionLevels::new(),
+        )
+    }
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+    fn body_of<'a>(varied: &'a VariedResponse, request_headers: &HeaderMap) -> Option<&'a [u8]> {
+        varied
+            .get_by_headers(request_headers)
+            .map(|response| &response.get_identity().body()[..])
+    }
+
+    #[test]
+    fn etag() {
+        let mut response = Response::new(Bytes::from_static(b"hello"));
+        CompressedResponse::add_etag(&mut response);
+        // The first 16 bytes of the SHA-256 hash, then the length.
+        assert_eq!(
+            response.headers()["etag"],
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e-5\""
+        );
+
+        // An `etag` set by an extension is kept.
+        let mut response = Response::builder()
+            .header("etag", "\"custom\"")
+            .body(Bytes::from_static(b"hello"))
+            .unwrap();
+        CompressedResponse::add_etag(&mut response);
+        assert_eq!(response.headers()["etag"], "\"custom\"");
+
+        let mut response = Response::new(Bytes::new());
+        CompressedResponse::add_etag(&mut response);
+        assert!(response.headers().get("etag").is_none());
+        let mut response = Response::new(Bytes::from_static(b"not found"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        CompressedResponse::add_etag(&mut response);
+        assert!(response.headers().get("etag").is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "br", feature = "zstd"))]
+    fn preferred_encoding() {
+        let preferred = |response: &CompressedResponse, accept_encoding: Option<&str>| {
+            let mut request = Request::get("/");
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.header("accept-encoding", accept_encoding);
+            }
+            response.preferred_encoding(&request.body(()).unwrap())
+        };
+        let text = response("", "hello world");
+        assert_eq!(
+            text.get_identity().headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        let table: &[(Option<&str>, Option<&str>)] = &[
+            (None, Some("identity")),
+            (Some(""), Some("identity")),
+            (Some("gzip"), Some("gzip")),
+            (Some("GZIP"), Some("gzip")),
+            (Some("gzip, br"), Some("br")),
+            (Some("gzip, br, zstd"), Some("zstd")),
+            (Some("br;q=0.5, gzip;q=0.8"), Some("gzip")),
+            (Some("*"), Some("zstd")),
+            (Some("*, zstd;q=0"), Some("br")),
+            (Some("gzip;q=0"), Some("identity")),
+            (Some("gzip;q=0.5, identity"), Some("identity")),
+            (Some("gzip;q=0.5, identity;q=0.5"), Some("gzip")),
+            (Some("gzip, identity;q=0"), Some("gzip")),
+            (Some("identity;q=0"), None),
+            (Some("gzip;q=0, identity;q=0"), None),
+            (Some("*;q=0"), None),
+            (Some("*;q=0, identity"), Some("identity")),
+        ];
+        for (accept_encoding, expected) in table {
+            assert_eq!(
+                preferred(&text, *accept_encoding).ok(),
+                *expected,
+                "accept-encoding: {:?}",
+                accept_encoding
+            );
+        }
+
+        // An encoding which is already compressed is preferred among equals.
+        let _ = text.get_gzip();
+        assert_eq!(preferred(&text, Some("br, gzip")), Ok("gzip"));
+        assert_eq!(preferred(&text, Some("br, gzip;q=0.9")), Ok("br"));
+
+        let mut uncompressed = response("", "hello world");
+        uncompressed.compress = CompressPreference::None;
+        assert_eq!(preferred(&uncompressed, Some("gzip")), Ok("identity"));
+        assert!(preferred(&uncompressed, Some("gzip, identity;q=0")).is_err());
+    }
+    #[test]
+    fn lru() {
+        let mut cache = TestCache::new(3, 1024);
+        assert_eq!(cache.eviction_policy(), EvictionPolicy::Lru);
+        for key in &["a", "b", "c"] {
+            cache.cache(*key, bytes(1));
+        }
+        // `a` is now the most recently used.
+        assert!(cache.get("a").into_option().is_some());
+        cache.cache("d", bytes(1));
+        assert_eq!(keys(&cache, &["a", "b", "c", "d"]), ["a", "c", "d"]);
+        cache.cache("e", bytes(1));
+        assert_eq!(keys(&cache, &["a", "b", "c", "d", "e"]), ["a", "d", "e"]);
+        assert_eq!(cache.stats().evictions(), 2);
+
+        cache.discard_one();
+        assert_eq!(keys(&cache, &["a", "d", "e"]), ["d", "e"]);
+    }
+    #[test]
+    fn lfu() {
+        let mut cache = TestCache::new(3, 1024).with_eviction_policy(EvictionPolicy::Lfu);
+        for key in &["a", "b", "c"] {
+            cache.cache(*key, bytes(1));
+        }
+        for _ in 0..3 {
+            assert!(cache.get("a").into_option().is_some());
+        }
+        assert!(cache.get("b").into_option().is_some());
+        // `c` is the least used.
+        cache.cache("d", bytes(1));
+        assert_eq!(keys(&cache, &["a", "b", "c", "d"]), ["a", "b", "d"]);
+        // `d` is used as little as `b` now, but `b` was used less recently.
+        assert!(cache.get("d").into_option().is_some());
+        cache.cache("e", bytes(1));
+        assert_eq!(keys(&cache, &["a", "b", "d", "e"]), ["a", "d", "e"]);
+        // `e` is the only item used once.
+        cache.cache("f", bytes(1));
+        assert_eq!(keys(&cache, &["a", "d", "e", "f"]), ["a", "d", "f"]);
+    }
+    #[test]
+    fn byte_budget() {
+        let mut cache = TestCache::new(100, 60).with_max_size(100);
+        cache.cache("a", bytes(40));
+        cache.cache("b", bytes(30));
+        cache.cache("c", bytes(20));
+        assert_eq!(cache.size(), 90);
+        assert_eq!(cache.stats().size(), 90);
+
+        // Makes room by discarding the least recently used.
+        cache.cache("d", bytes(50));
+        assert_eq!(keys(&cache, &["a", "b", "c", "d"]), ["b", "c", "d"]);
+        assert_eq!(cache.size(), 100);
+        assert_eq!(cache.stats().evictions(), 1);
+
+        // Larger than the size limit of an item.
+        assert!(matches!(
+            cache.cache("e", bytes(60)),
+            CacheOut::NotInserted(_)
+        ));
+        assert_eq!(cache.len(), 3);
+
+        // Replacing an item replaces it's size.
+        cache.cache("c", bytes(10));
+        assert_eq!(cache.size(), 90);
+        assert!(cache.remove("d").into_option().is_some());
+        assert_eq!(cache.size(), 40);
+        cache.clear();
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.stats().size(), 0);
+        assert!(cache.is_empty());
+    }
+    #[test]
+    fn byte_budget_smaller_than_size_limit() {
+        let mut cache = TestCache::new(100, 1024).with_max_size(100);
+        assert!(matches!(
+            cache.cache("a", bytes(101)),
+            CacheOut::NotInserted(_)
+        ));
+        cache.cache("a", bytes(100));
+        assert_eq!(cache.size(), 100);
+        cache.cache("b", bytes(1));
+        assert_eq!(keys(&cache, &["a", "b"]), ["b"]);
+    }
+    #[test]
+    fn variants() {
+        let english = headers("accept-language", "en");
+        let swedish = headers("accept-language", "sv");
+        let mut varied = VariedResponse::new(
+            response("Accept-Language, accept-encoding", "hello"),
+            &english,
+        )
+        .unwrap();
+        assert_eq!(varied.vary(), ["accept-language"]);
+        varied
+            .push(response("accept-language", "hej"), &swedish)
+            .unwrap();
+        assert_eq!(varied.len(), 2);
+        assert_eq!(body_of(&varied, &english), Some(&b"hello"[..]));
+        assert_eq!(body_of(&varied, &swedish), Some(&b"hej"[..]));
+        assert_eq!(body_of(&varied, &HeaderMap::new()), None);
+        assert_eq!(body_of(&varied, &headers("accept-language", "de")), None);
+
+        // A variant for the same headers replaces the previous.
+        varied
+            .push(response("accept-language", "hallå"), &swedish)
+            .unwrap();
+        assert_eq!(varied.len(), 2);
+        assert_eq!(body_of(&varied, &swedish), Some("hallå".as_bytes()));
+        // Responses varying on other headers can't be added.
+        assert!(varied.push(response("cookie", "hi"), &english).is_err());
+        assert!(varied.push(response("", "hi"), &english).is_err());
+
+        // Without `vary`, the response is used for all requests.
+        let varied = VariedResponse::new(response("", "hello"), &english).unwrap();
+        assert_eq!(body_of(&varied, &HeaderMap::new()), Some(&b"hello"[..]));
+    }
+    #[test]
+    fn vary_wildcard() {
+        assert!(VariedResponse::new(response("*", "hello"), &HeaderMap::new()).is_none());
+        assert!(VariedResponse::new(response("cookie, *", "hello"), &HeaderMap::new()).is_none());
+
+        let mut cache = Cache::<&'static str, VariedResponse>::new(10, 1024);
+        assert!(matches!(
+            cache.cache("/", response("*", "hello"), &HeaderMap::new()),
+            CacheOut::None
+        ));
+        assert!(cache.is_empty());
+    }
+    #[test]
+    fn cached_variants() {
+        let english = headers("accept-language", "en");
+        let swedish = headers("accept-language", "sv");
+        let mut cache = Cache::<&'static str, VariedResponse>::new(10, 1024);
+        cache.cache("/", response("accept-language", "hello"), &english);
+        cache.cache("/", response("accept-language", "hej"), &swedish);
+        assert_eq!(cache.len(), 1);
+        let varied = cache.get("/").into_option().unwrap().clone();
+        assert_eq!(body_of(&varied, &english), Some(&b"hello"[..]));
+        assert_eq!(body_of(&varied, &swedish), Some(&b"hej"[..]));
+    }
+    #[test]
+    fn max_variants() {
+        let cookie = |i: usize| headers("cookie", &format!("session={}", i));
+        let mut varied = VariedResponse::new(response("cookie", "0"), &cookie(0)).unwrap();
+        for i in 1..=MAX_VARIANTS {
+            varied.push(response("cookie", "n"), &cookie(i)).unwrap();
+        }
+        assert_eq!(varied.len(), MAX_VARIANTS);
+        // The oldest is discarded.
+        assert_eq!(body_of(&varied, &cookie(0)), None);
+        assert!(body_of(&varied, &cookie(1)).is_some());
+        assert!(body_of(&varied, &cookie(MAX_VARIANTS)).is_some());
+    }
+    #[test]
+    fn sharded_byte_budget() {
+        let cache = ShardedCache::<&'static str, Bytes>::new(100, 1024)
+            .with_shards(4)
+            .with_max_size(100);
+        assert_eq!(cache.shards(), 4);
+        // Each shard holds at most 25 bytes.
+        assert!(matches!(
+            cache.cache("a", bytes(26)),
+            CacheOut::NotInserted(_)
+        ));
+        cache.cache("a", bytes(25));
+        assert_eq!(cache.size(), 25);
+
+        let cache = cache.with_shards(1);
+        cache.cache("a", bytes(100));
+        assert_eq!(cache.size(), 100);
+    }
+
+    #[tokio::test]
+    async fn single_flight() {
+        let cache: FileCache = ShardedCache::default();
+        let path = PathBuf::from("index.html");
+        let guard = match cache.flight(&path) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(_) => panic!("the first task should lead"),
+        };
+        let wait = match cache.flight(&path) {
+            Flight::Follower(wait) => wait,
+            Flight::Leader(_) => panic!("the key is already in flight"),
+        };
+        // Other keys aren't affected.
+        assert!(matches!(
+            cache.flight(&PathBuf::from("other.html")),
+            Flight::Leader(_)
+        ));
+
+        let waiting = tokio::spawn(wait.wait(std::time::Duration::from_secs(10)));
+        cache.cache(path.clone(), Bytes::from_static(b"generated"));
+        drop(guard);
+        assert!(waiting.await.unwrap());
+        assert!(cache.get(&path).into_option().is_some());
+
+        // The flight is over.
+        let guard = cache.flight(&path);
+        assert!(matches!(guard, Flight::Leader(_)));
+        // The waiting is bounded.
+        let wait = match cache.flight(&path) {
+            Flight::Follower(wait) => wait,
+            Flight::Leader(_) => panic!("the key is already in flight"),
+        };
+        assert!(!wait.wait(std::time::Duration::from_millis(10)).await);
     }
 }
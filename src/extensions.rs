@@ -47,6 +47,9 @@ pub type Prime =
     Box<(dyn Fn(RequestWrapper, HostWrapper, SocketAddr) -> RetFut<Option<Uri>> + Sync + Send)>;
 /// A prepare extension.
 ///
+/// The request body can be processed as it's received by using the [`Body`]
+/// of the request as a [`futures::Stream`] or [`AsyncRead`].
+///
 /// See [module level documentation](extensions) and the extensions.md link for more info.
 pub type Prepare = Box<
     (dyn Fn(RequestWrapperMut, HostWrapper, PathOptionWrapper, SocketAddr) -> RetFut<FatResponse>
@@ -493,6 +496,96 @@ impl Extensions {
 
         self
     }
+    /// Adds extensions to guard the paths of `auth` and the login endpoint, if any.
+    ///
+    /// Requests to guarded paths without a valid token are responded to with
+    /// `401 Unauthorized`, and responses to the others are marked as private
+    /// so shared caches don't store them.
+    ///
+    /// See [`crate::auth`] for an example and more info.
+    #[cfg(feature = "auth")]
+    pub fn add_auth(&mut self, auth: Arc<crate::auth::Auth>) -> &mut Self {
+        let login_auth = Arc::clone(&auth);
+        let package_auth = Arc::clone(&auth);
+
+        // This priority has to be higher than the ones of the CORS extensions,
+        // so they override this for preflight and failed CORS requests.
+        self.add_prime(
+            Box::new(move |request, _, _| {
+                let request = unsafe { request.get_inner() };
+                ready(
+                    if auth.is_guarded(request.uri().path()) && auth.identity(request).is_none() {
+                        Some(Uri::from_static("/./auth_unauthorized"))
+                    } else {
+                        None
+                    },
+                )
+            }),
+            Id::new(
+                16_777_217,
+                "Reroute unauthenticated requests to guarded paths to /./auth_unauthorized",
+            ),
+        );
+
+        self.add_prepare_single(
+            "/./auth_unauthorized".to_owned(),
+            Box::new(|_, host, _, _| {
+                Box::pin(async move {
+                    let host = unsafe { host.get_inner() };
+                    crate::auth::Auth::unauthorized(host).await
+                })
+            }),
+        );
+
+        if let Some(path) = login_auth.login_path() {
+            let path = path.to_owned();
+            self.add_prepare_single(
+                path,
+                Box::new(move |mut request, host, _, _| {
+                    let auth = Arc::clone(&login_auth);
+                    Box::pin(async move {
+                        let (request, host) = unsafe { (request.get_inner(), host.get_inner()) };
+                        auth.login(request, host).await
+                    })
+                }),
+            );
+        }
+
+        // Low priority so it runs after the `cache-control` header is set.
+        self.add_package(
+            Box::new(move |mut response, request, _| {
+                let (response, request) = unsafe { (response.get_inner(), request.get_inner()) };
+
+                if package_auth.is_guarded(request.uri().path()) {
+                    let headers = response.headers_mut();
+                    let directives = headers
+                        .get("cache-control")
+                        .and_then(|header| header.to_str().ok())
+                        .unwrap_or("");
+                    if !directives.contains("private") && !directives.contains("no-store") {
+                        let directives = directives
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|directive| {
+                                !directive.is_empty() && !directive.eq_ignore_ascii_case("public")
+                            })
+                            .fold(String::from("private"), |mut acc, directive| {
+                                acc.push_str(", ");
+                                acc.push_str(directive);
+                                acc
+                            });
+                        // We only use bytes from the previous, valid, header.
+                        let value = HeaderValue::from_str(&directives).unwrap();
+                        utils::replace_header(headers, "cache-control", value);
+                    }
+                }
+                ready(())
+            }),
+            Id::new(-1025, "Marks responses to guarded paths as private"),
+        );
+
+        self
+    }
 
     /// Adds a prime extension. Higher [`Id::priority()`] extensions are ran first.
     pub fn add_prime(&mut self, extension: Prime, id: Id) {
@@ -502,6 +595,34 @@ impl Extensions {
     pub fn add_prepare_single(&mut self, path: String, extension: Prepare) {
         self.prepare_single.insert(path, extension);
     }
+    /// Adds a WebSocket `handler` at `path`.
+    ///
+    /// This adds a [`Prepare`] extension for `path` which performs the handshake.
+    /// See [`crate::websocket`] for more info.
+    #[cfg(feature = "websocket")]
+    pub fn add_websocket(&mut self, path: String, handler: crate::websocket::Handler) {
+        let handler = Arc::new(handler);
+        self.add_prepare_single(
+            path,
+            Box::new(move |mut request, host, _, address| {
+                let handler = Arc::clone(&handler);
+                Box::pin(async move {
+                    let request = unsafe { request.get_inner() };
+                    let host = unsafe { host.get_inner() };
+                    crate::websocket::response(request, host, address, handler).await
+                })
+            }),
+        );
+    }
+    /// Serves the Prometheus metrics of `metrics` at `path`.
+    ///
+    /// Anyone able to reach this host can read them;
+    /// consider [`crate::metrics::Metrics::port_descriptor`] instead.
+    /// See [`crate::metrics`] for more info.
+    #[cfg(feature = "metrics")]
+    pub fn add_metrics(&mut self, path: String, metrics: Arc<crate::metrics::Metrics>) {
+        self.add_prepare_single(path, Box::new(move |_, _, _, _| ready(metrics.response())));
+    }
     /// Adds a prepare extension run if `function` return `true`. Higher [`Id::priority()`] extensions are ran first.
     pub fn add_prepare_fn(&mut self, predicate: If, extension: Prepare, id: Id) {
         add_sort_list!(self.prepare_fn, id, predicate, extension,);
@@ -595,6 +716,7 @@ impl Extensions {
         server_cache_preference: &mut ServerCachePreference,
         host: &Host,
         address: SocketAddr,
+        keep_precompressed: &mut bool,
     ) -> io::Result<()> {
         let mut body = LazyRequestBody::new(request.body_mut());
         let body = &mut body;
@@ -602,6 +724,8 @@ impl Extensions {
 
         if let Some(extensions) = PresentExtensions::new(Bytes::clone(response.body())) {
             *response.body_mut() = response.body_mut().split_off(extensions.data_start());
+            // The precompressed files contain the extension declarations.
+            *keep_precompressed = false;
             for extension_name_args in extensions {
                 if let Some(extension) = self.present_internal.get(extension_name_args.name()) {
                     let mut data = PresentData {
@@ -613,6 +737,7 @@ impl Extensions {
                         server_cache_preference,
                         client_cache_preference,
                         response,
+                        keep_precompressed,
                         args: extension_name_args,
                     };
                     let data = PresentDataWrapper::new(&mut data);
@@ -634,6 +759,7 @@ impl Extensions {
                 server_cache_preference,
                 client_cache_preference,
                 response,
+                keep_precompressed,
                 args: PresentArguments::empty(),
             };
             let data = PresentDataWrapper::new(&mut data);
@@ -856,6 +982,7 @@ pub struct PresentData {
     server_cache_preference: *mut ServerCachePreference,
     client_cache_preference: *mut ClientCachePreference,
     response: *mut Response<Bytes>,
+    keep_precompressed: *mut bool,
     // Regarding extension
     args: PresentArguments,
 }
@@ -889,10 +1016,22 @@ impl PresentData {
     pub fn client_cache_preference(&mut self) -> &mut ClientCachePreference {
         unsafe { &mut *self.client_cache_preference }
     }
+    /// Gets the response to change it.
+    ///
+    /// The precompressed versions of the file (e.g. `index.html.gz`) aren't used after this,
+    /// as the body may have changed. Use [`Self::headers_mut`] to only change the headers.
     #[inline]
     pub fn response_mut(&mut self) -> &mut Response<Bytes> {
+        unsafe { *self.keep_precompressed = false };
         unsafe { &mut *self.response }
     }
+    /// Gets the headers of the response to change them.
+    ///
+    /// Unlike [`Self::response_mut`], this keeps the precompressed versions of the file.
+    #[inline]
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        unsafe { &mut *self.response }.headers_mut()
+    }
     #[inline]
     pub fn response(&self) -> &Response<Bytes> {
         unsafe { &*self.response }
@@ -926,6 +1065,7 @@ impl LazyRequestBody {
     /// # Errors
     ///
     /// Returns any errors from reading the inner [`Body`].
+    /// See [`Body::read_to_bytes`].
     #[inline]
     pub async fn get(&mut self) -> io::Result<&Bytes> {
         if let Some(ref result) = self.result {
@@ -1329,6 +1469,8 @@ mod macros {
     ///     match response_pipe {
     ///         application::ResponsePipe::Http1(c) => println!("This is a HTTP/1 connection. {:?}", c),
     ///         application::ResponsePipe::Http2(c) => println!("This is a HTTP/2 connection. {:?}", c),
+    ///         # #[allow(unreachable_patterns)]
+    ///         c => println!("This is a HTTP/3 connection. {:?}", c),
     ///     }
     /// });
     /// ```
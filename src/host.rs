@@ -7,17 +7,19 @@
 //! to use for a connection. This is done by having a default and
 //! other defined by their SNI (or `host` header in HTTP/1).
 //!
-//! This tactic might change in the future; if you have two domains pointing
-//! to a single Kvarn server, say `icelk.dev` and `kvarn.org`, but only `kvarn.org`
-//! is set up, the client will get a certificate error when going to `icelk.dev`.
-//! Therefore, I think the user of this library should have a choice to reject connections
-//! to a [`Host`] which isn't explicitly associated with said domain.
+//! If you have two domains pointing to a single Kvarn server, say `icelk.dev` and `kvarn.org`,
+//! but only `kvarn.org` is set up, the client will by default get the default host
+//! (and a certificate error) when going to `icelk.dev`.
+//! Use [`Data::set_unknown_host`] to instead reject connections
+//! to domains which aren't explicitly associated with a [`Host`].
 
 use crate::prelude::{internals::*, *};
 #[cfg(feature = "https")]
 use rustls::{
     internal::pemfile, sign, ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig,
 };
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
 
 /// A set of settings for a [virtual host](https://en.wikipedia.org/wiki/Virtual_hosting),
 /// allowing multiple DNS entries (domain names) to share a single IP address.
@@ -33,10 +35,22 @@ use rustls::{
 pub struct Host {
     /// The name of the host, will be used in matching the requests [SNI hostname](rustls::ClientHello::server_name())
     /// and `host` header to get the requested host to handle the request.
-    pub name: &'static str,
+    ///
+    /// This can be a wildcard pattern, such as `*.example.org`. See [`Data`] for more info.
+    pub name: String,
+    /// Other names of this host, matched the same way as [`Host::name`].
+    ///
+    /// Add aliases using [`Host::add_alias`] before adding the host to a [`Data`].
+    pub aliases: Vec<String>,
     /// The certificate of this host, if any.
     #[cfg(feature = "https")]
     pub certificate: Option<sign::CertifiedKey>,
+    /// The [`Acme`](crate::acme::Acme) getting the certificate of this host, if any.
+    /// Its certificate is used instead of [`Host::certificate`] when it's been issued.
+    ///
+    /// Set using [`Host::enable_acme`].
+    #[cfg(feature = "acme")]
+    pub acme: Option<Arc<crate::acme::Acme>>,
     /// Base path of all data for this host.
     ///
     /// If you enabled the `fs` feature (enabled by default),
@@ -52,9 +66,18 @@ pub struct Host {
     /// multiple hosts on the same instance.
     ///
     /// Can be used to clear the cache and to pass to the read functions in [`read`].
+    ///
+    /// Both caches are [sharded](comprash::ShardedCache), with the size and item limits
+    /// applying to each shard: a cache with a `max_size` of 16MiB in 16 shards
+    /// holds at most 1MiB in each shard, and doesn't cache files larger than that.
+    /// Set fewer shards with [`ShardedCache::with_shards`](comprash::ShardedCache::with_shards)
+    /// to cache larger items.
     pub file_cache: Option<FileCache>,
     /// The response cache of this host.
     /// See [`comprash`] and [`Host::file_cache`] for more info.
+    ///
+    /// Concurrent requests of a page which isn't cached are coalesced,
+    /// so the page is only generated once. See [`ResponseCache::flight`].
     pub response_cache: Option<ResponseCache>,
     /// The [`LimitManager`] checking for spam attacks
     /// for this host.
@@ -62,6 +85,15 @@ pub struct Host {
     /// Having this host-specific enables different virtual
     /// hosts to have varying degrees of strictness.
     pub limiter: LimitManager,
+    /// The log of the requests to this host, if any.
+    ///
+    /// Set using [`Host::enable_access_log`].
+    pub access_log: Option<Arc<crate::access_log::AccessLog>>,
+    /// The metrics of this host, if any.
+    ///
+    /// Set using [`Host::enable_metrics`].
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<crate::metrics::HostMetrics>>,
 
     /// Other settings.
     pub options: Options,
@@ -81,7 +113,7 @@ impl Host {
     /// Will return any error from [`get_certified_key()`] with a [`Host`] containing no certificates.
     #[cfg(feature = "https")]
     pub fn new(
-        host_name: &'static str,
+        host_name: impl Into<String>,
         cert_path: impl AsRef<Path>,
         private_key_path: impl AsRef<Path>,
         path: impl AsRef<Path>,
@@ -122,7 +154,7 @@ impl Host {
     /// ```
     #[cfg(feature = "https")]
     pub fn from_cert_and_pk(
-        host_name: &'static str,
+        host_name: impl Into<String>,
         cert: Vec<rustls::Certificate>,
         pk: Arc<Box<dyn sign::SigningKey>>,
         path: impl AsRef<Path>,
@@ -132,14 +164,20 @@ impl Host {
         let cert = sign::CertifiedKey::new(cert, pk);
 
         Self {
-            name: host_name,
+            name: host_name.into(),
+            aliases: Vec::new(),
             certificate: Some(cert),
+            #[cfg(feature = "acme")]
+            acme: None,
             path: path.as_ref().to_path_buf(),
             extensions,
-            file_cache: Some(Mutex::new(Cache::default())),
-            response_cache: Some(Mutex::new(Cache::default())),
+            file_cache: Some(FileCache::default()),
+            response_cache: Some(ResponseCache::default()),
             options,
             limiter: LimitManager::default(),
+            access_log: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
         }
     }
     /// Creates a new [`Host`] without a certificate.
@@ -147,21 +185,27 @@ impl Host {
     /// This host will only support non-encrypted HTTP/1 connections.
     /// Consider enabling the `https` flag and use a self-signed certificate or one from [Let's Encrypt](https://letsencrypt.org/).
     pub fn non_secure(
-        host_name: &'static str,
+        host_name: impl Into<String>,
         path: impl AsRef<Path>,
         extensions: Extensions,
         options: Options,
     ) -> Self {
         Self {
-            name: host_name,
+            name: host_name.into(),
+            aliases: Vec::new(),
             #[cfg(feature = "https")]
             certificate: None,
+            #[cfg(feature = "acme")]
+            acme: None,
             path: path.as_ref().to_path_buf(),
             extensions,
-            file_cache: Some(Mutex::new(Cache::default())),
-            response_cache: Some(Mutex::new(Cache::default())),
+            file_cache: Some(FileCache::default()),
+            response_cache: Some(ResponseCache::default()),
             options,
             limiter: LimitManager::default(),
+            access_log: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
         }
     }
 
@@ -173,7 +217,7 @@ impl Host {
     /// Consider [`Host::enable_hsts`] to harden the system.
     #[cfg(feature = "https")]
     pub fn with_http_redirect(
-        host_name: &'static str,
+        host_name: impl Into<String>,
         cert_path: impl AsRef<Path>,
         private_key_path: impl AsRef<Path>,
         path: impl AsRef<Path>,
@@ -241,8 +285,17 @@ impl Host {
         self.extensions.add_prime(
             Box::new(|request, _, _| {
                 let request: &FatRequest = unsafe { request.get_inner() };
+                // ACME HTTP-01 challenges have to be answered over HTTP.
+                #[cfg(feature = "acme")]
+                let challenge = request
+                    .uri()
+                    .path()
+                    .starts_with(crate::acme::CHALLENGE_PATH);
+                #[cfg(not(feature = "acme"))]
+                let challenge = false;
                 let uri = if request.uri().scheme_str() == Some("http")
                     && request.uri().port().is_none()
+                    && !challenge
                 {
                     // redirect
                     let mut uri = request.uri().clone().into_parts();
@@ -306,6 +359,75 @@ impl Host {
         );
         self
     }
+    /// Gets the certificate of this host using `acme`.
+    ///
+    /// Adds a [`Prepare`] extension (with a priority of `16_777_216`) answering the HTTP-01 challenges
+    /// of `acme`. The certificate is ordered when [`run`] is called.
+    ///
+    /// See [`crate::acme`] for an example and more info.
+    #[cfg(feature = "acme")]
+    pub fn enable_acme(&mut self, acme: Arc<crate::acme::Acme>) -> &mut Self {
+        let challenges = Arc::clone(&acme);
+        self.extensions.add_prepare_fn(
+            Box::new(|request, _| {
+                request
+                    .uri()
+                    .path()
+                    .starts_with(crate::acme::CHALLENGE_PATH)
+            }),
+            Box::new(move |mut request, host, _, _| {
+                let request: &FatRequest = unsafe { request.get_inner() };
+                let token = &request.uri().path()[crate::acme::CHALLENGE_PATH.len()..];
+                let key_authorization = challenges.key_authorization(token);
+                Box::pin(async move {
+                    if let Some(key_authorization) = key_authorization {
+                        FatResponse::no_cache(Response::new(Bytes::from(key_authorization)))
+                            .with_compress(CompressPreference::None)
+                    } else {
+                        let host = unsafe { host.get_inner() };
+                        default_error_response(StatusCode::NOT_FOUND, host, None).await
+                    }
+                })
+            }),
+            extensions::Id::new(16_777_216, "Answering ACME HTTP-01 challenges"),
+        );
+        self.acme = Some(acme);
+        self
+    }
+    /// Adds `name` as an alias of this host.
+    ///
+    /// Requests to `name` are then handled by this host, sharing its caches, extensions, and certificate.
+    /// Wildcard patterns, such as `*.example.org`, are accepted. See [`Data`] for more info.
+    pub fn add_alias(&mut self, name: impl Into<String>) -> &mut Self {
+        self.aliases.push(name.into());
+        self
+    }
+    /// Logs all requests to this host to `log`.
+    ///
+    /// See [`crate::access_log`] for more info.
+    pub fn enable_access_log(&mut self, log: Arc<crate::access_log::AccessLog>) -> &mut Self {
+        self.access_log = Some(log);
+        self
+    }
+    /// Records the requests to this host, and the use of its caches, in `metrics`.
+    ///
+    /// Call this after setting the caches; replacing them afterwards
+    /// stops the new caches from being measured.
+    ///
+    /// See [`crate::metrics`] for more info.
+    #[cfg(feature = "metrics")]
+    pub fn enable_metrics(&mut self, metrics: &crate::metrics::Metrics) -> &mut Self {
+        let file = self
+            .file_cache
+            .as_ref()
+            .map(|cache| Arc::clone(cache.stats()));
+        let response = self
+            .response_cache
+            .as_ref()
+            .map(|cache| Arc::clone(cache.stats()));
+        self.metrics = Some(metrics.register(&self.name, [file, response]));
+        self
+    }
     /// Disables client cache on this host.
     ///
     /// This makes all [`ClientCachePreference`]s `no-store`.
@@ -344,8 +466,23 @@ impl Host {
     #[cfg(feature = "https")]
     #[inline]
     pub fn is_secure(&self) -> bool {
+        #[cfg(feature = "acme")]
+        if self.acme.is_some() {
+            return true;
+        }
         self.certificate.is_some()
     }
+    /// Gets the certificate currently used by this host.
+    ///
+    /// This is the one from [`Host::acme`], if it has been issued, else [`Host::certificate`].
+    #[cfg(feature = "https")]
+    pub(crate) fn current_certificate(&self) -> Option<sign::CertifiedKey> {
+        #[cfg(feature = "acme")]
+        if let Some(certificate) = self.acme.as_ref().and_then(|acme| acme.certificate()) {
+            return Some(certificate);
+        }
+        self.certificate.clone()
+    }
     /// Whether or not this this host is secured with a certificate.
     ///
     /// See [`Host::certificate`].
@@ -359,12 +496,18 @@ impl Debug for Host {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut d = f.debug_struct("Host");
         d.field("host_name", &self.name);
+        d.field("aliases", &self.aliases);
         #[cfg(feature = "https")]
         d.field("certificate", &"[internal certificate]".as_clean());
+        #[cfg(feature = "acme")]
+        d.field("acme", &self.acme);
         d.field("path", &self.path);
         d.field("extensions", &"[internal extension data]".as_clean());
         d.field("file_cache", &"[internal cache]".as_clean());
         d.field("response_cache", &"[internal cache]".as_clean());
+        d.field("access_log", &self.access_log);
+        #[cfg(feature = "metrics")]
+        d.field("metrics", &self.metrics);
         d.field("settings", &self.options);
         d.finish()
     }
@@ -394,19 +537,78 @@ pub struct Options {
     /// Default is `public`
     pub public_data_dir: Option<PathBuf>,
 
-    /// Disables further caching by sending a [`StatusCode::NOT_MODIFIED`] when the
-    /// `if-modified-since` header is sent and the resource is fresh.
+    /// Disables the `last-modified` header and the `if-modified-since` and
+    /// `if-unmodified-since` preconditions.
+    ///
+    /// Conditional requests using the `etag` (`if-match` and `if-none-match`) are still handled.
     pub disable_if_modified_since: bool,
 
     /// Disables file system access for public files.
     ///
     /// This still enables custom error messages and reading of files through extensions.
     pub disable_fs: bool,
+
+    /// Files larger than this (in bytes) are streamed from disk in chunks
+    /// instead of being read into memory.
+    /// Streamed files are neither compressed nor cached,
+    /// and [`extensions::Present`] extensions only see an empty body.
+    ///
+    /// If no value is passed, [`Options::DEFAULT_STREAMING_THRESHOLD`] is assumed.
+    pub streaming_threshold: Option<u64>,
+
+    /// The time a client has to send the whole request body when it's read
+    /// using [`Body::read_to_bytes`].
+    /// If it runs out, an error of kind [`io::ErrorKind::TimedOut`] is returned.
+    ///
+    /// If no value is passed, [`Options::DEFAULT_BODY_TIMEOUT`] is assumed.
+    pub body_timeout: Option<Duration>,
+    /// The time we have to write the response head and body to the client.
+    /// If it runs out, the connection is closed.
+    ///
+    /// This doesn't limit [`extensions::ResponsePipeFuture`]s, which handle upgraded connections.
+    /// Increase this if you serve large files to clients on slow connections.
+    ///
+    /// If no value is passed, [`Options::DEFAULT_RESPONSE_TIMEOUT`] is assumed.
+    pub response_timeout: Option<Duration>,
+
+    /// The maximum size (in bytes) of request bodies.
+    /// Requests with a larger `content-length` get a [`StatusCode::PAYLOAD_TOO_LARGE`] response,
+    /// and reading more from a [`Body`] returns an error.
+    ///
+    /// If no value is passed, [`Options::DEFAULT_MAX_BODY_SIZE`] is assumed.
+    pub max_body_size: Option<u64>,
+    /// Overrides [`Self::max_body_size`] for request paths starting with the first value.
+    /// The longest matching path is used.
+    ///
+    /// Add paths using [`Self::set_path_max_body_size`].
+    pub path_max_body_sizes: Vec<(String, u64)>,
+
+    /// The levels to compress responses with.
+    ///
+    /// Bodies are compressed once and cached with the response,
+    /// unless the response isn't cached by the server.
+    pub compression_levels: comprash::CompressionLevels,
+    /// Sends the uncompressed body while a response is compressed for the first time,
+    /// instead of waiting for the compression to finish.
+    ///
+    /// The compression continues in the background, and later requests get the compressed body.
+    /// Useful if you have large responses which take long to compress.
+    pub identity_while_compressing: bool,
 }
 impl Options {
+    /// The default for [`Self::streaming_threshold`], 8 MiB.
+    pub const DEFAULT_STREAMING_THRESHOLD: u64 = 8 * 1024 * 1024;
+    /// The default for [`Self::body_timeout`], 30 seconds.
+    pub const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+    /// The default for [`Self::response_timeout`], 10 minutes.
+    pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(600);
+    /// The default for [`Self::max_body_size`], 16 MiB.
+    pub const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
     /// Creates a new [`Options`] with default settings.
     ///
-    /// All [`Option`]s are [`None`] and all booleans are `false`.
+    /// All [`Option`]s are [`None`], all booleans are `false`,
+    /// and the [`Self::compression_levels`] are the defaults.
     pub fn new() -> Self {
         Self {
             folder_default: None,
@@ -415,6 +617,13 @@ impl Options {
             public_data_dir: None,
             disable_if_modified_since: false,
             disable_fs: false,
+            streaming_threshold: None,
+            body_timeout: None,
+            response_timeout: None,
+            max_body_size: None,
+            path_max_body_sizes: Vec::new(),
+            compression_levels: comprash::CompressionLevels::new(),
+            identity_while_compressing: false,
         }
     }
     /// Disables client cache on this host.
@@ -432,6 +641,93 @@ impl Options {
         self.disable_fs = true;
         self
     }
+    /// Sets the size (in bytes) above which files are streamed from disk.
+    ///
+    /// See [`Self::streaming_threshold`] for more info.
+    pub fn set_streaming_threshold(&mut self, bytes: u64) -> &mut Self {
+        self.streaming_threshold = Some(bytes);
+        self
+    }
+    /// Gets the size (in bytes) above which files are streamed from disk.
+    #[must_use]
+    pub fn get_streaming_threshold(&self) -> u64 {
+        self.streaming_threshold
+            .unwrap_or(Self::DEFAULT_STREAMING_THRESHOLD)
+    }
+    /// Sets the time a client has to send the request body.
+    ///
+    /// See [`Self::body_timeout`] for more info.
+    pub fn set_body_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.body_timeout = Some(timeout);
+        self
+    }
+    /// Gets the time a client has to send the request body.
+    #[must_use]
+    pub fn get_body_timeout(&self) -> Duration {
+        self.body_timeout.unwrap_or(Self::DEFAULT_BODY_TIMEOUT)
+    }
+    /// Sets the time we have to write the response.
+    ///
+    /// See [`Self::response_timeout`] for more info.
+    pub fn set_response_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.response_timeout = Some(timeout);
+        self
+    }
+    /// Gets the time we have to write the response.
+    #[must_use]
+    pub fn get_response_timeout(&self) -> Duration {
+        self.response_timeout
+            .unwrap_or(Self::DEFAULT_RESPONSE_TIMEOUT)
+    }
+    /// Sets the maximum size (in bytes) of request bodies.
+    ///
+    /// See [`Self::max_body_size`] for more info.
+    pub fn set_max_body_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_body_size = Some(bytes);
+        self
+    }
+    /// Sets the maximum size (in bytes) of request bodies to paths starting with `path`.
+    ///
+    /// This can be used to allow large uploads to a single endpoint.
+    /// See [`Self::path_max_body_sizes`] for more info.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kvarn::prelude::*;
+    /// let mut options = host::Options::new();
+    /// options
+    ///     .set_max_body_size(1024)
+    ///     .set_path_max_body_size("/upload/", 1024 * 1024 * 1024);
+    ///
+    /// assert_eq!(options.get_max_body_size("/index.html"), 1024);
+    /// assert_eq!(options.get_max_body_size("/upload/video"), 1024 * 1024 * 1024);
+    /// ```
+    pub fn set_path_max_body_size(&mut self, path: impl Into<String>, bytes: u64) -> &mut Self {
+        let path = path.into();
+        self.path_max_body_sizes.retain(|(p, _)| *p != path);
+        self.path_max_body_sizes.push((path, bytes));
+        self
+    }
+    /// Gets the maximum size (in bytes) of request bodies to `path`.
+    #[must_use]
+    pub fn get_max_body_size(&self, path: &str) -> u64 {
+        self.path_max_body_sizes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or_else(
+                || self.max_body_size.unwrap_or(Self::DEFAULT_MAX_BODY_SIZE),
+                |(_, bytes)| *bytes,
+            )
+    }
+    /// Sets the levels to compress responses with.
+    ///
+    /// See [`Self::compression_levels`] for more info.
+    pub fn set_compression_levels(&mut self, levels: comprash::CompressionLevels) -> &mut Self {
+        self.compression_levels = levels;
+        self
+    }
     /// Sets the relative directory (from the [`Host::path`]) to fetch data for the web in.
     /// Defaults to `public`
     pub fn set_public_data_dir(&mut self, path: impl AsRef<Path>) -> &mut Self {
@@ -453,7 +749,14 @@ impl DataBuilder {
     /// Adds `host` to the builder. See [`Data::add_host`], which is called internally.
     #[inline]
     pub fn add_host(mut self, host: Host) -> Self {
-        self.0.add_host(host.name, host);
+        let name = host.name.clone();
+        self.0.add_host(name, host);
+        self
+    }
+    /// Sets what to do with requests to unknown hosts. See [`Data::set_unknown_host`].
+    #[inline]
+    pub fn set_unknown_host(mut self, action: UnknownHost) -> Self {
+        self.0.set_unknown_host(action);
         self
     }
     /// Puts the inner [`Data`] in a [`Arc`] and returns it.
@@ -471,76 +774,143 @@ impl DataBuilder {
 }
 
 /// A collection of [`Host`]s, with exactly one default and
-/// arbitrarily many other, indexed by [`Host.name`].
+/// arbitrarily many other, indexed by [`Host.name`] and [`Host.aliases`].
 ///
 /// If only a default is specified, all requests, (e.g. those who lack a `host` header,
 /// have none, or all other values of the header) are channelled to the default.
 ///
+/// Names can be wildcard patterns, such as `*.example.org`, which match exactly one label;
+/// `www.example.org` but neither `example.org` nor `a.b.example.org`.
+/// Names without wildcards take precedence, then the longest matching pattern.
+/// Names are matched case-insensitively, and the port of the `host` header is ignored.
+///
 /// If the feature `https` is enabled, [`rustls::ResolvesServerCert`] in implemented
 /// using this default and host name pattern.
 #[derive(Debug)]
 #[must_use]
 pub struct Data {
-    default: Host,
-    by_name: HashMap<&'static str, Host>,
+    /// All hosts; the first is the default.
+    hosts: Vec<Host>,
+    /// Indices in `hosts` by the lowercase name.
+    by_name: HashMap<String, usize>,
+    /// The suffixes of wildcard patterns (e.g. `.example.org`) and indices in `hosts`,
+    /// with the longest suffix first.
+    wildcards: Vec<(String, usize)>,
+    unknown_host: UnknownHost,
     has_secure: bool,
 }
 impl Data {
     /// Creates a new [`DataBuilder`] with `default_host` as the default.
     #[inline]
     pub fn builder(default_host: Host) -> DataBuilder {
-        DataBuilder(Self {
-            has_secure: default_host.is_secure(),
-            default: default_host,
-            by_name: HashMap::new(),
-        })
+        DataBuilder(Self::new(default_host))
     }
     /// Creates a new [`Data`] with `default_host` as the default.
     /// Consider using [`Data::builder`] for a more ergonomic API.
-    #[inline]
     pub fn new(default_host: Host) -> Self {
-        Self {
-            has_secure: default_host.is_secure(),
-            default: default_host,
+        let mut data = Self {
+            hosts: Vec::new(),
             by_name: HashMap::new(),
-        }
+            wildcards: Vec::new(),
+            unknown_host: UnknownHost::Default,
+            has_secure: false,
+        };
+        let name = default_host.name.clone();
+        data.add_host(name, default_host);
+        data
     }
     /// Creates a `Host` without certification, using the directories `./public` and `./templates`.
     #[inline]
-    pub fn simple_non_secure(default_host_name: &'static str, extensions: Extensions) -> Self {
-        Self {
-            default: Host::non_secure(
-                default_host_name,
-                ".",
-                extensions,
-                Options::default(),
-            ),
-            by_name: HashMap::new(),
-            has_secure: false,
-        }
+    pub fn simple_non_secure(default_host_name: impl Into<String>, extensions: Extensions) -> Self {
+        Self::new(Host::non_secure(
+            default_host_name,
+            ".",
+            extensions,
+            Options::default(),
+        ))
     }
     /// Adds a [`Host`] to self.
     ///
     /// `host_name` should often be [`Host.name`].
-    #[inline]
-    pub fn add_host(&mut self, host_name: &'static str, host_data: Host) {
+    /// The [`Host.name`] and [`Host.aliases`] of `host_data` are also added.
+    pub fn add_host(&mut self, host_name: impl Into<String>, host_data: Host) {
         if host_data.is_secure() {
             self.has_secure = true;
         }
-        self.by_name.insert(host_name, host_data);
+        let index = self.hosts.len();
+        let host_name = host_name.into();
+        let names = std::iter::once(host_name.as_str())
+            .chain(std::iter::once(host_data.name.as_str()))
+            .chain(host_data.aliases.iter().map(String::as_str));
+        for name in names {
+            let name = normalize_name(name);
+            match name.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') => {
+                    let suffix = suffix.to_owned();
+                    self.wildcards.retain(|(other, _)| *other != suffix);
+                    let position = self
+                        .wildcards
+                        .iter()
+                        .position(|(other, _)| other.len() < suffix.len())
+                        .unwrap_or_else(|| self.wildcards.len());
+                    self.wildcards.insert(position, (suffix, index));
+                }
+                _ => {
+                    self.by_name.insert(name, index);
+                }
+            }
+        }
+        self.hosts.push(host_data);
+    }
+
+    /// Sets what to do with requests to hosts which aren't in `self`.
+    ///
+    /// With anything but [`UnknownHost::Default`], TLS handshakes with an unknown
+    /// SNI hostname fail. Clients without SNI get the default [`Host`].
+    /// See [`Data::lookup`] for how requests are handled.
+    #[inline]
+    pub fn set_unknown_host(&mut self, action: UnknownHost) -> &mut Self {
+        self.unknown_host = action;
+        self
+    }
+    /// Gets what to do with requests to hosts which aren't in `self`.
+    #[inline]
+    pub fn get_unknown_host(&self) -> UnknownHost {
+        self.unknown_host
     }
 
     /// Returns a reference to the default [`Host`].
     ///
-    /// Use [`Data::smart_get`] to get the appropriate host.
+    /// Use [`Data::lookup`] to get the appropriate host.
     #[inline]
     pub fn get_default(&self) -> &Host {
-        &self.default
+        // There's always a default, added when creating `self`.
+        &self.hosts[0]
     }
-    /// Gets a [`Host`] by name.
-    #[inline]
+    /// Gets a [`Host`] by name or by a wildcard pattern matching `host`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kvarn::prelude::*;
+    /// let mut host = Host::non_secure("example.org", PathBuf::from("web"), Extensions::default(), host::Options::default());
+    /// host.add_alias("*.example.org");
+    /// let data = Data::builder(host).build();
+    ///
+    /// assert!(data.get_host("Example.org.").is_some());
+    /// assert!(data.get_host("www.example.org").is_some());
+    /// assert!(data.get_host("a.b.example.org").is_none());
+    /// ```
     pub fn get_host(&self, host: &str) -> Option<&Host> {
-        self.by_name.get(host)
+        let host = normalize_name(host);
+        let index = self.by_name.get(&host).copied().or_else(|| {
+            self.wildcards.iter().find_map(|(suffix, index)| {
+                host.strip_suffix(suffix.as_str())
+                    .filter(|label| !label.is_empty() && !label.contains('.'))
+                    .map(|_| *index)
+            })
+        })?;
+        Some(&self.hosts[index])
     }
     /// Gets a [`Host`] by name, and returns the [`default`](Data::get_default) if none were found.
     #[inline]
@@ -568,24 +938,112 @@ impl Data {
                 .get(header::HOST)
                 .map(HeaderValue::to_str)
                 .and_then(Result::ok)
+                .map(strip_port)
         }
 
         let host = sni_hostname.or_else(|| get_header(request.headers()));
 
         self.maybe_get_or_default(host)
     }
+    /// Gets the [`Host`] of a TLS connection with `sni_hostname`.
+    ///
+    /// Returns [`None`] if the handshake should fail, as set by [`Data::set_unknown_host`].
+    pub fn get_by_sni(&self, sni_hostname: Option<&str>) -> Option<&Host> {
+        match sni_hostname {
+            Some(name) => self.get_host(name).or_else(|| self.fallback().ok()),
+            None => Some(self.get_default()),
+        }
+    }
+    /// Gets the host for `request`, received on a connection with `sni_hostname`.
+    ///
+    /// The host is named by the authority of the [`Uri`] (HTTP/2 and HTTP/3)
+    /// or the [`header::HOST`], falling back to `sni_hostname`.
+    ///
+    /// # Errors
+    ///
+    /// If the request names a host other than the one of `sni_hostname`,
+    /// [`LookupError::Misdirected`] is returned. This happens when HTTP/2 clients reuse
+    /// a connection for another domain with the same certificate (connection coalescing);
+    /// the client then retries on a new connection.
+    ///
+    /// If the host isn't known, [`Data::get_unknown_host`] decides the outcome.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kvarn::prelude::*;
+    /// # use kvarn::host::{LookupError, UnknownHost};
+    /// let host = Host::non_secure("example.org", PathBuf::from("web"), Extensions::default(), host::Options::default());
+    /// let data = Data::builder(host).set_unknown_host(UnknownHost::Misdirected).build();
+    /// let request = |name| Request::get("/").header("host", name).body(application::Body::Empty).unwrap();
+    ///
+    /// assert!(data.lookup(&request("example.org:8080"), None).is_ok());
+    /// assert_eq!(data.lookup(&request("icelk.dev"), None).err(), Some(LookupError::Misdirected));
+    /// assert_eq!(data.lookup(&request("example.org"), Some("icelk.dev")).err(), Some(LookupError::Misdirected));
+    /// ```
+    pub fn lookup(
+        &self,
+        request: &Request<Body>,
+        sni_hostname: Option<&str>,
+    ) -> Result<&Host, LookupError> {
+        let requested = request.uri().host().or_else(|| {
+            request
+                .headers()
+                .get(header::HOST)
+                .and_then(|header| header.to_str().ok())
+                .map(strip_port)
+        });
+        let host = match sni_hostname {
+            Some(sni_hostname) => match self.get_host(sni_hostname) {
+                Some(host) => host,
+                None => self.fallback()?,
+            },
+            None => match requested {
+                Some(requested) => {
+                    return self.get_host(requested).map_or_else(|| self.fallback(), Ok)
+                }
+                None => return Ok(self.get_default()),
+            },
+        };
+        match requested.map(|requested| self.get_host(requested)) {
+            // The connection was made for another host.
+            Some(Some(requested)) if !std::ptr::eq(requested, host) => {
+                Err(LookupError::Misdirected)
+            }
+            Some(None) if self.unknown_host != UnknownHost::Default => self.fallback(),
+            _ => Ok(host),
+        }
+    }
+    /// The outcome for a request to an unknown host.
+    fn fallback(&self) -> Result<&Host, LookupError> {
+        match self.unknown_host {
+            UnknownHost::Default => Ok(self.get_default()),
+            UnknownHost::Misdirected => Err(LookupError::Misdirected),
+            UnknownHost::Drop => Err(LookupError::Drop),
+        }
+    }
 
     /// Returns if any [`Host`]s are [`Host::is_secure`].
     #[inline]
     pub fn has_secure(&self) -> bool {
         self.has_secure
     }
+    /// Starts getting the certificates of all [`Host`]s with ACME enabled.
+    #[cfg(feature = "acme")]
+    pub(crate) fn start_acme(&self) {
+        for host in &self.hosts {
+            if let Some(acme) = &host.acme {
+                acme.start(&host.path);
+            }
+        }
+    }
 
     /// Makes a [`rustls::ServerConfig`] from [`Data`].
     ///
     /// This takes [`Data`] in an [`Arc`] and clones it.
     ///
-    /// You should not have to call this, since [`PortDescriptor::new`] and [`PortDescriptor::https`] calls it internally.
+    /// You should not have to call this, since [`PortDescriptor::new`] and [`PortDescriptor::https`]
+    /// call [`DataHandle::make_config`] internally.
     /// Though, you could use the [`host`] system by itself, without the rest of Kvarn.
     #[cfg(feature = "https")]
     #[inline]
@@ -598,17 +1056,25 @@ impl Data {
         config
     }
 
+    /// Makes a [`quinn::ServerConfig`] for HTTP/3 from [`Data`].
+    ///
+    /// The certificates of the [`Host`]s are resolved the same way as with [`Data::make_config`].
+    /// Only TLS 1.3 is used, as required by QUIC.
+    ///
+    /// You should not have to call this, since [`PortDescriptor::enable_http3`]
+    /// calls [`DataHandle::make_quic_config`] internally.
+    #[cfg(feature = "http3")]
+    #[must_use]
+    pub fn make_quic_config(self: &Arc<Self>) -> quinn::ServerConfig {
+        DataHandle::new(Arc::clone(self)).make_quic_config()
+    }
+
     /// Clears all response caches.
     #[inline]
-    pub async fn clear_response_caches(&self) {
-        if let Some(cache) = &self.default.response_cache {
-            // Handle default host
-            cache.lock().await.clear();
-            // All other
-            for host in self.by_name.values() {
-                if let Some(cache) = &host.response_cache {
-                    cache.lock().await.clear();
-                }
+    pub fn clear_response_caches(&self) {
+        for host in &self.hosts {
+            if let Some(cache) = &host.response_cache {
+                cache.clear();
             }
         }
     }
@@ -621,29 +1087,21 @@ impl Data {
     /// This will probably become a error enum in the future.
     ///
     /// It will lever return (false, true).
-    pub async fn clear_page(&self, host: &str, uri: &Uri) -> (bool, bool) {
+    pub fn clear_page(&self, host: &str, uri: &Uri) -> (bool, bool) {
         let key = UriKey::path_and_query(uri);
 
+        let host = if host.is_empty() || host == "default" {
+            Some(self.get_default())
+        } else {
+            self.get_host(host)
+        };
         let mut found = false;
         let mut cleared = false;
-        if host.is_empty() || host == "default" {
-            found = true;
-            if let Some(cache) = &self.default.response_cache {
-                let mut lock = cache.lock().await;
-                if key
-                    .call_all(|key| lock.remove(key).into_option())
-                    .1
-                    .is_some()
-                {
-                    cleared = true;
-                }
-            }
-        } else if let Some(host) = self.by_name.get(host) {
+        if let Some(host) = host {
             found = true;
             if let Some(cache) = &host.response_cache {
-                let mut lock = cache.lock().await;
                 if key
-                    .call_all(|key| lock.remove(key).into_option())
+                    .call_all(|key| cache.remove(key).into_option())
                     .1
                     .is_some()
                 {
@@ -655,42 +1113,21 @@ impl Data {
     }
     /// Clears all file caches.
     #[inline]
-    pub async fn clear_file_caches(&self) {
-        if let Some(cache) = &self.default.file_cache {
-            cache.lock().await.clear();
-        }
-        for host in self.by_name.values() {
+    pub fn clear_file_caches(&self) {
+        for host in &self.hosts {
             if let Some(cache) = &host.file_cache {
-                cache.lock().await.clear();
+                cache.clear();
             }
         }
     }
     /// Clears the `path` from all caches.
     ///
-    /// This iterates over all caches and [locks](Mutex::lock) them, which takes a lot of time.
-    /// Though, it's not blocking.
-    pub async fn clear_file_in_cache<P: AsRef<Path>>(&self, path: &P) -> bool {
+    /// This iterates over all caches, locking the shard `path` is in of each.
+    pub fn clear_file_in_cache<P: AsRef<Path>>(&self, path: &P) -> bool {
         let mut found = false;
-        if let Some(cache) = &self.default.file_cache {
-            if cache
-                .lock()
-                .await
-                .remove(path.as_ref())
-                .into_option()
-                .is_some()
-            {
-                found = true;
-            }
-        }
-        for host in self.by_name.values() {
+        for host in &self.hosts {
             if let Some(cache) = &host.file_cache {
-                if cache
-                    .lock()
-                    .await
-                    .remove(path.as_ref())
-                    .into_option()
-                    .is_some()
-                {
+                if cache.remove(path.as_ref()).into_option().is_some() {
                     found = true;
                 }
             }
@@ -698,6 +1135,41 @@ impl Data {
         found
     }
 }
+/// What to do with requests to hosts which aren't in a [`Data`].
+///
+/// See [`Data::set_unknown_host`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownHost {
+    /// Handle the request with the default [`Host`].
+    ///
+    /// This is the default.
+    Default,
+    /// Respond with [`StatusCode::MISDIRECTED_REQUEST`] and fail TLS handshakes.
+    Misdirected,
+    /// Close the connection and fail TLS handshakes.
+    Drop,
+}
+/// The error of [`Data::lookup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupError {
+    /// Respond with [`StatusCode::MISDIRECTED_REQUEST`].
+    Misdirected,
+    /// Close the connection.
+    Drop,
+}
+
+/// Lowercases `name` and removes any trailing `.` of a fully qualified domain name.
+fn normalize_name(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+/// Removes the port from the value of a `host` header.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        // Don't remove parts of IPv6 addresses, e.g. `[::1]`.
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
 #[cfg(feature = "https")]
 impl ResolvesServerCert for Data {
     #[inline]
@@ -705,9 +1177,209 @@ impl ResolvesServerCert for Data {
         // Mostly returns true, since we have a default
         // Will however return false if certificate is not present
         // in found host or default host.
-        self.maybe_get_or_default(client_hello.server_name().map(|n| n.into()))
-            .certificate
-            .clone()
+        let host = self.get_by_sni(client_hello.server_name().map(|n| n.into()))?;
+        #[cfg(feature = "acme")]
+        if let Some(certificate) = host
+            .acme
+            .as_ref()
+            .and_then(|acme| acme.challenge_certificate(&client_hello))
+        {
+            return Some(certificate);
+        }
+        host.current_certificate()
+    }
+}
+
+/// A handle to [`Data`] which can be replaced while Kvarn is running.
+///
+/// This is what [`PortDescriptor`]s use. New connections get the current [`Data`],
+/// so when it's [replaced](Self::replace), they use the new [`Host`]s, extensions, and certificates.
+/// Connections already open keep using the [`Data`] they started with.
+///
+/// Cloning a [`DataHandle`] gives a new handle to the same [`Data`].
+/// Keep a clone to replace the [`Data`] after passing the handle to [`PortDescriptor`]s.
+///
+/// # Examples
+///
+/// ```
+/// # use kvarn::prelude::*;
+/// use kvarn::host::DataHandle;
+///
+/// let host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), host::Options::default());
+/// let handle = DataHandle::new(Data::builder(host).build());
+/// let port_descriptor = PortDescriptor::non_secure(8080, handle.clone());
+///
+/// // Later, e.g. after the configuration changed.
+/// let mut options = host::Options::default();
+/// options.disable_client_cache();
+/// let host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), options);
+/// handle.replace(Data::builder(host).build());
+/// ```
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct DataHandle(Arc<RwLock<Arc<Data>>>);
+impl DataHandle {
+    /// Creates a new handle to `data`.
+    #[inline]
+    pub fn new(data: Arc<Data>) -> Self {
+        Self(Arc::new(RwLock::new(data)))
+    }
+    /// Gets the current [`Data`].
+    #[inline]
+    #[must_use]
+    pub fn get(&self) -> Arc<Data> {
+        // The lock is only held to clone or replace the `Arc`; we never panic while holding it.
+        let data = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(&data)
+    }
+    /// Replaces the [`Data`] with `data`, returning the previous.
+    ///
+    /// New connections use `data`.
+    /// The previous [`Data`] is dropped when all connections using it are closed.
+    pub fn replace(&self, data: Arc<Data>) -> Arc<Data> {
+        info!("Replacing the host data.");
+        #[cfg(feature = "acme")]
+        data.start_acme();
+        let mut lock = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *lock, data)
+    }
+
+    /// Makes a [`rustls::ServerConfig`] resolving certificates from the current [`Data`] of this handle.
+    ///
+    /// See [`Data::make_config`].
+    #[cfg(feature = "https")]
+    #[inline]
+    #[must_use]
+    pub fn make_config(&self) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::new(self.clone());
+        config.alpn_protocols = alpn();
+        config
+    }
+    /// Makes a [`quinn::ServerConfig`] resolving certificates from the current [`Data`] of this handle.
+    ///
+    /// See [`Data::make_quic_config`].
+    #[cfg(feature = "http3")]
+    #[must_use]
+    pub fn make_quic_config(&self) -> quinn::ServerConfig {
+        use quinn::rustls;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            // `ring` supports TLS 1.3.
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(quic::CertResolver(self.clone())));
+        config.alpn_protocols = vec![b"h3".to_vec()];
+        // The config has TLS 1.3 and an initial cipher suite, as required by QUIC.
+        let config = quinn::crypto::rustls::QuicServerConfig::try_from(config).unwrap();
+        quinn::ServerConfig::with_crypto(Arc::new(config))
+    }
+}
+impl From<Arc<Data>> for DataHandle {
+    #[inline]
+    fn from(data: Arc<Data>) -> Self {
+        Self::new(data)
+    }
+}
+impl From<Data> for DataHandle {
+    #[inline]
+    fn from(data: Data) -> Self {
+        Self::new(Arc::new(data))
+    }
+}
+#[cfg(feature = "https")]
+impl ResolvesServerCert for DataHandle {
+    #[inline]
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<sign::CertifiedKey> {
+        self.get().resolve(client_hello)
+    }
+}
+
+/// Adapters to use the certificates of [`Host`]s with the newer version of [`rustls`] used by [`quinn`].
+#[cfg(feature = "http3")]
+mod quic {
+    use super::{sign, DataHandle};
+    use crate::prelude::*;
+    use ::rustls::internal::msgs::codec::Codec;
+    use quinn::rustls::{
+        self,
+        pki_types::CertificateDer,
+        server::{ClientHello, ResolvesServerCert},
+        sign as quic_sign, SignatureAlgorithm, SignatureScheme,
+    };
+
+    fn scheme_to_old(scheme: SignatureScheme) -> Option<::rustls::SignatureScheme> {
+        ::rustls::SignatureScheme::read_bytes(&u16::from(scheme).to_be_bytes())
+    }
+    fn scheme_from_old(scheme: ::rustls::SignatureScheme) -> SignatureScheme {
+        SignatureScheme::from(scheme.get_u16())
+    }
+
+    pub(super) struct CertResolver(pub(super) DataHandle);
+    impl Debug for CertResolver {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("CertResolver")
+                .field(&"[host data]".as_clean())
+                .finish()
+        }
+    }
+    impl ResolvesServerCert for CertResolver {
+        fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<quic_sign::CertifiedKey>> {
+            let data = self.0.get();
+            let certified_key = data
+                .get_by_sni(client_hello.server_name())?
+                .current_certificate()?;
+            let chain = certified_key
+                .cert
+                .iter()
+                .map(|cert| CertificateDer::from(cert.0.clone()))
+                .collect();
+            let mut key = quic_sign::CertifiedKey::new(
+                chain,
+                Arc::new(SigningKey(Arc::clone(&certified_key.key))),
+            );
+            key.ocsp.clone_from(&certified_key.ocsp);
+            Some(Arc::new(key))
+        }
+    }
+
+    struct SigningKey(Arc<Box<dyn sign::SigningKey>>);
+    impl Debug for SigningKey {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("SigningKey")
+                .field(&"[private key]".as_clean())
+                .finish()
+        }
+    }
+    impl quic_sign::SigningKey for SigningKey {
+        fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn quic_sign::Signer>> {
+            let offered: Vec<_> = offered.iter().copied().filter_map(scheme_to_old).collect();
+            self.0
+                .choose_scheme(&offered)
+                .map(|signer| Box::new(Signer(signer)) as Box<dyn quic_sign::Signer>)
+        }
+        fn algorithm(&self) -> SignatureAlgorithm {
+            SignatureAlgorithm::from(self.0.algorithm().get_u8())
+        }
+    }
+
+    struct Signer(Box<dyn sign::Signer>);
+    impl Debug for Signer {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("Signer").field(&self.0.get_scheme()).finish()
+        }
+    }
+    impl quic_sign::Signer for Signer {
+        fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+            self.0
+                .sign(message)
+                .map_err(|err| rustls::Error::General(err.to_string()))
+        }
+        fn scheme(&self) -> SignatureScheme {
+            scheme_from_old(self.0.get_scheme())
+        }
     }
 }
 
@@ -4,7 +4,7 @@
 //!
 //! It handles several things for you, including
 //! - Content-Type
-//! - Compression of body
+//! - Compression of body using Brotli, gzip, or Zstandard (with the `zstd` feature)
 //! - Correct and performant HTTP/1 and HTTP/2
 //! - Common API across HTTP/1 and HTTP/2
 //! - Easy integration with HTTP/2 push promises
@@ -12,6 +12,12 @@
 //! - Optional encryption with [`rustls`](https://docs.rs/rustls)
 //! - Several checks for illegal requests
 //! - `cache-control` and [`kvarn-cache-control`](parse::CacheControl::from_kvarn_cache_control) header limits server cache lifetimes
+//! - [WebSockets](websocket) over HTTP/1.1 and HTTP/2, with the `websocket` feature
+//! - [Authentication](auth) using signed tokens, with the `auth` feature
+//! - HTTP/3 over QUIC, with the `http3` feature. See [`PortDescriptor::enable_http3`]
+//! - [Access logs](access_log) in the Common, Combined, or a JSON format, with rotation
+//! - Certificates from [ACME](acme) certificate authorities, such as Let's Encrypt, with the `acme` feature
+//! - [Metrics](metrics) in the Prometheus format, with the `metrics` feature
 //!
 //! # Getting started
 //!
@@ -47,16 +53,25 @@
 #![doc(html_root_url = "https://doc.kvarn.org/")]
 
 // Module declaration
+pub mod access_log;
+#[cfg(feature = "acme")]
+pub mod acme;
 pub mod application;
+#[cfg(feature = "auth")]
+pub mod auth;
 pub mod comprash;
 pub mod encryption;
 pub mod error;
 pub mod extensions;
 pub mod host;
 pub mod limiting;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod prelude;
 pub mod read;
 pub mod shutdown;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 use prelude::{internals::*, networking::*, *};
 // When user only imports crate::* and not crate::prelude::*
@@ -82,8 +97,7 @@ macro_rules! ret_log_app_error {
 /// Run the Kvarn web server on `ports`.
 ///
 /// Will bind a [`TcpListener`] on every `port` in [`PortDescriptor`].
-///
-/// > This ↑ will change when HTTP/3 support arrives, then Udp will also be used.
+/// If HTTP/3 is [enabled](PortDescriptor::enable_http3), a QUIC endpoint is also bound on the UDP port.
 ///
 /// This is the last step in getting Kvarn spinning.
 /// You can interact with the caches through the [`Host`] and [`Data`] you created, and
@@ -117,20 +131,44 @@ macro_rules! ret_log_app_error {
 /// # };
 /// ```
 pub async fn run(ports: RunConfig) -> Arc<shutdown::Manager> {
-    let RunConfig { ports, handover, handover_socket_path } = ports;
+    let RunConfig {
+        ports,
+        handover,
+        handover_socket_path,
+        reload,
+    } = ports;
     info!("Starting server on {} ports.", ports.len());
 
     let len = ports.len();
     let mut shutdown_manager = shutdown::Manager::new(len);
 
     let mut listeners = Vec::with_capacity(len * 2);
+    #[cfg(feature = "http3")]
+    let mut quic_listeners = Vec::new();
     for descriptor in ports {
+        /// Creates a non-blocking socket for `address`.
+        ///
+        /// IPv6 sockets only accept IPv6 traffic, so the IPv4 and IPv6
+        /// unspecified addresses can be bound on the same port.
+        fn create_socket(address: SocketAddr, kind: socket2::Type) -> socket2::Socket {
+            let socket = socket2::Socket::new(socket2::Domain::for_address(address), kind, None)
+                .expect("Failed to create a new socket configuration");
+            if address.is_ipv6() {
+                socket
+                    .set_only_v6(true)
+                    .expect("Failed to disable IPv4 on IPv6 socket");
+            }
+            socket
+                .set_nonblocking(true)
+                .expect("Failed to set socket to non-blocking");
+            socket
+        }
         fn create_listener(
-            create_socket: impl Fn() -> TcpSocket,
             address: SocketAddr,
             shutdown_manager: &mut shutdown::Manager,
         ) -> AcceptManager {
-            let socket = create_socket();
+            let socket =
+                TcpSocket::from_std_stream(create_socket(address, socket2::Type::STREAM).into());
             #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
             {
                 if socket.set_reuseaddr(true).is_err() || socket.set_reuseport(true).is_err() {
@@ -146,27 +184,60 @@ pub async fn run(ports: RunConfig) -> Arc<shutdown::Manager> {
             shutdown_manager.add_listener(listener)
         }
 
+        #[cfg(feature = "http3")]
+        fn create_quic_listener(
+            config: &quinn::ServerConfig,
+            address: SocketAddr,
+            shutdown_manager: &mut shutdown::Manager,
+        ) -> Option<QuicAcceptManager> {
+            let socket = create_socket(address, socket2::Type::DGRAM);
+            let endpoint = socket.bind(&address.into()).and_then(|()| {
+                quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    Some(config.clone()),
+                    socket.into(),
+                    Arc::new(quinn::TokioRuntime),
+                )
+            });
+            match endpoint {
+                Ok(endpoint) => Some(shutdown_manager.add_quic_listener(endpoint)),
+                Err(err) => {
+                    error!(
+                        "Failed to bind UDP address {}. Not running HTTP/3 on it. {:?}",
+                        address, err
+                    );
+                    None
+                }
+            }
+        }
+
         let descriptor = Arc::new(descriptor);
 
-        if matches!(descriptor.version, BindIpVersion::V4 | BindIpVersion::Both) {
-            let listener = create_listener(
-                || TcpSocket::new_v4().expect("Failed to create a new IPv4 socket configuration"),
-                net::SocketAddrV4::new(net::Ipv4Addr::UNSPECIFIED, descriptor.port).into(),
-                &mut shutdown_manager,
-            );
+        for address in descriptor.addresses() {
+            let listener = create_listener(address, &mut shutdown_manager);
             listeners.push((listener, Arc::clone(&descriptor)));
-        }
-        if matches!(descriptor.version, BindIpVersion::V6 | BindIpVersion::Both) {
-            let listener = create_listener(
-                || TcpSocket::new_v6().expect("Failed to create a new IPv6 socket configuration"),
-                SocketAddr::new(IpAddr::V6(net::Ipv6Addr::LOCALHOST), descriptor.port),
-                &mut shutdown_manager,
-            );
-            listeners.push((listener, descriptor));
+
+            #[cfg(feature = "http3")]
+            if let Some(config) = &descriptor.quic_config {
+                if let Some(listener) = create_quic_listener(config, address, &mut shutdown_manager)
+                {
+                    quic_listeners.push((listener, Arc::clone(&descriptor)));
+                }
+            }
         }
     }
 
+    if let Some(reload) = reload {
+        shutdown_manager.set_reload(reload);
+    }
     let shutdown_manager = shutdown_manager.build();
+    #[cfg(unix)]
+    shutdown::Manager::reload_on_hangup(&shutdown_manager);
+
+    #[cfg(feature = "acme")]
+    for (_, descriptor) in &listeners {
+        descriptor.data.get().start_acme();
+    }
 
     if handover{
     shutdown::Manager::initiate_handover(&shutdown_manager, handover_socket_path).await;
@@ -182,10 +253,71 @@ pub async fn run(ports: RunConfig) -> Arc<shutdown::Manager> {
 
         tokio::spawn(future);
     }
+    #[cfg(feature = "http3")]
+    for (listener, descriptor) in quic_listeners {
+        let shutdown_manager = Arc::clone(&shutdown_manager);
+        tokio::spawn(async move { accept_quic(listener, descriptor, &shutdown_manager).await });
+    }
 
     shutdown_manager
 }
 
+#[cfg(feature = "http3")]
+async fn accept_quic(
+    mut listener: QuicAcceptManager,
+    descriptor: Arc<PortDescriptor>,
+    shutdown_manager: &Arc<shutdown::Manager>,
+) {
+    trace!(
+        "Started listening for QUIC on {:?}",
+        listener.get_inner().local_addr()
+    );
+
+    while let Some(incoming) = listener.accept(shutdown_manager).await {
+        let addr = incoming.remote_address();
+        let data = descriptor.data.get();
+        let host = data.get_default();
+        let action = host.limiter.register(addr.ip()).await;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &host.metrics {
+            metrics.limit(&action);
+        }
+        match action {
+            LimitAction::Drop => {
+                incoming.refuse();
+                continue;
+            }
+            LimitAction::Send | LimitAction::Passed => {}
+        }
+        let descriptor = Arc::clone(&descriptor);
+        #[cfg(feature = "graceful-shutdown")]
+        let shutdown_manager = Arc::clone(shutdown_manager);
+        tokio::spawn(async move {
+            #[cfg(feature = "graceful-shutdown")]
+            shutdown_manager.add_connection();
+            if let Err(err) = handle_quic_connection(incoming, descriptor, || {
+                #[cfg(feature = "graceful-shutdown")]
+                {
+                    !shutdown_manager.get_shutdown(threading::Ordering::Relaxed)
+                }
+                #[cfg(not(feature = "graceful-shutdown"))]
+                {
+                    true
+                }
+            })
+            .await
+            {
+                warn!(
+                    "An error occurred in the main processing function {:?}",
+                    err
+                );
+            }
+            #[cfg(feature = "graceful-shutdown")]
+            shutdown_manager.remove_connection();
+        });
+    }
+}
+
 async fn accept(
     mut listener: AcceptManager,
     descriptor: Arc<PortDescriptor>,
@@ -201,13 +333,14 @@ async fn accept(
             AcceptAction::Shutdown => return Ok(()),
             AcceptAction::Accept(result) => match result {
                 Ok((socket, addr)) => {
-                    match descriptor
-                        .data
-                        .get_default()
-                        .limiter
-                        .register(addr.ip())
-                        .await
-                    {
+                    let data = descriptor.data.get();
+                    let host = data.get_default();
+                    let action = host.limiter.register(addr.ip()).await;
+                    #[cfg(feature = "metrics")]
+                    if let Some(metrics) = &host.metrics {
+                        metrics.limit(&action);
+                    }
+                    match action {
                         LimitAction::Drop => {
                             drop(socket);
                             return Ok(());
@@ -265,12 +398,25 @@ pub async fn handle_connection(
     stream: TcpStream,
     address: SocketAddr,
     descriptors: Arc<PortDescriptor>,
-    mut continue_accepting: impl FnMut() -> bool,
+    continue_accepting: impl FnMut() -> bool,
 ) -> io::Result<()> {
+    // HTTP/3 is advertised on the same port as this connection.
+    #[cfg(feature = "http3")]
+    let alt_svc = stream
+        .local_addr()
+        .ok()
+        .and_then(|local| descriptors.alt_svc(local.port()));
+    #[cfg(not(feature = "http3"))]
+    let alt_svc = None;
+
     // LAYER 2
     #[cfg(feature = "https")]
-    let encrypted =
-        encryption::Encryption::new_tcp(stream, descriptors.server_config.as_ref()).await?;
+    let encrypted = timeout(
+        descriptors.timeouts.handshake,
+        encryption::Encryption::new_tcp(stream, descriptors.server_config.as_ref()),
+    )
+    .await
+    .map_err(|_| handshake_timed_out())??;
     #[cfg(not(feature = "https"))]
     let encrypted = encryption::Encryption::new_tcp(stream);
 
@@ -280,6 +426,9 @@ pub async fn handle_connection(
             None | Some(b"http/1.1") => Version::HTTP_11,
             Some(b"http/1.0") => Version::HTTP_10,
             Some(b"http/0.9") => Version::HTTP_09,
+            // The handshake is all the ACME validation needs.
+            #[cfg(feature = "acme")]
+            Some(acme::ALPN_PROTOCOL) => return Ok(()),
             _ => return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "HTTP version not supported. Something is probably wrong with your alpn config.",
@@ -289,19 +438,156 @@ pub async fn handle_connection(
     debug!("New connection requesting hostname '{:?}'", hostname);
 
     // LAYER 3
-    let mut http = application::HttpConnection::new(encrypted, version)
+    let http = timeout(
+        descriptors.timeouts.handshake,
+        application::HttpConnection::new(encrypted, version),
+    )
+    .await
+    .map_err(|_| handshake_timed_out())?
+    .map_err::<io::Error, _>(application::Error::into)?;
+
+    handle_requests(
+        http,
+        address,
+        &descriptors,
+        hostname.as_deref(),
+        alt_svc,
+        continue_accepting,
+    )
+    .await
+}
+
+/// Handles a single QUIC connection, the HTTP/3 equivalent of [`handle_connection()`].
+///
+/// # Errors
+///
+/// Will pass any errors from the QUIC handshake, reading the request, and writing the response.
+/// See [`handle_cache()`] and [`handle_request()`]; errors from them are passed up, through this fn.
+#[cfg(feature = "http3")]
+pub async fn handle_quic_connection(
+    incoming: quinn::Incoming,
+    descriptors: Arc<PortDescriptor>,
+    continue_accepting: impl FnMut() -> bool,
+) -> io::Result<()> {
+    let address = incoming.remote_address();
+    // LAYER 2
+    let connection = timeout(descriptors.timeouts.handshake, incoming)
         .await
-        .map_err::<io::Error, _>(application::Error::into)?;
+        .map_err(|_| handshake_timed_out())??;
+    let hostname = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.server_name);
+    debug!("New QUIC connection requesting hostname '{:?}'", hostname);
+
+    // LAYER 3
+    let http = timeout(
+        descriptors.timeouts.handshake,
+        application::HttpConnection::new_http3(connection),
+    )
+    .await
+    .map_err(|_| handshake_timed_out())?
+    .map_err::<io::Error, _>(application::Error::into)?;
+
+    handle_requests(
+        http,
+        address,
+        &descriptors,
+        hostname.as_deref(),
+        None,
+        continue_accepting,
+    )
+    .await
+}
+
+fn handshake_timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the client didn't complete the handshake in time",
+    )
+}
+
+/// The `alt-svc` header advertising HTTP/3, added to responses in [`SendKind::send`].
+#[cfg(feature = "http3")]
+#[derive(Debug)]
+struct AltSvc(HeaderValue);
 
+/// Accepts requests from `http` and passes them to [`handle_cache()`]
+/// until the connection is closed or `continue_accepting` returns `false`.
+///
+/// `alt_svc` is added to all the responses, to advertise HTTP/3.
+async fn handle_requests(
+    mut http: application::HttpConnection,
+    address: SocketAddr,
+    descriptors: &PortDescriptor,
+    hostname: Option<&str>,
+    #[cfg_attr(not(feature = "http3"), allow(unused_variables))] alt_svc: Option<HeaderValue>,
+    mut continue_accepting: impl FnMut() -> bool,
+) -> io::Result<()> {
     info!("Accepting requests from {}", address);
 
-    while let Ok((request, mut response_pipe)) = http
-        .accept(descriptors.data.get_default().name.as_bytes())
-        .await
-    {
+    // The connection keeps using this, even if the data is replaced.
+    let data = descriptors.data.get();
+    #[cfg(feature = "metrics")]
+    let _connection = data
+        .get_default()
+        .metrics
+        .as_ref()
+        .map(|metrics| metrics.connection());
+
+    loop {
+        #[allow(unused_mut)]
+        let (mut request, mut response_pipe) = match http
+            .accept(
+                data.get_default().name.as_bytes(),
+                descriptors.timeouts.idle,
+                descriptors.timeouts.header,
+            )
+            .await
+        {
+            Ok(request) => request,
+            Err(application::Error::Parse(utils::parse::Error::Timeout)) => {
+                debug!("Request head from {} timed out", address);
+                if let application::HttpConnection::Http1(stream) = &http {
+                    let mut response_pipe = application::ResponsePipe::Http1(Arc::clone(stream));
+                    let status = StatusCode::REQUEST_TIMEOUT;
+                    send_closing_error(&mut response_pipe, status, data.get_default()).await;
+                }
+                break;
+            }
+            Err(_) => break,
+        };
         trace!("Got request {:#?}", request);
-        let host = descriptors.data.smart_get(&request, hostname.as_deref());
-        match host.limiter.register(address.ip()).await {
+        #[cfg(feature = "http3")]
+        if let Some(alt_svc) = &alt_svc {
+            request.extensions_mut().insert(AltSvc(alt_svc.clone()));
+        }
+        let host = match data.lookup(&request, hostname) {
+            Ok(host) => host,
+            Err(host::LookupError::Drop) => {
+                debug!("Dropping connection from {} to unknown host", address);
+                return Ok(());
+            }
+            Err(host::LookupError::Misdirected) => {
+                debug!("Misdirected request from {}", address);
+                let status = StatusCode::MISDIRECTED_REQUEST;
+                send_closing_error(&mut response_pipe, status, data.get_default()).await;
+                if let application::ResponsePipe::Http1(_) = response_pipe {
+                    break;
+                }
+                continue;
+            }
+        };
+        let max_body_size = host.options.get_max_body_size(request.uri().path());
+        let body = request.body_mut();
+        body.set_timeout(host.options.get_body_timeout());
+        body.set_max_size(max_body_size);
+        let action = host.limiter.register(address.ip()).await;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &host.metrics {
+            metrics.limit(&action);
+        }
+        match action {
             LimitAction::Drop => return Ok(()),
             LimitAction::Send => {
                 let (mut response, body) = utils::split_response(limiting::get_too_many_requests());
@@ -313,9 +599,41 @@ pub async fn handle_connection(
             }
             LimitAction::Passed => {}
         }
+        if utils::get_body_length_request(&request) as u64 > max_body_size {
+            debug!("Request body from {} is too large", address);
+            let status = StatusCode::PAYLOAD_TOO_LARGE;
+            send_closing_error(&mut response_pipe, status, host).await;
+            // The body of the HTTP/1 request is left unread.
+            if let application::ResponsePipe::Http1(_) = response_pipe {
+                break;
+            }
+            continue;
+        }
         debug!("Accepting new connection from {} on {}", address, host.name);
-        // fn to handle getting from cache, generating response and sending it
-        handle_cache(request, address, SendKind::Send(&mut response_pipe), host).await?;
+        {
+            // fn to handle getting from cache, generating response and sending it
+            let handle = handle_cache_borrowed(
+                &mut request,
+                address,
+                SendKind::Send(&mut response_pipe),
+                host,
+            );
+            // HTTP/2 only receives the request body while the connection is driven.
+            let drive = http.drive();
+            futures::pin_mut!(handle, drive);
+            match futures::future::select(handle, drive).await {
+                futures::future::Either::Left((result, _)) => result?,
+                futures::future::Either::Right((never, _)) => match never {},
+            }
+        }
+        // The rest of the body would be parsed as the next request.
+        if !request.body().is_fully_read() {
+            debug!(
+                "Closing connection from {}; the request body wasn't read",
+                address
+            );
+            break;
+        }
 
         if !continue_accepting() {
             break;
@@ -325,6 +643,55 @@ pub async fn handle_connection(
     Ok(())
 }
 
+/// Sends the error `status` before the connection is closed.
+///
+/// On HTTP/1, the client is asked to close the connection, as the rest of the request isn't read.
+async fn send_closing_error(
+    response_pipe: &mut application::ResponsePipe,
+    status: StatusCode,
+    host: &Host,
+) {
+    let mut response = default(status, Some(host), None).await;
+    if let application::ResponsePipe::Http1(_) = response_pipe {
+        response
+            .headers_mut()
+            .insert("connection", HeaderValue::from_static("close"));
+    }
+    let (mut response, body) = utils::split_response(response);
+    response_pipe.ensure_version_and_length(&mut response, body.len());
+    let send = async {
+        let mut body_pipe = response_pipe.send_response(response, false).await?;
+        body_pipe.send_with_maybe_close(body, true).await
+    };
+    let deadline = tokio::time::Instant::now() + host.options.get_response_timeout();
+    if let Err(err) = write_before(deadline, send).await {
+        debug!("Failed to send {}: {:?}", status, err);
+    }
+}
+
+/// The response to a request with ranges outside the `len` bytes of the body.
+async fn range_not_satisfiable(len: u64, host: &Host) -> Response<Bytes> {
+    let mut response = default(StatusCode::RANGE_NOT_SATISFIABLE, Some(host), None).await;
+    let content_range =
+        HeaderValue::from_str(&format!("bytes */{}", len)).expect("we know the bytes are valid");
+    utils::replace_header(response.headers_mut(), "content-range", content_range);
+    response
+}
+
+/// Runs `future`, which writes (part of) a response, until `deadline`.
+///
+/// If the deadline passes, an error of kind [`io::ErrorKind::TimedOut`] is returned.
+async fn write_before<T>(
+    deadline: tokio::time::Instant,
+    future: impl Future<Output = Result<T, application::Error>>,
+) -> Result<T, application::Error> {
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "the response wasn't sent in time").into())
+        })
+}
+
 /// How to send data to the client.
 ///
 /// Most often, this is `Send`, but when a push promise is created,
@@ -357,32 +724,88 @@ impl<'a> SendKind<'a> {
         future: Option<
             impl FnOnce(extensions::ResponseBodyPipeWrapperMut, extensions::HostWrapper) -> F,
         >,
+        mut file_body: Option<read::FileBody>,
         address: SocketAddr,
-        data: Option<utils::CriticalRequestComponents>,
+        mut data: Option<utils::CriticalRequestComponents>,
+        log: Option<access_log::Start>,
     ) -> io::Result<()> {
-        if let Some(data) = &data {
-            data.apply_to_response(&mut response).await;
+        if let (Some(file), Some(range_data)) = (&mut file_body, &data) {
+            if let Err(err) = file.apply_range(range_data, &mut response).await {
+                debug!("Failed to apply range to streamed file: {:?}", err);
+                response = range_not_satisfiable(file.size(), host).await;
+                file_body = None;
+                data = None;
+            }
+        }
+        let len = if let Some(file_body) = &file_body {
+            // Files larger than `usize::MAX` can't be sent on 32-bit platforms anyway.
+            usize::try_from(file_body.len()).unwrap_or(usize::MAX)
+        } else {
+            if let Some(data) = &data {
+                if data.apply_to_response(&mut response).await.is_err() {
+                    let len = response.body().len() as u64;
+                    response = range_not_satisfiable(len, host).await;
+                }
+            }
+            response.body().len()
+        };
+        let status = response.status();
+        // The `future` may write more, so the length isn't known.
+        // On HTTP/1.1, the body is then sent in chunks.
+        if future.is_some()
+            && request.version() == Version::HTTP_11
+            && utils::method_has_response_body(request.method())
+            && !status.is_informational()
+            && !matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+            // The connection is a tunnel after a successful `CONNECT`.
+            && !(request.method() == Method::CONNECT && status.is_success())
+        {
+            utils::replace_header_static(response.headers_mut(), "transfer-encoding", "chunked");
         }
-
-        let len = response.body().len();
         self.ensure_version_and_length(&mut response, len);
 
         let (mut response, body) = utils::split_response(response);
 
+        #[cfg(feature = "http3")]
+        if let Some(AltSvc(alt_svc)) = request.extensions().get() {
+            response
+                .headers_mut()
+                .entry("alt-svc")
+                .or_insert_with(|| alt_svc.clone());
+        }
+
         host.extensions
             .resolve_package(&mut response, request, host)
             .await;
 
+        let status = response.status();
+        let compression = response.headers().get("content-encoding").cloned();
+        let sent_bytes = if utils::method_has_response_body(request.method()) {
+            len as u64
+        } else {
+            0
+        };
+
+        // The response (but not the `future`) has to be written before this.
+        let deadline = tokio::time::Instant::now() + host.options.get_response_timeout();
+
         match self {
             SendKind::Send(response_pipe) => {
-                // Send response
-                let mut body_pipe =
-                    ret_log_app_error!(response_pipe.send_response(response, false).await);
-
-                if utils::method_has_response_body(request.method()) {
-                    // Send body
-                    ret_log_app_error!(body_pipe.send_with_maybe_close(body, false).await);
-                }
+                let send = async {
+                    // Send response
+                    let mut body_pipe = response_pipe.send_response(response, false).await?;
+
+                    if utils::method_has_response_body(request.method()) {
+                        // Send body
+                        if let Some(file_body) = &mut file_body {
+                            body_pipe.send_file(file_body).await?;
+                        } else {
+                            body_pipe.send_with_maybe_close(body, false).await?;
+                        }
+                    }
+                    Ok(body_pipe)
+                };
+                let mut body_pipe = ret_log_app_error!(write_before(deadline, send).await);
 
                 if let Some(future) = future {
                     future(
@@ -398,7 +821,7 @@ impl<'a> SendKind<'a> {
                     .await;
 
                 // Close the pipe.
-                ret_log_app_error!(body_pipe.close().await);
+                ret_log_app_error!(write_before(deadline, body_pipe.close()).await);
             }
             SendKind::Push(push_pipe) => {
                 let send_body = utils::method_has_response_body(request.method());
@@ -408,12 +831,20 @@ impl<'a> SendKind<'a> {
                     push_pipe.send_response(response, !send_body && future.is_none())
                 );
                 if send_body {
-                    // Send body
-                    ret_log_app_error!(
-                        body_pipe
-                            .send_with_maybe_close(body, future.is_none())
-                            .await
-                    );
+                    let close = future.is_none();
+                    let send = async {
+                        // Send body
+                        if let Some(file_body) = &mut file_body {
+                            body_pipe.send_file(file_body).await?;
+                            if close {
+                                body_pipe.close().await?;
+                            }
+                        } else {
+                            body_pipe.send_with_maybe_close(body, close).await?;
+                        }
+                        Ok(())
+                    };
+                    ret_log_app_error!(write_before(deadline, send).await);
                 }
                 if let Some(future) = future {
                     future(
@@ -428,6 +859,22 @@ impl<'a> SendKind<'a> {
                 }
             }
         }
+
+        #[cfg(feature = "metrics")]
+        if let (Some(metrics), Some(start)) = (&host.metrics, &log) {
+            metrics.request(request.method(), status, sent_bytes, start.elapsed());
+        }
+        if let (Some(access_log), Some(start)) = (&host.access_log, log) {
+            access_log.log(&access_log::Entry {
+                start,
+                request,
+                host,
+                address,
+                status,
+                bytes: sent_bytes,
+                compression: compression.as_ref(),
+            });
+        }
         Ok(())
     }
 }
@@ -440,139 +887,142 @@ impl<'a> SendKind<'a> {
 /// Errors are passed from writing the response.
 ///
 /// LAYER 4
+#[inline]
 pub async fn handle_cache(
     mut request: Request<application::Body>,
     address: SocketAddr,
+    pipe: SendKind<'_>,
+    host: &Host,
+) -> io::Result<()> {
+    handle_cache_borrowed(&mut request, address, pipe, host).await
+}
+/// [`handle_cache()`], but the `request` is kept, so the state of the body can be checked afterwards.
+async fn handle_cache_borrowed(
+    request: &mut Request<application::Body>,
+    address: SocketAddr,
     mut pipe: SendKind<'_>,
     host: &Host,
 ) -> io::Result<()> {
-    let sanitize_data = utils::sanitize_request(&request);
+    #[cfg(feature = "metrics")]
+    let measured = host.metrics.is_some();
+    #[cfg(not(feature = "metrics"))]
+    let measured = false;
+    let mut log = (host.access_log.is_some() || measured).then(access_log::Start::now);
+    let sanitize_data = utils::sanitize_request(request);
 
-    let overide_uri = host
-        .extensions
-        .resolve_prime(&mut request, host, address)
-        .await;
+    let overide_uri = host.extensions.resolve_prime(request, host, address).await;
 
     let path_query =
         comprash::UriKey::path_and_query(overide_uri.as_ref().unwrap_or_else(|| request.uri()));
 
-    let mut lock = if let Some(response_cache) = &host.response_cache {
-        Some(response_cache.lock().await)
-    } else {
-        None
-    };
-
-    let cached = if let Some(lock) = &mut lock {
-        // copy of [`UriKey::call_all`].
-        // I got the message
-        // ```
-        // captured variable cannot escape `FnMut` closure body
-        // `FnMut` closures only have access to their captured variables while they are executing...
-        // ...therefore, they cannot allow references to captured variables to escape
-        // ```
-        // and had to inline it.
-        match lock.get_with_lifetime(&path_query).into_option() {
-            Some(t) => Some(t),
-            None => match path_query {
-                UriKey::Path(_) => None,
-                UriKey::PathQuery(p) => {
-                    let p = UriKey::Path(p.into_path());
-                    let t = lock.get_with_lifetime(&p).into_option();
-                    t
+    let cache_stats = host
+        .response_cache
+        .as_ref()
+        .map(|cache| Arc::clone(cache.stats()));
+    let cacheable =
+        sanitize_data.is_ok() && matches!(request.method(), &Method::GET | &Method::HEAD);
+    let mut flight = None;
+    let cached = if let Some(cache) = &host.response_cache {
+        // Only the variant for this request is cloned out of the cache.
+        let lookup = || {
+            path_query
+                .clone()
+                .call_all(|key| {
+                    cache
+                        .get_with(key, |(varied, lifetime)| {
+                            varied
+                                .get_by_headers(request.headers())
+                                .cloned()
+                                .map(|response| (response, *lifetime))
+                        })
+                        .into_option()
+                })
+                .1
+                .flatten()
+        };
+        match lookup() {
+            Some(cached) => Some(cached),
+            // Only let one of the concurrent requests of an uncached page generate it.
+            // The flights are keyed by the path and query, so requests for another variant
+            // (e.g. with another `accept-encoding`) also wait, then generate their own.
+            None if cacheable => match cache.flight(&path_query) {
+                comprash::Flight::Leader(guard) => {
+                    // It could have been cached since we looked.
+                    let cached = lookup();
+                    if cached.is_none() {
+                        flight = Some(guard);
+                    }
+                    cached
+                }
+                comprash::Flight::Follower(wait) => {
+                    debug!("Waiting for a concurrent request of {:?}.", path_query);
+                    if !wait.wait(host.options.get_response_timeout()).await {
+                        debug!("Concurrent request of {:?} timed out.", path_query);
+                    }
+                    // If it wasn't cached, this request generates it.
+                    lookup()
                 }
             },
+            None => None,
         }
     } else {
         None
     };
 
     #[allow(clippy::single_match_else)]
-    let (response, identity, future) = match cached {
-        Some((resp, (creation, _)))
-            if sanitize_data.is_ok()
-                && matches!(request.method(), &Method::GET | &Method::HEAD) =>
-        {
+    let (response, identity, future, file_body) = match cached {
+        Some((resp, (creation, _))) if cacheable => {
             info!("Found in cache!");
+            if let Some(stats) = &cache_stats {
+                stats.record(true);
+            }
+            if let Some(log) = &mut log {
+                log.cache = access_log::Cache::Hit;
+            }
 
-            let creation = *creation;
-
-            let if_modified_since: Option<time::DateTime<time::Utc>> =
-                if host.options.disable_if_modified_since {
-                    None
-                } else {
-                    request
-                        .headers()
-                        .get("if-modified-since")
-                        .and_then(|h| h.to_str().ok())
-                        .and_then(|s| time::NaiveDateTime::parse_from_str(s, parse::HTTP_DATE).ok())
-                        .map(|date_time| time::DateTime::from_utc(date_time, time::Utc))
-                };
-
-            let client_request_is_fresh = if_modified_since.map_or(false, |timestamp| {
-                // - 1s because the sent datetime floors the seconds, so the `creation`
-                // datetime is 0-1s ahead.
-                timestamp >= creation - time::Duration::seconds(1)
-            });
-
-            let mut response_data =
-                // We don't need to check for `host.options.disable_if_modified_since`
-                // but `if_modified_since` is `None` and therefore `client_request` is false
-                // if the option is enabled, as defined in the if in the `if_modified_since`
-                // definition.
-                if  client_request_is_fresh {
-                    drop(lock);
-                    let mut response = Response::new(Bytes::new());
-                    *response.status_mut() = StatusCode::NOT_MODIFIED;
-                    (response, Bytes::new(), None)
-                } else {
-                    let response = match resp.clone_preferred(&request) {
-                        Err(message) => {
-                            error::default(
-                                StatusCode::NOT_ACCEPTABLE,
-                                Some(host),
-                                Some(message.as_bytes()),
-                            )
-                            .await
-                        }
-                        Ok(response) => response,
-                    };
-                    let identity_body = Bytes::clone(resp.get_identity().body());
-                    drop(lock);
+            let preferred = resp
+                .clone_preferred_nonblocking(request, host.options.identity_while_compressing)
+                .await;
+            let mut response = match preferred {
+                Err(message) => {
+                    error::default(
+                        StatusCode::NOT_ACCEPTABLE,
+                        Some(host),
+                        Some(message.as_bytes()),
+                    )
+                    .await
+                }
+                Ok(response) => response,
+            };
+            let identity_body = Bytes::clone(resp.get_identity().body());
 
-                    (response, identity_body, None)
-                };
             if !host.options.disable_if_modified_since {
-                let last_modified =
-                    HeaderValue::from_str(&creation.format(parse::HTTP_DATE).to_string())
-                        .expect("We know these bytes are valid.");
-                utils::replace_header(
-                    response_data.0.headers_mut(),
-                    "last-modified",
-                    last_modified,
-                );
+                response
+                    .headers_mut()
+                    .entry("last-modified")
+                    .or_insert_with(|| http_date(creation));
             }
-            response_data
+            (response, identity_body, None, None)
         }
         _ => {
-            async fn maybe_cache<T>(
+            fn maybe_cache<T>(
                 host: &Host,
                 server_cache: ServerCachePreference,
                 path_query: PathQuery,
                 response: CompressedResponse,
-                method: &Method,
+                request: &FatRequest,
                 future: &Option<T>,
             ) -> bool {
                 if future.is_none() {
                     if let Some(response_cache) = &host.response_cache {
-                        if server_cache.cache(response.get_identity(), method) {
-                            let mut lock = response_cache.lock().await;
+                        if server_cache.cache(response.get_identity(), request.method()) {
                             let key = if server_cache.query_matters() {
                                 comprash::UriKey::PathQuery(path_query)
                             } else {
                                 comprash::UriKey::Path(path_query.into_path())
                             };
                             info!("Caching uri {:?}!", &key);
-                            lock.cache(key, response);
+                            response_cache.cache(key, response, request.headers());
                             return true;
                         }
                     }
@@ -582,46 +1032,68 @@ pub async fn handle_cache(
                 false
             }
 
-            drop(lock);
+            if let Some(stats) = &cache_stats {
+                stats.record(false);
+            }
             let path_query = comprash::PathQuery::from_uri(request.uri());
             // LAYER 5.1
-            let (mut resp, mut client_cache, mut server_cache, compress, future) =
-                match sanitize_data.as_ref() {
-                    Ok(_) => {
-                        let path = if host.options.disable_fs {
-                            None
-                        } else {
-                            Some(utils::make_path(
-                                &host.path,
-                                host.options
-                                    .public_data_dir
-                                    .as_deref()
-                                    .unwrap_or_else(|| Path::new("public")),
-                                // Ok, since Uri's have to start with a `/` (https://github.com/hyperium/http/issues/465).
-                                // We also are OK with all Uris, since we did a check on the
-                                // incoming and presume all internal extension changes are good.
-                                utils::parse::uri(request.uri().path()).unwrap(),
-                                None,
-                            ))
-                        };
-
-                        handle_request(&mut request, overide_uri.as_ref(), address, host, &path)
-                            .await?
-                    }
-                    Err(err) => error::sanitize_error_into_response(*err, host).await,
+            let (
+                mut resp,
+                mut client_cache,
+                mut server_cache,
+                mut compress,
+                future,
+                file_body,
+                precompressed,
+            ) = match sanitize_data.as_ref() {
+                Ok(_) => {
+                    let path = if host.options.disable_fs {
+                        None
+                    } else {
+                        Some(utils::make_path(
+                            &host.path,
+                            host.options
+                                .public_data_dir
+                                .as_deref()
+                                .unwrap_or_else(|| Path::new("public")),
+                            // Ok, since Uri's have to start with a `/` (https://github.com/hyperium/http/issues/465).
+                            // We also are OK with all Uris, since we did a check on the
+                            // incoming and presume all internal extension changes are good.
+                            utils::parse::uri(request.uri().path()).unwrap(),
+                            None,
+                        ))
+                    };
+
+                    handle_request(request, overide_uri.as_ref(), address, host, &path).await?
                 }
-                .into_parts();
+                Err(err) => error::sanitize_error_into_response(*err, host).await,
+            }
+            .into_parts();
 
+            // The precompressed bodies only apply to the body they were read for.
+            let mut keep_precompressed = true;
             host.extensions
                 .resolve_present(
-                    &mut request,
+                    request,
                     &mut resp,
                     &mut client_cache,
                     &mut server_cache,
                     host,
                     address,
+                    &mut keep_precompressed,
                 )
                 .await?;
+            // An extension read the body, which was larger than allowed.
+            let future = if request.body().is_too_large() {
+                debug!("Request body from {} is too large", address);
+                resp = error::default(StatusCode::PAYLOAD_TOO_LARGE, Some(host), None).await;
+                keep_precompressed = false;
+                client_cache = ClientCachePreference::None;
+                server_cache = ServerCachePreference::None;
+                None
+            } else {
+                future
+            };
 
             let extension = match Path::new(request.uri().path())
                 .extension()
@@ -633,15 +1105,34 @@ pub async fn handle_cache(
                     None => "",
                 },
             };
-            let compressed_response = comprash::CompressedResponse::new(
+            // If a `Present` extension replaced the response (e.g. `private` files), don't send the file.
+            let file_body =
+                file_body.filter(|_| resp.status() == StatusCode::OK && resp.body().is_empty());
+            if file_body.is_some() {
+                // The body is streamed from disk; we can't compress nor cache it.
+                compress = CompressPreference::None;
+                server_cache = ServerCachePreference::None;
+            }
+            if future.is_some() || !server_cache.cache(&resp, request.method()) {
+                // It won't be cached, so the waiting requests shouldn't wait for it to be sent.
+                drop(flight.take());
+            }
+            let mut compressed_response = comprash::CompressedResponse::new(
                 resp,
                 compress,
                 client_cache,
                 extension,
                 host.options.disable_client_cache,
+                host.options.compression_levels,
             );
+            if keep_precompressed && !precompressed.is_empty() {
+                compressed_response.set_precompressed(precompressed);
+            }
 
-            let mut response = match compressed_response.clone_preferred(&request) {
+            let preferred = compressed_response
+                .clone_preferred_nonblocking(request, host.options.identity_while_compressing)
+                .await;
+            let mut response = match preferred {
                 Err(message) => {
                     error::default(
                         StatusCode::NOT_ACCEPTABLE,
@@ -660,36 +1151,184 @@ pub async fn handle_cache(
                 server_cache,
                 path_query,
                 compressed_response,
-                request.method(),
+                request,
                 &future,
-            )
-            .await;
+            );
+            // Let the requests waiting for this one look in the cache.
+            drop(flight);
 
             if !host.options.disable_if_modified_since && should_cache {
-                let last_modified =
-                    HeaderValue::from_str(&time::Utc::now().format(parse::HTTP_DATE).to_string())
-                        .expect("We know these bytes are valid.");
-                utils::replace_header(response.headers_mut(), "last-modified", last_modified);
+                response
+                    .headers_mut()
+                    .entry("last-modified")
+                    .or_insert_with(|| http_date(time::Utc::now()));
             }
 
-            (response, identity_body, future)
+            (response, identity_body, future, file_body)
         }
     };
 
+    // A `future` may upgrade the connection or write the body; we can't replace the response.
+    let precondition = if future.is_none() {
+        precondition_status(
+            request,
+            &response,
+            !host.options.disable_if_modified_since,
+        )
+    } else {
+        None
+    };
+    let (mut response, identity, file_body) = match precondition {
+        Some(StatusCode::NOT_MODIFIED) => (not_modified(&response), Bytes::new(), None),
+        Some(status) => (
+            error::default(status, Some(host), None).await,
+            Bytes::new(),
+            None,
+        ),
+        None => (response, identity, file_body),
+    };
+    // The connection is closed after the response, as the rest of the body isn't read.
+    if let SendKind::Send(application::ResponsePipe::Http1(_)) = &pipe {
+        if future.is_none() && !request.body().is_fully_read() {
+            response
+                .headers_mut()
+                .insert("connection", HeaderValue::from_static("close"));
+        }
+    }
+
     pipe.send(
         response,
         identity,
-        &request,
+        request,
         host,
         future,
+        file_body,
         address,
         sanitize_data.ok(),
+        log,
     )
     .await?;
 
     Ok(())
 }
 
+/// Formats `date_time` as a [`HeaderValue`] according to [`parse::HTTP_DATE`].
+fn http_date(date_time: time::DateTime<time::Utc>) -> HeaderValue {
+    HeaderValue::from_str(&date_time.format(parse::HTTP_DATE).to_string())
+        .expect("We know these bytes are valid.")
+}
+
+/// Evaluates the conditional headers of `request` against the `etag` and `last-modified`
+/// of `response`, in the order defined by
+/// [RFC 7232](https://www.rfc-editor.org/rfc/rfc7232#section-6).
+///
+/// If `dates` is false, `if-modified-since` and `if-unmodified-since` are ignored.
+///
+/// The preconditions are only evaluated for `GET` and `HEAD` requests.
+/// The response is generated before they are evaluated, so the extensions handling
+/// other, state-changing, methods have already had their side effects.
+/// Those extensions have to evaluate `if-match` and `if-unmodified-since` themselves.
+///
+/// Returns the status the response should be replaced with, if any;
+/// either [`StatusCode::NOT_MODIFIED`] or [`StatusCode::PRECONDITION_FAILED`].
+fn precondition_status<T>(
+    request: &Request<T>,
+    response: &Response<Bytes>,
+    dates: bool,
+) -> Option<StatusCode> {
+    let safe = matches!(request.method(), &Method::GET | &Method::HEAD);
+    // The preconditions only apply to the successful representation of the resource.
+    if !safe || !response.status().is_success() {
+        return None;
+    }
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|header: &HeaderValue| header.to_str().ok())
+    };
+    let date = |value: &str| time::NaiveDateTime::parse_from_str(value, parse::HTTP_DATE).ok();
+    let etag = response.headers().get("etag").map(HeaderValue::as_bytes);
+    let last_modified = response
+        .headers()
+        .get("last-modified")
+        .and_then(|header| header.to_str().ok())
+        .and_then(date)
+        .filter(|_| dates);
+
+    if let Some(if_match) = header("if-match") {
+        if !etag_matches(if_match, etag, true) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let (Some(since), Some(modified)) =
+        (header("if-unmodified-since").and_then(date), last_modified)
+    {
+        if modified > since {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = header("if-none-match") {
+        if etag_matches(if_none_match, etag, false) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    } else if let (Some(since), Some(modified)) =
+        (header("if-modified-since").and_then(date), last_modified)
+    {
+        if modified <= since {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+    None
+}
+/// Checks if any entity tag in `list`, the value of a `if-match` or `if-none-match` header,
+/// matches `etag`.
+///
+/// `*` matches any `etag`, even [`None`], as the resource exists.
+/// If `strong`, weak entity tags never match.
+fn etag_matches(list: &str, etag: Option<&[u8]>, strong: bool) -> bool {
+    fn weak_stripped(tag: &[u8]) -> &[u8] {
+        tag.strip_prefix(b"W/".as_ref()).unwrap_or(tag)
+    }
+    if list.trim() == "*" {
+        return true;
+    }
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    list.split(',').map(str::trim).any(|tag| {
+        if strong {
+            !etag.starts_with(b"W/") && tag.as_bytes() == etag
+        } else {
+            weak_stripped(tag.as_bytes()) == weak_stripped(etag)
+        }
+    })
+}
+/// Creates a [`StatusCode::NOT_MODIFIED`] response with the headers of `response`
+/// which [RFC 7232](https://www.rfc-editor.org/rfc/rfc7232#section-4.1) requires.
+fn not_modified(response: &Response<Bytes>) -> Response<Bytes> {
+    let mut not_modified = Response::new(Bytes::new());
+    *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+    *not_modified.version_mut() = response.version();
+    let headers = not_modified.headers_mut();
+    for name in &[
+        "cache-control",
+        "content-location",
+        "date",
+        "etag",
+        "expires",
+        "last-modified",
+        "server",
+        "vary",
+    ] {
+        for value in response.headers().get_all(*name) {
+            headers.append(*name, value.clone());
+        }
+    }
+    not_modified
+}
+
 /// Handles a single request and returns response with cache and compress preference.
 ///
 ///  
@@ -711,6 +1350,8 @@ pub async fn handle_request(
     let mut server_cache = None;
     let mut compress = None;
     let mut future = None;
+    let mut file_body = None;
+    let mut precompressed = comprash::Precompressed::new();
 
     #[allow(unused_mut)]
     let mut status = None;
@@ -729,6 +1370,10 @@ pub async fn handle_request(
             if let Some(f) = resp.4 {
                 future.replace(f);
             }
+            if let Some(body) = resp.5 {
+                file_body.replace(body);
+            }
+            precompressed = resp.6;
         }
     }
 
@@ -736,8 +1381,43 @@ pub async fn handle_request(
         if let Some(path) = path {
             match *request.method() {
                 Method::GET | Method::HEAD => {
-                    if let Some(content) = read_file(&path, host.file_cache.as_ref()).await {
-                        response = Some(Response::new(content));
+                    if let Some(body) =
+                        read::FileBody::open_if_larger(path, host.options.get_streaming_threshold())
+                            .await
+                    {
+                        let mut streamed = Response::new(Bytes::new());
+                        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+                        // Mime will only contains valid bytes.
+                        let content_type = HeaderValue::from_maybe_shared(Bytes::copy_from_slice(
+                            content_type.to_string().as_bytes(),
+                        ))
+                        .unwrap();
+                        streamed.headers_mut().insert("content-type", content_type);
+                        if let Some(etag) = body.etag() {
+                            streamed.headers_mut().insert("etag", etag);
+                        }
+                        if !host.options.disable_if_modified_since {
+                            if let Some(modified) = body.modified() {
+                                streamed
+                                    .headers_mut()
+                                    .insert("last-modified", http_date(modified.into()));
+                            }
+                        }
+                        response = Some(streamed);
+                        file_body = Some(body);
+                    } else if let Some(content) = read_file(&path, host.file_cache.as_ref()).await {
+                        precompressed = read::precompressed(path, host.file_cache.as_ref()).await;
+                        let mut file = Response::new(content);
+                        if !host.options.disable_if_modified_since {
+                            let modified = tokio::fs::metadata(path)
+                                .await
+                                .and_then(|metadata| metadata.modified());
+                            if let Ok(modified) = modified {
+                                file.headers_mut()
+                                    .insert("last-modified", http_date(modified.into()));
+                            }
+                        }
+                        response = Some(file);
                     }
                 }
                 _ => status = Some(StatusCode::METHOD_NOT_ALLOWED),
@@ -766,8 +1446,9 @@ pub async fn handle_request(
     maybe_with!(response, server_cache, with_server_cache);
     maybe_with!(response, compress, with_compress);
     maybe_with!(response, future, with_future);
+    maybe_with!(response, file_body, with_file_body);
 
-    Ok(response)
+    Ok(response.with_precompressed(precompressed))
 }
 
 /// Which version of the [Internet Protocol](https://en.wikipedia.org/wiki/Internet_Protocol)
@@ -810,6 +1491,7 @@ pub struct RunConfig {
     ports: Vec<PortDescriptor>,
     handover: bool,
     handover_socket_path: Option<&'static str>,
+    reload: Option<shutdown::Reload>,
 }
 impl RunConfig {
     /// Creates an empty [`RunConfig`].
@@ -818,6 +1500,7 @@ impl RunConfig {
             ports: vec![],
             handover: true,
             handover_socket_path: None,
+            reload: None,
         }
     }
 
@@ -842,6 +1525,36 @@ impl RunConfig {
         self.handover_socket_path = Some(path);
         self
     }
+    /// Sets the function which reloads the configuration,
+    /// usually by [replacing](host::DataHandle::replace) the [`Data`] of the [`PortDescriptor`]s.
+    ///
+    /// It's called by [`shutdown::Manager::reload`], when the process receives `SIGHUP` (on Unix),
+    /// and when `reload` is sent to the [handover](https://kvarn.org/shutdown-handover.) socket.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kvarn::prelude::*;
+    /// use kvarn::host::DataHandle;
+    ///
+    /// fn make_data() -> Arc<Data> {
+    ///     let host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), host::Options::default());
+    ///     Data::builder(host).build()
+    /// }
+    ///
+    /// let handle = DataHandle::new(make_data());
+    /// let reload_handle = handle.clone();
+    /// let config = RunConfig::new()
+    ///     .add(PortDescriptor::non_secure(8080, handle))
+    ///     .set_reload_handler(Box::new(move || {
+    ///         reload_handle.replace(make_data());
+    ///         Ok(())
+    ///     }));
+    /// ```
+    pub fn set_reload_handler(mut self, handler: shutdown::ReloadFn) -> Self {
+        self.reload = Some(shutdown::Reload(handler));
+        self
+    }
 }
 impl Default for RunConfig {
     fn default() -> Self {
@@ -874,72 +1587,105 @@ pub struct PortDescriptor {
     port: u16,
     #[cfg(feature = "https")]
     server_config: Option<Arc<rustls::ServerConfig>>,
-    data: Arc<Data>,
+    #[cfg(feature = "http3")]
+    quic_config: Option<quinn::ServerConfig>,
+    data: host::DataHandle,
     version: BindIpVersion,
+    addresses: Vec<SocketAddr>,
+    timeouts: Timeouts,
 }
 impl PortDescriptor {
+    /// The default for [`Self::handshake_timeout`], 10 seconds.
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+    /// The default for [`Self::idle_timeout`], 5 seconds.
+    pub const DEFAULT_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+    /// The default for [`Self::header_timeout`], 10 seconds.
+    pub const DEFAULT_HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
     /// Uses the defaults for non-secure HTTP with `host_data`
-    pub fn http(host_data: Arc<Data>) -> Self {
+    pub fn http(host_data: impl Into<host::DataHandle>) -> Self {
         Self {
             port: 80,
             #[cfg(feature = "https")]
             server_config: None,
-            data: host_data,
+            #[cfg(feature = "http3")]
+            quic_config: None,
+            data: host_data.into(),
             version: BindIpVersion::Both,
+            addresses: Vec::new(),
+            timeouts: Timeouts::default(),
         }
     }
     /// Uses the defaults for secure HTTP, HTTPS, with `host_data`.
-    /// Gets a [`rustls::ServerConfig`] from [`Data::make_config()`].
+    /// Gets a [`rustls::ServerConfig`] from [`host::DataHandle::make_config()`].
     #[cfg(feature = "https")]
-    pub fn https(host_data: Arc<Data>) -> Self {
+    pub fn https(host_data: impl Into<host::DataHandle>) -> Self {
+        let data = host_data.into();
         Self {
             port: 443,
-            server_config: Some(Arc::new(host_data.make_config())),
-            data: host_data,
+            server_config: Some(Arc::new(data.make_config())),
+            #[cfg(feature = "http3")]
+            quic_config: None,
+            data,
             version: BindIpVersion::Both,
+            addresses: Vec::new(),
+            timeouts: Timeouts::default(),
         }
     }
     /// Creates a new descriptor for `port` with `host_data` and an optional [`rustls::ServerConfig`].
     #[cfg(feature = "https")]
     pub fn with_server_config(
         port: u16,
-        host_data: Arc<Data>,
+        host_data: impl Into<host::DataHandle>,
         server_config: Option<Arc<rustls::ServerConfig>>,
     ) -> Self {
         Self {
             port,
             server_config,
-            data: host_data,
+            #[cfg(feature = "http3")]
+            quic_config: None,
+            data: host_data.into(),
             version: BindIpVersion::Both,
+            addresses: Vec::new(),
+            timeouts: Timeouts::default(),
         }
     }
     /// Creates a new descriptor for `port` with `host_data`.
     /// If the feature `https` is enabled, a `rustls::ServerConfig` is created
     /// from the `host_data`.
-    pub fn new(port: u16, host_data: Arc<Data>) -> Self {
+    pub fn new(port: u16, host_data: impl Into<host::DataHandle>) -> Self {
+        let data = host_data.into();
         Self {
             port,
             #[cfg(feature = "https")]
-            server_config: Some(Arc::new(host_data.make_config())),
-            data: host_data,
+            server_config: Some(Arc::new(data.make_config())),
+            #[cfg(feature = "http3")]
+            quic_config: None,
+            data,
             version: BindIpVersion::Both,
+            addresses: Vec::new(),
+            timeouts: Timeouts::default(),
         }
     }
     /// Creates a new non-secure descriptor for `port` with `host_data`.
     /// Does not try to assign a certificate.
-    pub fn non_secure(port: u16, host_data: Arc<Data>) -> Self {
+    pub fn non_secure(port: u16, host_data: impl Into<host::DataHandle>) -> Self {
         Self {
             port,
             #[cfg(feature = "https")]
             server_config: None,
-            data: host_data,
+            #[cfg(feature = "http3")]
+            quic_config: None,
+            data: host_data.into(),
             version: BindIpVersion::Both,
+            addresses: Vec::new(),
+            timeouts: Timeouts::default(),
         }
     }
     /// Binds to IPv4 only.
     /// The default is to bind both.
     ///
-    /// This disables IPv6 for this port.
+    /// This disables IPv6 for this port, including any IPv6 addresses added by [`Self::add_address`].
     pub fn ipv4_only(mut self) -> Self {
         self.version = BindIpVersion::V4;
         self
@@ -947,16 +1693,122 @@ impl PortDescriptor {
     /// Binds to IPv6 only.
     /// The default is to bind both.
     ///
-    /// This disables IPv4 for this port.
+    /// This disables IPv4 for this port, including any IPv4 addresses added by [`Self::add_address`].
     pub fn ipv6_only(mut self) -> Self {
         self.version = BindIpVersion::V6;
         self
     }
+    /// Binds to `address`, for example a specific network interface.
+    ///
+    /// The default is to bind the unspecified addresses (`0.0.0.0` and `::`) on the port
+    /// of this descriptor. When any address is added, only the added addresses are bound.
+    /// Call this multiple times to bind several addresses.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kvarn::prelude::*;
+    /// let host = Host::non_secure("localhost", "web", Extensions::default(), host::Options::default());
+    /// let data = Data::builder(host).build();
+    ///
+    /// let descriptor = PortDescriptor::non_secure(8080, data)
+    ///     .add_address(SocketAddr::new(IpAddr::V4(net::Ipv4Addr::LOCALHOST), 8080))
+    ///     .add_address(SocketAddr::new(IpAddr::V6(net::Ipv6Addr::LOCALHOST), 8081));
+    /// assert_eq!(descriptor.addresses().len(), 2);
+    /// ```
+    pub fn add_address(mut self, address: SocketAddr) -> Self {
+        self.addresses.push(address);
+        self
+    }
+    /// Gets the addresses this descriptor binds to.
+    ///
+    /// See [`Self::add_address`], [`Self::ipv4_only`], and [`Self::ipv6_only`].
+    #[must_use]
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let unspecified = [
+            SocketAddr::new(IpAddr::V4(net::Ipv4Addr::UNSPECIFIED), self.port),
+            SocketAddr::new(IpAddr::V6(net::Ipv6Addr::UNSPECIFIED), self.port),
+        ];
+        let addresses = if self.addresses.is_empty() {
+            &unspecified[..]
+        } else {
+            &self.addresses[..]
+        };
+        addresses
+            .iter()
+            .filter(|address| match self.version {
+                BindIpVersion::V4 => address.is_ipv4(),
+                BindIpVersion::V6 => address.is_ipv6(),
+                BindIpVersion::Both => true,
+            })
+            .copied()
+            .collect()
+    }
+    /// Also serves HTTP/3 over QUIC on this port, using UDP.
+    /// Gets a [`quinn::ServerConfig`] from [`host::DataHandle::make_quic_config()`].
+    ///
+    /// Responses sent over TCP advertise HTTP/3 using the `alt-svc` header,
+    /// so browsers upgrade on subsequent requests.
+    ///
+    /// HTTP/3 is always encrypted; this has no effect on non-secure descriptors.
+    #[cfg(feature = "http3")]
+    pub fn enable_http3(mut self) -> Self {
+        if self.server_config.is_some() {
+            self.quic_config = Some(self.data.make_quic_config());
+        } else {
+            warn!(
+                "Tried to enable HTTP/3 on non-secure port {}. HTTP/3 is always encrypted.",
+                self.port
+            );
+        }
+        self
+    }
+    /// Sets the time a client has to complete the TLS (or QUIC) handshake and,
+    /// on HTTP/2, the HTTP/2 handshake. The connection is closed if it runs out.
+    ///
+    /// The default is [`Self::DEFAULT_HANDSHAKE_TIMEOUT`].
+    pub fn handshake_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+    /// Sets the time a kept-alive connection may wait for the next request to start.
+    /// The connection is closed if it runs out.
+    ///
+    /// The default is [`Self::DEFAULT_IDLE_TIMEOUT`].
+    pub fn idle_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+    /// Sets the time a HTTP/1 client has to send the head of a request,
+    /// counted from the first received byte.
+    /// If it runs out, [`StatusCode::REQUEST_TIMEOUT`] is sent and the connection is closed.
+    ///
+    /// This defeats clients which trickle the headers to keep connections open.
+    ///
+    /// The default is [`Self::DEFAULT_HEADER_TIMEOUT`].
+    pub fn header_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeouts.header = timeout;
+        self
+    }
+    /// Gets the handle to the [`Data`] of this descriptor.
+    ///
+    /// Use it to [replace](host::DataHandle::replace) the [`Data`] while Kvarn is running.
+    pub fn data(&self) -> &host::DataHandle {
+        &self.data
+    }
+    /// Gets the `alt-svc` header advertising HTTP/3 on `port`, if enabled.
+    #[cfg(feature = "http3")]
+    fn alt_svc(&self, port: u16) -> Option<HeaderValue> {
+        self.quic_config.as_ref()?;
+        // The string only contains ASCII.
+        Some(HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).unwrap())
+    }
 }
 impl Debug for PortDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut s = f.debug_struct("HostDescriptor");
         s.field("port", &self.port);
+        s.field("addresses", &self.addresses());
 
         #[cfg(feature = "https")]
         s.field(
@@ -966,10 +1818,29 @@ impl Debug for PortDescriptor {
                 .as_ref()
                 .map(|_| "certificate".as_clean()),
         );
+        #[cfg(feature = "http3")]
+        s.field("http3", &self.quic_config.is_some());
+        s.field("timeouts", &self.timeouts);
 
         s.field("host_data", &self.data).finish()
     }
 }
+/// The connection-level timeouts of a [`PortDescriptor`].
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    handshake: std::time::Duration,
+    idle: std::time::Duration,
+    header: std::time::Duration,
+}
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: PortDescriptor::DEFAULT_HANDSHAKE_TIMEOUT,
+            idle: PortDescriptor::DEFAULT_IDLE_TIMEOUT,
+            header: PortDescriptor::DEFAULT_HEADER_TIMEOUT,
+        }
+    }
+}
 
 /// The `Request` used within Kvarn.
 pub type FatRequest = Request<application::Body>;
@@ -986,6 +1857,8 @@ pub struct FatResponse {
     compress: CompressPreference,
 
     future: Option<ResponsePipeFuture>,
+    file_body: Option<read::FileBody>,
+    precompressed: comprash::Precompressed,
 }
 impl FatResponse {
     /// Creates a new [`FatResponse`] with `server_cache_preference` advising Kvarn of how to cache the content.
@@ -1006,6 +1879,8 @@ impl FatResponse {
             compress: CompressPreference::Full,
 
             future: None,
+            file_body: None,
+            precompressed: comprash::Precompressed::new(),
         }
     }
     /// Creates a new [`FatResponse`] with all preferences set to `Full` and no `Future`.
@@ -1025,6 +1900,8 @@ impl FatResponse {
             server: ServerCachePreference::None,
             compress: CompressPreference::Full,
             future: None,
+            file_body: None,
+            precompressed: comprash::Precompressed::new(),
         }
     }
     /// Sets the inner [`ClientCachePreference`].
@@ -1043,10 +1920,32 @@ impl FatResponse {
         self
     }
     /// Sets the inner `Future`.
+    ///
+    /// As the `Future` can write more to the body, the length of it isn't known.
+    /// On HTTP/1.1, the body is therefore sent with `transfer-encoding: chunked`.
     pub fn with_future(mut self, future: ResponsePipeFuture) -> Self {
         self.future = Some(future);
         self
     }
+    /// Sets the inner [`read::FileBody`].
+    ///
+    /// The file is streamed to the client after the body of the response,
+    /// which should therefore be empty.
+    /// This disables compression and server caching of the response.
+    pub fn with_file_body(mut self, file_body: read::FileBody) -> Self {
+        self.file_body = Some(file_body);
+        self.server = ServerCachePreference::None;
+        self.compress = CompressPreference::None;
+        self
+    }
+    /// Sets the compressed versions of the body, which are then sent instead of
+    /// compressing the body at runtime.
+    ///
+    /// They are discarded if a [`Present`] extension changes the body.
+    pub fn with_precompressed(mut self, precompressed: comprash::Precompressed) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
     /// Turns `self` into a tuple of all it's parts.
     pub fn into_parts(
         self,
@@ -1056,6 +1955,8 @@ impl FatResponse {
         ServerCachePreference,
         CompressPreference,
         Option<ResponsePipeFuture>,
+        Option<read::FileBody>,
+        comprash::Precompressed,
     ) {
         (
             self.response,
@@ -1063,6 +1964,8 @@ impl FatResponse {
             self.server,
             self.compress,
             self.future,
+            self.file_body,
+            self.precompressed,
         )
     }
 }
@@ -1086,6 +1989,7 @@ impl Debug for FatResponse {
             .field("server", &self.server)
             .field("compress", &self.compress)
             .field("future", &"opaque Future".as_clean())
+            .field("file_body", &self.file_body)
             .finish()
     }
 }
@@ -1133,5 +2037,147 @@ pub fn alpn() -> Vec<Vec<u8>> {
     {
         vec.push(b"h2".to_vec());
     }
+    #[cfg(feature = "acme")]
+    {
+        vec.push(acme::ALPN_PROTOCOL.to_vec());
+    }
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"abc\"";
+    const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+    const BEFORE: &str = "Tue, 20 Oct 2015 07:28:00 GMT";
+    const AFTER: &str = "Thu, 22 Oct 2015 07:28:00 GMT";
+
+    fn response() -> Response<Bytes> {
+        Response::builder()
+            .header("etag", ETAG)
+            .header("last-modified", LAST_MODIFIED)
+            .body(Bytes::from_static(b"body"))
+            .unwrap()
+    }
+    fn status(method: Method, headers: &[(&str, &str)]) -> Option<StatusCode> {
+        let mut request = Request::builder().method(method).uri("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        precondition_status(&request.body(()).unwrap(), &response(), true)
+    }
+
+    #[test]
+    fn etag_comparison() {
+        assert!(etag_matches("\"abc\"", Some(b"\"abc\""), true));
+        assert!(etag_matches("\"x\", \"abc\"", Some(b"\"abc\""), true));
+        assert!(!etag_matches("W/\"abc\"", Some(b"\"abc\""), true));
+        assert!(!etag_matches("\"abc\"", Some(b"W/\"abc\""), true));
+        assert!(etag_matches("W/\"abc\"", Some(b"\"abc\""), false));
+        assert!(etag_matches("\"abc\"", Some(b"W/\"abc\""), false));
+        assert!(!etag_matches("\"abd\"", Some(b"\"abc\""), false));
+        assert!(etag_matches(" * ", None, true));
+        assert!(!etag_matches("\"abc\"", None, false));
+    }
+    #[test]
+    fn if_match() {
+        assert_eq!(status(Method::GET, &[("if-match", ETAG)]), None);
+        assert_eq!(status(Method::GET, &[("if-match", "*")]), None);
+        assert_eq!(
+            status(Method::GET, &[("if-match", "\"other\"")]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        // If-Match takes precedence over If-Unmodified-Since.
+        assert_eq!(
+            status(
+                Method::GET,
+                &[("if-match", ETAG), ("if-unmodified-since", BEFORE)]
+            ),
+            None
+        );
+    }
+    #[test]
+    fn if_unmodified_since() {
+        assert_eq!(
+            status(Method::GET, &[("if-unmodified-since", LAST_MODIFIED)]),
+            None
+        );
+        assert_eq!(
+            status(Method::GET, &[("if-unmodified-since", BEFORE)]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            status(Method::GET, &[("if-unmodified-since", "invalid")]),
+            None
+        );
+    }
+    #[test]
+    fn if_none_match() {
+        assert_eq!(
+            status(Method::GET, &[("if-none-match", ETAG)]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            status(Method::HEAD, &[("if-none-match", "W/\"abc\"")]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(status(Method::GET, &[("if-none-match", "\"other\"")]), None);
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert_eq!(
+            status(
+                Method::GET,
+                &[("if-none-match", "\"other\""), ("if-modified-since", AFTER)]
+            ),
+            None
+        );
+    }
+    #[test]
+    fn if_modified_since() {
+        assert_eq!(
+            status(Method::GET, &[("if-modified-since", LAST_MODIFIED)]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            status(Method::GET, &[("if-modified-since", AFTER)]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(status(Method::GET, &[("if-modified-since", BEFORE)]), None);
+
+        // The dates can be disabled.
+        let request = Request::get("/")
+            .header("if-modified-since", AFTER)
+            .body(())
+            .unwrap();
+        assert_eq!(precondition_status(&request, &response(), false), None);
+    }
+    #[test]
+    fn preconditions_only_apply_to_safe_methods() {
+        // The extension has already handled the request.
+        assert_eq!(status(Method::PUT, &[("if-match", "\"other\"")]), None);
+        assert_eq!(
+            status(Method::DELETE, &[("if-unmodified-since", BEFORE)]),
+            None
+        );
+        assert_eq!(status(Method::POST, &[("if-none-match", "*")]), None);
+        assert_eq!(status(Method::POST, &[("if-modified-since", AFTER)]), None);
+    }
+    #[test]
+    fn preconditions_only_apply_to_success() {
+        let mut response = response();
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        let request = Request::get("/")
+            .header("if-none-match", ETAG)
+            .body(())
+            .unwrap();
+        assert_eq!(precondition_status(&request, &response, true), None);
+    }
+    #[test]
+    fn not_modified_headers() {
+        let not_modified = not_modified(&response());
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(not_modified.headers()["etag"], ETAG);
+        assert_eq!(not_modified.headers()["last-modified"], LAST_MODIFIED);
+        assert!(not_modified.body().is_empty());
+    }
+}
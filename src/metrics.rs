@@ -0,0 +1,522 @@
+//! Metrics of a running Kvarn instance, in the [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//!
+//! A [`Metrics`] counts the requests, connections, bytes sent, [`LimitManager`] actions,
+//! and the hits, misses, evictions, and sizes of the caches of the [`Host`]s it's enabled on.
+//! Use [`Host::enable_metrics`] to measure a [`Host`].
+//!
+//! The metrics are served either on a path of a host, using [`Extensions::add_metrics`],
+//! or on a separate port, using [`Metrics::port_descriptor`].
+//! The latter keeps the metrics private if the port isn't reachable from the internet.
+//!
+//! The following metrics are exported:
+//! - `kvarn_requests_total{host, method, status}`, with the `method` being `other`
+//!   for methods not defined by HTTP
+//! - `kvarn_request_duration_seconds{host}`, a histogram of the time from receiving
+//!   the request to having sent the response, in [`BUCKETS`]
+//! - `kvarn_sent_bytes_total{host}`, the size of the response bodies
+//! - `kvarn_limiter_actions_total{host, action}`, with the `action` being `send` or `drop`;
+//!   see [`LimitAction`]
+//! - `kvarn_cache_hits_total{host, cache}`, `kvarn_cache_misses_total{host, cache}`,
+//!   `kvarn_cache_evictions_total{host, cache}`, and `kvarn_cache_size_bytes{host, cache}`,
+//!   with the `cache` being `file` or `response`
+//! - `kvarn_connections`, the number of open connections, and `kvarn_connections_total`
+//!
+//! Connections are counted by the [`Metrics`] of the default host of the [`Data`].
+//!
+//! # Examples
+//!
+//! ```
+//! # use kvarn::prelude::*;
+//! use kvarn::metrics::Metrics;
+//!
+//! let metrics = Metrics::new();
+//!
+//! let mut host = Host::non_secure("localhost", PathBuf::from("web"), Extensions::default(), host::Options::default());
+//! host.enable_metrics(&metrics);
+//! let data = Data::builder(host).build();
+//!
+//! let config = run_config![
+//!     PortDescriptor::new(8080, data),
+//!     // Only listen for scrapes on the loopback interface.
+//!     metrics
+//!         .port_descriptor(9100)
+//!         .add_address("127.0.0.1:9100".parse().unwrap())
+//! ];
+//! ```
+
+use crate::prelude::{threading::*, *};
+use atomic::{AtomicI64, AtomicU64};
+use comprash::CacheStats;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Mutex as StdMutex, PoisonError};
+use std::time::Duration;
+
+/// The upper bounds of the buckets of `kvarn_request_duration_seconds`, in seconds.
+pub const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// The `content-type` of [`Metrics::render`].
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The metrics of one or more [`Host`]s.
+///
+/// See the [module level documentation](self) for an example and more info.
+#[derive(Debug)]
+#[must_use]
+pub struct Metrics {
+    hosts: StdMutex<BTreeMap<String, Arc<HostMetrics>>>,
+    connections: Arc<Connections>,
+}
+impl Metrics {
+    /// Creates a new [`Metrics`] without any hosts.
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            hosts: StdMutex::new(BTreeMap::new()),
+            connections: Arc::new(Connections::default()),
+        })
+    }
+
+    /// Gets the metrics of the host named `name`, creating them if they don't exist.
+    ///
+    /// The caches are replaced by `caches`, as a reloaded [`Host`] gets new caches.
+    /// The other counters are kept.
+    pub(crate) fn register(
+        &self,
+        name: &str,
+        caches: [Option<Arc<CacheStats>>; 2],
+    ) -> Arc<HostMetrics> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(PoisonError::into_inner);
+        let host = hosts.entry(name.to_owned()).or_insert_with(|| {
+            Arc::new(HostMetrics {
+                name: name.to_owned(),
+                requests: StdMutex::new(HashMap::new()),
+                durations: Histogram::default(),
+                sent_bytes: AtomicU64::new(0),
+                limited: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+                caches: StdMutex::new([None, None]),
+                connections: Arc::clone(&self.connections),
+            })
+        });
+        *host.caches.lock().unwrap_or_else(PoisonError::into_inner) = caches;
+        Arc::clone(host)
+    }
+
+    /// Gets the number of open connections.
+    #[must_use]
+    pub fn get_connections(&self) -> i64 {
+        self.connections.open.load(Ordering::Relaxed)
+    }
+
+    /// Renders all the metrics in the Prometheus text format.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let hosts: Vec<Arc<HostMetrics>> = self
+            .hosts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        let mut out = String::with_capacity(4096);
+
+        // Writing to a `String` can't fail.
+        header(
+            &mut out,
+            "kvarn_requests_total",
+            "counter",
+            "The number of responses sent.",
+        );
+        for host in &hosts {
+            let mut requests: Vec<_> = host
+                .requests
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|((method, status), count)| (*method, *status, *count))
+                .collect();
+            requests.sort_unstable();
+            for (method, status, count) in requests {
+                writeln!(
+                    out,
+                    "kvarn_requests_total{{host={},method={},status=\"{}\"}} {}",
+                    label(&host.name),
+                    label(method),
+                    status,
+                    count
+                )
+                .unwrap();
+            }
+        }
+
+        header(
+            &mut out,
+            "kvarn_request_duration_seconds",
+            "histogram",
+            "The time from receiving a request to having sent the response.",
+        );
+        for host in &hosts {
+            let name = label(&host.name);
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(host.durations.buckets.iter()) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "kvarn_request_duration_seconds_bucket{{host={},le=\"{}\"}} {}",
+                    name, bound, cumulative
+                )
+                .unwrap();
+            }
+            let count = host.durations.count.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "kvarn_request_duration_seconds_bucket{{host={},le=\"+Inf\"}} {}",
+                name, count
+            )
+            .unwrap();
+            #[allow(clippy::cast_precision_loss)]
+            let sum = host.durations.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            writeln!(
+                out,
+                "kvarn_request_duration_seconds_sum{{host={}}} {}",
+                name, sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvarn_request_duration_seconds_count{{host={}}} {}",
+                name, count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "kvarn_sent_bytes_total",
+            "counter",
+            "The size of the response bodies sent.",
+        );
+        for host in &hosts {
+            writeln!(
+                out,
+                "kvarn_sent_bytes_total{{host={}}} {}",
+                label(&host.name),
+                host.sent_bytes.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "kvarn_limiter_actions_total",
+            "counter",
+            "The number of times the limiter sent a 429 response or dropped a connection.",
+        );
+        for host in &hosts {
+            for (action, counter) in &[("send", &host.limited), ("drop", &host.dropped)] {
+                writeln!(
+                    out,
+                    "kvarn_limiter_actions_total{{host={},action=\"{}\"}} {}",
+                    label(&host.name),
+                    action,
+                    counter.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+        }
+
+        let caches: Vec<_> = hosts
+            .iter()
+            .map(|host| {
+                (
+                    host.name.as_str(),
+                    host.caches
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone(),
+                )
+            })
+            .collect();
+        let families: [CacheFamily; 4] = [
+            (
+                "kvarn_cache_hits_total",
+                "counter",
+                "The number of lookups which found the item in the cache.",
+                CacheStats::hits,
+            ),
+            (
+                "kvarn_cache_misses_total",
+                "counter",
+                "The number of lookups which didn't find the item in the cache.",
+                CacheStats::misses,
+            ),
+            (
+                "kvarn_cache_evictions_total",
+                "counter",
+                "The number of items discarded to make room for new ones.",
+                CacheStats::evictions,
+            ),
+            (
+                "kvarn_cache_size_bytes",
+                "gauge",
+                "The total size of the items in the cache.",
+                CacheStats::size,
+            ),
+        ];
+        for (family, kind, help, get) in &families {
+            header(&mut out, family, kind, help);
+            for (name, [file, response]) in &caches {
+                for (cache, stats) in &[("file", file), ("response", response)] {
+                    if let Some(stats) = stats {
+                        writeln!(
+                            out,
+                            "{}{{host={},cache=\"{}\"}} {}",
+                            family,
+                            label(name),
+                            cache,
+                            get(stats)
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "kvarn_connections",
+            "gauge",
+            "The number of open connections.",
+        );
+        writeln!(out, "kvarn_connections {}", self.get_connections()).unwrap();
+        header(
+            &mut out,
+            "kvarn_connections_total",
+            "counter",
+            "The number of accepted connections.",
+        );
+        writeln!(
+            out,
+            "kvarn_connections_total {}",
+            self.connections.total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out
+    }
+    /// Creates a response to a scrape, containing [`Self::render`].
+    pub fn response(&self) -> FatResponse {
+        let response = Response::builder()
+            .header("content-type", CONTENT_TYPE)
+            .body(Bytes::from(self.render()))
+            .unwrap();
+        FatResponse::no_cache(response)
+    }
+
+    /// Creates a [`PortDescriptor`] on `port` serving the metrics at `/metrics`.
+    ///
+    /// It only accepts non-encrypted connections and doesn't serve any files.
+    /// Use [`PortDescriptor::add_address`] to only listen on the loopback interface.
+    pub fn port_descriptor(self: &Arc<Self>, port: u16) -> PortDescriptor {
+        let mut extensions = Extensions::empty();
+        extensions.add_metrics("/metrics".to_owned(), Arc::clone(self));
+        let mut options = host::Options::new();
+        options.disable_fs();
+        let mut host = Host::non_secure("metrics", PathBuf::from("."), extensions, options);
+        host.disable_server_cache();
+        PortDescriptor::non_secure(port, Data::builder(host).build())
+    }
+}
+
+/// The name, type, help, and getter of a metric of the caches.
+type CacheFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&CacheStats) -> u64,
+);
+
+/// The methods counted by their name in `kvarn_requests_total`.
+const METHODS: [&str; 9] = [
+    "CONNECT", "DELETE", "GET", "HEAD", "OPTIONS", "PATCH", "POST", "PUT", "TRACE",
+];
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+/// Quotes `value` and escapes `\`, `"`, and newlines.
+fn label(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    open: AtomicI64,
+    total: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .and_then(|index| self.buckets.get(index))
+        {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// The metrics of a single [`Host`], part of a [`Metrics`].
+///
+/// Set using [`Host::enable_metrics`].
+#[derive(Debug)]
+pub struct HostMetrics {
+    name: String,
+    /// By method (one of [`METHODS`] or `other`) and status.
+    requests: StdMutex<HashMap<(&'static str, u16), u64>>,
+    durations: Histogram,
+    sent_bytes: AtomicU64,
+    limited: AtomicU64,
+    dropped: AtomicU64,
+    /// The stats of the file and response caches.
+    caches: StdMutex<[Option<Arc<CacheStats>>; 2]>,
+    connections: Arc<Connections>,
+}
+impl HostMetrics {
+    /// Records a response with `status` and a body of `bytes` sent `duration` after
+    /// the request was received.
+    ///
+    /// Methods not in [`METHODS`] are counted as `other`, so clients can't add series.
+    pub(crate) fn request(
+        &self,
+        method: &Method,
+        status: StatusCode,
+        bytes: u64,
+        duration: Duration,
+    ) {
+        *self
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((
+                METHODS
+                    .iter()
+                    .find(|name| **name == method.as_str())
+                    .map_or("other", |name| *name),
+                status.as_u16(),
+            ))
+            .or_insert(0) += 1;
+        self.durations.observe(duration);
+        self.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Records the `action` of the [`LimitManager`].
+    pub(crate) fn limit(&self, action: &LimitAction) {
+        match action {
+            LimitAction::Send => self.limited.fetch_add(1, Ordering::Relaxed),
+            LimitAction::Drop => self.dropped.fetch_add(1, Ordering::Relaxed),
+            LimitAction::Passed => return,
+        };
+    }
+    /// Records a new connection, which is open until the returned value is dropped.
+    pub(crate) fn connection(&self) -> Connection {
+        self.connections.open.fetch_add(1, Ordering::Relaxed);
+        self.connections.total.fetch_add(1, Ordering::Relaxed);
+        Connection(Arc::clone(&self.connections))
+    }
+}
+
+/// An open connection. See [`HostMetrics::connection`].
+#[derive(Debug)]
+pub(crate) struct Connection(Arc<Connections>);
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        let host = metrics.register("example.org", [None, None]);
+        host.request(&Method::GET, StatusCode::OK, 100, Duration::from_millis(20));
+        host.request(&Method::GET, StatusCode::OK, 50, Duration::from_secs(20));
+        for method in &["BREW", "PROPFIND"] {
+            host.request(
+                &Method::from_bytes(method.as_bytes()).unwrap(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                0,
+                Duration::from_millis(1),
+            );
+        }
+        host.limit(&LimitAction::Drop);
+        let connection = host.connection();
+
+        let rendered = metrics.render();
+        for line in &[
+            "# TYPE kvarn_requests_total counter",
+            "kvarn_requests_total{host=\"example.org\",method=\"GET\",status=\"200\"} 2",
+            "kvarn_requests_total{host=\"example.org\",method=\"other\",status=\"405\"} 2",
+            "# TYPE kvarn_request_duration_seconds histogram",
+            "kvarn_request_duration_seconds_bucket{host=\"example.org\",le=\"0.005\"} 2",
+            "kvarn_request_duration_seconds_bucket{host=\"example.org\",le=\"0.025\"} 3",
+            "kvarn_request_duration_seconds_bucket{host=\"example.org\",le=\"10\"} 3",
+            "kvarn_request_duration_seconds_bucket{host=\"example.org\",le=\"+Inf\"} 4",
+            "kvarn_request_duration_seconds_sum{host=\"example.org\"} 20.022",
+            "kvarn_request_duration_seconds_count{host=\"example.org\"} 4",
+            "kvarn_sent_bytes_total{host=\"example.org\"} 150",
+            "kvarn_limiter_actions_total{host=\"example.org\",action=\"send\"} 0",
+            "kvarn_limiter_actions_total{host=\"example.org\",action=\"drop\"} 1",
+            "kvarn_connections 1",
+            "kvarn_connections_total 1",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == *line),
+                "{} isn't in\n{}",
+                line,
+                rendered
+            );
+        }
+        assert!(!rendered.contains("BREW"));
+
+        drop(connection);
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "kvarn_connections 0"));
+    }
+    #[test]
+    fn label_escaping() {
+        assert_eq!(label("example.org"), "\"example.org\"");
+        assert_eq!(label("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+}
@@ -35,6 +35,8 @@ pub use extensions::{
 };
 pub use host::{Data, Host};
 pub use read::{file as read_file, file_cached as read_file_cached};
+#[cfg(feature = "http3")]
+pub use shutdown::QuicAcceptManager;
 pub use shutdown::{AcceptAction, AcceptManager};
 pub use utils::{build_bytes, chars::*, parse, parse::SanitizeError, AsCleanDebug};
 
@@ -43,7 +45,7 @@ pub use utils::{build_bytes, chars::*, parse, parse::SanitizeError, AsCleanDebug
 /// The purpose of this module is to expose common file system operations.
 pub mod fs {
     pub use super::async_bits::*;
-    pub use super::read::{file as read_file, file_cached as read_file_cached};
+    pub use super::read::{file as read_file, file_cached as read_file_cached, FileBody};
     pub use tokio::fs::File;
 }
 
@@ -68,7 +70,9 @@ pub mod internals {
         Body, HttpConnection, PushedResponsePipe, ResponseBodyPipe, ResponsePipe,
     };
     pub use async_bits::*;
-    pub use comprash::{Cache, CacheOut, FileCache, PathQuery, ResponseCache};
+    pub use comprash::{
+        Cache, CacheOut, FileCache, PathQuery, ResponseCache, ShardedCache, VariedResponse,
+    };
     pub use encryption::Encryption;
     pub use error::default as default_error;
     pub use extensions::{ready, RetFut, RetSyncFut};
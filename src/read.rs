@@ -2,8 +2,11 @@
 //!
 //! Mainly used for reading file internally in Kvarn.
 //! All functions return [`Bytes`] to be used in the Kvarn cache.
+//! Large files are instead streamed using a [`FileBody`].
 
 use crate::prelude::{fs::*, *};
+use std::collections::VecDeque;
+use tokio::io::AsyncSeekExt;
 
 /// Reads a file using a `cache`.
 /// Should be used instead of [`fs::File::open()`].
@@ -12,8 +15,10 @@ use crate::prelude::{fs::*, *};
 #[inline]
 pub async fn file_cached<P: AsRef<Path>>(path: &P, cache: Option<&FileCache>) -> Option<Bytes> {
     if let Some(cache) = cache {
-        if let CacheOut::Present(file) = cache.lock().await.get(path.as_ref()) {
-            return Some(Bytes::clone(file));
+        let file = cache.get(path.as_ref()).into_option();
+        cache.stats().record(file.is_some());
+        if file.is_some() {
+            return file;
         }
     }
 
@@ -22,10 +27,7 @@ pub async fn file_cached<P: AsRef<Path>>(path: &P, cache: Option<&FileCache>) ->
     async_bits::read_to_end(&mut buffer, file).await.ok()?;
     let buffer = buffer.freeze();
     if let Some(cache) = cache {
-        cache
-            .lock()
-            .await
-            .cache(path.as_ref().to_path_buf(), Bytes::clone(&buffer));
+        cache.cache(path.as_ref().to_path_buf(), Bytes::clone(&buffer));
     }
     Some(buffer)
 }
@@ -37,8 +39,10 @@ pub async fn file_cached<P: AsRef<Path>>(path: &P, cache: Option<&FileCache>) ->
 #[inline]
 pub async fn file<P: AsRef<Path>>(path: &P, cache: Option<&FileCache>) -> Option<Bytes> {
     if let Some(cache) = cache {
-        if let CacheOut::Present(cached) = cache.lock().await.get(path.as_ref()) {
-            return Some(Bytes::clone(cached));
+        let cached = cache.get(path.as_ref()).into_option();
+        cache.stats().record(cached.is_some());
+        if cached.is_some() {
+            return cached;
         }
     }
 
@@ -47,3 +51,259 @@ pub async fn file<P: AsRef<Path>>(path: &P, cache: Option<&FileCache>) -> Option
     async_bits::read_to_end(&mut buffer, file).await.ok()?;
     Some(buffer.freeze())
 }
+
+/// Reads the compressed versions of the file at `path` which build pipelines often emit;
+/// `<path>.gz`, `<path>.br`, and `<path>.zst`.
+///
+/// Files older than the file at `path` are ignored, as they are probably stale.
+/// They are read using `cache`, as with [`file`].
+pub async fn precompressed<P: AsRef<Path>>(
+    path: &P,
+    cache: Option<&FileCache>,
+) -> comprash::Precompressed {
+    #[allow(unused_mut)]
+    let mut precompressed = comprash::Precompressed::new();
+    #[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+    {
+        let path = path.as_ref();
+        let modified = tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        #[cfg(feature = "gzip")]
+        {
+            precompressed.gzip = sibling(path, "gz", modified, cache).await;
+        }
+        #[cfg(feature = "br")]
+        {
+            precompressed.br = sibling(path, "br", modified, cache).await;
+        }
+        #[cfg(feature = "zstd")]
+        {
+            precompressed.zstd = sibling(path, "zst", modified, cache).await;
+        }
+    }
+    precompressed
+}
+/// Reads `<path>.<extension>` if it isn't older than `modified`.
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+async fn sibling(
+    path: &Path,
+    extension: &str,
+    modified: Option<std::time::SystemTime>,
+    cache: Option<&FileCache>,
+) -> Option<Bytes> {
+    let mut sibling = path.as_os_str().to_os_string();
+    sibling.push(".");
+    sibling.push(extension);
+    let sibling = PathBuf::from(sibling);
+    let metadata = tokio::fs::metadata(&sibling).await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    if let (Some(modified), Ok(sibling_modified)) = (modified, metadata.modified()) {
+        if sibling_modified < modified {
+            debug!("Ignoring stale {}", sibling.display());
+            return None;
+        }
+    }
+    file(&sibling, cache).await
+}
+
+/// The size of the chunks [`FileBody`] is sent in.
+pub const FILE_BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file which is sent to the client in chunks instead of being read into memory.
+///
+/// [`handle_request`] returns one of these when a public file is larger than
+/// [`host::Options::streaming_threshold`].
+/// It is never cached by the response cache or the file cache.
+#[derive(Debug)]
+#[must_use]
+pub struct FileBody {
+    file: File,
+    size: u64,
+    modified: Option<std::time::SystemTime>,
+    remaining: u64,
+    /// The bytes left to read from the file for the current part.
+    part_remaining: u64,
+    /// The parts of a `multipart/byteranges` body after the current one.
+    parts: VecDeque<FilePart>,
+}
+/// A part of a [`FileBody`]; `head` followed by the bytes of the file from `start` to `end`.
+#[derive(Debug)]
+struct FilePart {
+    head: Bytes,
+    start: u64,
+    end: u64,
+}
+impl FileBody {
+    /// Opens the file at `path` if it's larger than `threshold` bytes.
+    ///
+    /// Returns [`None`] if the file is smaller or if it can't be opened.
+    pub async fn open_if_larger<P: AsRef<Path>>(path: &P, threshold: u64) -> Option<Self> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        if !metadata.is_file() || metadata.len() <= threshold {
+            return None;
+        }
+        Self::open(path).await.ok()
+    }
+    /// Opens the file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Passes errors from [`File::open`] and [`File::metadata`].
+    pub async fn open<P: AsRef<Path>>(path: &P) -> io::Result<Self> {
+        let file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        let size = metadata.len();
+        Ok(Self {
+            file,
+            size,
+            modified: metadata.modified().ok(),
+            remaining: size,
+            part_remaining: size,
+            parts: VecDeque::new(),
+        })
+    }
+    /// The size of the whole file.
+    #[inline]
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    /// The time the file was last modified, if the platform supports it.
+    #[inline]
+    #[must_use]
+    pub fn modified(&self) -> Option<std::time::SystemTime> {
+        self.modified
+    }
+    /// A weak entity tag derived from the modification time and size of the file.
+    ///
+    /// It's weak as the file can be changed without changing either,
+    /// for example within the precision of the modification time.
+    /// Weak entity tags never match an `if-range`, so ranges of a changed file aren't combined.
+    ///
+    /// Returns [`None`] if the modification time isn't known.
+    #[must_use]
+    pub fn etag(&self) -> Option<HeaderValue> {
+        let modified = self
+            .modified?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_nanos();
+        // Hex digits, `W/`, and `"` are valid.
+        Some(HeaderValue::from_str(&format!("W/\"{:x}-{:x}\"", modified, self.size)).unwrap())
+    }
+    /// The number of bytes left to send.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.remaining
+    }
+    /// If there are no bytes left to send.
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+    /// Applies the ranges of `data` to `self` and `response`.
+    ///
+    /// This does the same thing as [`utils::CriticalRequestComponents::apply_to_response`],
+    /// but seeks in the file instead of slicing a body.
+    ///
+    /// # Errors
+    ///
+    /// Passes errors from seeking in the file.
+    /// If none of the ranges are in the file, an error of kind
+    /// [`io::ErrorKind::InvalidInput`] is returned.
+    pub async fn apply_range(
+        &mut self,
+        data: &utils::CriticalRequestComponents,
+        response: &mut Response<Bytes>,
+    ) -> io::Result<()> {
+        let ranges = match data.get_ranges(response, self.size) {
+            Ok(Some(ranges)) => ranges,
+            Ok(None) => {
+                utils::replace_header_static(response.headers_mut(), "accept-ranges", "bytes");
+                return Ok(());
+            }
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the ranges are past the end of the file",
+                ))
+            }
+        };
+
+        if let [(start, end)] = ranges[..] {
+            utils::replace_header(
+                response.headers_mut(),
+                "content-range",
+                // We know integers, b"-", and b"/" are OK!
+                HeaderValue::from_maybe_shared(parse::content_range(start, end, self.size))
+                    .unwrap(),
+            );
+            self.file.seek(io::SeekFrom::Start(start)).await?;
+            self.remaining = end - start;
+            self.part_remaining = end - start;
+        } else {
+            let multipart = parse::MultipartByteranges::new(
+                &ranges,
+                self.size,
+                response.headers().get("content-type"),
+            );
+            self.remaining =
+                multipart.len() as u64 + ranges.iter().map(|(start, end)| end - start).sum::<u64>();
+            self.part_remaining = 0;
+            self.parts = multipart
+                .heads
+                .into_iter()
+                .zip(ranges)
+                .map(|(head, (start, end))| FilePart { head, start, end })
+                .collect();
+            // The closing boundary is a part without data.
+            self.parts.push_back(FilePart {
+                head: multipart.tail,
+                start: self.size,
+                end: self.size,
+            });
+            utils::replace_header(
+                response.headers_mut(),
+                "content-type",
+                multipart.content_type,
+            );
+        }
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        Ok(())
+    }
+    /// Reads the next chunk of at most [`FILE_BODY_CHUNK_SIZE`] bytes.
+    ///
+    /// Returns [`None`] when all bytes have been read.
+    ///
+    /// # Errors
+    ///
+    /// Passes errors from reading the file.
+    /// If the file is truncated while reading, an [`io::ErrorKind::UnexpectedEof`] is returned.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if self.part_remaining == 0 {
+            let part = match self.parts.pop_front() {
+                Some(part) => part,
+                None => return Ok(None),
+            };
+            self.file.seek(io::SeekFrom::Start(part.start)).await?;
+            self.part_remaining = part.end - part.start;
+            self.remaining -= part.head.len() as u64;
+            return Ok(Some(part.head));
+        }
+        // The chunk size fits in an usize.
+        #[allow(clippy::cast_possible_truncation)]
+        let len = self.part_remaining.min(FILE_BODY_CHUNK_SIZE as u64) as usize;
+        let mut buffer = BytesMut::with_capacity(len);
+        buffer.resize(len, 0);
+        self.file.read_exact(&mut buffer).await?;
+        self.part_remaining -= len as u64;
+        self.remaining -= len as u64;
+        Ok(Some(buffer.freeze()))
+    }
+}
@@ -54,6 +54,23 @@ unsafe impl Sync for WakerList {}
 #[cfg(feature = "graceful-shutdown")]
 unsafe impl Send for WakerList {}
 
+/// A function which reloads the configuration of Kvarn,
+/// usually by [replacing](crate::host::DataHandle::replace) the [`Data`].
+///
+/// Return a description of the error if the reload failed.
+///
+/// See [`RunConfig::set_reload_handler`] and [`Manager::reload`].
+pub type ReloadFn = Box<dyn Fn() -> Result<(), String> + Send + Sync>;
+/// Wrapper of [`ReloadFn`] implementing [`Debug`].
+pub(crate) struct Reload(pub(crate) ReloadFn);
+impl Debug for Reload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Reload")
+            .field(&"[handler]".as_clean())
+            .finish()
+    }
+}
+
 /// Shutdown manager.
 /// Contains a counter of connections and a shutdown flag
 /// to determine when to initiate a shutdown.
@@ -77,6 +94,12 @@ pub struct Manager {
 
     #[cfg(feature = "graceful-shutdown")]
     handover_socket_path: Option<&'static str>,
+
+    addresses: Vec<SocketAddr>,
+    #[cfg(feature = "http3")]
+    quic_addresses: Vec<SocketAddr>,
+
+    reload: Option<Reload>,
 }
 impl Manager {
     /// Creates a new shutdown manager with the capacity of the list of wakers set to `_capacity`.
@@ -92,17 +115,34 @@ impl Manager {
                 channel: watch_channel(()),
 
                 handover_socket_path: None,
+
+                addresses: Vec::new(),
+                #[cfg(feature = "http3")]
+                quic_addresses: Vec::new(),
+
+                reload: None,
             }
         }
         #[cfg(not(feature = "graceful-shutdown"))]
         {
-            Self {}
+            Self {
+                addresses: Vec::new(),
+                #[cfg(feature = "http3")]
+                quic_addresses: Vec::new(),
+
+                reload: None,
+            }
         }
     }
     /// Adds a listener to this manager.
     ///
     /// This is used so the `accept` future resolves immediately when the shutdown is triggered.
+    ///
+    /// The address the `listener` is bound to is available from [`Self::get_addresses`].
     pub fn add_listener(&mut self, listener: TcpListener) -> AcceptManager {
+        if let Ok(address) = listener.local_addr() {
+            self.addresses.push(address);
+        }
         AcceptManager {
             #[cfg(feature = "graceful-shutdown")]
             index: {
@@ -114,6 +154,27 @@ impl Manager {
             listener,
         }
     }
+    /// Adds a QUIC `endpoint` to this manager.
+    ///
+    /// This is used so the `accept` future resolves immediately when the shutdown is triggered.
+    ///
+    /// The address the `endpoint` is bound to is available from [`Self::get_quic_addresses`].
+    #[cfg(feature = "http3")]
+    pub fn add_quic_listener(&mut self, endpoint: quinn::Endpoint) -> QuicAcceptManager {
+        if let Ok(address) = endpoint.local_addr() {
+            self.quic_addresses.push(address);
+        }
+        QuicAcceptManager {
+            #[cfg(feature = "graceful-shutdown")]
+            index: {
+                let wakers = self.wakers.get_mut();
+                let len = wakers.len();
+                wakers.push(None);
+                WakerIndex(len)
+            },
+            endpoint,
+        }
+    }
     /// Adds to the count of connections.
     /// When this connection is closed, you must call [`Manager::remove_connection`]
     /// or a logic error will occur and a shutdown will never occur.
@@ -145,6 +206,28 @@ impl Manager {
             }
         }
     }
+    /// Gets the addresses of the TCP listeners.
+    ///
+    /// These are the addresses actually bound; if port `0` was requested,
+    /// these contain the ports assigned by the operating system.
+    #[must_use]
+    pub fn get_addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+    /// Gets the addresses of the QUIC (HTTP/3) endpoints.
+    ///
+    /// See [`Self::get_addresses`].
+    #[cfg(feature = "http3")]
+    #[must_use]
+    pub fn get_quic_addresses(&self) -> &[SocketAddr] {
+        &self.quic_addresses
+    }
+    /// Gets the number of open connections.
+    #[cfg(feature = "graceful-shutdown")]
+    #[must_use]
+    pub fn get_connections(&self) -> isize {
+        self.connections.load(Ordering::Acquire)
+    }
     /// Gets the value of the internal shutdown flag. This signals a graceful shutdown is underway.
     #[cfg(feature = "graceful-shutdown")]
     pub fn get_shutdown(&self, order: Ordering) -> bool {
@@ -169,6 +252,58 @@ impl Manager {
         wakers[index.0] = None;
     }
 
+    /// Sets the handler called by [`Self::reload`].
+    pub(crate) fn set_reload(&mut self, reload: Reload) {
+        self.reload = Some(reload);
+    }
+    /// Reloads the configuration using the handler set by [`RunConfig::set_reload_handler`].
+    ///
+    /// This is also called when the process receives `SIGHUP` (on Unix)
+    /// and when `reload` is sent to the [handover](https://kvarn.org/shutdown-handover.) socket.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the handler, or an error if no handler is set.
+    pub fn reload(&self) -> Result<(), String> {
+        let reload = match &self.reload {
+            Some(reload) => reload,
+            None => return Err("no reload handler is set".to_owned()),
+        };
+        info!("Reloading.");
+        let result = (reload.0)();
+        if let Err(err) = &result {
+            error!("Failed to reload: {}", err);
+        }
+        result
+    }
+    /// Calls [`Self::reload`] every time the process receives `SIGHUP`,
+    /// if a reload handler is set.
+    #[cfg(unix)]
+    pub(crate) fn reload_on_hangup(manager: &Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if manager.reload.is_none() {
+            return;
+        }
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!(
+                    "Failed to listen for SIGHUP. Reloading on it won't work. {:?}",
+                    err
+                );
+                return;
+            }
+        };
+        let manager = Arc::clone(manager);
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                // Errors are logged in `reload`.
+                drop(manager.reload());
+            }
+        });
+    }
+
     /// Wraps [`Self`] in a [`Arc`] to use across [`tokio::task`]s.
     #[must_use]
     pub fn build(self) -> Arc<Self> {
@@ -234,6 +369,10 @@ impl Manager {
                                 manager.shutdown();
                                 (true, Vec::from("ok"))
                             }
+                            b"reload" => match manager.reload() {
+                                Ok(()) => (false, Vec::from("ok")),
+                                Err(err) => (false, Vec::from(format!("error: {}", err))),
+                            },
                             _ => {
                                 let data = data.get(..128).unwrap_or(data);
                                 warn!(
@@ -395,8 +534,9 @@ pub enum AcceptAction {
     /// Accept a new connection or handle a IO error.
     Accept(io::Result<(TcpStream, SocketAddr)>),
 }
-/// A wrapper around [`TcpListener`] (and `UdpListener` when HTTP/3 comes around)
-/// which waits for a new connection **or** a shutdown signal.
+/// A wrapper around [`TcpListener`] which waits for a new connection **or** a shutdown signal.
+///
+/// See [`QuicAcceptManager`] for the HTTP/3 equivalent.
 #[derive(Debug)]
 #[must_use]
 pub struct AcceptManager {
@@ -457,3 +597,53 @@ impl<'a> Future for AcceptFuture<'a> {
         }
     }
 }
+
+/// A wrapper around a [`quinn::Endpoint`] which waits for a new connection **or** a shutdown signal.
+#[cfg(feature = "http3")]
+#[derive(Debug)]
+#[must_use]
+pub struct QuicAcceptManager {
+    #[cfg(feature = "graceful-shutdown")]
+    index: WakerIndex,
+    endpoint: quinn::Endpoint,
+}
+#[cfg(feature = "http3")]
+impl QuicAcceptManager {
+    /// Waits for a new connection or a shutdown signal.
+    ///
+    /// Returns [`None`] if a shutdown is triggered or the endpoint is closed.
+    /// After a shutdown, the endpoint refuses new connections, but the current ones are kept alive.
+    ///
+    /// Please increase the count of connections on [`Manager`] when this connection is accepted
+    /// and decrease it when the connection dies.
+    pub async fn accept(&mut self, _manager: &Manager) -> Option<quinn::Incoming> {
+        let mut accept = Box::pin(self.endpoint.accept());
+        #[cfg(feature = "graceful-shutdown")]
+        let index = self.index;
+        let incoming = futures::future::poll_fn(|cx| {
+            #[cfg(feature = "graceful-shutdown")]
+            {
+                if _manager.shutdown.load(Ordering::Acquire) {
+                    return Poll::Ready(None);
+                }
+                _manager.set_waker(index, Waker::clone(cx.waker()));
+            }
+            accept.as_mut().poll(cx)
+        })
+        .await;
+        drop(accept);
+        #[cfg(feature = "graceful-shutdown")]
+        {
+            _manager.remove_waker(self.index);
+            if incoming.is_none() {
+                self.endpoint.set_server_config(None);
+            }
+        }
+        incoming
+    }
+    /// Returns a reference to the inner endpoint.
+    #[must_use]
+    pub fn get_inner(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+}
@@ -0,0 +1,379 @@
+//! [WebSocket](https://en.wikipedia.org/wiki/WebSocket) support.
+//!
+//! The handshake is done over HTTP/1.1 using the `upgrade` header and over HTTP/2
+//! using the extended `CONNECT` method from [RFC 8441](https://datatracker.ietf.org/doc/html/rfc8441).
+//! After the handshake, the connection is handed to a [`Handler`] as a [`WebSocket`];
+//! a [`Stream`](futures::Stream) and [`Sink`](futures::Sink) of [`Message`]s.
+//!
+//! The easiest way to get started is [`Extensions::add_websocket`].
+//!
+//! # Examples
+//!
+//! ```
+//! # use kvarn::prelude::*;
+//! use futures::{SinkExt, StreamExt};
+//!
+//! let mut extensions = Extensions::new();
+//! extensions.add_websocket(
+//!     "/echo".to_string(),
+//!     Box::new(|_request, _host, _addr, mut ws| {
+//!         Box::pin(async move {
+//!             while let Some(Ok(message)) = ws.next().await {
+//!                 if message.is_text() || message.is_binary() {
+//!                     if ws.send(message).await.is_err() {
+//!                         break;
+//!                     }
+//!                 }
+//!             }
+//!         })
+//!     }),
+//! );
+//! ```
+
+use crate::prelude::{internals::*, *};
+use bytes::Buf;
+pub use tokio_tungstenite::tungstenite::{self, protocol::CloseFrame, Message};
+
+/// A WebSocket connection to a client.
+///
+/// This is a [`Stream`](futures::Stream) of incoming [`Message`]s and a
+/// [`Sink`](futures::Sink) of outgoing.
+/// Pings are answered automatically.
+pub type WebSocket = tokio_tungstenite::WebSocketStream<WSStream>;
+
+/// A handler of [`WebSocket`] connections.
+///
+/// It gets an empty clone of the upgrade request, the host, the address of the client,
+/// and the [`WebSocket`].
+/// When the returned future resolves, the connection is closed.
+///
+/// The [`extensions::HostWrapper`] is valid until the returned future resolves.
+pub type Handler = Box<
+    dyn Fn(Request<()>, extensions::HostWrapper, SocketAddr, WebSocket) -> RetSyncFut<()>
+        + Send
+        + Sync,
+>;
+
+/// Creates a response to the WebSocket upgrade request `request`.
+///
+/// If the request is a valid handshake, the response accepts it
+/// and `handler` is called with the connection after the response is sent.
+/// Otherwise, a `400 Bad Request` (or `426 Upgrade Required` if the `upgrade` header is missing)
+/// is returned.
+///
+/// This takes the body of `request`; it's the incoming stream of the WebSocket.
+pub async fn response(
+    request: &mut FatRequest,
+    host: &Host,
+    address: SocketAddr,
+    handler: Arc<Handler>,
+) -> FatResponse {
+    let response = match handshake(request) {
+        Ok(response) => response,
+        Err(status) => {
+            let mut response =
+                default_error_response(status, host, Some("invalid WebSocket handshake")).await;
+            if status == StatusCode::UPGRADE_REQUIRED {
+                utils::replace_header_static(
+                    response.response.headers_mut(),
+                    "upgrade",
+                    "websocket",
+                );
+            }
+            return response;
+        }
+    };
+
+    let body = std::mem::replace(request.body_mut(), application::Body::Empty);
+    let empty_request = utils::empty_clone_request(request);
+
+    let future: ResponsePipeFuture = Box::new(move |pipe, host| {
+        Box::pin(async move {
+            let stream = match WSStream::new(body, pipe) {
+                Some(stream) => stream,
+                None => {
+                    warn!("Tried to upgrade a request without a stream to a WebSocket.");
+                    return;
+                }
+            };
+            let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+                stream,
+                tungstenite::protocol::Role::Server,
+                None,
+            )
+            .await;
+            handler(empty_request, host, address, ws).await;
+        })
+    });
+
+    FatResponse::no_cache(response)
+        .with_compress(CompressPreference::None)
+        .with_future(future)
+}
+
+/// Checks the handshake in `request` and returns the response to accept it.
+///
+/// # Errors
+///
+/// Returns the status code which should be sent if the handshake is invalid.
+fn handshake<T>(request: &Request<T>) -> Result<Response<Bytes>, StatusCode> {
+    let headers = request.headers();
+    if !utils::header_eq(headers, "sec-websocket-version", "13") {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match request.version() {
+        #[cfg(feature = "http2")]
+        Version::HTTP_2 => {
+            let protocol = request.extensions().get::<h2::ext::Protocol>();
+            if request.method() != Method::CONNECT
+                || protocol.map(h2::ext::Protocol::as_str) != Some("websocket")
+            {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Ok(Response::new(Bytes::new()))
+        }
+        Version::HTTP_11 => {
+            if request.method() != Method::GET {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let upgrade = headers
+                .get("upgrade")
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |v| v.eq_ignore_ascii_case("websocket"));
+            if !upgrade {
+                return Err(StatusCode::UPGRADE_REQUIRED);
+            }
+            let connection_upgrade = headers
+                .get_all("connection")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case("upgrade"));
+            let key = headers
+                .get("sec-websocket-key")
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            if !connection_upgrade {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let accept = tungstenite::handshake::derive_accept_key(key.as_bytes());
+
+            let mut response = Response::new(Bytes::new());
+            *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+            let headers = response.headers_mut();
+            headers.insert("upgrade", HeaderValue::from_static("websocket"));
+            headers.insert("connection", HeaderValue::from_static("upgrade"));
+            // The accept key is base64, which is a valid header value.
+            headers.insert(
+                "sec-websocket-accept",
+                HeaderValue::from_str(&accept).unwrap(),
+            );
+            Ok(response)
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// The underlying stream of a [`WebSocket`].
+///
+/// Reads from the [`application::Body`] of the upgrade request
+/// and writes to the [`ResponseBodyPipe`] of the response.
+/// It's only valid while the [`ResponsePipeFuture`] it was created in runs.
+#[must_use]
+pub struct WSStream {
+    reader: Reader,
+    buffer: Bytes,
+    pipe: extensions::ResponseBodyPipeWrapperMut,
+}
+enum Reader {
+    Http1(
+        Arc<Mutex<Encryption>>,
+        Option<application::Locking<Encryption>>,
+    ),
+    #[cfg(feature = "http2")]
+    Http2(h2::RecvStream),
+}
+impl WSStream {
+    fn new(body: application::Body, pipe: extensions::ResponseBodyPipeWrapperMut) -> Option<Self> {
+        let (reader, buffer) = match body {
+            application::Body::Empty => return None,
+            application::Body::Http1(body) => {
+                let (reader, buffer) = body.into_inner();
+                (Reader::Http1(reader, None), buffer)
+            }
+            #[cfg(feature = "http2")]
+            application::Body::Http2(body) => {
+                let (reader, buffer) = body.into_inner();
+                (Reader::Http2(reader), buffer)
+            }
+            // WebSockets over HTTP/3 (RFC 9220) aren't supported; the handshake fails.
+            #[cfg(feature = "http3")]
+            application::Body::Http3(_) => return None,
+        };
+        Some(Self {
+            reader,
+            buffer,
+            pipe,
+        })
+    }
+    fn pipe(&mut self) -> Pin<&mut ResponseBodyPipe> {
+        // See the safety notes of `Self`; the pipe lives for the duration of the future.
+        Pin::new(unsafe { self.pipe.get_inner() })
+    }
+}
+impl Debug for WSStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let reader = match &self.reader {
+            Reader::Http1(..) => "HTTP/1 stream",
+            #[cfg(feature = "http2")]
+            Reader::Http2(_) => "HTTP/2 stream",
+        };
+        f.debug_struct("WSStream")
+            .field("reader", &reader.as_clean())
+            .field("buffer", &self.buffer.len())
+            .field("pipe", &"[response body pipe]".as_clean())
+            .finish()
+    }
+}
+impl AsyncRead for WSStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        if me.buffer.is_empty() {
+            match &mut me.reader {
+                Reader::Http1(stream, locking) => {
+                    return match application::poll_lock(stream, locking, cx) {
+                        Poll::Ready(mut stream) => Pin::new(&mut *stream).poll_read(cx, buf),
+                        Poll::Pending => Poll::Pending,
+                    };
+                }
+                #[cfg(feature = "http2")]
+                Reader::Http2(stream) => match stream.poll_data(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    // End of stream
+                    Poll::Ready(None) => return Poll::Ready(Ok(())),
+                    Poll::Ready(Some(Err(err))) => {
+                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)))
+                    }
+                    Poll::Ready(Some(Ok(data))) => {
+                        // Let the client send more data.
+                        let _ = stream.flow_control().release_capacity(data.len());
+                        me.buffer = data;
+                    }
+                },
+            }
+        }
+        let len = me.buffer.len().min(buf.remaining());
+        buf.put_slice(&me.buffer[..len]);
+        me.buffer.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+impl AsyncWrite for WSStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.pipe().poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.pipe().poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.pipe().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> http::request::Builder {
+        Request::get("/ws")
+            .header("upgrade", "websocket")
+            .header("connection", "keep-alive, Upgrade")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+    fn status(request: http::request::Builder) -> StatusCode {
+        match handshake(&request.body(()).unwrap()) {
+            Ok(response) => response.status(),
+            Err(status) => status,
+        }
+    }
+    fn without(name: &str) -> http::request::Builder {
+        let mut builder = request();
+        builder.headers_mut().unwrap().remove(name);
+        builder
+    }
+
+    #[test]
+    fn accept_key() {
+        let response = handshake(&request().body(()).unwrap()).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        // The example of RFC 6455, section 1.3.
+        assert_eq!(
+            response.headers()["sec-websocket-accept"],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(response.headers()["upgrade"], "websocket");
+        assert_eq!(response.headers()["connection"], "upgrade");
+    }
+    #[test]
+    fn invalid_handshakes() {
+        assert_eq!(status(without("upgrade")), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(
+            status(without("upgrade").header("upgrade", "h2c")),
+            StatusCode::UPGRADE_REQUIRED
+        );
+        assert_eq!(status(without("connection")), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(without("sec-websocket-key")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(without("sec-websocket-version")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(without("sec-websocket-version").header("sec-websocket-version", "8")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(request().method(Method::POST)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(request().version(Version::HTTP_10)),
+            StatusCode::BAD_REQUEST
+        );
+    }
+    #[cfg(feature = "http2")]
+    #[test]
+    fn http2_handshake() {
+        let connect = || {
+            Request::builder()
+                .method(Method::CONNECT)
+                .version(Version::HTTP_2)
+                .uri("https://example.org/ws")
+                .header("sec-websocket-version", "13")
+        };
+        let request = connect()
+            .extension(h2::ext::Protocol::from_static("websocket"))
+            .body(())
+            .unwrap();
+        assert_eq!(handshake(&request).unwrap().status(), StatusCode::OK);
+        assert_eq!(status(connect()), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(
+                connect()
+                    .extension(h2::ext::Protocol::from_static("websocket"))
+                    .method(Method::GET)
+            ),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
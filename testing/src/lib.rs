@@ -71,6 +71,7 @@ pub struct ServerBuilder {
     extensions: Extensions,
     options: host::Options,
     path: Option<PathBuf>,
+    port: Option<Box<dyn Fn(PortDescriptor) -> PortDescriptor>>,
 }
 impl ServerBuilder {
     /// Creates a new builder with `extensions` and `options`,
@@ -87,6 +88,7 @@ impl ServerBuilder {
             extensions,
             options,
             path: None,
+            port: None,
         }
     }
     /// Disables HTTPS.
@@ -106,6 +108,15 @@ impl ServerBuilder {
         mutation(&mut self.options);
         self
     }
+    /// Modifies the [`PortDescriptor`] of the server with `mutation`,
+    /// e.g. to change the timeouts.
+    pub fn with_port(
+        mut self,
+        mutation: impl Fn(PortDescriptor) -> PortDescriptor + 'static,
+    ) -> Self {
+        self.port = Some(Box::new(mutation));
+        self
+    }
     /// Sets the [`Host::path`] of this server.
     pub fn path(mut self, path: impl AsRef<Path>) -> Self {
         self.path = Some(path.as_ref().to_path_buf());
@@ -119,7 +130,7 @@ impl ServerBuilder {
     pub async fn run(self) -> Server {
         use rand::prelude::*;
 
-        let Self {https, extensions, options, path} = self;
+        let Self {https, extensions, options, path, port: port_mutation} = self;
 
         let path = path.as_deref().unwrap_or(Path::new("tests"));
 
@@ -163,7 +174,14 @@ impl ServerBuilder {
                 .as_ref()
                 .map(|cert_key| cert_key.cert[0].clone());
             let data = Data::builder(host).build();
-            let port_descriptor = PortDescriptor::new(port, data);
+            let mut port_descriptor = if https {
+                PortDescriptor::new(port, data)
+            } else {
+                PortDescriptor::non_secure(port, data)
+            };
+            if let Some(mutation) = &port_mutation {
+                port_descriptor = mutation(port_descriptor);
+            }
             let config = RunConfig::new().add(port_descriptor).disable_handover();
             let shutdown = run(config).await;
             return Server {
@@ -198,6 +216,8 @@ impl From<(Extensions, host::Options)> for ServerBuilder {
 #[cfg(test)]
 mod tests {
     use super::ServerBuilder;
+    use kvarn::prelude::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[tokio::test]
     async fn index() {
@@ -217,4 +237,397 @@ mod tests {
         );
         assert!(response.text().await.unwrap().contains("404 Not Found"));
     }
+
+    /// Adds a slow extension at `path` and returns the number of times it's been called.
+    fn counted(
+        extensions: &mut Extensions,
+        path: &str,
+        server_cache: ServerCachePreference,
+    ) -> Arc<AtomicUsize> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        extensions.add_prepare_single(
+            path.to_owned(),
+            prepare!(_req, _host, _path, _addr, move |counter, server_cache| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                FatResponse::cache(Response::new(Bytes::from_static(b"generated")))
+                    .with_server_cache(*server_cache)
+            }),
+        );
+        calls
+    }
+    async fn get_concurrently(server: &super::Server, path: &str, requests: usize) {
+        let requests: Vec<_> = (0..requests)
+            .map(|_| tokio::spawn(server.get(path).send()))
+            .collect();
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            assert_eq!(response.text().await.unwrap(), "generated");
+        }
+    }
+
+    /// Creates a directory for a host with `files` in it's `public` directory.
+    fn site(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kvarn-testing-{}-{}", name, std::process::id()));
+        let public = path.join("public");
+        drop(std::fs::remove_dir_all(&path));
+        std::fs::create_dir_all(&public).unwrap();
+        for (name, contents) in files {
+            std::fs::write(public.join(name), contents).unwrap();
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn streamed_file() {
+        let contents: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+        let path = site("streamed", &[("big.bin", &contents)]);
+        let server = ServerBuilder::default()
+            .path(&path)
+            .with_options(|options| {
+                options.set_streaming_threshold(1024);
+            })
+            .run()
+            .await;
+
+        let response = server.get("big.bin").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-length"], "200000");
+        assert_eq!(response.bytes().await.unwrap(), contents);
+
+        let response = server
+            .get("big.bin")
+            .header("range", "bytes=100000-100009")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()["content-range"],
+            "bytes 100000-100009/200000"
+        );
+        assert_eq!(response.bytes().await.unwrap(), contents[100_000..100_010]);
+
+        let response = server
+            .get("big.bin")
+            .header("range", "bytes=-5,0-9")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        let boundary = response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_owned();
+        let mut expected = Vec::new();
+        for (start, end) in &[(0, 10), (199_995, 200_000)] {
+            expected.extend_from_slice(
+                format!(
+                    "--{}\r\ncontent-type: application/octet-stream\r\n\
+                     content-range: bytes {}-{}/200000\r\n\r\n",
+                    boundary,
+                    start,
+                    end - 1
+                )
+                .as_bytes(),
+            );
+            expected.extend_from_slice(&contents[*start..*end]);
+            expected.extend_from_slice(b"\r\n");
+        }
+        expected.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        assert_eq!(response.bytes().await.unwrap(), expected);
+
+        drop(server);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trickled_head() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = ServerBuilder::default()
+            .http()
+            .with_port(|port| port.header_timeout(std::time::Duration::from_millis(200)))
+            .run()
+            .await;
+        let mut stream = tokio::net::TcpStream::connect(("localhost", server.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nx-slow: ")
+            .await
+            .unwrap();
+        let start = std::time::Instant::now();
+        // Send a byte of the header value every 50ms, never finishing the head.
+        for _ in 0..40 {
+            if stream.write_all(b"a").await.is_err() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            stream.read_to_end(&mut response),
+        )
+        .await
+        .expect("the connection should be closed");
+        // The head was being sent, so the connection isn't closed for being idle.
+        assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+        // The connection can be reset if we wrote after it was closed.
+        if read.is_ok() && !response.is_empty() {
+            let response = String::from_utf8_lossy(&response);
+            assert!(
+                response.starts_with("HTTP/1.1 408 "),
+                "got response {}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn precompressed_siblings() {
+        let text = "hello, hello, hello, hello, hello, hello, hello".as_bytes();
+        let path = site(
+            "precompressed",
+            &[
+                ("stale.txt.gz", b"precompressed gzip"),
+                ("fresh.txt", text),
+                ("fresh.txt.gz", b"precompressed gzip"),
+                ("fresh.txt.br", b"precompressed br"),
+                ("page.shout", text),
+                ("page.shout.gz", b"precompressed gzip"),
+                ("page.header", text),
+                ("page.header.gz", b"precompressed gzip"),
+            ],
+        );
+        // The sibling is older than the file.
+        let stale = path.join("public").join("stale.txt.gz");
+        std::fs::File::options()
+            .write(true)
+            .open(stale)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60))
+            .unwrap();
+        std::fs::write(path.join("public").join("stale.txt"), text).unwrap();
+
+        let mut extensions = Extensions::empty();
+        extensions.add_present_file(
+            "shout".to_owned(),
+            present!(data {
+                let body = data.response().body().to_ascii_uppercase();
+                *data.response_mut().body_mut() = Bytes::from(body);
+            }),
+        );
+        extensions.add_present_file(
+            "header".to_owned(),
+            present!(data {
+                data.headers_mut()
+                    .insert("content-type", HeaderValue::from_static("text/plain"));
+            }),
+        );
+        let server = ServerBuilder::from(extensions).path(&path).run().await;
+        let get = |path: &'static str, encoding: &'static str| {
+            server.get(path).header("accept-encoding", encoding).send()
+        };
+
+        let response = get("fresh.txt", "gzip").await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.bytes().await.unwrap(), "precompressed gzip");
+        let response = get("fresh.txt", "br").await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "br");
+        assert_eq!(response.bytes().await.unwrap(), "precompressed br");
+        let response = get("page.header", "gzip").await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), "precompressed gzip");
+
+        // Compressed by Kvarn instead.
+        let response = get("stale.txt", "gzip").await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.bytes().await.unwrap()[..2], [0x1f, 0x8b]);
+        // The body was changed by the extension.
+        let response = get("page.shout", "gzip").await.unwrap();
+        assert_ne!(response.bytes().await.unwrap(), "precompressed gzip");
+        let response = get("page.shout", "identity").await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), text.to_ascii_uppercase());
+
+        drop(server);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Sends `request` on a new connection and reads the responses for at most 500ms.
+    ///
+    /// Returns the responses and if the server closed the connection.
+    async fn send_raw(server: &super::Server, request: &[u8]) -> (String, bool) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(("localhost", server.port()))
+            .await
+            .unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        let closed = tokio::time::timeout(
+            std::time::Duration::from_millis(500),
+            stream.read_to_end(&mut response),
+        )
+        .await
+        .is_ok();
+        (String::from_utf8(response).unwrap(), closed)
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let mut extensions = Extensions::empty();
+        extensions.add_prepare_single(
+            "/upload".to_owned(),
+            prepare!(req, host, _path, _addr {
+                match req.body_mut().read_to_bytes().await {
+                    Ok(body) => FatResponse::no_cache(Response::new(Bytes::from(
+                        body.len().to_string(),
+                    ))),
+                    Err(_) => default_error_response(StatusCode::BAD_REQUEST, host, None).await,
+                }
+            }),
+        );
+        let server = ServerBuilder::from(extensions)
+            .http()
+            .with_options(|options| {
+                options.set_max_body_size(16);
+            })
+            .run()
+            .await;
+
+        let (response, _) = send_raw(
+            &server,
+            b"POST /upload HTTP/1.1\r\nhost: localhost\r\n\
+              content-length: 16\r\n\r\n0123456789abcdef",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with("\r\n\r\n16"), "{}", response);
+
+        // The second request on the connection is never handled.
+        let oversized = b"POST /upload HTTP/1.1\r\nhost: localhost\r\n\
+              content-length: 17\r\n\r\n0123456789abcdefg\
+              GET /upload HTTP/1.1\r\nhost: localhost\r\n\r\n";
+        let (response, closed) = send_raw(&server, oversized).await;
+        assert!(closed);
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        assert!(
+            response.contains("\r\nconnection: close\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+
+        let chunked = b"POST /upload HTTP/1.1\r\nhost: localhost\r\n\
+              transfer-encoding: chunked\r\n\r\n\
+              10\r\n0123456789abcdef\r\n1\r\ng\r\n0\r\n\r\n\
+              GET /upload HTTP/1.1\r\nhost: localhost\r\n\r\n";
+        let (response, closed) = send_raw(&server, chunked).await;
+        assert!(closed);
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        assert!(
+            response.contains("\r\nconnection: close\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+    }
+
+    #[tokio::test]
+    async fn coalesce_misses() {
+        let mut extensions = Extensions::empty();
+        let cached = counted(&mut extensions, "/cached", ServerCachePreference::Full);
+        let uncached = counted(&mut extensions, "/uncached", ServerCachePreference::None);
+        let server = ServerBuilder::from(extensions).run().await;
+
+        get_concurrently(&server, "cached", 8).await;
+        assert_eq!(cached.load(Ordering::SeqCst), 1);
+        // The waiting requests generate the page themselves.
+        get_concurrently(&server, "uncached", 8).await;
+        assert_eq!(uncached.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn auth_guard() {
+        use kvarn::auth::{Auth, Key};
+
+        let auth = Auth::new(Key::hmac(b"secret"))
+            .guard("/admin/")
+            .with_login(
+                "/login",
+                Box::new(|credentials| {
+                    let valid =
+                        credentials.username() == "admin" && credentials.password() == "hunter2";
+                    extensions::ready(if valid {
+                        Some(credentials.username().to_owned())
+                    } else {
+                        None
+                    })
+                }),
+            )
+            .build();
+        let mut extensions = Extensions::empty();
+        extensions.add_auth(auth);
+        extensions.add_prepare_single(
+            "/admin/page".to_owned(),
+            prepare!(_req, _host, _path, _addr {
+                FatResponse::cache(Response::new(Bytes::from_static(b"secret page")))
+            }),
+        );
+        let server = ServerBuilder::from(extensions).run().await;
+
+        let response = server.get("admin/page").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        let response = server
+            .get("admin/page")
+            .bearer_auth("invalid.token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = server.get("login").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+        let response = server
+            .post("login")
+            .basic_auth("admin", Some("wrong"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let response = server
+            .post("login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("username=admin&password=hunter2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .starts_with("kvarn-auth="));
+        let token = response.text().await.unwrap();
+
+        let response = server
+            .get("admin/page")
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers()["cache-control"]
+            .to_str()
+            .unwrap()
+            .starts_with("private"));
+        assert_eq!(response.text().await.unwrap(), "secret page");
+    }
 }
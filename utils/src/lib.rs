@@ -20,7 +20,9 @@ pub mod parse;
 pub mod prelude;
 use prelude::*;
 
-pub use parse::{list_header, sanitize_request, CriticalRequestComponents, ValueQualitySet};
+pub use parse::{
+    list_header, sanitize_request, ByteRange, CriticalRequestComponents, ValueQualitySet,
+};
 pub use extensions::{PresentArguments, PresentExtensions, PresentArgumentsIter, PresentExtensionsIter};
 
 /// Common characters expressed as a single byte each, according to UTF-8.
@@ -435,6 +437,22 @@ pub fn get_body_length_response<T>(response: &Response<T>, method: Option<&Metho
         0
     }
 }
+/// Checks if the body of a message with `headers` is sent in chunks.
+///
+/// This is the case if `chunked` is the last coding of the `transfer-encoding` header.
+#[must_use]
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .last()
+        .map(HeaderValue::to_str)
+        .and_then(Result::ok)
+        .and_then(|value| value.rsplit(',').next())
+        .map_or(false, |coding| {
+            coding.trim().eq_ignore_ascii_case("chunked")
+        })
+}
 
 /// Does a request of type `method` have a body?
 #[inline]
@@ -45,6 +45,8 @@ pub enum Error {
     IllegalName,
     /// There are illegal bytes in a [`HeaderValue`]
     IllegalValue,
+    /// The data wasn't received in time.
+    Timeout,
 }
 impl Error {
     /// Gets a string representation of [`Error`].
@@ -62,6 +64,7 @@ impl Error {
             Self::Syntax => "invalid syntax of data. The input might unexpectedly be encrypted (HTTPS) or compressed (HTTP/2)",
             Self::IllegalName => "header name invalid",
             Self::IllegalValue => "header value invalid",
+            Self::Timeout => "timed out while reading",
         }
     }
 }
@@ -85,6 +88,7 @@ impl From<Error> for io::Error {
             | Error::IllegalName
             | Error::IllegalValue => io::Error::new(io::ErrorKind::InvalidData, err.as_str()),
             Error::Done => io::Error::new(io::ErrorKind::BrokenPipe, err.as_str()),
+            Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, err.as_str()),
         }
     }
 }
@@ -370,7 +374,7 @@ pub fn list_header(header: &str) -> Vec<ValueQualitySet<'_>> {
 ///
 /// `query` should not contains the `?`, but start the byte after.
 ///
-/// Both the keys and values can be empty.
+/// Both the keys and values can be empty. They aren't percent-decoded.
 ///
 /// > **Note:** if multiple of the same keys only the last will be present.
 #[must_use]
@@ -380,33 +384,12 @@ pub fn query(query: &str) -> HashMap<&str, &str> {
         .fold(1, |acc, byte| if byte == '&' { acc + 1 } else { acc });
     let mut map = HashMap::with_capacity(elements);
 
-    let mut pair_start = 0;
-    let mut value_start = 0;
-    for (position, byte) in query.char_indices() {
-        match byte {
-            '=' => {
-                value_start = position + 1;
-            }
-            '&' => {
-                let key = query.get(pair_start..value_start);
-                let value = query.get(value_start..position);
-
-                if let (Some(key), Some(value)) = (key, value) {
-                    map.insert(key, value);
-                }
-
-                pair_start = position + 1;
-            }
-            _ => {}
-        }
-    }
-    {
-        let key = query.get(pair_start..value_start - 1);
-        let value = query.get(value_start..);
-
-        if let (Some(key), Some(value)) = (key, value) {
-            map.insert(key, value);
-        }
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(separator) => (&pair[..separator], &pair[separator + 1..]),
+            None => (pair, ""),
+        };
+        map.insert(key, value);
     }
     map
 }
@@ -428,52 +411,275 @@ pub fn uri(path: &str) -> Option<&Path> {
 
     Some(Path::new(stripped_path))
 }
+/// A range of bytes requested by the `range` header.
+///
+/// See [RFC 7233](https://www.rfc-editor.org/rfc/rfc7233#section-2.1) for more info.
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=<first>-<last>`, where `last` is inclusive.
+    FromTo(u64, u64),
+    /// `bytes=<first>-`; from `first` to the end.
+    From(u64),
+    /// `bytes=-<length>`; the last `length` bytes.
+    Suffix(u64),
+}
+impl ByteRange {
+    /// Parses a single `byte-range-spec`, such as `500-` or `-500`.
+    ///
+    /// Returns [`None`] if the range is invalid, including when the last byte is before the first.
+    #[must_use]
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (first, last) = spec.trim().split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        match (first.is_empty(), last.is_empty()) {
+            (true, true) => None,
+            (true, false) => last.parse().ok().map(Self::Suffix),
+            (false, true) => first.parse().ok().map(Self::From),
+            (false, false) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                if last < first {
+                    None
+                } else {
+                    Some(Self::FromTo(first, last))
+                }
+            }
+        }
+    }
+    /// Gets the start and (exclusive) end of this range in a body of `len` bytes.
+    ///
+    /// The end is clamped to `len`.
+    /// Returns [`None`] if the range isn't satisfiable.
+    #[must_use]
+    pub fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self {
+            Self::FromTo(first, last) => (first, last.saturating_add(1).min(len)),
+            Self::From(first) => (first, len),
+            Self::Suffix(length) => (len.saturating_sub(length), len),
+        };
+        if start < end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+/// The body of a `multipart/byteranges` response, without the data of the ranges.
+///
+/// The body is the data of each range prefixed by the corresponding [`Self::heads`],
+/// followed by the [`Self::tail`].
+///
+/// See [RFC 7233](https://www.rfc-editor.org/rfc/rfc7233#appendix-A) for more info.
+#[derive(Debug)]
+pub struct MultipartByteranges {
+    /// The `content-type` of the response, containing the boundary.
+    pub content_type: HeaderValue,
+    /// The boundary and headers sent before the data of each range.
+    pub heads: Vec<Bytes>,
+    /// The closing boundary.
+    pub tail: Bytes,
+}
+impl MultipartByteranges {
+    /// Creates the parts for the `ranges` of a body of `len` bytes.
+    /// Each part gets the `content_type` of the whole body.
+    pub fn new(ranges: &[(u64, u64)], len: u64, content_type: Option<&HeaderValue>) -> Self {
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        // The boundary has to be unlikely to appear in the body.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.subsec_nanos());
+        let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let boundary = format!("kvarn-byteranges-{:08x}{:x}", nanos, count);
+
+        let heads = ranges
+            .iter()
+            .enumerate()
+            .map(|(index, (start, end))| {
+                let mut head = BytesMut::with_capacity(128);
+                if index != 0 {
+                    head.extend_from_slice(b"\r\n");
+                }
+                head.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                if let Some(content_type) = content_type {
+                    head.extend_from_slice(b"content-type: ");
+                    head.extend_from_slice(content_type.as_bytes());
+                    head.extend_from_slice(b"\r\n");
+                }
+                head.extend_from_slice(b"content-range: ");
+                head.extend_from_slice(&content_range(*start, *end, len));
+                head.extend_from_slice(b"\r\n\r\n");
+                head.freeze()
+            })
+            .collect();
+        let tail = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+        // The boundary only contains valid characters.
+        let content_type =
+            HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap();
+        Self {
+            content_type,
+            heads,
+            tail,
+        }
+    }
+    /// The length of the body, excluding the data of the ranges.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.heads.iter().map(Bytes::len).sum::<usize>() + self.tail.len()
+    }
+    /// If there are no parts.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.heads.is_empty()
+    }
+}
+/// Formats the value of a `content-range` header for the bytes from
+/// `start` to (exclusive) `end` of a body of `len` bytes.
+#[must_use]
+pub fn content_range(start: u64, end: u64, len: u64) -> Bytes {
+    build_bytes!(
+        b"bytes ",
+        start.to_string().as_bytes(),
+        b"-",
+        (end - 1).to_string().as_bytes(),
+        b"/",
+        len.to_string().as_bytes()
+    )
+}
+
 /// Critical components from request to apply to response.
 #[must_use]
 #[derive(Debug)]
 pub struct CriticalRequestComponents {
-    range: Option<(usize, usize)>,
+    ranges: Vec<ByteRange>,
+    if_range: Option<HeaderValue>,
 }
 impl CriticalRequestComponents {
+    /// The maximum number of ranges in a request. If more are requested, the `range` header is ignored.
+    pub const MAX_RANGES: usize = 32;
+
     /// Applies the critical components' info to the `response`.
     ///
-    /// For now applies range and replaces the `accept-ranges` header.
-    pub async fn apply_to_response(&self, response: &mut Response<Bytes>) {
-        if let Some((range_start, mut range_end)) = self.get_range() {
-            // Clamp to length
-            if range_end >= response.body().len() {
-                range_end = response.body().len();
+    /// For now applies the ranges and replaces the `accept-ranges` header.
+    /// Multiple ranges are sent as a `multipart/byteranges` body.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SanitizeError::RangeNotSatisfiable`] if none of the ranges are in the body.
+    /// The response should then be replaced by a [`StatusCode::RANGE_NOT_SATISFIABLE`]
+    /// with a `content-range` of `bytes */<len>`.
+    pub async fn apply_to_response(
+        &self,
+        response: &mut Response<Bytes>,
+    ) -> Result<(), SanitizeError> {
+        let len = response.body().len() as u64;
+        let ranges = if let Some(ranges) = self.get_ranges(response, len)? {
+            ranges
+        } else {
+            if !response.body().is_empty() {
+                replace_header_static(response.headers_mut(), "accept-ranges", "bytes");
             }
-
-            let len = response.body().len().to_string();
-            let start = range_start.to_string();
-            let end = (range_end - 1).to_string();
-            let bytes = build_bytes!(start.as_bytes(), b"-", end.as_bytes(), b"/", len.as_bytes());
-
+            return Ok(());
+        };
+        // The ranges are within the body, which fits in an `usize`.
+        #[allow(clippy::cast_possible_truncation)]
+        let slice =
+            |body: &Bytes, (start, end): (u64, u64)| body.slice(start as usize..end as usize);
+        #[allow(clippy::cast_possible_truncation)]
+        let ranges_len = ranges.iter().map(|(start, end)| (end - start) as usize);
+        let body = if let [range] = ranges[..] {
             crate::replace_header(
                 response.headers_mut(),
                 "content-range",
                 // We know integers, b"-", and b"/" are OK!
-                HeaderValue::from_maybe_shared(bytes).unwrap(),
+                HeaderValue::from_maybe_shared(content_range(range.0, range.1, len)).unwrap(),
             );
-
-            let body = response.body().slice(range_start..range_end);
-            *response.body_mut() = body;
-            if response.status() == StatusCode::OK {
-                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            slice(response.body(), range)
+        } else {
+            let parts =
+                MultipartByteranges::new(&ranges, len, response.headers().get("content-type"));
+            let mut body = BytesMut::with_capacity(parts.len() + ranges_len.sum::<usize>());
+            for (head, range) in parts.heads.iter().zip(ranges.iter().copied()) {
+                body.extend_from_slice(head);
+                body.extend_from_slice(&slice(response.body(), range));
             }
-        } else if !response.body().is_empty() {
-            replace_header_static(response.headers_mut(), "accept-ranges", "bytes")
+            body.extend_from_slice(&parts.tail);
+            crate::replace_header(response.headers_mut(), "content-type", parts.content_type);
+            body.freeze()
+        };
+        *response.body_mut() = body;
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        Ok(())
+    }
+    /// Get the ranges wanted by the request.
+    #[inline]
+    pub fn ranges(&self) -> &[ByteRange] {
+        &self.ranges
+    }
+    /// Gets the start and (exclusive) end of the satisfiable ranges
+    /// for the `response` with a body of `len` bytes.
+    /// The ranges are sorted, and overlapping or adjacent ranges are merged.
+    ///
+    /// Returns [`None`] if the whole body should be sent.
+    /// This is the case if no ranges were requested, the `response` isn't a
+    /// [`StatusCode::OK`], or the `if-range` of the request doesn't match the `response`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SanitizeError::RangeNotSatisfiable`] if none of the ranges are in the body.
+    pub fn get_ranges<T>(
+        &self,
+        response: &Response<T>,
+        len: u64,
+    ) -> Result<Option<Vec<(u64, u64)>>, SanitizeError> {
+        if self.ranges.is_empty()
+            || response.status() != StatusCode::OK
+            || !self.if_range_matches(response)
+        {
+            return Ok(None);
+        }
+        let mut ranges: Vec<_> = self
+            .ranges
+            .iter()
+            .filter_map(|range| range.resolve(len))
+            .collect();
+        // Coalesce overlapping and adjacent ranges, so no part of the body is sent more than once.
+        ranges.sort_unstable();
+        ranges.dedup_by(|next, previous| {
+            if next.0 <= previous.1 {
+                previous.1 = previous.1.max(next.1);
+                true
+            } else {
+                false
+            }
+        });
+        if ranges.is_empty() {
+            Err(SanitizeError::RangeNotSatisfiable)
+        } else {
+            Ok(Some(ranges))
         }
     }
-    /// Get the range wanted by the request.
+    /// Checks the `if-range` of the request against the `etag` or `last-modified` of `response`.
     ///
-    /// The first value is the start and the second is the end.
-    /// Both are relative to the start of the data.
-    #[inline]
-    #[must_use]
-    pub fn get_range(&self) -> Option<(usize, usize)> {
-        self.range
+    /// Returns `true` if the request has no `if-range`.
+    fn if_range_matches<T>(&self, response: &Response<T>) -> bool {
+        let if_range = match &self.if_range {
+            Some(if_range) => if_range.as_bytes(),
+            None => return true,
+        };
+        // Weak entity tags never match.
+        if if_range.starts_with(b"W/") {
+            false
+        } else if if_range.starts_with(b"\"") {
+            response
+                .headers()
+                .get("etag")
+                .map_or(false, |etag| etag.as_bytes() == if_range)
+        } else {
+            response
+                .headers()
+                .get("last-modified")
+                .map_or(false, |last_modified| last_modified.as_bytes() == if_range)
+        }
     }
 }
 /// An error regarding the sanitization of a request.
@@ -486,16 +692,41 @@ pub enum SanitizeError {
     ///
     /// This occurs when the path is absolute or contains `./`.
     UnsafePath,
-    /// The range is too large or the beginning is greater than the end.
+    /// None of the requested ranges are in the body.
+    ///
+    /// Returned by [`CriticalRequestComponents::apply_to_response`].
     RangeNotSatisfiable,
 }
 
+/// Parses the value of a `range` header.
+///
+/// Returns [`None`] if the unit isn't `bytes`, any of the ranges is invalid,
+/// or there are more than [`CriticalRequestComponents::MAX_RANGES`] ranges.
+#[must_use]
+pub fn ranges(value: &str) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let ranges = specs
+        .split(',')
+        .filter(|spec| !spec.trim().is_empty())
+        .map(ByteRange::parse)
+        .collect::<Option<Vec<_>>>()?;
+    if ranges.is_empty() || ranges.len() > CriticalRequestComponents::MAX_RANGES {
+        None
+    } else {
+        Some(ranges)
+    }
+}
+
 /// Sanitizes `request` for unwanted data and returns critical components.
 ///
+/// The `range` header is only used on `GET` requests, and is ignored if it's invalid.
+///
 /// # Errors
 ///
 /// Will alert you when the request's path contains a `./` or [`Path::is_absolute()`].
-/// Also rejects ranges which have a start after the end.
 ///
 /// See [`SanitizeError`] for all the variants.
 pub fn sanitize_request<T>(
@@ -509,28 +740,21 @@ pub fn sanitize_request<T>(
     if !path_ok {
         return Err(SanitizeError::UnsafePath);
     }
-    let range = request.headers().get("range").and_then(|v| {
-        let v = v.to_str().ok()?;
-        if !v.starts_with("bytes=") {
-            return None;
-        }
-        if v.contains(|c| c == ',' || c == ' ') {
-            return None;
-        }
-        let separator = v.find('-')?;
-        let first: usize = v.get(6..separator)?.parse().ok()?;
-        let second: usize = v.get(separator + 1..)?.parse().ok()?;
-        Some((first, second))
-    });
-    let mut data = CriticalRequestComponents { range: None };
-
-    if let Some((start, end)) = range {
-        if start >= end {
-            return Err(SanitizeError::RangeNotSatisfiable);
-        }
-        data.range = Some((start, end + 1))
-    }
-    Ok(data)
+    let requested = if request.method() == Method::GET {
+        request
+            .headers()
+            .get("range")
+            .map(HeaderValue::to_str)
+            .and_then(Result::ok)
+            .and_then(ranges)
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    Ok(CriticalRequestComponents {
+        ranges: requested,
+        if_range: request.headers().get("if-range").cloned(),
+    })
 }
 
 /// Parses a [`Version`].
@@ -645,3 +869,145 @@ pub fn headers(bytes: &Bytes) -> Result<(HeaderMap, usize), Error> {
     }
     Ok((headers, header_end))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(range: &str) -> CriticalRequestComponents {
+        let request = Request::get("/").header("range", range).body(()).unwrap();
+        sanitize_request(&request).unwrap()
+    }
+    fn response(body: &'static [u8]) -> Response<Bytes> {
+        Response::builder()
+            .header("content-type", "text/plain")
+            .body(Bytes::from_static(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_byte_range() {
+        assert_eq!(ByteRange::parse("0-499"), Some(ByteRange::FromTo(0, 499)));
+        assert_eq!(ByteRange::parse(" 9500- "), Some(ByteRange::From(9500)));
+        assert_eq!(ByteRange::parse("-500"), Some(ByteRange::Suffix(500)));
+        assert_eq!(ByteRange::parse("5-5"), Some(ByteRange::FromTo(5, 5)));
+        assert_eq!(ByteRange::parse("500-499"), None);
+        assert_eq!(ByteRange::parse("-"), None);
+        assert_eq!(ByteRange::parse("500"), None);
+        assert_eq!(ByteRange::parse("a-b"), None);
+        assert_eq!(ByteRange::parse("--5"), None);
+    }
+    #[test]
+    fn resolve_byte_range() {
+        assert_eq!(ByteRange::FromTo(0, 499).resolve(10_000), Some((0, 500)));
+        assert_eq!(ByteRange::FromTo(0, 499).resolve(100), Some((0, 100)));
+        assert_eq!(ByteRange::FromTo(0, u64::MAX).resolve(100), Some((0, 100)));
+        assert_eq!(ByteRange::FromTo(100, 199).resolve(100), None);
+        assert_eq!(ByteRange::From(9500).resolve(10_000), Some((9500, 10_000)));
+        assert_eq!(ByteRange::From(10_000).resolve(10_000), None);
+        assert_eq!(ByteRange::Suffix(500).resolve(10_000), Some((9500, 10_000)));
+        assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 100)));
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+    }
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(
+            ranges("bytes=0-0, -1,5-"),
+            Some(vec![
+                ByteRange::FromTo(0, 0),
+                ByteRange::Suffix(1),
+                ByteRange::From(5)
+            ])
+        );
+        assert_eq!(ranges("BYTES=1-2"), Some(vec![ByteRange::FromTo(1, 2)]));
+        assert_eq!(ranges("items=1-2"), None);
+        assert_eq!(ranges("bytes=1-2,3"), None);
+        assert_eq!(ranges("bytes="), None);
+        let many = format!("bytes={}", vec!["0-1"; 33].join(","));
+        assert_eq!(ranges(&many), None);
+    }
+    #[test]
+    fn parse_query() {
+        let parsed = query("a=1&b=&c&&d=x=y&a=2");
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(parsed.get("a"), Some(&"2"));
+        assert_eq!(parsed.get("b"), Some(&""));
+        assert_eq!(parsed.get("c"), Some(&""));
+        assert_eq!(parsed.get("d"), Some(&"x=y"));
+        assert!(query("").is_empty());
+    }
+    #[test]
+    fn coalesce_ranges() {
+        let response = response(b"0123456789");
+        let get = |range| components(range).get_ranges(&response, 10);
+        assert_eq!(get("bytes=5-7,0-1").unwrap(), Some(vec![(0, 2), (5, 8)]));
+        // Overlapping.
+        assert_eq!(
+            get("bytes=0-4,2-6,-2").unwrap(),
+            Some(vec![(0, 7), (8, 10)])
+        );
+        // Adjacent.
+        assert_eq!(get("bytes=0-1,2-3").unwrap(), Some(vec![(0, 4)]));
+        // The same range many times over is only sent once.
+        let many = format!("bytes={}", vec!["0-"; 32].join(","));
+        assert_eq!(get(&many).unwrap(), Some(vec![(0, 10)]));
+        // Unsatisfiable ranges are skipped.
+        assert_eq!(get("bytes=20-30,1-1").unwrap(), Some(vec![(1, 2)]));
+        assert!(matches!(
+            get("bytes=20-30"),
+            Err(SanitizeError::RangeNotSatisfiable)
+        ));
+    }
+    #[test]
+    fn if_range() {
+        let mut response = response(b"0123456789");
+        response
+            .headers_mut()
+            .insert("etag", HeaderValue::from_static("\"a\""));
+        let get = |if_range| {
+            let request = Request::get("/")
+                .header("range", "bytes=0-1")
+                .header("if-range", if_range)
+                .body(())
+                .unwrap();
+            sanitize_request(&request)
+                .unwrap()
+                .get_ranges(&response, 10)
+                .unwrap()
+        };
+        assert_eq!(get("\"a\""), Some(vec![(0, 2)]));
+        assert_eq!(get("\"b\""), None);
+        assert_eq!(get("W/\"a\""), None);
+    }
+    #[tokio::test]
+    async fn single_range_response() {
+        let mut response = response(b"0123456789");
+        components("bytes=-3")
+            .apply_to_response(&mut response)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 7-9/10");
+        assert_eq!(response.body(), "789");
+    }
+    #[tokio::test]
+    async fn multipart_response() {
+        let mut response = response(b"0123456789");
+        components("bytes=6-7,0-1")
+            .apply_to_response(&mut response)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers()["content-type"].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 6-7/10\r\n\r\n67\r\n\
+             --{0}--\r\n",
+            boundary
+        );
+        assert_eq!(response.body(), expected.as_bytes());
+    }
+}